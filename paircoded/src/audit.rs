@@ -0,0 +1,101 @@
+//! Append-only audit log of spawned terminal commands.
+//!
+//! Each spawned command is recorded as a single line of JSON. The file is opened
+//! with `O_APPEND` for every record so concurrent writers never interleave lines,
+//! and is created with mode 0600 on Unix.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A single audit record for a spawned command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    /// User who owns the session
+    pub user: String,
+    /// PID of the spawned process
+    pub pid: u32,
+    /// Working directory of the spawned process
+    #[serde(rename = "workingDir")]
+    pub working_dir: String,
+    /// Full command line that was spawned
+    pub command: String,
+}
+
+impl AuditRecord {
+    /// Create a record timestamped with the current time
+    pub fn now(user: &str, pid: u32, working_dir: &Path, command: String) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        AuditRecord {
+            timestamp,
+            user: user.to_string(),
+            pid,
+            working_dir: working_dir.display().to_string(),
+            command,
+        }
+    }
+}
+
+/// Append a record to the audit log at `path`
+pub fn append_record(path: &Path, record: &AuditRecord) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to open audit log {}", path.display()))?;
+
+    // A single write keeps the record atomic under O_APPEND
+    file.write_all(&line)
+        .with_context(|| format!("failed to write audit log {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+
+        let first = AuditRecord::now("alice", 42, Path::new("/tmp"), "/bin/sh".to_string());
+        let second = AuditRecord::now("alice", 43, Path::new("/tmp"), "/bin/sh -c ls".to_string());
+        append_record(&path, &first).unwrap();
+        append_record(&path, &second).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<AuditRecord> = content
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].pid, 42);
+        assert_eq!(records[1].command, "/bin/sh -c ls");
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -3,7 +3,7 @@
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tracing::{info, warn};
 
@@ -11,6 +11,12 @@ use tracing::{info, warn};
 /// This is a public client ID for the Device Flow
 const GITHUB_CLIENT_ID: &str = "Ov23liJOmsIBB3qHy0x6";
 
+/// GitHub REST API base URL
+const GITHUB_API_URL: &str = "https://api.github.com";
+
+/// Environment variable holding a pre-existing GitHub token (for CI)
+pub const GITHUB_TOKEN_ENV: &str = "PAIRCODED_GITHUB_TOKEN";
+
 /// Stored authentication data
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthData {
@@ -66,72 +72,164 @@ struct RelayErrorResponse {
 }
 
 /// Get the config directory for paircoded
-fn config_dir() -> Result<PathBuf> {
+pub fn config_dir() -> Result<PathBuf> {
     let dir = dirs::config_dir()
         .ok_or_else(|| anyhow!("could not determine config directory"))?
         .join("paircoded");
     Ok(dir)
 }
 
-/// Get the path to the auth file
-fn auth_file_path() -> Result<PathBuf> {
-    Ok(config_dir()?.join("auth.json"))
+/// Validate a `--profile` name, which becomes part of file names
+pub fn parse_profile(name: &str) -> Result<String, String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(name.to_string())
+    } else {
+        Err("profile names may only contain letters, digits, '-' and '_'".to_string())
+    }
+}
+
+/// File name scoped to a profile: `<stem>.<ext>` for the default profile,
+/// `<stem>-<profile>.<ext>` otherwise
+pub fn profile_file_name(stem: &str, ext: &str, profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{}-{}.{}", stem, profile, ext),
+        None => format!("{}.{}", stem, ext),
+    }
+}
+
+/// Get the path to the auth file for a profile
+pub fn auth_file_path(profile: Option<&str>) -> Result<PathBuf> {
+    Ok(config_dir()?.join(profile_file_name("auth", "json", profile)))
+}
+
+/// Load saved authentication data for a profile
+pub fn load_auth(profile: Option<&str>) -> Result<Option<AuthData>> {
+    load_auth_from(&auth_file_path(profile)?)
 }
 
-/// Load saved authentication data
-pub fn load_auth() -> Result<Option<AuthData>> {
-    let path = auth_file_path()?;
+/// Load saved authentication data from a specific file
+///
+/// A file that doesn't parse (partial write, manual edit) counts as no saved
+/// auth; it is moved aside to `<name>.bak` so the next login can replace it.
+/// Read errors are still returned.
+fn load_auth_from(path: &Path) -> Result<Option<AuthData>> {
     if !path.exists() {
         return Ok(None);
     }
 
-    let content = fs::read_to_string(&path)?;
-    let auth: AuthData = serde_json::from_str(&content)?;
-    Ok(Some(auth))
+    let content = fs::read_to_string(path)?;
+    match serde_json::from_str(&content) {
+        Ok(auth) => Ok(Some(auth)),
+        Err(e) => {
+            let backup = path.with_extension("json.bak");
+            warn!(path = %path.display(), error = %e, "saved auth is corrupt, logging in again");
+            match fs::rename(path, &backup) {
+                Ok(()) => info!(backup = %backup.display(), "moved corrupt auth file aside"),
+                Err(e) => warn!(error = %e, "failed to back up corrupt auth file"),
+            }
+            Ok(None)
+        }
+    }
 }
 
-/// Save authentication data
-fn save_auth(auth: &AuthData) -> Result<()> {
-    let dir = config_dir()?;
-    fs::create_dir_all(&dir)?;
+/// Save authentication data to a specific file
+fn save_auth_to(path: &Path, auth: &AuthData) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
 
-    let path = auth_file_path()?;
     let content = serde_json::to_string_pretty(auth)?;
-    fs::write(&path, content)?;
+    fs::write(path, content)?;
 
     // Set file permissions to 0600 (owner read/write only) on Unix
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(&path)?.permissions();
+        let mut perms = fs::metadata(path)?.permissions();
         perms.set_mode(0o600);
-        fs::set_permissions(&path, perms)?;
+        fs::set_permissions(path, perms)?;
     }
 
     info!(?path, "saved authentication data");
     Ok(())
 }
 
-/// Clear saved authentication data
-pub fn clear_auth() -> Result<()> {
-    let path = auth_file_path()?;
+/// Clear saved authentication data at a specific file
+fn clear_auth_at(path: &Path) -> Result<()> {
     if path.exists() {
-        fs::remove_file(&path)?;
+        fs::remove_file(path)?;
         info!(?path, "cleared authentication data");
     }
     Ok(())
 }
 
+/// Which identity provider to authenticate with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum AuthProviderKind {
+    /// GitHub Device Flow
+    #[default]
+    Github,
+    /// Generic OpenID Connect (not implemented yet, so not offered on the command line)
+    #[value(skip)]
+    Oidc,
+}
+
+/// An identity provider whose access token is exchanged for a relay JWT.
+///
+/// Persistence of the resulting `AuthData` is handled by `get_auth`, so
+/// providers only deal with talking to their identity service.
+#[allow(async_fn_in_trait)] // only used within this crate
+pub trait AuthProvider {
+    /// Perform an interactive login and return fresh credentials
+    async fn login(&self) -> Result<AuthData>;
+
+    /// Check whether saved credentials are still valid
+    async fn validate(&self, auth: &AuthData) -> Result<bool>;
+
+    /// Login name of the authenticated user
+    fn user_login<'a>(&self, auth: &'a AuthData) -> &'a str {
+        &auth.user.login
+    }
+}
+
+/// GitHub Device Flow provider
+pub struct GitHubProvider;
+
+impl AuthProvider for GitHubProvider {
+    async fn login(&self) -> Result<AuthData> {
+        device_flow_login().await
+    }
+
+    async fn validate(&self, auth: &AuthData) -> Result<bool> {
+        validate_token(auth).await
+    }
+}
+
+/// GitHub login (OAuth) base URL
+const GITHUB_LOGIN_URL: &str = "https://github.com";
+
+/// Shortest poll interval GitHub allows for the device flow
+const DEVICE_FLOW_MIN_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Perform GitHub Device Flow authentication
 pub async fn device_flow_login() -> Result<AuthData> {
-    let client = reqwest::Client::new();
+    device_flow_login_with(GITHUB_LOGIN_URL, GITHUB_API_URL, DEVICE_FLOW_MIN_INTERVAL).await
+}
+
+/// Device flow against `login_base`/`api_base`, polling no faster than `min_interval`
+async fn device_flow_login_with(login_base: &str, api_base: &str, min_interval: Duration) -> Result<AuthData> {
+    let client = crate::tls::http_client()?;
 
     // Step 1: Request device code
     println!();
     println!("  Authenticating with GitHub...");
 
     let device_resp: DeviceCodeResponse = client
-        .post("https://github.com/login/device/code")
+        .post(format!("{}/login/device/code", login_base))
         .header("Accept", "application/json")
         .form(&[
             ("client_id", GITHUB_CLIENT_ID),
@@ -149,31 +247,24 @@ pub async fn device_flow_login() -> Result<AuthData> {
     println!();
     println!("  Waiting for authorization...");
 
-    // Step 3: Poll for token
-    let interval = Duration::from_secs(device_resp.interval.max(5));
-    let mut attempts = 0;
-    let max_attempts = (device_resp.expires_in / device_resp.interval.max(5)) as u32;
+    // Step 3: Poll for token until the device code expires
+    let interval = Duration::from_secs(device_resp.interval).max(min_interval);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(device_resp.expires_in);
 
     let token_resp = loop {
-        attempts += 1;
-        if attempts > max_attempts {
+        tokio::time::sleep(interval).await;
+        if tokio::time::Instant::now() >= deadline {
             return Err(anyhow!("authorization timed out"));
         }
 
-        tokio::time::sleep(interval).await;
-
-        let resp: TokenResponse = client
-            .post("https://github.com/login/oauth/access_token")
-            .header("Accept", "application/json")
-            .form(&[
-                ("client_id", GITHUB_CLIENT_ID),
-                ("device_code", &device_resp.device_code),
-                ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
-            ])
-            .send()
-            .await?
-            .json()
-            .await?;
+        // A failed poll is retried on the next tick; only the deadline ends the flow
+        let resp: TokenResponse = match poll_device_token(&client, login_base, &device_resp.device_code).await {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!(error = %e, "device flow poll failed, retrying");
+                continue;
+            }
+        };
 
         if let Some(ref error) = resp.error {
             match error.as_str() {
@@ -199,7 +290,7 @@ pub async fn device_flow_login() -> Result<AuthData> {
 
     // Step 4: Get user info
     let user: GitHubUser = client
-        .get("https://api.github.com/user")
+        .get(format!("{}/user", api_base))
         .header("Authorization", format!("Bearer {}", access_token))
         .header("User-Agent", "paircoded")
         .header("Accept", "application/vnd.github.v3+json")
@@ -211,25 +302,36 @@ pub async fn device_flow_login() -> Result<AuthData> {
     println!("  Logged in as: {}", user.login);
     println!();
 
-    let auth = AuthData {
+    Ok(AuthData {
         access_token,
         token_type,
         scope,
         user,
-    };
-
-    // Save for future use
-    save_auth(&auth)?;
+    })
+}
 
-    Ok(auth)
+/// One poll of the device flow token endpoint
+async fn poll_device_token(client: &reqwest::Client, login_base: &str, device_code: &str) -> Result<TokenResponse> {
+    Ok(client
+        .post(format!("{}/login/oauth/access_token", login_base))
+        .header("Accept", "application/json")
+        .form(&[
+            ("client_id", GITHUB_CLIENT_ID),
+            ("device_code", device_code),
+            ("grant_type", "urn:ietf:params:oauth:grant-type:device_code"),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?)
 }
 
 /// Validate that a saved token is still valid
 pub async fn validate_token(auth: &AuthData) -> Result<bool> {
-    let client = reqwest::Client::new();
+    let client = crate::tls::http_client()?;
 
     let resp = client
-        .get("https://api.github.com/user")
+        .get(format!("{}/user", GITHUB_API_URL))
         .header("Authorization", format!("Bearer {}", auth.access_token))
         .header("User-Agent", "paircoded")
         .header("Accept", "application/vnd.github.v3+json")
@@ -247,32 +349,98 @@ pub async fn validate_token(auth: &AuthData) -> Result<bool> {
     }
 }
 
+/// Build authentication from a pre-existing GitHub token (never persisted)
+async fn auth_from_token(api_base: &str, token: &str) -> Result<AuthData> {
+    let client = crate::tls::http_client()?;
+
+    let resp = client
+        .get(format!("{}/user", api_base))
+        .header("Authorization", format!("Bearer {}", token))
+        .header("User-Agent", "paircoded")
+        .header("Accept", "application/vnd.github.v3+json")
+        .send()
+        .await?;
+
+    if resp.status() == reqwest::StatusCode::UNAUTHORIZED {
+        return Err(anyhow!("the supplied GitHub token is invalid (401 Unauthorized)"));
+    }
+    if !resp.status().is_success() {
+        return Err(anyhow!("failed to fetch GitHub user with supplied token: HTTP {}", resp.status()));
+    }
+
+    let user: GitHubUser = resp.json().await?;
+    info!(user = %user.login, "using supplied GitHub token");
+
+    Ok(AuthData {
+        access_token: token.to_string(),
+        token_type: "bearer".to_string(),
+        scope: String::new(),
+        user,
+    })
+}
+
 /// Get authentication, loading from disk or prompting for login
-pub async fn get_auth(force_login: bool) -> Result<AuthData> {
-    // If force_login, always do device flow
-    if force_login {
-        return device_flow_login().await;
+///
+/// Saved auth is kept per `profile` (`None` is the default `auth.json`).
+/// A `github_token` (from `--github-token` or `PAIRCODED_GITHUB_TOKEN`) skips
+/// both the saved auth file and device flow. With `skip_validation`
+/// (`--skip-token-validation`) saved auth is used without a network check.
+pub async fn get_auth(
+    provider: AuthProviderKind,
+    profile: Option<&str>,
+    force_login: bool,
+    skip_validation: bool,
+    github_token: Option<&str>,
+) -> Result<AuthData> {
+    match provider {
+        AuthProviderKind::Github => {
+            if let Some(token) = github_token {
+                return auth_from_token(GITHUB_API_URL, token).await;
+            }
+            get_auth_with(&GitHubProvider, &auth_file_path(profile)?, force_login, skip_validation).await
+        }
+        AuthProviderKind::Oidc => Err(anyhow!(
+            "the oidc auth provider is not supported yet; use --auth-provider github"
+        )),
     }
+}
 
-    // Try to load existing auth
-    if let Some(auth) = load_auth()? {
-        // Validate token is still good
-        if validate_token(&auth).await? {
-            info!(user = %auth.user.login, "using saved authentication");
-            return Ok(auth);
-        } else {
-            // Token expired, clear and re-login
-            clear_auth()?;
+/// Get authentication from `provider`, persisting it at `auth_path`
+async fn get_auth_with<P: AuthProvider>(
+    provider: &P,
+    auth_path: &Path,
+    force_login: bool,
+    skip_validation: bool,
+) -> Result<AuthData> {
+    if !force_login {
+        // Try to load existing auth
+        if let Some(auth) = load_auth_from(auth_path)? {
+            if skip_validation {
+                info!(user = %provider.user_login(&auth), "using saved authentication without validation");
+                return Ok(auth);
+            }
+            // Validate token is still good
+            if provider.validate(&auth).await? {
+                info!(user = %provider.user_login(&auth), "using saved authentication");
+                return Ok(auth);
+            } else {
+                // Token expired, clear and re-login
+                clear_auth_at(auth_path)?;
+            }
         }
     }
 
-    // No valid auth, need to login
-    device_flow_login().await
+    // No valid auth (or forced), need to login
+    let auth = provider.login().await?;
+
+    // Save for future use
+    save_auth_to(auth_path, &auth)?;
+    Ok(auth)
 }
 
 /// Get a relay JWT token by exchanging the GitHub token
 pub async fn get_relay_token(relay_base_url: &url::Url, github_token: &str) -> Result<String> {
-    let client = reqwest::Client::new();
+    let client = crate::tls::http_client()?;
 
     // Build the token endpoint URL
     let mut token_url = relay_base_url.clone();
@@ -311,3 +479,255 @@ pub async fn get_relay_token(relay_base_url: &url::Url, github_token: &str) -> R
         Err(anyhow!("failed to get relay token: {} ({})", error_resp.error, error_resp.code))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Provider that logs in without any network and counts calls
+    struct MockProvider {
+        valid: bool,
+        logins: AtomicUsize,
+        validations: AtomicUsize,
+    }
+
+    impl MockProvider {
+        fn new(valid: bool) -> Self {
+            MockProvider {
+                valid,
+                logins: AtomicUsize::new(0),
+                validations: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl AuthProvider for MockProvider {
+        async fn login(&self) -> Result<AuthData> {
+            let n = self.logins.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(AuthData {
+                access_token: format!("mock-token-{}", n),
+                token_type: "bearer".to_string(),
+                scope: String::new(),
+                user: GitHubUser {
+                    id: 7,
+                    login: "mock-user".to_string(),
+                    name: None,
+                    avatar_url: String::new(),
+                },
+            })
+        }
+
+        async fn validate(&self, _auth: &AuthData) -> Result<bool> {
+            self.validations.fetch_add(1, Ordering::SeqCst);
+            Ok(self.valid)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_auth_with_mock_provider() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.json");
+        let provider = MockProvider::new(true);
+
+        // No saved auth: logs in and persists
+        let auth = get_auth_with(&provider, &path, false, false).await.unwrap();
+        assert_eq!(provider.user_login(&auth), "mock-user");
+        assert_eq!(auth.access_token, "mock-token-1");
+        assert!(path.exists());
+
+        // Saved auth is validated and reused
+        let auth = get_auth_with(&provider, &path, false, false).await.unwrap();
+        assert_eq!(auth.access_token, "mock-token-1");
+        assert_eq!(provider.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(provider.validations.load(Ordering::SeqCst), 1);
+
+        // Forced login skips validation
+        let auth = get_auth_with(&provider, &path, true, false).await.unwrap();
+        assert_eq!(auth.access_token, "mock-token-2");
+        assert_eq!(provider.validations.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_get_auth_with_corrupt_saved_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.json");
+        fs::write(&path, r#"{"access_token": "trunc"#).unwrap();
+        assert!(load_auth_from(&path).unwrap().is_none());
+        assert!(dir.path().join("auth.json.bak").exists());
+
+        // Falls back to logging in instead of failing
+        fs::write(&path, "not json at all").unwrap();
+        let provider = MockProvider::new(true);
+        let auth = get_auth_with(&provider, &path, false, false).await.unwrap();
+        assert_eq!(auth.access_token, "mock-token-1");
+        assert_eq!(provider.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(provider.validations.load(Ordering::SeqCst), 0);
+        assert_eq!(load_auth_from(&path).unwrap().unwrap().access_token, "mock-token-1");
+    }
+
+    #[tokio::test]
+    async fn test_get_auth_with_invalid_saved_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.json");
+        get_auth_with(&MockProvider::new(true), &path, false, false).await.unwrap();
+
+        let provider = MockProvider::new(false);
+        let auth = get_auth_with(&provider, &path, false, false).await.unwrap();
+        assert_eq!(provider.validations.load(Ordering::SeqCst), 1);
+        assert_eq!(provider.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(load_auth_from(&path).unwrap().unwrap().access_token, auth.access_token);
+    }
+
+    #[tokio::test]
+    async fn test_get_auth_skip_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("auth.json");
+
+        // Nothing saved: still logs in
+        let provider = MockProvider::new(false);
+        let auth = get_auth_with(&provider, &path, false, true).await.unwrap();
+        assert_eq!(provider.logins.load(Ordering::SeqCst), 1);
+
+        // Saved auth is trusted as-is, even though validation would reject it
+        let reused = get_auth_with(&provider, &path, false, true).await.unwrap();
+        assert_eq!(reused.access_token, auth.access_token);
+        assert_eq!(provider.logins.load(Ordering::SeqCst), 1);
+        assert_eq!(provider.validations.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_profiles_keep_separate_auth() {
+        let dir = tempfile::tempdir().unwrap();
+        let default_path = dir.path().join(profile_file_name("auth", "json", None));
+        let work_path = dir.path().join(profile_file_name("auth", "json", Some("work")));
+        assert!(default_path.ends_with("auth.json"));
+        assert!(work_path.ends_with("auth-work.json"));
+
+        let provider = MockProvider::new(true);
+        let personal = get_auth_with(&provider, &default_path, false, false).await.unwrap();
+        let work = get_auth_with(&provider, &work_path, false, false).await.unwrap();
+        assert_ne!(personal.access_token, work.access_token);
+
+        assert_eq!(load_auth_from(&default_path).unwrap().unwrap().access_token, personal.access_token);
+        assert_eq!(load_auth_from(&work_path).unwrap().unwrap().access_token, work.access_token);
+
+        // Clearing one profile leaves the other alone
+        clear_auth_at(&work_path).unwrap();
+        assert!(load_auth_from(&work_path).unwrap().is_none());
+        assert!(load_auth_from(&default_path).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_parse_profile() {
+        assert_eq!(parse_profile("work_2-x").unwrap(), "work_2-x");
+        assert!(parse_profile("").is_err());
+        assert!(parse_profile("../evil").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oidc_provider_not_supported() {
+        let err = get_auth(AuthProviderKind::Oidc, None, false, false, None).await.unwrap_err();
+        assert!(err.to_string().contains("oidc"));
+    }
+
+    /// Start a mock GitHub API that accepts only `Bearer good-token` on `/user`
+    async fn mock_github() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+
+                let (status, body) = if request.starts_with("get /user ")
+                    && request.contains("authorization: bearer good-token")
+                {
+                    (
+                        "200 OK",
+                        r#"{"id":1,"login":"ci-bot","name":null,"avatar_url":"https://example/a.png"}"#,
+                    )
+                } else {
+                    ("401 Unauthorized", r#"{"message":"Bad credentials"}"#)
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    /// Start a mock GitHub that runs the device flow: the first token poll
+    /// drops the connection, the next is still pending, then the token is granted
+    async fn mock_device_flow() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut polls = 0;
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).to_lowercase();
+
+                let body = if request.starts_with("post /login/device/code ") {
+                    r#"{"device_code":"dev","user_code":"ABCD-1234","verification_uri":"https://example/device","expires_in":60,"interval":0}"#
+                } else if request.starts_with("post /login/oauth/access_token ") {
+                    polls += 1;
+                    match polls {
+                        1 => continue, // dropped without a response
+                        2 => r#"{"error":"authorization_pending"}"#,
+                        _ => r#"{"access_token":"granted","token_type":"bearer","scope":"read:user"}"#,
+                    }
+                } else {
+                    r#"{"id":1,"login":"octocat","name":null,"avatar_url":"https://example/a.png"}"#
+                };
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_device_flow_survives_failed_poll() {
+        let base = mock_device_flow().await;
+
+        let login = device_flow_login_with(&base, &base, Duration::from_millis(10));
+        let auth = tokio::time::timeout(Duration::from_secs(10), login).await.unwrap().unwrap();
+        assert_eq!(auth.access_token, "granted");
+        assert_eq!(auth.user.login, "octocat");
+    }
+
+    #[tokio::test]
+    async fn test_auth_from_token() {
+        let api = mock_github().await;
+
+        let auth = auth_from_token(&api, "good-token").await.unwrap();
+        assert_eq!(auth.user.login, "ci-bot");
+        assert_eq!(auth.access_token, "good-token");
+    }
+
+    #[tokio::test]
+    async fn test_auth_from_invalid_token() {
+        let api = mock_github().await;
+
+        let err = auth_from_token(&api, "bad-token").await.unwrap_err();
+        assert!(err.to_string().contains("invalid"));
+    }
+}
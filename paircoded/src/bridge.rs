@@ -5,39 +5,895 @@
 //! state snapshots using vt100 terminal emulation.
 
 use anyhow::Result;
+use encoding_rs::{Decoder, Encoding};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use tokio::sync::mpsc;
+use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 
-use crate::protocol::{ClientMessage, RelayMessage, SnapshotMessage};
-use crate::pty::AsyncPty;
+use crate::input_log::InputLog;
+use crate::metrics::Metrics;
+use crate::protocol::{
+    Capabilities, ClientMessage, ConnectionRole, RelayMessage, ResizeMessage, ScrollbackChunkMeta, ScrollbackEnd,
+    SnapshotMessage,
+};
+use crate::pty::{self, AsyncPty};
+use crate::redact::{Redaction, Redactor};
+
+/// Default window for coalescing rapid resize requests
+pub const DEFAULT_RESIZE_DEBOUNCE: Duration = Duration::from_millis(50);
+
+/// Default time allowed for the exit status to appear once the PTY output closes
+pub const DEFAULT_EXIT_GRACE: Duration = Duration::from_millis(500);
+
+/// Default pause after the exit notice before the connection is closed
+pub const DEFAULT_EXIT_FLUSH_DELAY: Duration = Duration::from_millis(100);
+
+/// How often the exit status is polled during the grace period
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long to wait for more in-flight PTY output after the process exits
+const EXIT_DRAIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Smallest width or height a resize can set (a minimized viewer may ask for 0)
+const MIN_TERMINAL_DIMENSION: u16 = 1;
+
+/// Output held back as a possible secret is released after this much silence
+const REDACT_HOLD: Duration = Duration::from_millis(50);
+
+/// Terminal reset the browser gets before anything else on a reconnect
+/// (`--reset-on-reconnect`), clearing state left over from the last connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetSequence {
+    /// Full reset (RIS, `ESC c`)
+    Full,
+    /// Soft reset (DECSTR, `CSI ! p`), which keeps the screen contents
+    Soft,
+}
+
+impl ResetSequence {
+    pub fn bytes(self) -> &'static [u8] {
+        match self {
+            ResetSequence::Full => b"\x1bc",
+            ResetSequence::Soft => b"\x1b[!p",
+        }
+    }
+}
+
+/// Recent output kept for replay to a reconnecting client
+const REPLAY_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Output held back by `--max-output-rate` before PTY reads pause
+const THROTTLE_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Burst allowance of the output rate limiter, as time at the configured rate
+const THROTTLE_BURST: Duration = Duration::from_millis(100);
+
+/// Queued input above which the relay is asked to hold input
+const INPUT_HIGH_WATERMARK: usize = 256 * 1024;
+
+/// Queued input at or below which held input is released again
+const INPUT_LOW_WATERMARK: usize = 64 * 1024;
+
+/// Queued input at which relay messages are left unread, so a relay that ignores
+/// (or never negotiated) input pauses is held back by the websocket instead
+const INPUT_MAX_PENDING: usize = 4 * INPUT_HIGH_WATERMARK;
+
+/// Rows that scrolled off the screen kept for snapshots
+const SNAPSHOT_SCROLLBACK_ROWS: usize = 1000;
+
+/// Largest scrollback chunk sent in one frame
+const SCROLLBACK_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Paused output kept in memory before the rest goes to a spill file (`--pause-spill-dir`)
+const PAUSE_MEMORY_BYTES: usize = 256 * 1024;
+
+/// Size of the chunks spilled output is read back in
+const SPILL_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Notice shown to viewers when a data connection is established (`--announce-join`)
+const JOIN_NOTICE: &str = "\u{2014} viewer connected \u{2014}";
+
+/// `text` in the SGR style `sgr` (e.g. `"2"` for dim), or as is when styling
+/// of paircoded's own messages is off (`--no-color`)
+pub(crate) fn styled(text: &str, sgr: &str, color: bool) -> String {
+    if color {
+        format!("\x1b[{}m{}\x1b[0m", sgr, text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Local input stream (`--stdin-input`), taken by the first bridge that starts
+pub type LocalInput = Arc<std::sync::Mutex<Option<mpsc::Receiver<Vec<u8>>>>>;
+
+/// End-of-transmission (Ctrl-D): end of input for a program reading the terminal
+const EOT: u8 = 0x04;
+
+/// Requests for a bridge that arrive on the control connection
+#[derive(Debug)]
+pub enum BridgeRequest {
+    /// Send the recorded output history as chunks, then an end marker
+    Scrollback { request_id: String },
+    /// Resize the PTY, e.g. to follow the host terminal
+    Resize { cols: u16, rows: u16 },
+}
+
+/// Tunable bridge behavior
+#[derive(Debug, Clone)]
+pub struct BridgeOptions {
+    /// Resizes arriving within this window are coalesced into the last one (zero disables)
+    pub resize_debounce: Duration,
+    /// After the PTY output closes, keep polling for the exit status this long
+    /// (the reader can see EOF just before the child is reaped)
+    pub exit_grace: Duration,
+    /// After sending `Exit`, wait this long before the connection is closed so
+    /// the relay can deliver the final output (`--preserve-exit-output-ms`)
+    pub exit_flush_delay: Duration,
+    /// Traffic counters shared with the rest of the process
+    pub metrics: Arc<Metrics>,
+    /// Send a notice to the relay when a connection starts (never written to the PTY)
+    pub announce_join: bool,
+    /// Style the messages paircoded adds to the output (off with `--no-color` or `NO_COLOR`)
+    pub color: bool,
+    /// Reset sent to the relay first on every connection after the first (never written to the PTY)
+    pub reset_on_reconnect: Option<ResetSequence>,
+    /// Banner sent to the relay on every connection, after any reset (`--motd`, never written to the PTY)
+    pub motd: Option<Vec<u8>>,
+    /// Record every input payload (`--log-input`)
+    pub input_log: Option<Arc<InputLog>>,
+    /// Cap on output sent to the relay, in bytes per second (`None` is unlimited)
+    pub max_output_rate: Option<u64>,
+    /// Charset the PTY output is in, transcoded to UTF-8 (`None` passes bytes through)
+    pub output_charset: Option<&'static Encoding>,
+    /// Typed into the shell (with a trailing newline) as soon as it starts (`--init-command`)
+    pub init_command: Option<String>,
+    /// Extra input forwarded to the PTY alongside relay input, ended with EOF
+    pub local_input: Option<LocalInput>,
+    /// Tell the relay once output has been silent this long (`--output-idle-ms`)
+    pub output_idle: Option<Duration>,
+    /// Bytes of raw output recorded for scrollback requests (`--scrollback-bytes`, 0 disables)
+    pub scrollback_bytes: usize,
+    /// Secrets masked in output before it is forwarded (`--redact`)
+    pub redact: Option<Arc<Redaction>>,
+    /// Snapshots larger than this are sent without styling (`--max-snapshot-bytes`)
+    pub max_snapshot_bytes: Option<usize>,
+    /// Directory for paused output that outgrows memory (`--pause-spill-dir`)
+    pub pause_spill_dir: Option<PathBuf>,
+}
+
+impl Default for BridgeOptions {
+    fn default() -> Self {
+        BridgeOptions {
+            resize_debounce: DEFAULT_RESIZE_DEBOUNCE,
+            exit_grace: DEFAULT_EXIT_GRACE,
+            exit_flush_delay: DEFAULT_EXIT_FLUSH_DELAY,
+            metrics: Arc::default(),
+            announce_join: false,
+            color: true,
+            reset_on_reconnect: None,
+            motd: None,
+            input_log: None,
+            max_output_rate: None,
+            output_charset: None,
+            init_command: None,
+            local_input: None,
+            output_idle: None,
+            scrollback_bytes: 0,
+            redact: None,
+            max_snapshot_bytes: None,
+            pause_spill_dir: None,
+        }
+    }
+}
+
+/// Cursor shape as set by DECSCUSR (`ESC [ Ps SP q`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorShape {
+    Block,
+    Underline,
+    Bar,
+}
+
+impl CursorShape {
+    /// Shape for a DECSCUSR parameter (0-6)
+    fn from_param(param: u16) -> Option<Self> {
+        match param {
+            0..=2 => Some(CursorShape::Block),
+            3 | 4 => Some(CursorShape::Underline),
+            5 | 6 => Some(CursorShape::Bar),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CursorShape::Block => "block",
+            CursorShape::Underline => "underline",
+            CursorShape::Bar => "bar",
+        }
+    }
+}
+
+/// Streaming scanner for DECSCUSR sequences.
+///
+/// vt100 doesn't track cursor shape, so output is scanned separately. The
+/// scanner keeps its state between chunks, so sequences split across PTY reads
+/// are still recognized.
+#[derive(Debug)]
+struct CursorShapeTracker {
+    shape: CursorShape,
+    state: ScanState,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ScanState {
+    Ground,
+    Escape,
+    /// Inside a CSI sequence; `None` param means the sequence isn't DECSCUSR-shaped
+    Csi { param: Option<u16> },
+    /// Saw the space intermediate, expecting the final `q`
+    CsiSpace { param: Option<u16> },
+}
+
+impl CursorShapeTracker {
+    fn new() -> Self {
+        CursorShapeTracker {
+            shape: CursorShape::Block,
+            state: ScanState::Ground,
+        }
+    }
+
+    fn process(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (_, 0x1b) => ScanState::Escape,
+                (ScanState::Escape, b'[') => ScanState::Csi { param: Some(0) },
+                (ScanState::Csi { param }, b'0'..=b'9') => ScanState::Csi {
+                    param: param.and_then(|p| p.checked_mul(10)?.checked_add((byte - b'0') as u16)),
+                },
+                (ScanState::Csi { param }, b' ') => ScanState::CsiSpace { param },
+                // Other parameter bytes (';', '?', ...) rule out DECSCUSR
+                (ScanState::Csi { .. }, 0x30..=0x3f) => ScanState::Csi { param: None },
+                (ScanState::CsiSpace { param: Some(param) }, b'q') => {
+                    if let Some(shape) = CursorShape::from_param(param) {
+                        self.shape = shape;
+                    }
+                    ScanState::Ground
+                }
+                _ => ScanState::Ground,
+            };
+        }
+    }
+}
+
+/// Streaming scanner for window resize requests (`ESC [ 8 ; rows ; cols t`).
+///
+/// vt100 ignores these, so the screen size never reflects them; the request
+/// is kept until taken so it can be passed on to the relay.
+#[derive(Debug)]
+struct WindowSizeTracker {
+    state: WindowScanState,
+    /// Latest requested size as (cols, rows), not yet reported
+    requested: Option<(u16, u16)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum WindowScanState {
+    Ground,
+    Escape,
+    /// Inside a CSI sequence; `None` once it can't be a resize request
+    Csi { params: Option<[u16; 3]>, index: usize },
+}
+
+impl WindowSizeTracker {
+    fn new() -> Self {
+        WindowSizeTracker {
+            state: WindowScanState::Ground,
+            requested: None,
+        }
+    }
+
+    fn process(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.state = match (self.state, byte) {
+                (_, 0x1b) => WindowScanState::Escape,
+                (WindowScanState::Escape, b'[') => WindowScanState::Csi { params: Some([0; 3]), index: 0 },
+                (WindowScanState::Csi { params, index }, b'0'..=b'9') => WindowScanState::Csi {
+                    params: params.and_then(|mut params| {
+                        params[index] = params[index].checked_mul(10)?.checked_add((byte - b'0') as u16)?;
+                        Some(params)
+                    }),
+                    index,
+                },
+                (WindowScanState::Csi { params, index }, b';') if index < 2 => {
+                    WindowScanState::Csi { params, index: index + 1 }
+                }
+                // Other parameter bytes or a fourth parameter rule out a resize
+                (WindowScanState::Csi { index, .. }, 0x30..=0x3f) => WindowScanState::Csi { params: None, index },
+                (WindowScanState::Csi { params: Some([8, rows, cols]), index: 2 }, b't') => {
+                    if rows > 0 && cols > 0 {
+                        self.requested = Some((cols, rows));
+                    }
+                    WindowScanState::Ground
+                }
+                _ => WindowScanState::Ground,
+            };
+        }
+    }
+
+    /// Size requested since the last call, as (cols, rows)
+    fn take_request(&mut self) -> Option<(u16, u16)> {
+        self.requested.take()
+    }
+}
+
+/// Bounded ring buffer of recent PTY output, addressed by absolute stream offset
+#[derive(Debug)]
+struct ReplayBuffer {
+    data: VecDeque<u8>,
+    capacity: usize,
+    /// Offset just past the newest byte ever pushed
+    end: u64,
+}
+
+impl ReplayBuffer {
+    fn new(capacity: usize) -> Self {
+        ReplayBuffer {
+            data: VecDeque::with_capacity(capacity),
+            capacity,
+            end: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.end += bytes.len() as u64;
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.data.len() + bytes.len()).saturating_sub(self.capacity);
+        self.data.drain(..overflow);
+        self.data.extend(bytes);
+    }
+
+    /// Retained bytes in `[start, end)`; older bytes that were evicted are skipped
+    fn range(&self, start: u64, end: u64) -> Vec<u8> {
+        let first = self.end - self.data.len() as u64;
+        let start = start.max(first);
+        let end = end.min(self.end);
+        if start >= end {
+            return Vec::new();
+        }
+        self.data
+            .range((start - first) as usize..(end - first) as usize)
+            .copied()
+            .collect()
+    }
+}
+
+/// Token bucket pacing output to the relay, with a bounded queue of held-back chunks
+#[derive(Debug)]
+struct OutputThrottle {
+    /// Bytes per second
+    rate: f64,
+    /// Most tokens that can accumulate
+    burst: f64,
+    tokens: f64,
+    last_refill: Instant,
+    queue: VecDeque<Vec<u8>>,
+    queued_bytes: usize,
+}
+
+impl OutputThrottle {
+    fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        let burst = (rate * THROTTLE_BURST.as_secs_f64()).max(1.0);
+        OutputThrottle {
+            rate,
+            burst,
+            tokens: burst,
+            last_refill: Instant::now(),
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+        }
+    }
+
+    fn push(&mut self, data: Vec<u8>) {
+        self.queued_bytes += data.len();
+        self.queue.push_back(data);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Whether PTY reads should wait for the queue to drain
+    fn is_full(&self) -> bool {
+        self.queued_bytes >= THROTTLE_BUFFER_BYTES
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last_refill = now;
+    }
+
+    /// Tokens needed before the next send: the whole front chunk, or a full
+    /// burst of it, so output goes out in a few sizeable frames rather than
+    /// a trickle of tiny ones
+    fn wanted(&self) -> f64 {
+        self.queue
+            .front()
+            .map_or(1.0, |chunk| (chunk.len() as f64).clamp(1.0, self.burst))
+    }
+
+    /// When the next chunk may be sent
+    fn next_send_at(&self) -> Instant {
+        let missing = self.wanted() - self.tokens;
+        if missing <= 0.0 {
+            self.last_refill
+        } else {
+            self.last_refill + Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    /// Next chunk the rate allows, splitting the front chunk if only part of it fits
+    fn pop_ready(&mut self, now: Instant) -> Option<Vec<u8>> {
+        self.refill(now);
+        if self.tokens < self.wanted() {
+            return None;
+        }
+        let allowed = self.tokens.floor() as usize;
+        let front = self.queue.front_mut()?;
+        let chunk = if front.len() <= allowed {
+            self.queue.pop_front()?
+        } else {
+            let rest = front.split_off(allowed);
+            std::mem::replace(front, rest)
+        };
+        self.tokens -= chunk.len() as f64;
+        self.queued_bytes -= chunk.len();
+        Some(chunk)
+    }
+
+    /// Everything still queued, ignoring the rate
+    fn drain(&mut self) -> Vec<Vec<u8>> {
+        self.queued_bytes = 0;
+        self.queue.drain(..).collect()
+    }
+}
+
+/// Output held back while the relay has paused the stream
+///
+/// With a spill directory, output past `PAUSE_MEMORY_BYTES` is appended to a
+/// temp file there instead of growing the buffer, so a long pause costs disk
+/// rather than memory and nothing is dropped.
+#[derive(Debug, Default)]
+struct PauseBuffer {
+    /// Chunks from before the spill file was started
+    chunks: Vec<Vec<u8>>,
+    spill_dir: Option<PathBuf>,
+    spill: Option<SpillFile>,
+    /// Whether this pause already tried to start a spill file
+    spill_tried: bool,
+    /// Chunks after a failed write to the spill file
+    tail: Vec<Vec<u8>>,
+    /// Total bytes held, wherever they are
+    bytes: usize,
+}
+
+impl PauseBuffer {
+    fn new(spill_dir: Option<PathBuf>) -> Self {
+        PauseBuffer { spill_dir, ..PauseBuffer::default() }
+    }
+
+    async fn push(&mut self, data: Vec<u8>) {
+        if !self.spill_tried && self.bytes + data.len() > PAUSE_MEMORY_BYTES {
+            // Only tried once per pause: without a file, output stays in memory
+            self.spill_tried = true;
+            if let Some(ref dir) = self.spill_dir {
+                match SpillFile::create(dir).await {
+                    Ok(spill) => {
+                        info!(dir = %dir.display(), "spilling paused output to disk");
+                        self.spill = Some(spill);
+                    }
+                    Err(e) => warn!(dir = %dir.display(), error = %e, "failed to create spill file"),
+                }
+            }
+        }
+        self.bytes += data.len();
+        match self.spill {
+            Some(ref mut spill) if self.tail.is_empty() => match spill.file.write_all(&data).await {
+                Ok(()) => {}
+                // Later output has to come after the file, so it all stays in the tail
+                Err(e) => {
+                    warn!(error = %e, "failed to write spill file, keeping paused output in memory");
+                    self.tail.push(data);
+                }
+            },
+            Some(_) => self.tail.push(data),
+            None => self.chunks.push(data),
+        }
+    }
+
+    /// Whether output is going to a spill file
+    #[cfg(test)]
+    fn spilled(&self) -> bool {
+        self.spill.is_some()
+    }
+
+    /// Everything held, oldest first, emptying the buffer
+    async fn take(&mut self) -> PausedOutput {
+        let bytes = std::mem::take(&mut self.bytes);
+        self.spill_tried = false;
+        let mut spill = self.spill.take();
+        if let Some(ref mut file) = spill {
+            if let Err(e) = file.rewind().await {
+                warn!(error = %e, "failed to rewind spill file, dropping spilled output");
+                spill = None;
+            }
+        }
+        PausedOutput {
+            chunks: std::mem::take(&mut self.chunks).into_iter(),
+            spill,
+            tail: std::mem::take(&mut self.tail).into_iter(),
+            bytes,
+        }
+    }
+}
+
+/// Buffered paused output: memory chunks, then the spill file read back in
+/// chunks, then anything that couldn't be spilled
+#[derive(Debug)]
+struct PausedOutput {
+    chunks: std::vec::IntoIter<Vec<u8>>,
+    spill: Option<SpillFile>,
+    tail: std::vec::IntoIter<Vec<u8>>,
+    /// Bytes not handed out yet
+    bytes: usize,
+}
+
+impl PausedOutput {
+    /// Next chunk, or `None` once everything was handed out
+    async fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        let chunk = match self.chunks.next() {
+            Some(chunk) => Some(chunk),
+            None => {
+                let spilled = match self.spill {
+                    Some(ref mut spill) => spill.read_chunk().await,
+                    None => None,
+                };
+                if spilled.is_none() {
+                    // Read back: the file goes away now rather than with the output
+                    self.spill = None;
+                }
+                spilled.or_else(|| self.tail.next())
+            }
+        };
+        match chunk {
+            Some(ref chunk) => self.bytes = self.bytes.saturating_sub(chunk.len()),
+            None => self.bytes = 0,
+        }
+        chunk
+    }
+
+    /// Everything left, read into memory (tests only: spilled output can be large)
+    #[cfg(test)]
+    async fn collect(mut self) -> Vec<u8> {
+        let mut all = Vec::new();
+        while let Some(chunk) = self.next_chunk().await {
+            all.extend_from_slice(&chunk);
+        }
+        all
+    }
+}
+
+/// Temp file of spilled output. On Unix it is unlinked as soon as it's
+/// created, so it's gone however the process exits; elsewhere it is removed
+/// when dropped.
+#[derive(Debug)]
+struct SpillFile {
+    file: tokio::fs::File,
+    /// Still to be removed
+    path: Option<PathBuf>,
+}
+
+impl SpillFile {
+    async fn create(dir: &Path) -> std::io::Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let name = format!("paircoded-pause-{}-{}.spill", std::process::id(), NEXT.fetch_add(1, Ordering::Relaxed));
+        let path = dir.join(name);
+        let file = tokio::fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path).await?;
+        // Fails where open files can't be removed; Drop tries again
+        let path = tokio::fs::remove_file(&path).await.err().map(|_| path);
+        Ok(SpillFile { file, path })
+    }
+
+    /// Finish pending writes and go back to the start for reading
+    async fn rewind(&mut self) -> std::io::Result<()> {
+        self.file.flush().await?;
+        self.file.seek(SeekFrom::Start(0)).await.map(|_| ())
+    }
+
+    /// Next chunk from the current position, or `None` at the end
+    async fn read_chunk(&mut self) -> Option<Vec<u8>> {
+        let mut chunk = vec![0; SPILL_CHUNK_BYTES];
+        match self.file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(len) => {
+                chunk.truncate(len);
+                Some(chunk)
+            }
+            Err(e) => {
+                warn!(error = %e, "failed to read spill file, dropping the rest of the spilled output");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Feed local input into the PTY writer until it ends, then signal EOF
+async fn forward_local_input(mut rx: mpsc::Receiver<Vec<u8>>, tx: mpsc::Sender<Vec<u8>>) {
+    let mut at_line_start = true;
+    while let Some(data) = rx.recv().await {
+        if let Some(&last) = data.last() {
+            at_line_start = last == b'\n';
+        }
+        for chunk in data.chunks(pty::INPUT_CHUNK_SIZE) {
+            if tx.send(chunk.to_vec()).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Ctrl-D only means EOF at the start of a line; mid-line it just flushes the line
+    info!("local input ended, closing terminal input");
+    let eof = if at_line_start { vec![EOT] } else { vec![EOT, EOT] };
+    let _ = tx.send(eof).await;
+}
+
+/// Streaming transcoder from the PTY's charset to UTF-8
+///
+/// A multi-byte sequence split across two reads is held back until the rest arrives.
+struct OutputDecoder {
+    decoder: Decoder,
+}
+
+impl OutputDecoder {
+    fn new(charset: &'static Encoding) -> Self {
+        OutputDecoder {
+            decoder: charset.new_decoder_without_bom_handling(),
+        }
+    }
+
+    fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+        let capacity = self.decoder.max_utf8_buffer_length(data.len()).unwrap_or(data.len() * 3);
+        let mut out = String::with_capacity(capacity);
+        let (_, read, _) = self.decoder.decode_to_string(data, &mut out, false);
+        debug_assert_eq!(read, data.len());
+        out.into_bytes()
+    }
+}
+
+/// A PTY that could not be bridged.
+///
+/// Setup failures are fatal: the PTY reader can only be taken once, so a
+/// retry would fail the same way. The PTY process is killed and its exit code
+/// reported instead.
+#[derive(Debug)]
+pub struct SetupError {
+    pub error: anyhow::Error,
+    /// Exit code of the killed PTY process
+    pub exit_code: i32,
+}
+
+impl SetupError {
+    /// Kill a PTY that can't be bridged and collect its exit code
+    async fn abandon(pty: AsyncPty, error: anyhow::Error, grace: Duration) -> Self {
+        if let Err(e) = pty.kill().await {
+            warn!(error = %e, "failed to kill PTY after bridge setup failure");
+        }
+        let exit_code = wait_for_exit(&pty, grace).await.map_or(1, |status| pty::exit_code(&status));
+        SetupError { error, exit_code }
+    }
+}
+
+impl std::fmt::Display for SetupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "bridge setup failed: {:#}", self.error)
+    }
+}
+
+impl std::error::Error for SetupError {}
+
+/// The screen as plain text, then the cursor position, with no styling
+///
+/// Leading rows are left blank until it fits in `max` bytes; the rows kept
+/// stay where they are on screen.
+fn plain_screen(screen: &vt100::Screen, max: usize) -> Vec<u8> {
+    let (_, cols) = screen.size();
+    let (cursor_row, cursor_col) = screen.cursor_position();
+    let rows: Vec<String> = screen.rows(0, cols).collect();
+    let render = |first: usize| {
+        let mut out = format!("\x1b[H\x1b[J\x1b[{};1H", first + 1).into_bytes();
+        out.extend_from_slice(rows[first..].join("\r\n").as_bytes());
+        out.extend_from_slice(format!("\x1b[{};{}H", cursor_row + 1, cursor_col + 1).as_bytes());
+        out
+    };
+
+    // Each row dropped saves at least its "\r\n"
+    let mut out = render(0);
+    let mut first = 0;
+    while out.len() > max && first < rows.len() {
+        let excess = out.len() - max;
+        let mut saved = 0;
+        while saved < excess && first < rows.len() {
+            saved += rows[first].len() + 2;
+            first += 1;
+        }
+        out = render(first);
+    }
+    out
+}
+
+/// The `--announce-join` notice on a line of its own, dimmed if `color` is on
+fn join_notice(color: bool) -> Vec<u8> {
+    format!("\r\n{}\r\n", styled(JOIN_NOTICE, "2", color)).into_bytes()
+}
+
+/// Poll for the exit status, giving the child up to `grace` to be reaped
+async fn wait_for_exit(pty: &AsyncPty, grace: Duration) -> Option<portable_pty::ExitStatus> {
+    let deadline = Instant::now() + grace;
+    loop {
+        match pty.try_wait().await {
+            Ok(Some(status)) => return Some(status),
+            Ok(None) => {}
+            Err(e) => {
+                error!(error = %e, "failed to check PTY status");
+                return None;
+            }
+        }
+        if Instant::now() >= deadline {
+            warn!(grace_ms = grace.as_millis(), "PTY output closed but process has not exited");
+            return None;
+        }
+        tokio::time::sleep(EXIT_POLL_INTERVAL).await;
+    }
+}
 
 /// Bridge connecting PTY to relay
 pub struct Bridge {
     pty: AsyncPty,
     pty_rx: mpsc::Receiver<Vec<u8>>,
+    /// Queue into the PTY writer thread
+    pty_input_tx: mpsc::Sender<Vec<u8>>,
+    /// Input chunks waiting for room in the writer queue (kept across reconnects)
+    pending_input: VecDeque<Vec<u8>>,
+    /// Total size of `pending_input`
+    pending_input_bytes: usize,
+    /// Whether the current connection was told to hold input
+    input_paused: bool,
     paused: bool,
     /// Terminal emulator for tracking screen state
     parser: vt100::Parser,
+    /// Cursor shape, which vt100 doesn't track
+    cursor_shape: CursorShapeTracker,
+    /// Window size requests from the program, which vt100 doesn't apply
+    window_size: WindowSizeTracker,
+    options: BridgeOptions,
+    /// Number of resizes applied to the PTY
+    #[cfg(test)]
+    resize_count: u64,
+    /// Recent output, replayed to a reconnecting client that asks for it
+    replay: ReplayBuffer,
+    /// Stream offset up to which output reached a relay connection
+    delivered_end: u64,
+    /// Whether `run` was called before, making the next connection a reconnect
+    connected_before: bool,
+    /// Whether the current connection asked for sequenced output frames
+    sequenced: bool,
+    /// Sequence number of the next sequenced frame (continues across reconnects)
+    next_seq: u32,
+    /// Whether the current connection negotiated raw snapshot frames
+    raw_snapshots: bool,
+    /// Whether the current connection negotiated input pause/resume frames
+    input_flow: bool,
+    /// Output rate limiter (`--max-output-rate`); its queue is kept across reconnects
+    throttle: Option<OutputThrottle>,
+    /// Resumed paused output still to go through the throttle, read back as
+    /// the throttle makes room so a spill file isn't pulled into memory at once
+    backlog: Option<PausedOutput>,
+    /// Output transcoder (`--output-charset`)
+    decoder: Option<OutputDecoder>,
+    /// Secret masking (`--redact`)
+    redactor: Option<Redactor>,
+    /// Requests from the control connection (closed unless set with `set_requests`)
+    requests: mpsc::Receiver<BridgeRequest>,
+    /// Requests that arrived between connections and need one to be answered
+    deferred_requests: VecDeque<BridgeRequest>,
+    /// Output history for scrollback requests (`--scrollback-bytes`)
+    history: Option<ReplayBuffer>,
 }
 
 impl Bridge {
     /// Create a new bridge with the given PTY and terminal dimensions
     ///
     /// Starts the PTY reader immediately and initializes the vt100 parser
-    /// for terminal state tracking.
-    pub async fn new(pty: AsyncPty, cols: u16, rows: u16) -> Result<Self> {
-        let pty_rx = pty.start_reader().await?;
-        let parser = vt100::Parser::new(rows, cols, 0); // scrollback = 0
+    /// for terminal state tracking. If that fails, the PTY is killed (see [`SetupError`]).
+    pub async fn new(pty: AsyncPty, cols: u16, rows: u16, options: BridgeOptions) -> Result<Self, SetupError> {
+        let pty_rx = match pty.start_reader().await {
+            Ok(pty_rx) => pty_rx,
+            Err(e) => return Err(SetupError::abandon(pty, e, options.exit_grace).await),
+        };
+        if let Some(ref command) = options.init_command {
+            // Written like keystrokes so the shell stays interactive afterwards
+            debug!(command = %command, "writing init command to PTY");
+            match pty.write(format!("{}\n", command).as_bytes()).await {
+                Ok(()) => {}
+                // The shell is alive but not reading; leave it running without the command
+                Err(e) if e.is::<pty::WriteTimeout>() => warn!(error = %e, "init command not fully written"),
+                Err(e) => return Err(SetupError::abandon(pty, e, options.exit_grace).await),
+            }
+        }
+        let pty_input_tx = pty.input_sender();
+        if let Some(rx) = options.local_input.as_ref().and_then(|input| input.lock().unwrap().take()) {
+            tokio::spawn(forward_local_input(rx, pty_input_tx.clone()));
+        }
+        let parser = vt100::Parser::new(rows, cols, SNAPSHOT_SCROLLBACK_ROWS);
+        let history = Some(options.scrollback_bytes).filter(|&bytes| bytes > 0).map(ReplayBuffer::new);
+        let throttle = options.max_output_rate.map(OutputThrottle::new);
+        let decoder = options.output_charset.map(OutputDecoder::new);
+        let redactor = options.redact.clone().map(Redactor::new);
         Ok(Bridge {
             pty,
             pty_rx,
+            pty_input_tx,
+            pending_input: VecDeque::new(),
+            pending_input_bytes: 0,
+            input_paused: false,
             paused: false,
             parser,
+            cursor_shape: CursorShapeTracker::new(),
+            window_size: WindowSizeTracker::new(),
+            options,
+            #[cfg(test)]
+            resize_count: 0,
+            replay: ReplayBuffer::new(REPLAY_BUFFER_BYTES),
+            delivered_end: 0,
+            connected_before: false,
+            sequenced: false,
+            next_seq: 0,
+            raw_snapshots: false,
+            input_flow: false,
+            throttle,
+            backlog: None,
+            decoder,
+            redactor,
+            requests: mpsc::channel(1).1,
+            deferred_requests: VecDeque::new(),
+            history,
         })
     }
 
     /// Run the bridge with the given relay connection
     ///
+    /// Messages the relay sent before `run` starts (e.g. a resize right after the
+    /// handshake) wait in `relay_rx` and are applied in order.
+    ///
     /// This method handles:
     /// - Forwarding PTY output to relay
     /// - Forwarding relay input to PTY
@@ -55,25 +911,111 @@ impl Bridge {
         mut relay_rx: mpsc::Receiver<RelayMessage>,
     ) -> Result<Option<i32>> {
         // Buffer for paused output
-        let mut output_buffer: Vec<Vec<u8>> = Vec::new();
+        let mut output_buffer = PauseBuffer::new(self.options.pause_spill_dir.clone());
+
+        // Latest resize waiting for the debounce window to elapse
+        let mut pending_resize: Option<ResizeMessage> = None;
+        let resize_timer = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(resize_timer);
+
+        // Fires once per quiet period; re-armed by the next output
+        let output_idle = self.options.output_idle;
+        let idle_timer = tokio::time::sleep(output_idle.unwrap_or(Duration::ZERO));
+        tokio::pin!(idle_timer);
+        let mut idle_armed = output_idle.is_some();
+
+        // Releases output the redactor held back once the PTY goes quiet
+        let redact_timer = tokio::time::sleep(Duration::ZERO);
+        tokio::pin!(redact_timer);
+
+        // Each connection is a controller with legacy framing until the relay says otherwise
+        let mut role = ConnectionRole::Controller;
+        self.sequenced = false;
+
+        // Output that never reached the previous connection, replayed if the
+        // relay's handshake asks for it
+        let mut gap = Some((self.delivered_end, self.replay.end)).filter(|(start, end)| start < end);
+
+        // Like the join notice below, the reset goes to the relay only
+        let reconnect = std::mem::replace(&mut self.connected_before, true);
+        if let Some(reset) = self.options.reset_on_reconnect.filter(|_| reconnect) {
+            if relay_tx.send(ClientMessage::Output(reset.bytes().to_vec())).await.is_err() {
+                warn!("relay connection lost before terminal reset");
+                return Ok(None);
+            }
+        }
+
+        // Shown in the browser ahead of any terminal output, and after the reset
+        // so it isn't cleared straight away
+        if let Some(ref motd) = self.options.motd {
+            if relay_tx.send(ClientMessage::Output(motd.clone())).await.is_err() {
+                warn!("relay connection lost before MOTD");
+                return Ok(None);
+            }
+        }
+
+        // The notice goes to the relay only: the shell never sees it and the
+        // vt100 state used for snapshots is left untouched
+        if self.options.announce_join
+            && relay_tx.send(ClientMessage::Output(join_notice(self.options.color))).await.is_err()
+        {
+            warn!("relay connection lost before join notice");
+            return Ok(None);
+        }
+
+        // A new connection starts out sending input; input still queued from the
+        // last one may mean it has to be held right away
+        self.input_paused = false;
+        if !self.update_input_flow(&relay_tx).await {
+            warn!("relay connection lost before input pause");
+            return Ok(None);
+        }
+
+        // Answer what was asked while there was no connection
+        while let Some(request) = self.deferred_requests.pop_front() {
+            if !self.handle_request(&relay_tx, request).await {
+                warn!("relay connection lost while answering deferred requests");
+                return Ok(None);
+            }
+        }
+
+        // Local handle so reserving a slot doesn't borrow `self` across the select
+        let pty_input_tx = self.pty_input_tx.clone();
 
         loop {
             tokio::select! {
-                // Handle PTY output
-                pty_result = self.pty_rx.recv() => {
+                // Handle PTY output (paused while the throttle queue is full, so
+                // the reader channel fills up and pushes back on the PTY)
+                pty_result = self.pty_rx.recv(), if !self.output_backed_up() => {
                     match pty_result {
                         Some(data) => {
+                            let data = self.decode_output(data);
+                            let data = self.redact_output(data);
+                            if self.redactor.as_ref().is_some_and(Redactor::is_holding) {
+                                redact_timer.as_mut().reset(Instant::now() + REDACT_HOLD);
+                            }
+                            if data.is_empty() {
+                                // Only the start of a multi-byte sequence or of a
+                                // possible secret so far
+                                continue;
+                            }
+
                             // Feed output to vt100 parser for state tracking
-                            self.parser.process(&data);
-
-                            if self.paused {
-                                // Buffer output while paused
-                                output_buffer.push(data);
-                                debug!(buffered = output_buffer.len(), "buffering PTY output (paused)");
-                            } else {
-                                // Send output to relay
-                                let msg = ClientMessage::Output(data);
-                                if relay_tx.send(msg).await.is_err() {
+                            self.process_output(&data);
+
+                            if let Some(idle) = output_idle {
+                                idle_timer.as_mut().reset(Instant::now() + idle);
+                                idle_armed = true;
+                            }
+
+                            if !self.forward_output(data, &relay_tx, &mut output_buffer).await {
+                                warn!("relay connection lost");
+                                return Ok(None);
+                            }
+
+                            if let Some((cols, rows)) = self.window_size.take_request() {
+                                info!(cols, rows, "program requested a window size change");
+                                if relay_tx.send(ClientMessage::SizeChanged { cols, rows }).await.is_err() {
                                     warn!("relay connection lost");
                                     return Ok(None);
                                 }
@@ -87,25 +1029,46 @@ impl Bridge {
                     }
                 }
 
-                // Handle relay messages
-                relay_result = relay_rx.recv() => {
+                // Handle relay messages, unless the PTY is too far behind on input
+                relay_result = relay_rx.recv(), if self.pending_input_bytes < INPUT_MAX_PENDING => {
                     match relay_result {
                         Some(msg) => {
                             match msg {
+                                RelayMessage::Input(_) if role == ConnectionRole::Observer => {
+                                    debug!("ignoring input from observer connection");
+                                }
+
                                 RelayMessage::Input(data) => {
-                                    // Forward input to PTY
-                                    if let Err(e) = self.pty.write(&data).await {
-                                        error!(error = %e, "failed to write to PTY");
+                                    // Queue input for the PTY in bounded chunks; the writer
+                                    // branch below feeds them in without blocking this loop
+                                    self.options.metrics.add_bytes_in(data.len());
+                                    if let Some(ref log) = self.options.input_log {
+                                        log.record(&data);
+                                    }
+                                    self.pending_input_bytes += data.len();
+                                    self.pending_input.extend(
+                                        data.chunks(pty::INPUT_CHUNK_SIZE).map(|c| c.to_vec()),
+                                    );
+                                    if !self.update_input_flow(&relay_tx).await {
+                                        warn!("relay connection lost");
+                                        return Ok(None);
                                     }
                                 }
 
                                 RelayMessage::Resize(size) => {
-                                    info!(cols = size.cols, rows = size.rows, "resize requested");
-                                    if let Err(e) = self.pty.resize(size.cols, size.rows).await {
-                                        error!(error = %e, "failed to resize PTY");
+                                    debug!(cols = size.cols, rows = size.rows, "resize requested");
+                                    if self.options.resize_debounce.is_zero() {
+                                        self.apply_resize(size).await;
+                                    } else {
+                                        // The window starts at the first resize so a continuous
+                                        // drag still applies at least once per window
+                                        if pending_resize.is_none() {
+                                            resize_timer
+                                                .as_mut()
+                                                .reset(Instant::now() + self.options.resize_debounce);
+                                        }
+                                        pending_resize = Some(size);
                                     }
-                                    // Also resize the vt100 parser
-                                    self.parser.set_size(size.rows, size.cols);
                                 }
 
                                 RelayMessage::Pause => {
@@ -118,10 +1081,45 @@ impl Bridge {
                                     self.paused = false;
 
                                     // Flush buffered output
-                                    for data in output_buffer.drain(..) {
-                                        let msg = ClientMessage::Output(data);
-                                        if relay_tx.send(msg).await.is_err() {
-                                            warn!("relay connection lost while flushing buffer");
+                                    let mut buffered = output_buffer.take().await;
+                                    if self.throttle.is_some() {
+                                        // Nothing is buffered while an earlier backlog drains
+                                        self.backlog.get_or_insert(buffered);
+                                        self.refill_throttle().await;
+                                    } else {
+                                        while let Some(data) = buffered.next_chunk().await {
+                                            let msg = self.output_message(data);
+                                            if relay_tx.send(msg).await.is_err() {
+                                                warn!("relay connection lost while flushing buffer");
+                                                return Ok(None);
+                                            }
+                                        }
+                                    }
+                                    self.mark_delivered();
+                                }
+
+                                RelayMessage::Handshake(handshake) => {
+                                    info!(
+                                        role = ?handshake.role,
+                                        replay = handshake.replay,
+                                        sequence = handshake.sequence,
+                                        "relay handshake"
+                                    );
+                                    role = handshake.role;
+                                    self.sequenced = handshake.sequence;
+
+                                    if let (true, Some((start, end))) = (handshake.replay, gap.take()) {
+                                        let missed = self.replay.range(start, end);
+                                        info!(bytes = missed.len(), "replaying output missed while disconnected");
+                                        // The replayed range covers whatever the throttle still held
+                                        if let Some(ref mut throttle) = self.throttle {
+                                            throttle.drain();
+                                        }
+                                        self.backlog = None;
+                                        if !missed.is_empty()
+                                            && relay_tx.send(self.output_message(missed)).await.is_err()
+                                        {
+                                            warn!("relay connection lost while replaying output");
                                             return Ok(None);
                                         }
                                     }
@@ -130,7 +1128,12 @@ impl Bridge {
                                 RelayMessage::RequestSnapshot(request) => {
                                     debug!(request_id = %request.request_id, "snapshot requested");
                                     let snapshot = self.create_snapshot(request.request_id);
-                                    if relay_tx.send(ClientMessage::Snapshot(snapshot)).await.is_err() {
+                                    let msg = if self.raw_snapshots {
+                                        ClientMessage::RawSnapshot(snapshot)
+                                    } else {
+                                        ClientMessage::Snapshot(snapshot)
+                                    };
+                                    if relay_tx.send(msg).await.is_err() {
                                         warn!("relay connection lost while sending snapshot");
                                         return Ok(None);
                                     }
@@ -139,25 +1142,96 @@ impl Bridge {
                         }
                         None => {
                             // Relay connection closed - need to reconnect
+                            if let Some(size) = pending_resize.take() {
+                                self.apply_resize(size).await;
+                            }
                             warn!("relay channel closed, will reconnect");
                             return Ok(None);
                         }
                     }
                 }
-            }
 
-            // Check if PTY process has exited
-            match self.pty.try_wait().await {
-                Ok(Some(status)) => {
-                    let code = if status.success() { 0 } else { 1 };
-                    info!(exit_code = code, "PTY process exited");
-
-                    // Notify relay
-                    let _ = relay_tx.send(ClientMessage::Exit(code)).await;
-                    return Ok(Some(code));
+                // Hand queued input to the PTY writer as room frees up
+                permit = pty_input_tx.reserve(), if !self.pending_input.is_empty() => {
+                    match permit {
+                        Ok(permit) => {
+                            if let Some(chunk) = self.pending_input.pop_front() {
+                                self.pending_input_bytes -= chunk.len();
+                                permit.send(chunk);
+                            }
+                        }
+                        Err(_) => {
+                            error!(dropped = self.pending_input.len(), "PTY writer closed, dropping input");
+                            self.pending_input.clear();
+                            self.pending_input_bytes = 0;
+                        }
+                    }
+                    if !self.update_input_flow(&relay_tx).await {
+                        warn!("relay connection lost");
+                        return Ok(None);
+                    }
                 }
-                Ok(None) => {
-                    // Still running
+
+                // Send throttled output as the rate allows
+                _ = tokio::time::sleep_until(self.throttle.as_ref().map_or_else(Instant::now, OutputThrottle::next_send_at)),
+                    if !self.paused && self.throttle.as_ref().is_some_and(|t| !t.is_empty()) =>
+                {
+                    while let Some(chunk) = self.throttle.as_mut().and_then(|t| t.pop_ready(Instant::now())) {
+                        let msg = self.output_message(chunk);
+                        if relay_tx.send(msg).await.is_err() {
+                            warn!("relay connection lost");
+                            return Ok(None);
+                        }
+                    }
+                    self.refill_throttle().await;
+                    self.mark_delivered();
+                }
+
+                // Answer requests from the control connection on this data connection
+                Some(request) = self.requests.recv() => {
+                    if !self.handle_request(&relay_tx, request).await {
+                        warn!("relay connection lost while answering request");
+                        return Ok(None);
+                    }
+                }
+
+                // Report a quiet terminal once per stretch of silence
+                _ = &mut idle_timer, if idle_armed => {
+                    idle_armed = false;
+                    debug!("PTY output idle");
+                    if relay_tx.send(ClientMessage::OutputIdle).await.is_err() {
+                        warn!("relay connection lost while sending idle notice");
+                        return Ok(None);
+                    }
+                }
+
+                // The held tail wasn't continued into a secret; let it through
+                _ = &mut redact_timer, if self.backlog.is_none() && self.redactor.as_ref().is_some_and(Redactor::is_holding) => {
+                    let data = self.flush_redactor();
+                    self.process_output(&data);
+                    if !self.forward_output(data, &relay_tx, &mut output_buffer).await {
+                        warn!("relay connection lost");
+                        return Ok(None);
+                    }
+                }
+
+                // Apply the last resize once the debounce window elapses
+                _ = &mut resize_timer, if pending_resize.is_some() => {
+                    if let Some(size) = pending_resize.take() {
+                        self.apply_resize(size).await;
+                    }
+                }
+            }
+
+            // Check if PTY process has exited
+            match self.pty.try_wait().await {
+                Ok(Some(status)) => {
+                    let code = pty::exit_code(&status);
+                    info!(exit_code = code, "PTY process exited");
+                    return self.finish_exit(&relay_tx, &mut output_buffer, code).await;
+                }
+                Ok(None) => {
+                    // Still running
                 }
                 Err(e) => {
                     error!(error = %e, "failed to check PTY status");
@@ -165,22 +1239,404 @@ impl Bridge {
             }
         }
 
+        // The reader hit EOF: the process has exited or is about to
+        if let Some(status) = self.wait_for_exit_status().await {
+            let code = pty::exit_code(&status);
+            info!(exit_code = code, "PTY process exited");
+            return self.finish_exit(&relay_tx, &mut output_buffer, code).await;
+        }
+
+        // Don't lose buffered output even if the exit status isn't available yet
+        self.flush_held_output(&mut output_buffer).await;
+        self.send_remaining_output(&relay_tx, &mut output_buffer).await;
+
         Ok(None)
     }
 
+    /// Poll for the exit status for up to `exit_grace`
+    async fn wait_for_exit_status(&self) -> Option<portable_pty::ExitStatus> {
+        wait_for_exit(&self.pty, self.options.exit_grace).await
+    }
+
+    /// Deliver all remaining output, then notify the relay that the PTY exited.
+    ///
+    /// Drains whatever the reader still has in flight (bounded by a short idle
+    /// timeout) and flushes output buffered while paused, so the final screen
+    /// reaches the relay before the `Exit` message. The connection then stays
+    /// open for `exit_flush_delay` so the close frame doesn't race that output.
+    async fn finish_exit(
+        &mut self,
+        relay_tx: &mpsc::Sender<ClientMessage>,
+        output_buffer: &mut PauseBuffer,
+        code: i32,
+    ) -> Result<Option<i32>> {
+        while let Ok(Some(data)) = tokio::time::timeout(EXIT_DRAIN_TIMEOUT, self.pty_rx.recv()).await {
+            let data = self.decode_output(data);
+            let data = self.redact_output(data);
+            self.process_output(&data);
+            output_buffer.push(data).await;
+        }
+        self.flush_held_output(output_buffer).await;
+
+        if !self.send_remaining_output(relay_tx, output_buffer).await {
+            warn!("relay connection lost while flushing final output");
+            return Ok(Some(code));
+        }
+
+        // Notify relay
+        if relay_tx.send(ClientMessage::Exit(code)).await.is_ok() && !self.options.exit_flush_delay.is_zero() {
+            tokio::time::sleep(self.options.exit_flush_delay).await;
+        }
+        Ok(Some(code))
+    }
+
+    /// Send everything still held, unthrottled: the rate limiter's queue, the
+    /// resumed backlog, then output buffered while paused
+    ///
+    /// Returns false if the relay connection is gone.
+    async fn send_remaining_output(&mut self, relay_tx: &mpsc::Sender<ClientMessage>, output_buffer: &mut PauseBuffer) -> bool {
+        for data in self.throttle.as_mut().map(OutputThrottle::drain).unwrap_or_default() {
+            let msg = self.output_message(data);
+            if relay_tx.send(msg).await.is_err() {
+                return false;
+            }
+        }
+        let backlog = self.backlog.take();
+        for mut held in backlog.into_iter().chain([output_buffer.take().await]) {
+            while let Some(data) = held.next_chunk().await {
+                let msg = self.output_message(data);
+                if relay_tx.send(msg).await.is_err() {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Whether PTY reads should wait: the throttle queue is full, or resumed
+    /// output still has to go out ahead of new output
+    fn output_backed_up(&self) -> bool {
+        self.backlog.is_some() || self.throttle.as_ref().is_some_and(OutputThrottle::is_full)
+    }
+
+    /// Move the resumed backlog into the throttle queue as far as it has room
+    async fn refill_throttle(&mut self) {
+        while let (Some(throttle), Some(backlog)) = (self.throttle.as_mut(), self.backlog.as_mut()) {
+            if throttle.is_full() {
+                break;
+            }
+            match backlog.next_chunk().await {
+                Some(chunk) => throttle.push(chunk),
+                None => self.backlog = None,
+            }
+        }
+    }
+
+    /// Record that everything but the throttled backlog reached the relay
+    fn mark_delivered(&mut self) {
+        let queued = self.throttle.as_ref().map_or(0, |t| t.queued_bytes) + self.backlog.as_ref().map_or(0, |b| b.bytes);
+        self.delivered_end = self.replay.end - queued as u64;
+    }
+
+    /// Wrap PTY output in the framing negotiated for the current connection
+    fn output_message(&mut self, data: Vec<u8>) -> ClientMessage {
+        if self.sequenced {
+            let seq = self.next_seq;
+            self.next_seq = self.next_seq.wrapping_add(1);
+            ClientMessage::SequencedOutput { seq, data }
+        } else {
+            ClientMessage::Output(data)
+        }
+    }
+
+    /// Raw PTY output as UTF-8, when a `--output-charset` is set
+    fn decode_output(&mut self, data: Vec<u8>) -> Vec<u8> {
+        match self.decoder {
+            Some(ref mut decoder) => decoder.decode(&data),
+            None => data,
+        }
+    }
+
+    /// Output with secrets masked, when `--redact` patterns are set
+    fn redact_output(&mut self, data: Vec<u8>) -> Vec<u8> {
+        match self.redactor {
+            Some(ref mut redactor) => redactor.process(&data),
+            None => data,
+        }
+    }
+
+    /// Output the redactor held back as a possible secret
+    fn flush_redactor(&mut self) -> Vec<u8> {
+        self.redactor.as_mut().map(Redactor::flush).unwrap_or_default()
+    }
+
+    /// Queue held-back output behind `output_buffer` for the final flush
+    async fn flush_held_output(&mut self, output_buffer: &mut PauseBuffer) {
+        let data = self.flush_redactor();
+        if !data.is_empty() {
+            self.process_output(&data);
+            output_buffer.push(data).await;
+        }
+    }
+
+    /// Send output to the relay, or queue it while paused or throttled
+    ///
+    /// Returns false if the relay connection is gone.
+    async fn forward_output(
+        &mut self,
+        data: Vec<u8>,
+        relay_tx: &mpsc::Sender<ClientMessage>,
+        output_buffer: &mut PauseBuffer,
+    ) -> bool {
+        if self.paused {
+            // Buffer output while paused
+            output_buffer.push(data).await;
+            debug!(buffered = output_buffer.bytes, "buffering PTY output (paused)");
+        } else if let Some(ref mut throttle) = self.throttle {
+            throttle.push(data);
+        } else {
+            let msg = self.output_message(data);
+            if relay_tx.send(msg).await.is_err() {
+                return false;
+            }
+            self.mark_delivered();
+        }
+        true
+    }
+
+    /// Update terminal state tracking with PTY output
+    ///
+    /// Reads may end in the middle of an escape sequence. The vt100 parser and
+    /// the cursor shape tracker keep their state between calls, so nothing needs
+    /// buffering here: the partial sequence takes effect once the rest arrives.
+    fn process_output(&mut self, data: &[u8]) {
+        self.options.metrics.add_bytes_out(data.len());
+        self.replay.push(data);
+        if let Some(ref mut history) = self.history {
+            history.push(data);
+        }
+        self.parser.process(data);
+        self.cursor_shape.process(data);
+        self.window_size.process(data);
+    }
+
+    /// Ask the relay to hold input once queued input passes the high watermark,
+    /// and to release it once the PTY writer has drained it to the low watermark
+    ///
+    /// Relays that didn't negotiate `input_flow` aren't sent anything. Returns
+    /// false if the relay connection is gone.
+    async fn update_input_flow(&mut self, relay_tx: &mpsc::Sender<ClientMessage>) -> bool {
+        let msg = if !self.input_paused && self.pending_input_bytes > INPUT_HIGH_WATERMARK {
+            debug!(pending = self.pending_input_bytes, "PTY input backed up, pausing relay input");
+            ClientMessage::InputPause
+        } else if self.input_paused && self.pending_input_bytes <= INPUT_LOW_WATERMARK {
+            debug!(pending = self.pending_input_bytes, "PTY input drained, resuming relay input");
+            ClientMessage::InputResume
+        } else {
+            return true;
+        };
+        self.input_paused = matches!(msg, ClientMessage::InputPause);
+        !self.input_flow || relay_tx.send(msg).await.is_ok()
+    }
+
+    /// Resize the PTY and the vt100 parser
+    async fn apply_resize(&mut self, mut size: ResizeMessage) {
+        if size.cols < MIN_TERMINAL_DIMENSION || size.rows < MIN_TERMINAL_DIMENSION {
+            warn!(cols = size.cols, rows = size.rows, min = MIN_TERMINAL_DIMENSION, "clamping resize to the minimum size");
+            size.cols = size.cols.max(MIN_TERMINAL_DIMENSION);
+            size.rows = size.rows.max(MIN_TERMINAL_DIMENSION);
+        }
+        info!(cols = size.cols, rows = size.rows, "resizing terminal");
+        if let Err(e) = self.pty.resize(size.cols, size.rows).await {
+            error!(error = %e, "failed to resize PTY");
+        }
+        // Also resize the vt100 parser
+        self.parser.set_size(size.rows, size.cols);
+        #[cfg(test)]
+        {
+            self.resize_count += 1;
+        }
+    }
+
+    /// Number of resizes applied to the PTY so far
+    #[cfg(test)]
+    fn resize_count(&self) -> u64 {
+        self.resize_count
+    }
+
+    /// Take requests from the control connection while running
+    pub fn set_requests(&mut self, requests: mpsc::Receiver<BridgeRequest>) {
+        self.requests = requests;
+    }
+
+    /// Wait for the next request while not running (`None` once the sender is gone)
+    pub async fn next_request(&mut self) -> Option<BridgeRequest> {
+        self.requests.recv().await
+    }
+
+    /// Act on a request that arrived between connections.
+    ///
+    /// Resizes are applied right away; anything answered on the data
+    /// connection waits for the next `run`.
+    pub async fn handle_request_disconnected(&mut self, request: BridgeRequest) {
+        match request {
+            BridgeRequest::Resize { cols, rows } => self.apply_resize(ResizeMessage { cols, rows }).await,
+            request => self.deferred_requests.push_back(request),
+        }
+    }
+
+    /// Act on a request from the control connection while connected.
+    ///
+    /// Returns false if the relay connection is gone.
+    async fn handle_request(&mut self, relay_tx: &mpsc::Sender<ClientMessage>, request: BridgeRequest) -> bool {
+        match request {
+            BridgeRequest::Scrollback { request_id } => self.send_scrollback(relay_tx, request_id).await.is_ok(),
+            BridgeRequest::Resize { cols, rows } => {
+                self.apply_resize(ResizeMessage { cols, rows }).await;
+                // The relay's view follows the size the PTY is at now
+                let (cols, rows) = self.size();
+                relay_tx.send(ClientMessage::SizeChanged { cols, rows }).await.is_ok()
+            }
+        }
+    }
+
+    /// Recorded output, oldest first; without history, just the current screen
+    fn scrollback(&self) -> Vec<u8> {
+        match self.history {
+            Some(ref history) => history.range(0, history.end),
+            None => self.parser.screen().contents_formatted(),
+        }
+    }
+
+    /// Send the scrollback as ordered chunks followed by an end marker
+    async fn send_scrollback(
+        &self,
+        relay_tx: &mpsc::Sender<ClientMessage>,
+        request_id: String,
+    ) -> Result<(), mpsc::error::SendError<ClientMessage>> {
+        let text = self.scrollback();
+        let mut chunks = 0;
+        for data in text.chunks(SCROLLBACK_CHUNK_BYTES) {
+            let meta = ScrollbackChunkMeta { request_id: request_id.clone(), index: chunks };
+            relay_tx.send(ClientMessage::ScrollbackChunk { meta, data: data.to_vec() }).await?;
+            chunks += 1;
+        }
+        debug!(request_id = %request_id, bytes = text.len(), chunks, "sent scrollback");
+        relay_tx.send(ClientMessage::ScrollbackEnd(ScrollbackEnd { request_id, chunks })).await
+    }
+
+    /// Use the features negotiated by the connection about to be run
+    pub fn set_capabilities(&mut self, capabilities: &Capabilities) {
+        self.raw_snapshots = capabilities.raw_snapshot;
+        self.input_flow = capabilities.input_flow;
+    }
+
+    /// Current terminal size as (cols, rows), including resizes from the relay
+    pub fn size(&self) -> (u16, u16) {
+        let (rows, cols) = self.parser.screen().size();
+        (cols, rows)
+    }
+
+    /// Rows that scrolled off the top of the screen, oldest first, each
+    /// formatted on its own
+    fn scrollback_rows(&mut self) -> Vec<Vec<u8>> {
+        // vt100 only scrolls back by up to a screen's height, so the screen is
+        // made tall enough to show all of it at once, then put back
+        let (rows, cols) = self.parser.screen().size();
+        self.parser.set_size(rows.saturating_add(SNAPSHOT_SCROLLBACK_ROWS as u16), cols);
+        self.parser.set_scrollback(SNAPSHOT_SCROLLBACK_ROWS);
+        let count = self.parser.screen().scrollback();
+        let history = self.parser.screen().rows_formatted(0, cols).take(count).collect();
+        self.parser.set_scrollback(0);
+        self.parser.set_size(rows, cols);
+        history
+    }
+
     /// Create a snapshot of the current terminal state
-    fn create_snapshot(&self, request_id: String) -> SnapshotMessage {
+    ///
+    /// Snapshots hold the scrollback the screen keeps (up to
+    /// `SNAPSHOT_SCROLLBACK_ROWS`), scrolled off by blank lines, then the visible
+    /// screen. One over `max_snapshot_bytes` leaves out the oldest scrollback
+    /// rows until it fits and is marked `truncated`. If the visible screen alone
+    /// is over the limit, it is sent without styling and with leading rows
+    /// blanked until it fits.
+    fn create_snapshot(&mut self, request_id: String) -> SnapshotMessage {
+        let history = self.scrollback_rows();
         let screen = self.parser.screen();
+        let (rows, _) = screen.size();
         let (cursor_row, cursor_col) = screen.cursor_position();
 
+        // contents_formatted() returns the screen content with ANSI escape sequences
+        let visible = screen.contents_formatted();
+        // With each history row ending in a newline, enough blank lines to push
+        // the last of them into the viewer's scrollback
+        let scroll_off = "\r\n".repeat(rows as usize - 1);
+        let row_bytes = |row: &Vec<u8>| row.len() + b"\x1b[m\r\n".len();
+        let mut size = visible.len() + scroll_off.len() + history.iter().map(row_bytes).sum::<usize>();
+
+        let mut first = 0;
+        if let Some(max) = self.options.max_snapshot_bytes {
+            while size > max && first < history.len() {
+                size -= row_bytes(&history[first]);
+                first += 1;
+            }
+        }
+        if first == history.len() {
+            size -= scroll_off.len();
+        }
+
+        let mut contents = Vec::with_capacity(size);
+        let mut truncated = first > 0;
+        if let Some(max) = self.options.max_snapshot_bytes.filter(|&max| size > max) {
+            contents = plain_screen(screen, max);
+            truncated = true;
+            if contents.len() > max {
+                warn!(bytes = contents.len(), max, "snapshot size limit is too small for even an empty screen");
+            } else {
+                debug!(max, "sending snapshot without styling to fit the size limit");
+            }
+        } else {
+            if first < history.len() {
+                for row in &history[first..] {
+                    contents.extend_from_slice(row);
+                    contents.extend_from_slice(b"\x1b[m\r\n");
+                }
+                contents.extend_from_slice(scroll_off.as_bytes());
+            }
+            contents.extend_from_slice(&visible);
+            if truncated {
+                debug!(rows = first, "left out the oldest scrollback rows to fit the snapshot size limit");
+            }
+        }
+
         SnapshotMessage {
             request_id,
-            // contents_formatted() returns the screen content with ANSI escape sequences
-            screen: screen.contents_formatted(),
+            screen: contents,
             cols: screen.size().1,
             rows: screen.size().0,
             cursor_x: cursor_col,
             cursor_y: cursor_row,
+            cursor_shape: self.cursor_shape.shape.as_str().to_string(),
+            truncated,
+        }
+    }
+
+    /// Ask the PTY process to exit with SIGTERM and return its exit code
+    ///
+    /// Waits up to `exit_grace` for the exit status; reports 1 if it doesn't arrive.
+    pub async fn terminate_pty(&self) -> i32 {
+        #[cfg(unix)]
+        let result = self.pty.signal(libc::SIGTERM).await;
+        // Without signals, the child can only be killed
+        #[cfg(not(unix))]
+        let result = self.pty.kill().await;
+
+        if let Err(e) = result {
+            warn!(error = %e, "failed to terminate PTY process");
+        }
+        match self.wait_for_exit_status().await {
+            Some(status) => pty::exit_code(&status),
+            None => 1,
         }
     }
 
@@ -193,3 +1649,1007 @@ impl Bridge {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{RelayHandshake, SnapshotRequest};
+    use crate::pty::{PtyHandle, SpawnOptions};
+
+    /// Spawn a PTY running a shell command in the temp directory
+    pub(crate) fn spawn_pty(command: &str) -> AsyncPty {
+        let handle = PtyHandle::spawn(&SpawnOptions::sh(command)).unwrap();
+        AsyncPty::new(handle).unwrap()
+    }
+
+    /// Advance the paused clock in small steps, letting the bridge task run in between.
+    ///
+    /// The PTY reader runs on the blocking pool, which stops tokio from
+    /// auto-advancing a paused clock, so tests advance it explicitly.
+    pub(crate) async fn advance(duration: Duration) {
+        let step = Duration::from_millis(1);
+        let mut elapsed = Duration::ZERO;
+        while elapsed < duration {
+            tokio::time::advance(step).await;
+            tokio::task::yield_now().await;
+            elapsed += step;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_resize_debounce() {
+        let pty = spawn_pty("sleep 5");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        let (relay_tx, _client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+
+        // A burst of resizes is applied once, at its last size
+        for (cols, rows) in [(90, 25), (100, 30), (110, 35), (120, 40)] {
+            input_tx.send(RelayMessage::Resize(ResizeMessage { cols, rows })).await.unwrap();
+        }
+        advance(DEFAULT_RESIZE_DEBOUNCE * 2).await;
+        drop(input_tx);
+
+        let bridge = handle.await.unwrap();
+        assert_eq!(bridge.resize_count(), 1);
+        assert_eq!(bridge.pty.size().await.unwrap(), (120, 40));
+        assert_eq!(bridge.parser.screen().size(), (40, 120));
+        assert_eq!(bridge.size(), (120, 40));
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_zero_resize_is_clamped() {
+        let pty = spawn_pty("sleep 5");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        let (relay_tx, _client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+
+        input_tx.send(RelayMessage::Resize(ResizeMessage { cols: 0, rows: 0 })).await.unwrap();
+        drop(input_tx);
+
+        let bridge = handle.await.unwrap();
+        assert_eq!(bridge.resize_count(), 1);
+        assert_eq!(bridge.pty.size().await.unwrap(), (MIN_TERMINAL_DIMENSION, MIN_TERMINAL_DIMENSION));
+        assert_eq!(bridge.parser.screen().size(), (MIN_TERMINAL_DIMENSION, MIN_TERMINAL_DIMENSION));
+        let _ = bridge.pty.kill().await;
+    }
+
+    /// Collect everything the bridge sent to the relay
+    pub(crate) fn collect_sent(client_rx: &mut mpsc::Receiver<ClientMessage>) -> Vec<ClientMessage> {
+        let mut sent = Vec::new();
+        while let Ok(msg) = client_rx.try_recv() {
+            sent.push(msg);
+        }
+        sent
+    }
+
+    /// Concatenated output bytes from the sent messages
+    pub(crate) fn output_bytes(sent: &[ClientMessage]) -> Vec<u8> {
+        sent.iter()
+            .filter_map(|msg| match msg {
+                ClientMessage::Output(data) | ClientMessage::SequencedOutput { data, .. } => Some(data.as_slice()),
+                _ => None,
+            })
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_final_output_reaches_relay() {
+        let pty = spawn_pty("printf 'final line\\n'");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (_input_tx, input_rx) = mpsc::channel(64);
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = collect_sent(&mut client_rx);
+        let output = String::from_utf8_lossy(&output_bytes(&sent)).to_string();
+        assert!(output.contains("final line"), "output was {:?}", output);
+
+        // The exit comes after all output
+        assert_eq!(result, Some(0));
+        assert!(matches!(sent.last(), Some(ClientMessage::Exit(0))));
+    }
+
+    #[tokio::test]
+    async fn test_paused_output_flushed_on_exit() {
+        let pty = spawn_pty("sleep 0.3; printf 'while paused'; sleep 0.2");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        input_tx.send(RelayMessage::Pause).await.unwrap();
+
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let sent = collect_sent(&mut client_rx);
+        let output = String::from_utf8_lossy(&output_bytes(&sent)).to_string();
+        assert!(output.contains("while paused"), "output was {:?}", output);
+        assert_eq!(result, Some(0));
+        assert!(matches!(sent.last(), Some(ClientMessage::Exit(0))));
+    }
+
+    #[tokio::test]
+    async fn test_pause_buffer_spills_past_memory_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut buffer = PauseBuffer::new(Some(dir.path().to_path_buf()));
+        let mut expected = Vec::new();
+        for i in 0..100u32 {
+            let chunk = vec![(i % 251) as u8; 10_000];
+            expected.extend_from_slice(&chunk);
+            buffer.push(chunk).await;
+        }
+        assert!(buffer.spilled());
+        assert!(buffer.chunks.iter().map(Vec::len).sum::<usize>() <= PAUSE_MEMORY_BYTES);
+        // Unlinked as soon as it was created
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let recovered = buffer.take().await.collect().await;
+        assert_eq!(recovered, expected);
+        assert!(!buffer.spilled());
+        assert!(buffer.take().await.next_chunk().await.is_none());
+
+        // A second pause/resume cycle on the same connection spills again
+        for _ in 0..100 {
+            buffer.push(vec![7; 10_000]).await;
+        }
+        assert!(buffer.spilled());
+        assert_eq!(buffer.take().await.collect().await, vec![7; 1_000_000]);
+
+        // Without a spill directory it all stays in memory
+        let mut buffer = PauseBuffer::new(None);
+        buffer.push(vec![0; PAUSE_MEMORY_BYTES + 1]).await;
+        assert!(!buffer.spilled());
+        assert_eq!(buffer.take().await.collect().await.len(), PAUSE_MEMORY_BYTES + 1);
+    }
+
+    #[tokio::test]
+    async fn test_long_pause_recovered_from_spill_file() {
+        // Several times the in-memory limit, produced while paused; with a
+        // throttle the spill file is streamed back as the queue drains
+        for max_output_rate in [None, Some(4_000_000)] {
+            let pty = spawn_pty("seq 1 100000; sleep 1.5");
+            let dir = tempfile::tempdir().unwrap();
+            let options = BridgeOptions { pause_spill_dir: Some(dir.path().to_path_buf()), max_output_rate, ..BridgeOptions::default() };
+            let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+            let (relay_tx, mut client_rx) = mpsc::channel(64);
+            let (input_tx, input_rx) = mpsc::channel(64);
+            input_tx.send(RelayMessage::Pause).await.unwrap();
+            let run = tokio::spawn(async move { bridge.run(relay_tx, input_rx).await });
+
+            tokio::time::sleep(Duration::from_millis(800)).await;
+            assert!(collect_sent(&mut client_rx).iter().all(|msg| !matches!(msg, ClientMessage::Output(_))));
+            input_tx.send(RelayMessage::Resume).await.unwrap();
+
+            let mut sent = Vec::new();
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+            while let Ok(Some(msg)) = tokio::time::timeout_at(deadline, client_rx.recv()).await {
+                let exited = matches!(msg, ClientMessage::Exit(_));
+                sent.push(msg);
+                if exited {
+                    break;
+                }
+            }
+            assert_eq!(run.await.unwrap().unwrap(), Some(0));
+
+            let output = String::from_utf8(output_bytes(&sent)).unwrap();
+            let numbers: Vec<u32> = output.split("\r\n").filter_map(|line| line.parse().ok()).collect();
+            assert_eq!(numbers, (1..=100000).collect::<Vec<_>>());
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fast_exit_reports_exit_code() {
+        // A child that exits immediately races the reader's EOF against the exit status
+        for _ in 0..10 {
+            let pty = spawn_pty("exit 3");
+            let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+            let (relay_tx, mut client_rx) = mpsc::channel(64);
+            let (_input_tx, input_rx) = mpsc::channel(64);
+            let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+                .await
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(result, Some(3));
+            assert!(matches!(collect_sent(&mut client_rx).last(), Some(ClientMessage::Exit(3))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_signal_exit_reports_128_plus_signal() {
+        let pty = spawn_pty("kill -KILL $$");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (_input_tx, input_rx) = mpsc::channel(64);
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(result, Some(128 + 9));
+        assert!(matches!(collect_sent(&mut client_rx).last(), Some(ClientMessage::Exit(137))));
+    }
+
+    #[test]
+    fn test_cursor_shape_tracker() {
+        let mut tracker = CursorShapeTracker::new();
+        assert_eq!(tracker.shape, CursorShape::Block);
+
+        tracker.process(b"hello\x1b[4 q");
+        assert_eq!(tracker.shape, CursorShape::Underline);
+
+        // Split across reads
+        tracker.process(b"\x1b[");
+        tracker.process(b"6 ");
+        tracker.process(b"q");
+        assert_eq!(tracker.shape, CursorShape::Bar);
+
+        // Reset to default, unrelated CSI sequences, and out-of-range values
+        tracker.process(b"\x1b[ q");
+        assert_eq!(tracker.shape, CursorShape::Block);
+        tracker.process(b"\x1b[31m\x1b[1;4 q\x1b[9 q");
+        assert_eq!(tracker.shape, CursorShape::Block);
+    }
+
+    #[test]
+    fn test_window_size_tracker() {
+        let mut tracker = WindowSizeTracker::new();
+        tracker.process(b"\x1b[8;30;100t");
+        assert_eq!(tracker.take_request(), Some((100, 30)));
+        assert_eq!(tracker.take_request(), None);
+
+        // Split across reads
+        tracker.process(b"\x1b[8;4");
+        tracker.process(b"0;120t");
+        assert_eq!(tracker.take_request(), Some((120, 40)));
+
+        // Other window ops, extra parameters and zero sizes are ignored
+        tracker.process(b"\x1b[14t\x1b[9;1;2t\x1b[8;1;2;3t\x1b[8;0;80t");
+        assert_eq!(tracker.take_request(), None);
+    }
+
+    #[tokio::test]
+    async fn test_program_resize_reported() {
+        let pty = spawn_pty("sleep 0.2; printf '\\033[8;30;100t'; sleep 0.3");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (_input_tx, input_rx) = mpsc::channel(64);
+        bridge.run(relay_tx, input_rx).await.unwrap();
+
+        let sent = collect_sent(&mut client_rx);
+        assert!(
+            sent.iter().any(|msg| matches!(msg, ClientMessage::SizeChanged { cols: 100, rows: 30 })),
+            "{:?}",
+            sent
+        );
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_reports_cursor_shape() {
+        let pty = spawn_pty("sleep 5");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        bridge.process_output(b"\x1b[4 q");
+        let snapshot = bridge.create_snapshot("req".to_string());
+        assert_eq!(snapshot.cursor_shape, "underline");
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_with_escape_split_across_reads() {
+        let pty = spawn_pty("sleep 5");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        // A snapshot between the reads shows neither the partial sequence nor its effect
+        bridge.process_output(b"plain \x1b[3");
+        let screen = bridge.create_snapshot("mid".to_string()).screen;
+        assert_eq!(bridge.parser.screen().contents(), "plain ");
+        assert!(!String::from_utf8_lossy(&screen).contains("[3"), "{:?}", screen);
+
+        bridge.process_output(b"1mred\x1b[0m");
+        let cell = bridge.parser.screen().cell(0, 6).unwrap();
+        assert_eq!(cell.contents(), "r");
+        assert_eq!(cell.fgcolor(), vt100::Color::Idx(1));
+        let screen = String::from_utf8_lossy(&bridge.create_snapshot("after".to_string()).screen).into_owned();
+        assert!(screen.contains("\x1b[31mred"), "{:?}", screen);
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_snapshot_drops_oldest_scrollback() {
+        let pty = spawn_pty("sleep 5");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+        for n in 0..200 {
+            bridge.process_output(format!("\x1b[3{}m<{}>\x1b[0m\r\n", n % 8, n).as_bytes());
+        }
+        bridge.process_output(b"$ ");
+        let visible = bridge.parser.screen().contents_formatted();
+
+        // Without a limit, the history goes into the viewer's scrollback
+        let snapshot = bridge.create_snapshot("full".to_string());
+        assert!(!snapshot.truncated);
+        assert!(snapshot.screen.ends_with(&visible));
+        let mut replay = vt100::Parser::new(24, 80, 1000);
+        replay.process(&snapshot.screen);
+        assert_eq!(replay.screen().contents(), bridge.parser.screen().contents());
+        assert_eq!(replay.screen().cursor_position(), (23, 2));
+        replay.set_size(24 + 177, 80);
+        replay.set_scrollback(1000);
+        assert_eq!(replay.screen().scrollback(), 177);
+        let history: Vec<String> = replay.screen().rows(0, 80).take(177).collect();
+        assert_eq!(history.first().unwrap(), "<0>");
+        assert_eq!(history.last().unwrap(), "<176>");
+
+        // The oldest rows are left out; the visible screen is sent as is
+        bridge.options.max_snapshot_bytes = Some(visible.len() + 1000);
+        let snapshot = bridge.create_snapshot("trimmed".to_string());
+        assert!(snapshot.truncated);
+        assert!(snapshot.screen.len() <= visible.len() + 1000, "{} bytes", snapshot.screen.len());
+        assert!(snapshot.screen.ends_with(&visible));
+        let text = String::from_utf8_lossy(&snapshot.screen);
+        assert!(!text.contains("<0>") && text.contains("<176>"), "{:?}", text);
+        let mut replay = vt100::Parser::new(24, 80, 1000);
+        replay.process(&snapshot.screen);
+        assert_eq!(replay.screen().contents(), bridge.parser.screen().contents());
+
+        // Just room for the visible screen
+        bridge.options.max_snapshot_bytes = Some(visible.len());
+        let snapshot = bridge.create_snapshot("screen".to_string());
+        assert!(snapshot.truncated);
+        assert_eq!(snapshot.screen, visible);
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_oversized_snapshot_drops_styling() {
+        let pty = spawn_pty("sleep 5");
+        let options = BridgeOptions {
+            max_snapshot_bytes: Some(4096),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+        // Every cell in a different color than its neighbours
+        for row in 0..24 {
+            let line: String = (0..79).map(|col| format!("\x1b[3{}m{}", (row + col) % 8, col % 10)).collect();
+            bridge.process_output(format!("{}\r\n", line).as_bytes());
+        }
+        bridge.process_output(b"\x1b[0m$ ");
+        // The visible screen alone is over the limit
+        assert!(bridge.parser.screen().contents_formatted().len() > 4096);
+
+        let snapshot = bridge.create_snapshot("big".to_string());
+        assert!(snapshot.truncated);
+        assert!(snapshot.screen.len() <= 4096, "{} bytes", snapshot.screen.len());
+        // Replaying the snapshot gives back the same text and cursor
+        let mut replay = vt100::Parser::new(24, 80, 0);
+        replay.process(&snapshot.screen);
+        assert_eq!(replay.screen().contents(), bridge.parser.screen().contents());
+        assert_eq!(replay.screen().cursor_position(), (23, 2));
+
+        // Still too big as plain text: the top rows are left out, the rest stay in place
+        bridge.options.max_snapshot_bytes = Some(1000);
+        let snapshot = bridge.create_snapshot("trimmed".to_string());
+        assert!(snapshot.truncated);
+        assert!(snapshot.screen.len() <= 1000, "{} bytes", snapshot.screen.len());
+        let mut replay = vt100::Parser::new(24, 80, 0);
+        replay.process(&snapshot.screen);
+        let kept: Vec<String> = replay.screen().rows(0, 80).collect();
+        let rows: Vec<String> = bridge.parser.screen().rows(0, 80).collect();
+        let first = kept.iter().position(|row| !row.is_empty()).unwrap();
+        assert!(first > 0 && first < 23, "first kept row {}", first);
+        assert_eq!(kept[first..], rows[first..]);
+        assert_eq!(replay.screen().cursor_position(), (23, 2));
+
+        bridge.options.max_snapshot_bytes = None;
+        let snapshot = bridge.create_snapshot("full".to_string());
+        assert!(!snapshot.truncated);
+        assert!(snapshot.screen.len() > 4096);
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_scrollback_request_sends_ordered_chunks() {
+        let pty = spawn_pty("sleep 5");
+        let options = BridgeOptions {
+            scrollback_bytes: 1024 * 1024,
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+        let lines: Vec<String> = (0..3000).map(|i| format!("line {:04} {}", i, "x".repeat(40))).collect();
+        let output = lines.join("\r\n");
+        for chunk in output.as_bytes().chunks(4096) {
+            bridge.process_output(chunk);
+        }
+
+        let (requests_tx, requests_rx) = mpsc::channel(8);
+        bridge.set_requests(requests_rx);
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+        requests_tx.send(BridgeRequest::Scrollback { request_id: "log".to_string() }).await.unwrap();
+
+        let mut text = Vec::new();
+        let mut indexes = Vec::new();
+        let end = loop {
+            match tokio::time::timeout(Duration::from_secs(5), client_rx.recv()).await.unwrap().unwrap() {
+                ClientMessage::ScrollbackChunk { meta, data } => {
+                    assert_eq!(meta.request_id, "log");
+                    assert!(data.len() <= SCROLLBACK_CHUNK_BYTES);
+                    indexes.push(meta.index);
+                    text.extend_from_slice(&data);
+                }
+                ClientMessage::ScrollbackEnd(end) => break end,
+                other => panic!("unexpected message {:?}", other),
+            }
+        };
+        assert_eq!(end, ScrollbackEnd { request_id: "log".to_string(), chunks: indexes.len() as u32 });
+        assert!(indexes.len() > 1, "history should span several chunks");
+        assert_eq!(indexes, (0..indexes.len() as u32).collect::<Vec<_>>());
+        assert_eq!(String::from_utf8(text).unwrap(), output);
+
+        drop(input_tx);
+        let bridge = handle.await.unwrap();
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_announce_join() {
+        let pty = spawn_pty("sleep 5");
+        let options = BridgeOptions {
+            announce_join: true,
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        drop(input_tx);
+        bridge.run(relay_tx, input_rx).await.unwrap();
+
+        let sent = collect_sent(&mut client_rx);
+        match sent.first() {
+            Some(ClientMessage::Output(data)) => {
+                assert!(String::from_utf8_lossy(data).contains("viewer connected"));
+            }
+            other => panic!("expected join notice, got {:?}", other),
+        }
+        let snapshot = bridge.create_snapshot("req".to_string());
+        assert!(!String::from_utf8_lossy(&snapshot.screen).contains("viewer connected"));
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[test]
+    fn test_injected_messages_without_color() {
+        assert_eq!(join_notice(true), "\r\n\x1b[2m\u{2014} viewer connected \u{2014}\x1b[0m\r\n".as_bytes());
+        let plain = join_notice(false);
+        assert!(!plain.contains(&0x1b), "{:?}", String::from_utf8_lossy(&plain));
+        assert_eq!(plain, "\r\n\u{2014} viewer connected \u{2014}\r\n".as_bytes());
+        assert_eq!(styled("hi", "7", false), "hi");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_output_idle_after_silence() {
+        let pty = spawn_pty("printf hi; sleep 5");
+        let options = BridgeOptions {
+            output_idle: Some(Duration::from_millis(500)),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+
+        // Let the reader pick up the output in real time
+        std::thread::sleep(Duration::from_millis(300));
+        let is_idle = |msg: &ClientMessage| matches!(msg, ClientMessage::OutputIdle);
+
+        advance(Duration::from_millis(200)).await;
+        let sent = collect_sent(&mut client_rx);
+        assert_eq!(output_bytes(&sent), b"hi");
+        assert!(!sent.iter().any(is_idle), "idle before the interval elapsed");
+
+        advance(Duration::from_millis(400)).await;
+        let sent = collect_sent(&mut client_rx);
+        assert_eq!(sent.iter().filter(|msg| is_idle(msg)).count(), 1);
+
+        // Once per quiet period
+        advance(Duration::from_secs(1)).await;
+        assert!(!collect_sent(&mut client_rx).iter().any(is_idle));
+
+        drop(input_tx);
+        let bridge = handle.await.unwrap();
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_max_output_rate_paces_output() {
+        let pty = spawn_pty("head -c 6000 /dev/zero | tr '\\0' x; sleep 5");
+        let options = BridgeOptions {
+            max_output_rate: Some(1000),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(1024);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+
+        // Let the reader pick up all the output in real time
+        std::thread::sleep(Duration::from_millis(300));
+
+        // 100 bytes of burst, then 1000 bytes per second
+        let mut received = 0;
+        for (step, elapsed, expected) in [(1, 1, 1100), (2, 3, 3100)] {
+            advance(Duration::from_secs(step)).await;
+            received += output_bytes(&collect_sent(&mut client_rx)).len();
+            assert!(
+                received.abs_diff(expected) <= 150,
+                "after {}s received {} bytes, expected about {}",
+                elapsed,
+                received,
+                expected
+            );
+        }
+
+        drop(input_tx);
+        let bridge = handle.await.unwrap();
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_sequence_numbers_increase_monotonically() {
+        let pty = spawn_pty("for i in 1 2 3 4 5; do echo line$i; sleep 0.05; done");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        input_tx
+            .send(RelayMessage::Handshake(RelayHandshake { sequence: true, ..Default::default() }))
+            .await
+            .unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, Some(0));
+
+        let sent = collect_sent(&mut client_rx);
+        let seqs: Vec<u32> = sent
+            .iter()
+            .filter_map(|msg| match msg {
+                ClientMessage::SequencedOutput { seq, .. } => Some(*seq),
+                _ => None,
+            })
+            .collect();
+        assert!(seqs.len() >= 2, "sent {:?}", sent);
+        assert_eq!(seqs, (0..seqs.len() as u32).collect::<Vec<_>>());
+
+        let output = String::from_utf8_lossy(&output_bytes(&sent)).to_string();
+        assert!(output.contains("line5"), "output was {:?}", output);
+    }
+
+    #[tokio::test]
+    async fn test_init_command_runs_in_interactive_shell() {
+        let handle = PtyHandle::spawn(&SpawnOptions { working_dir: std::env::temp_dir(), ..SpawnOptions::default() }).unwrap();
+        let pty = AsyncPty::new(handle).unwrap();
+        let options = BridgeOptions {
+            init_command: Some("echo init-$((6 * 7)); exit 3".to_string()),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (_input_tx, input_rx) = mpsc::channel(64);
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, Some(3));
+
+        // The expansion only appears if the shell actually ran the command
+        let output = String::from_utf8_lossy(&output_bytes(&collect_sent(&mut client_rx))).to_string();
+        assert!(output.contains("init-42"), "output was {:?}", output);
+    }
+
+    #[tokio::test]
+    async fn test_local_input_reaches_pty() {
+        let pty = spawn_pty("tr a-z A-Z");
+        let (stdin_tx, stdin_rx) = mpsc::channel(8);
+        let options = BridgeOptions {
+            local_input: Some(Arc::new(std::sync::Mutex::new(Some(stdin_rx)))),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        // The last line has no newline, so EOF needs the double Ctrl-D
+        stdin_tx.send(b"piped\n".to_vec()).await.unwrap();
+        stdin_tx.send(b"input".to_vec()).await.unwrap();
+        drop(stdin_tx);
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (_input_tx, input_rx) = mpsc::channel(64);
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, Some(0));
+
+        let output = String::from_utf8_lossy(&output_bytes(&collect_sent(&mut client_rx))).to_string();
+        assert!(output.contains("PIPED") && output.contains("INPUT"), "output was {:?}", output);
+    }
+
+    #[tokio::test]
+    async fn test_output_charset_transcodes_latin1() {
+        let pty = spawn_pty("printf 'caf\\351 na\\357ve\\n'");
+        let options = BridgeOptions {
+            output_charset: Encoding::for_label(b"latin1"),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (_input_tx, input_rx) = mpsc::channel(64);
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, Some(0));
+
+        let output = String::from_utf8(output_bytes(&collect_sent(&mut client_rx))).unwrap();
+        assert!(output.contains("café naïve"), "output was {:?}", output);
+        assert!(bridge.parser.screen().contents().contains("café naïve"));
+    }
+
+    #[tokio::test]
+    async fn test_redact_secret_split_across_reads() {
+        // The sleep splits the token across two PTY reads
+        let pty = spawn_pty("printf 'token ghp_abc'; sleep 0.02; printf 'def123 done\\n'");
+        let options = BridgeOptions {
+            redact: Some(Arc::new(Redaction::new(&["ghp_[A-Za-z0-9]+".to_string()]).unwrap())),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (_input_tx, input_rx) = mpsc::channel(64);
+        let result = tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result, Some(0));
+
+        let output = String::from_utf8(output_bytes(&collect_sent(&mut client_rx))).unwrap();
+        assert!(output.contains("token *** done"), "output was {:?}", output);
+        assert!(!output.contains("abc") && !output.contains("def123"), "output was {:?}", output);
+        assert!(!bridge.parser.screen().contents().contains("abc"));
+    }
+
+    #[test]
+    fn test_output_decoder_buffers_split_sequences() {
+        // "日本" in Shift_JIS, split inside the first character
+        let mut decoder = OutputDecoder::new(encoding_rs::SHIFT_JIS);
+        assert!(decoder.decode(&[0x93]).is_empty());
+        assert_eq!(decoder.decode(&[0xfa, 0x96]), "日".as_bytes());
+        assert_eq!(decoder.decode(&[0x7b]), "本".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_observer_input_ignored() {
+        let pty = spawn_pty("sleep 5");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+        let metrics = bridge.options.metrics.clone();
+
+        let (relay_tx, _client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        input_tx
+            .send(RelayMessage::Handshake(RelayHandshake {
+                role: ConnectionRole::Observer,
+                ..Default::default()
+            }))
+            .await
+            .unwrap();
+        input_tx.send(RelayMessage::Input(b"rm -rf /\n".to_vec())).await.unwrap();
+        drop(input_tx);
+        bridge.run(relay_tx, input_rx).await.unwrap();
+
+        assert_eq!(metrics.bytes_in(), 0);
+        assert!(bridge.pending_input.is_empty());
+
+        // The next connection starts as a controller again
+        let (relay_tx, _client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        input_tx.send(RelayMessage::Input(b"ls\n".to_vec())).await.unwrap();
+        drop(input_tx);
+        bridge.run(relay_tx, input_rx).await.unwrap();
+        assert_eq!(metrics.bytes_in(), 3);
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[test]
+    fn test_replay_buffer() {
+        let mut replay = ReplayBuffer::new(8);
+        replay.push(b"hello");
+        assert_eq!(replay.range(0, 5), b"hello");
+        assert_eq!(replay.range(2, 4), b"ll");
+
+        // Older bytes are evicted once the capacity is exceeded
+        replay.push(b" world");
+        assert_eq!(replay.end, 11);
+        assert_eq!(replay.range(0, 11), b"lo world");
+        assert_eq!(replay.range(6, 11), b"world");
+
+        // A single push larger than the capacity keeps its tail
+        replay.push(b"0123456789");
+        assert_eq!(replay.range(0, replay.end), b"23456789");
+        assert!(replay.range(21, 21).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_output_during_gap_replayed_on_reconnect() {
+        let pty = spawn_pty("printf 'missed output'; sleep 5");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        // First connection drops before the output can be delivered
+        let (relay_tx, client_rx) = mpsc::channel(64);
+        drop(client_rx);
+        let (_input_tx, input_rx) = mpsc::channel(64);
+        assert_eq!(bridge.run(relay_tx, input_rx).await.unwrap(), None);
+
+        // The reconnecting client asks for a replay
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        input_tx
+            .send(RelayMessage::Handshake(RelayHandshake { replay: true, ..Default::default() }))
+            .await
+            .unwrap();
+        drop(input_tx);
+        bridge.run(relay_tx, input_rx).await.unwrap();
+
+        let output = String::from_utf8_lossy(&output_bytes(&collect_sent(&mut client_rx))).to_string();
+        assert!(output.contains("missed output"), "output was {:?}", output);
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_reset_on_reconnect_precedes_replay_and_snapshot() {
+        let pty = spawn_pty("sleep 0.3; printf 'missed output'; sleep 5");
+        let options = BridgeOptions {
+            reset_on_reconnect: Some(ResetSequence::Full),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        // The first connection gets no reset, and ends before the output arrives
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        drop(input_tx);
+        bridge.run(relay_tx, input_rx).await.unwrap();
+        assert!(collect_sent(&mut client_rx).is_empty());
+        tokio::time::sleep(Duration::from_millis(500)).await;
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+        input_tx
+            .send(RelayMessage::Handshake(RelayHandshake { replay: true, ..Default::default() }))
+            .await
+            .unwrap();
+        input_tx
+            .send(RelayMessage::RequestSnapshot(SnapshotRequest { request_id: "s".to_string() }))
+            .await
+            .unwrap();
+        let mut sent = Vec::new();
+        while !String::from_utf8_lossy(&output_bytes(&sent)).contains("missed output") {
+            let msg = tokio::time::timeout(Duration::from_secs(5), client_rx.recv()).await.unwrap().unwrap();
+            sent.push(msg);
+        }
+        drop(input_tx);
+        let bridge = handle.await.unwrap();
+        sent.extend(collect_sent(&mut client_rx));
+
+        assert!(matches!(sent.first(), Some(ClientMessage::Output(data)) if data == b"\x1bc"), "{:?}", sent);
+        assert!(sent.iter().any(|msg| matches!(msg, ClientMessage::Snapshot(_))), "{:?}", sent);
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_motd_follows_reset_on_reconnect() {
+        let pty = spawn_pty("sleep 5");
+        let options = BridgeOptions {
+            reset_on_reconnect: Some(ResetSequence::Full),
+            motd: Some(b"Session recorded\r\n".to_vec()),
+            ..BridgeOptions::default()
+        };
+        let mut bridge = Bridge::new(pty, 80, 24, options).await.unwrap();
+
+        let mut firsts = Vec::new();
+        for _ in 0..2 {
+            let (relay_tx, mut client_rx) = mpsc::channel(64);
+            let (input_tx, input_rx) = mpsc::channel(64);
+            drop(input_tx);
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            let outputs: Vec<Vec<u8>> = collect_sent(&mut client_rx)
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    ClientMessage::Output(data) => Some(data),
+                    _ => None,
+                })
+                .collect();
+            firsts.push(outputs);
+        }
+
+        assert_eq!(firsts[0], vec![b"Session recorded\r\n".to_vec()]);
+        assert_eq!(firsts[1], vec![b"\x1bc".to_vec(), b"Session recorded\r\n".to_vec()]);
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_large_input_does_not_stall_bridge() {
+        // The child doesn't read its input for a while, so the PTY input buffer fills up
+        let pty = spawn_pty("sleep 1; stty size; cat > /dev/null");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+
+        input_tx.send(RelayMessage::Input(vec![b'x'; 1024 * 1024])).await.unwrap();
+        input_tx.send(RelayMessage::Resize(ResizeMessage { cols: 120, rows: 40 })).await.unwrap();
+
+        // Output arrives while the paste is still being written
+        let mut output = String::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !output.contains("40 120") {
+            let msg = tokio::time::timeout_at(deadline, client_rx.recv())
+                .await
+                .expect("bridge stalled")
+                .unwrap();
+            if let ClientMessage::Output(data) = msg {
+                output.push_str(&String::from_utf8_lossy(&data));
+            }
+        }
+
+        // The echoed paste can still fill the relay channel, so stop reading it too
+        drop(client_rx);
+        drop(input_tx);
+        let bridge = handle.await.unwrap();
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_input_flow_watermarks() {
+        // Input is held back until the child starts reading, then drained by `cat`
+        let pty = spawn_pty("stty -echo; sleep 0.5; cat > /dev/null");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+        bridge.set_capabilities(&Capabilities { input_flow: true, ..Default::default() });
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+
+        // Below the high watermark nothing is paused
+        let line = [vec![b'x'; 63], vec![b'\n']].concat();
+        input_tx.send(RelayMessage::Input(line.repeat(INPUT_HIGH_WATERMARK / 64))).await.unwrap();
+        // Pushing past it pauses once, and draining to the low watermark resumes
+        input_tx.send(RelayMessage::Input(line.repeat(INPUT_HIGH_WATERMARK / 64))).await.unwrap();
+
+        let mut flow = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !flow.contains(&"resume") {
+            let msg = tokio::time::timeout_at(deadline, client_rx.recv())
+                .await
+                .expect("input never resumed")
+                .unwrap();
+            match msg {
+                ClientMessage::InputPause => flow.push("pause"),
+                ClientMessage::InputResume => flow.push("resume"),
+                _ => {}
+            }
+        }
+        assert_eq!(flow, ["pause", "resume"]);
+
+        drop(input_tx);
+        let bridge = handle.await.unwrap();
+        assert!(bridge.pending_input_bytes <= INPUT_LOW_WATERMARK);
+        let _ = bridge.pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_input_capped_without_input_flow() {
+        // The child never reads (and without canonical mode the terminal stops
+        // taking input once its buffer fills), and the relay can't be asked to pause
+        let pty = spawn_pty("stty -echo -icanon; sleep 1");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(1);
+        let handle = tokio::spawn(async move {
+            bridge.run(relay_tx, input_rx).await.unwrap();
+            bridge
+        });
+        let relay = tokio::spawn(async move {
+            for _ in 0..8 {
+                if input_tx.send(RelayMessage::Input(vec![b'x'; INPUT_HIGH_WATERMARK])).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Past the cap the bridge stops reading, which holds the relay back
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!relay.is_finished());
+        let bridge = handle.await.unwrap();
+        relay.abort();
+
+        // The input taken is still queued, up to one message past the cap, and
+        // no pause frame went out
+        assert!(bridge.pending_input_bytes > INPUT_HIGH_WATERMARK, "{}", bridge.pending_input_bytes);
+        assert!(bridge.pending_input_bytes < INPUT_MAX_PENDING + INPUT_HIGH_WATERMARK);
+        while let Ok(msg) = client_rx.try_recv() {
+            assert!(!matches!(msg, ClientMessage::InputPause | ClientMessage::InputResume), "{:?}", msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resize_before_first_output() {
+        let pty = spawn_pty("sleep 0.3; stty size; sleep 0.2");
+        let mut bridge = Bridge::new(pty, 80, 24, BridgeOptions::default()).await.unwrap();
+
+        // Queued before the bridge starts running, like a resize right after the handshake
+        let (relay_tx, mut client_rx) = mpsc::channel(64);
+        let (input_tx, input_rx) = mpsc::channel(64);
+        input_tx.send(RelayMessage::Resize(ResizeMessage { cols: 120, rows: 40 })).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(5), bridge.run(relay_tx, input_rx))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let output = String::from_utf8_lossy(&output_bytes(&collect_sent(&mut client_rx))).to_string();
+        assert!(output.contains("40 120"), "output was {:?}", output);
+        assert_eq!(bridge.pty.size().await.unwrap(), (120, 40));
+    }
+}
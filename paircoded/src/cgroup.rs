@@ -0,0 +1,117 @@
+//! CPU and memory limits for terminals (`--memory-limit`, `--cpu-quota`).
+//!
+//! On Linux the command is started through `systemd-run --scope`, which moves
+//! it into a cgroup v2 scope of its own with the limits set before it execs,
+//! so everything the shell starts is capped together. The PID stays the same,
+//! and so do the PTY, environment and working directory.
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// Limits applied to each terminal's process tree
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ResourceLimits {
+    /// Hard memory cap in bytes (`MemoryMax`)
+    pub memory_max: Option<u64>,
+    /// CPU time as a percentage of one CPU (`CPUQuota`, over 100 allows several)
+    pub cpu_quota: Option<u32>,
+}
+
+impl ResourceLimits {
+    /// Whether no limit is set (commands then run unwrapped)
+    pub fn is_unlimited(&self) -> bool {
+        self.memory_max.is_none() && self.cpu_quota.is_none()
+    }
+
+    /// systemd unit properties that apply the limits
+    pub fn properties(&self) -> Vec<String> {
+        let mut properties = Vec::new();
+        if let Some(bytes) = self.memory_max {
+            properties.push(format!("MemoryMax={}", bytes));
+        }
+        if let Some(percent) = self.cpu_quota {
+            properties.push(format!("CPUQuota={}%", percent));
+        }
+        properties
+    }
+}
+
+/// Wrap `program args` so it runs in a scope with `limits`.
+///
+/// Returns (command, args), unchanged when there are no limits. Root uses the
+/// system manager; anyone else their own user manager.
+pub fn build_scope_args(limits: &ResourceLimits, program: String, args: Vec<String>) -> (String, Vec<String>) {
+    if limits.is_unlimited() {
+        return (program, args);
+    }
+    let mut scope_args: Vec<String> = vec!["--scope".into(), "--quiet".into(), "--collect".into()];
+    if !crate::privilege::is_root() {
+        scope_args.insert(0, "--user".into());
+    }
+    for property in limits.properties() {
+        scope_args.extend(["-p".into(), property]);
+    }
+    scope_args.push("--".into());
+    scope_args.push(program);
+    scope_args.extend(args);
+    ("systemd-run".into(), scope_args)
+}
+
+/// Check that `limits` can be applied on this host by starting `true` under
+/// them, so a missing piece is reported once at startup instead of as a
+/// failed spawn for every terminal
+#[cfg(target_os = "linux")]
+pub fn check_available(limits: &ResourceLimits) -> Result<()> {
+    use anyhow::bail;
+    use std::process::Command;
+
+    if !std::path::Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+        bail!("--memory-limit and --cpu-quota need cgroup v2, but /sys/fs/cgroup is not a cgroup2 mount");
+    }
+    let (program, args) = build_scope_args(limits, "true".into(), Vec::new());
+    let output = match Command::new(&program).args(&args).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("--memory-limit and --cpu-quota need systemd-run, which is not installed")
+        }
+        Err(e) => bail!("failed to run systemd-run: {}", e),
+    };
+    if !output.status.success() {
+        bail!(
+            "systemd-run could not create a scope with the resource limits \
+             (is a systemd user session running, with the cpu and memory controllers delegated?): {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(())
+}
+
+/// Resource limits need cgroups, so Linux only
+#[cfg(not(target_os = "linux"))]
+pub fn check_available(_limits: &ResourceLimits) -> Result<()> {
+    anyhow::bail!("--memory-limit and --cpu-quota are only supported on Linux")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_properties() {
+        assert!(ResourceLimits::default().properties().is_empty());
+        let limits = ResourceLimits { memory_max: Some(512 * 1024 * 1024), cpu_quota: Some(150) };
+        assert_eq!(limits.properties(), ["MemoryMax=536870912", "CPUQuota=150%"]);
+    }
+
+    #[test]
+    fn test_build_scope_args() {
+        let command = ("/bin/bash".to_string(), vec!["-l".to_string()]);
+        assert_eq!(build_scope_args(&ResourceLimits::default(), command.0.clone(), command.1.clone()), command);
+
+        let limits = ResourceLimits { memory_max: None, cpu_quota: Some(50) };
+        let (program, args) = build_scope_args(&limits, command.0, command.1);
+        assert_eq!(program, "systemd-run");
+        assert_eq!(args.contains(&"--user".to_string()), !crate::privilege::is_root());
+        assert!(args.ends_with(&["-p".into(), "CPUQuota=50%".into(), "--".into(), "/bin/bash".into(), "-l".into()]));
+    }
+}
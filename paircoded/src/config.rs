@@ -1,14 +1,31 @@
 //! Configuration and CLI argument handling.
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
+use encoding_rs::{Encoding, UTF_8};
 use rand::Rng;
+use serde::{Deserialize, Serialize, Serializer};
 use std::env;
-use std::path::PathBuf;
-use tracing::warn;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use tracing::{debug, warn};
 use url::Url;
 
+use crate::auth::{self, AuthProviderKind};
+use crate::bridge::{ResetSequence, DEFAULT_EXIT_FLUSH_DELAY, DEFAULT_EXIT_GRACE, DEFAULT_RESIZE_DEBOUNCE};
+use crate::cgroup::ResourceLimits;
+use crate::env_file;
+use crate::pty::{validate_env_var, DEFAULT_MAX_READER_RESTARTS};
+use crate::redact::Redaction;
+use crate::relay::{
+    fill_path_template, validate_path_template, BackoffStrategy, Keepalive, DEFAULT_CONTROL_PATH_TEMPLATE, DEFAULT_DATA_URL_TEMPLATE,
+};
 use crate::sandbox;
+use crate::terminal_manager::DEFAULT_QUICK_RETRIES;
 
 /// Default relay URL
 const DEFAULT_RELAY_URL: &str = "https://retrievable-timidly-drusilla.ngrok-free.app";
@@ -20,60 +37,606 @@ pub struct Args {
     /// Working directory path for the terminal (default: current directory)
     pub path: Option<PathBuf>,
 
+    /// Config file with defaults (default: ~/.config/paircoded/config.toml, or
+    /// config-<profile>.toml with --profile)
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Named profile with its own saved login and config defaults
+    #[arg(long, value_name = "NAME", value_parser = auth::parse_profile)]
+    pub profile: Option<String>,
+
+    /// Relay URL (default: $PAIRCODED_RELAY_URL or the public relay)
+    #[arg(long, value_name = "URL")]
+    pub relay_url: Option<String>,
+
     /// Authenticate with GitHub (uses Device Flow)
     #[arg(long)]
     pub login: bool,
 
-    /// Session name (default: <username>-<8 random digits>)
+    /// Identity provider to authenticate with
+    #[arg(long, value_enum, default_value_t = AuthProviderKind::Github)]
+    pub auth_provider: AuthProviderKind,
+
+    /// Use this GitHub token instead of device flow (never saved; also $PAIRCODED_GITHUB_TOKEN)
+    #[arg(long, value_name = "TOKEN")]
+    pub github_token: Option<String>,
+
+    /// Trust saved authentication without checking it against the GitHub API
+    /// (for offline setups; logs in only if nothing is saved)
+    #[arg(long)]
+    pub skip_token_validation: bool,
+
+    /// Path of the relay's control endpoint, with `{session}` filled in
+    #[arg(long, value_name = "PATH", value_parser = parse_control_path_template)]
+    pub control_path_template: Option<String>,
+
+    /// Path of each terminal's data endpoint, with `{session}` and `{name}` filled in
+    #[arg(long, value_name = "PATH", value_parser = parse_data_url_template)]
+    pub data_url_template: Option<String>,
+
+    /// Session name (default: [<prefix>-]<username>-<8 random digits>)
     #[arg(short = 'n', long)]
     pub session: Option<String>,
 
+    /// Prefix for generated session names (also $PAIRCODED_SESSION_PREFIX)
+    #[arg(long, value_name = "PREFIX", value_parser = parse_session_prefix)]
+    pub session_prefix: Option<String>,
+
+    /// Refuse to start if another paircoded on this machine is running the same session
+    #[arg(long)]
+    pub session_lock: bool,
+
+    /// Hostname reported to the relay (default: the OS hostname)
+    #[arg(long, value_name = "NAME", value_parser = parse_hostname)]
+    pub hostname: Option<String>,
+
     /// Shell to spawn (default: $SHELL or /bin/sh)
     #[arg(short, long)]
     pub shell: Option<String>,
 
+    /// Extra argument for the interactive shell (repeatable, ignored with --command)
+    #[arg(long = "shell-arg", value_name = "ARG", allow_hyphen_values = true)]
+    pub shell_args: Vec<String>,
+
     /// Run a specific command instead of shell
     #[arg(short, long)]
     pub command: Option<String>,
 
+    /// Kill the --command after this many seconds, exiting with 124 like
+    /// coreutils `timeout` (ignored without --command)
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub command_timeout: Option<u64>,
+
+    /// Type this into the interactive shell once it starts, followed by a newline
+    /// (ignored with --command)
+    #[arg(long, value_name = "COMMAND")]
+    pub init_command: Option<String>,
+
+    /// Environment variable for spawned terminals (repeatable; overrides --env-file)
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_var)]
+    pub env: Vec<(String, String)>,
+
+    /// Load environment variables for spawned terminals from this dotenv-style
+    /// file (repeatable; later files win, and it overrides the config file)
+    #[arg(long = "env-file", value_name = "PATH")]
+    pub env_files: Vec<PathBuf>,
+
+    /// Show this banner in the browser whenever a terminal connects; it is
+    /// sent to the relay only and never reaches the shell
+    #[arg(long, value_name = "TEXT")]
+    pub motd: Option<String>,
+
+    /// Exit after the first terminal exits, with its exit code
+    #[arg(long)]
+    pub once: bool,
+
     /// Enable verbose logging
     #[arg(short, long)]
     pub verbose: bool,
 
+    /// Log a one-line summary of every protocol frame sent or received
+    #[arg(long)]
+    pub trace_protocol: bool,
+
+    /// Show at most this many payload bytes per frame in protocol logs
+    #[arg(long, value_name = "BYTES")]
+    pub max_frame_log_bytes: Option<usize>,
+
+    /// Run in the background, printing the pairing URL once connected; output
+    /// and logs go to --log-file (Unix only)
+    #[arg(long)]
+    pub detach: bool,
+
+    /// Append logs to this file instead of printing them (with --detach, the
+    /// session banner too)
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<PathBuf>,
+
+    /// Write the process ID to this file while running
+    #[arg(long, value_name = "PATH")]
+    pub pid_file: Option<PathBuf>,
+
     /// Disable automatic reconnection on disconnect
     #[arg(long)]
     pub no_reconnect: bool,
 
+    /// Connect without exchanging the GitHub login for a relay token (for
+    /// local and test relays that don't check auth)
+    #[arg(long)]
+    pub no_relay_token: bool,
+
+    /// Delay between reconnect attempts: exponential (1s doubling up to 60s),
+    /// exponential:<BASE_MS>:<MAX_MS> or fixed:<MS> (default: exponential, up
+    /// to 30s for data connections)
+    #[arg(long, value_name = "STRATEGY", value_parser = parse_reconnect_strategy)]
+    pub reconnect_strategy: Option<BackoffStrategy>,
+
+    /// Quick retries of a new terminal's first data connection before the
+    /// reconnect backoff applies (default: 3, 0 disables)
+    #[arg(long, value_name = "N")]
+    pub data_quick_retries: Option<u32>,
+
+    /// Delay between quick data connection retries (default: 200)
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub data_quick_retry_ms: Option<u64>,
+
+    /// Terminate a terminal's process when its data connection drops
+    #[arg(long)]
+    pub kill_on_disconnect: bool,
+
+    /// Show a notice in viewers' terminals when they connect
+    #[arg(long)]
+    pub announce_join: bool,
+
+    /// Send paircoded's own messages (MOTD, join notices) without ANSI styling;
+    /// also set by a non-empty NO_COLOR. The shell's output is never changed
+    #[arg(long)]
+    pub no_color: bool,
+
+    /// Style paircoded's own messages even when NO_COLOR is set
+    #[arg(long, conflicts_with = "no_color")]
+    pub force_color: bool,
+
+    /// Reset the browser's terminal before anything else on every reconnect:
+    /// `full` (ESC c, the default) or `soft` (CSI ! p, keeps the screen)
+    #[arg(long, value_enum, value_name = "MODE", num_args = 0..=1, default_missing_value = "full")]
+    pub reset_on_reconnect: Option<ResetSequence>,
+
+    /// Forward piped stdin into the first terminal as input, ending it on EOF
+    /// (ignored when stdin is a terminal)
+    #[arg(long)]
+    pub stdin_input: bool,
+
+    /// Show a live status line while running (only when stdout is a terminal)
+    #[arg(long)]
+    pub status: bool,
+
     /// Enable sandboxing to restrict filesystem access to the working directory
     /// (Linux: bubblewrap, macOS: sandbox-exec)
     #[arg(long)]
     pub sandbox: bool,
+
+    /// Cap each terminal's memory (bytes, or with a K/M/G/T suffix); Linux
+    /// with cgroup v2 and systemd only
+    #[arg(long, value_name = "BYTES", value_parser = parse_memory_limit)]
+    pub memory_limit: Option<u64>,
+
+    /// Cap each terminal's CPU time as a percentage of one CPU (200 = two
+    /// CPUs); Linux with cgroup v2 and systemd only
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u32).range(1..))]
+    pub cpu_quota: Option<u32>,
+
+    /// Allow running as root (anyone in the session gets a root shell)
+    #[arg(long)]
+    pub allow_root: bool,
+
+    /// Append a JSON record of every spawned command to this file
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<PathBuf>,
+
+    /// Keep a list of running terminals in this file; on startup, terminals a
+    /// previous run left behind are reported
+    #[arg(long, value_name = "PATH")]
+    pub state_file: Option<PathBuf>,
+
+    /// Record every keystroke sent to terminals to this file (captures passwords too)
+    #[arg(long, value_name = "PATH")]
+    pub log_input: Option<PathBuf>,
+
+    /// File created while connected to the relay (readiness probe)
+    #[arg(long, value_name = "PATH")]
+    pub ready_file: Option<PathBuf>,
+
+    /// File updated with a timestamp on each keepalive (liveness probe)
+    #[arg(long, value_name = "PATH")]
+    pub health_file: Option<PathBuf>,
+
+    /// Coalesce resize requests arriving within this many milliseconds (0 disables)
+    #[arg(long, value_name = "MS")]
+    pub resize_debounce_ms: Option<u64>,
+
+    /// How long to wait for the exit status after the PTY output closes
+    #[arg(long, value_name = "MS")]
+    pub exit_grace_ms: Option<u64>,
+
+    /// Keep a terminal's data connection open this long after reporting its exit,
+    /// so the final output reaches viewers before the close (0 disables)
+    #[arg(long, value_name = "MS")]
+    pub preserve_exit_output_ms: Option<u64>,
+
+    /// Reopen the PTY reader after this many read errors before treating output as ended
+    #[arg(long, value_name = "N")]
+    pub max_reader_restarts: Option<u32>,
+
+    /// Treat a relay that sends nothing (not even a ping) this long after the
+    /// handshake as dead and reconnect
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub handshake_timeout_ms: Option<u64>,
+
+    /// On shutdown, wait up to this long for the relay to answer the control
+    /// connection's close frame before exiting
+    #[arg(long, value_name = "MS")]
+    pub drain_timeout_ms: Option<u64>,
+
+    /// Record up to this many bytes of each terminal's output for scrollback requests
+    #[arg(long, value_name = "BYTES")]
+    pub scrollback_bytes: Option<usize>,
+
+    /// Keep snapshots under this size by leaving out the oldest scrollback;
+    /// a visible screen alone over it is sent without styling and top rows
+    #[arg(long, value_name = "BYTES")]
+    pub max_snapshot_bytes: Option<usize>,
+
+    /// While the relay has output paused, write what doesn't fit in memory to
+    /// a temp file in this directory instead of holding it all in memory
+    #[arg(long, value_name = "DIR")]
+    pub pause_spill_dir: Option<PathBuf>,
+
+    /// Ping the relay this often on each terminal's data connection
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub keepalive_interval_ms: Option<u64>,
+
+    /// Spread keepalive pings of different terminals by up to this much
+    /// (default: a tenth of the interval)
+    #[arg(long, value_name = "MS")]
+    pub keepalive_jitter_ms: Option<u64>,
+
+    /// Tell the relay when a terminal has produced no output for this long
+    #[arg(long, value_name = "MS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub output_idle_ms: Option<u64>,
+
+    /// Present this hostname as the TLS server name (SNI) instead of the relay
+    /// URL host, e.g. when connecting to a shared ingress by IP
+    #[arg(long, value_name = "HOSTNAME")]
+    pub tls_sni: Option<String>,
+
+    /// Send terminal output that is valid UTF-8 as websocket text frames
+    /// (other output, sequenced output and all other messages stay binary)
+    #[arg(long)]
+    pub text_output: bool,
+
+    /// Present this PEM certificate to the relay (mTLS); needs --client-key
+    #[arg(long, value_name = "PEM", requires = "client_key")]
+    pub client_cert: Option<PathBuf>,
+
+    /// PKCS#8 PEM private key for --client-cert
+    #[arg(long, value_name = "PEM", requires = "client_cert")]
+    pub client_key: Option<PathBuf>,
+
+    /// Limit output sent to the relay to this many bytes per second per terminal
+    #[arg(long, value_name = "BYTES_PER_SEC", value_parser = clap::value_parser!(u64).range(1..))]
+    pub max_output_rate: Option<u64>,
+
+    /// Character set the shell writes (e.g. latin1, shift_jis); output is
+    /// transcoded to UTF-8 before forwarding
+    #[arg(long, value_name = "CHARSET", value_parser = parse_charset)]
+    pub output_charset: Option<&'static Encoding>,
+
+    /// Replace matches of this regex in terminal output with *** before it
+    /// leaves the host (repeatable)
+    #[arg(long = "redact", value_name = "REGEX")]
+    pub redact: Vec<String>,
+
+    /// Print the resolved configuration (secrets redacted) as JSON and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    /// Reuse the relay URL and session of the last successful connection
+    /// (explicit --relay-url/--session still win)
+    #[arg(long)]
+    pub resume: bool,
+
+    /// Extra header for relay websocket connections (repeatable)
+    #[arg(long = "header", value_name = "KEY:VALUE", value_parser = parse_header)]
+    pub headers: Vec<(String, String)>,
+}
+
+/// Parse and validate a `KEY:VALUE` header argument
+pub fn parse_header(s: &str) -> std::result::Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("invalid header '{}': expected KEY:VALUE", s))?;
+    let name = name.trim();
+    let value = value.trim();
+
+    HeaderName::from_bytes(name.as_bytes())
+        .map_err(|_| format!("invalid header name '{}'", name))?;
+    HeaderValue::from_str(value)
+        .map_err(|_| format!("invalid value for header '{}'", name))?;
+
+    Ok((name.to_string(), value.to_string()))
+}
+
+/// Parse and validate a `KEY=VALUE` environment variable argument
+pub fn parse_env_var(s: &str) -> std::result::Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid environment variable '{}': expected KEY=VALUE", s))?;
+    validate_env_var(key, value).map_err(|e| e.to_string())?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+/// Look up an `--output-charset` label (WHATWG encoding names and aliases)
+pub fn parse_charset(label: &str) -> std::result::Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| format!("unknown charset '{}'", label))
+}
+
+/// Defaults loaded from a config file.
+///
+/// Keys mirror the CLI flags. Values here are only used when the corresponding
+/// flag is not given on the command line.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FileConfig {
+    pub path: Option<PathBuf>,
+    pub relay_url: Option<String>,
+    pub session: Option<String>,
+    pub session_prefix: Option<String>,
+    pub session_lock: Option<bool>,
+    pub control_path_template: Option<String>,
+    pub data_url_template: Option<String>,
+    pub hostname: Option<String>,
+    pub shell: Option<String>,
+    pub shell_arg: Option<Vec<String>>,
+    pub command: Option<String>,
+    pub command_timeout: Option<u64>,
+    pub env: Option<Vec<String>>,
+    pub env_file: Option<Vec<PathBuf>>,
+    pub once: Option<bool>,
+    pub verbose: Option<bool>,
+    pub trace_protocol: Option<bool>,
+    pub max_frame_log_bytes: Option<usize>,
+    pub log_file: Option<PathBuf>,
+    pub pid_file: Option<PathBuf>,
+    pub no_reconnect: Option<bool>,
+    pub no_relay_token: Option<bool>,
+    pub reconnect_strategy: Option<String>,
+    pub data_quick_retries: Option<u32>,
+    pub data_quick_retry_ms: Option<u64>,
+    pub kill_on_disconnect: Option<bool>,
+    pub status: Option<bool>,
+    pub announce_join: Option<bool>,
+    pub no_color: Option<bool>,
+    pub force_color: Option<bool>,
+    pub reset_on_reconnect: Option<ResetSequence>,
+    pub stdin_input: Option<bool>,
+    pub sandbox: Option<bool>,
+    pub memory_limit: Option<String>,
+    pub cpu_quota: Option<u32>,
+    pub allow_root: Option<bool>,
+    pub skip_token_validation: Option<bool>,
+    pub audit_log: Option<PathBuf>,
+    pub state_file: Option<PathBuf>,
+    pub log_input: Option<PathBuf>,
+    pub init_command: Option<String>,
+    pub motd: Option<String>,
+    pub header: Option<Vec<String>>,
+    pub ready_file: Option<PathBuf>,
+    pub health_file: Option<PathBuf>,
+    pub resize_debounce_ms: Option<u64>,
+    pub exit_grace_ms: Option<u64>,
+    pub preserve_exit_output_ms: Option<u64>,
+    pub max_reader_restarts: Option<u32>,
+    pub max_output_rate: Option<u64>,
+    pub handshake_timeout_ms: Option<u64>,
+    pub drain_timeout_ms: Option<u64>,
+    pub output_idle_ms: Option<u64>,
+    pub keepalive_interval_ms: Option<u64>,
+    pub keepalive_jitter_ms: Option<u64>,
+    pub scrollback_bytes: Option<usize>,
+    pub max_snapshot_bytes: Option<usize>,
+    pub pause_spill_dir: Option<PathBuf>,
+    pub tls_sni: Option<String>,
+    pub text_output: Option<bool>,
+    pub client_cert: Option<PathBuf>,
+    pub client_key: Option<PathBuf>,
+    pub output_charset: Option<String>,
+    pub redact: Option<Vec<String>>,
+}
+
+impl FileConfig {
+    /// Load the config file at `path`, or the profile's default location if `None`.
+    ///
+    /// A missing default file yields empty defaults; a missing explicit file is an error.
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let path = Self::default_path(profile)?;
+                if !path.exists() {
+                    debug!(?path, "no config file, using defaults");
+                    return Ok(FileConfig::default());
+                }
+                path
+            }
+        };
+
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        Self::parse(&path, &content)
+    }
+
+    /// Parse config file content, as JSON for `.json` files and TOML otherwise
+    pub fn parse(path: &Path, content: &str) -> Result<Self> {
+        let is_json = path.extension().is_some_and(|ext| ext == "json");
+        let result = if is_json {
+            serde_json::from_str(content).map_err(anyhow::Error::from)
+        } else {
+            toml::from_str(content).map_err(anyhow::Error::from)
+        };
+        result.with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    /// Default config file location for a profile
+    pub fn default_path(profile: Option<&str>) -> Result<PathBuf> {
+        Ok(auth::config_dir()?.join(auth::profile_file_name("config", "toml", profile)))
+    }
+}
+
+impl Args {
+    /// Fill in `--relay-url` and `--session` from a previous connection (`--resume`)
+    pub fn resume_from(&mut self, last: LastSession) {
+        self.relay_url.get_or_insert(last.relay_url);
+        self.session.get_or_insert(last.session);
+    }
+}
+
+/// Relay and session of the last successful control connection, kept for
+/// `--resume` (separate from the saved login)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LastSession {
+    pub relay_url: String,
+    pub session: String,
+}
+
+impl LastSession {
+    /// Record the relay origin and session a config connects to
+    pub fn from_config(config: &Config) -> Self {
+        LastSession {
+            relay_url: config.relay_url.origin().ascii_serialization(),
+            session: config.session_name.clone(),
+        }
+    }
+
+    /// Default location for a profile
+    pub fn default_path(profile: Option<&str>) -> Result<PathBuf> {
+        Ok(auth::config_dir()?.join(auth::profile_file_name("last-session", "json", profile)))
+    }
+
+    /// Load the last session from `path` (`None` if nothing was saved yet)
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read last session {}", path.display()))?;
+        let last = serde_json::from_str(&content)
+            .with_context(|| format!("invalid last session file {}", path.display()))?;
+        Ok(Some(last))
+    }
+
+    /// Save to `path`, creating its directory if needed
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("failed to write last session {}", path.display()))
+    }
 }
 
 /// Runtime configuration derived from CLI args and environment
-#[derive(Debug, Clone)]
+///
+/// Serializes to a redacted view for `--print-config`.
+#[derive(Debug, Clone, Serialize)]
 pub struct Config {
     /// Parsed and validated relay URL (WebSocket)
+    #[serde(serialize_with = "serialize_display")]
     pub relay_url: Url,
 
     /// Session name (e.g., "saurabhdas-12345678")
     pub session_name: String,
 
+    /// Control endpoint path on the relay, with `{session}` placeholder
+    pub control_path_template: String,
+
+    /// Data endpoint path on the relay, with `{session}` and `{name}` placeholders
+    pub data_url_template: String,
+
     /// Dashboard URL to display to user
     pub dashboard_url: String,
 
+    /// Browser URL for pairing on this session (plain text, safe to copy-paste)
+    pub browser_url: String,
+
     /// Working directory for the terminal
     pub working_dir: PathBuf,
 
     /// Shell command to execute
     pub shell: String,
 
+    /// Extra arguments for the interactive shell
+    pub shell_args: Vec<String>,
+
     /// Optional command to run instead of interactive shell
     pub command: Option<String>,
 
+    /// Kill `command` after this long (`None` with an interactive shell)
+    #[serde(rename = "command_timeout_ms", serialize_with = "serialize_opt_millis")]
+    pub command_timeout: Option<Duration>,
+
+    /// Typed into each interactive shell on start (`None` with `command`)
+    pub init_command: Option<String>,
+
+    /// Banner shown to viewers on each data connection
+    pub motd: Option<String>,
+
+    /// Extra environment for spawned terminals: `--env-file` contents, then `--env`
+    #[serde(serialize_with = "serialize_redacted_env")]
+    pub env: Vec<(String, String)>,
+
+    /// Exit after the first terminal exits, propagating its exit code
+    pub once: bool,
+
+    /// Hold a per-session lock so a second instance can't start (`--session-lock`)
+    pub session_lock: bool,
+
     /// Auto-reconnect on disconnect
     pub reconnect: bool,
 
+    /// Connect without a relay token
+    pub no_relay_token: bool,
+
+    /// Delay between reconnect attempts (each connection's default if not set)
+    #[serde(serialize_with = "serialize_opt_display")]
+    pub reconnect_strategy: Option<BackoffStrategy>,
+
+    /// Quick retries of each terminal's first data connection
+    pub data_quick_retries: u32,
+
+    /// Delay between quick data connection retries
+    #[serde(rename = "data_quick_retry_ms", serialize_with = "serialize_millis")]
+    pub data_quick_retry_interval: Duration,
+
+    /// Terminate a terminal's process instead of reconnecting its data connection
+    pub kill_on_disconnect: bool,
+
+    /// Show a live status line on stdout
+    pub status: bool,
+
+    /// Announce new viewer connections in the terminal output
+    pub announce_join: bool,
+
+    /// Style the messages paircoded injects into the output
+    pub color: bool,
+
+    /// Reset sent to viewers first on each reconnect (`--reset-on-reconnect`)
+    pub reset_on_reconnect: Option<ResetSequence>,
+
+    /// Forward local stdin into the first terminal
+    pub stdin_input: bool,
+
     /// Computer hostname
     pub hostname: String,
 
@@ -82,20 +645,123 @@ pub struct Config {
 
     /// Sandbox mode (uses bubblewrap on Linux)
     pub sandbox: bool,
+
+    /// CPU and memory caps for each terminal (`--memory-limit`, `--cpu-quota`)
+    pub resource_limits: ResourceLimits,
+
+    /// Audit log file for spawned commands (line-delimited JSON)
+    pub audit_log: Option<PathBuf>,
+
+    /// Running terminals file (JSON)
+    pub state_file: Option<PathBuf>,
+
+    /// Keystroke log file (line-delimited JSON, base64 payloads)
+    pub log_input: Option<PathBuf>,
+
+    /// Extra headers sent on relay websocket upgrades
+    #[serde(serialize_with = "serialize_redacted_headers")]
+    pub headers: Vec<(String, String)>,
+
+    /// Readiness probe file (exists while connected)
+    pub ready_file: Option<PathBuf>,
+
+    /// Liveness probe file (timestamp of last keepalive)
+    pub health_file: Option<PathBuf>,
+
+    /// Window for coalescing rapid resize requests
+    #[serde(rename = "resize_debounce_ms", serialize_with = "serialize_millis")]
+    pub resize_debounce: Duration,
+
+    /// Grace period for the exit status after the PTY output closes
+    #[serde(rename = "exit_grace_ms", serialize_with = "serialize_millis")]
+    pub exit_grace: Duration,
+
+    /// Pause between the exit notice and closing the data connection
+    #[serde(rename = "preserve_exit_output_ms", serialize_with = "serialize_millis")]
+    pub exit_flush_delay: Duration,
+
+    /// PTY read errors recovered from by reopening the reader
+    pub max_reader_restarts: u32,
+
+    /// Per-terminal output cap in bytes per second (unlimited if not set)
+    pub max_output_rate: Option<u64>,
+
+    /// Required response window after a handshake (disabled if not set)
+    #[serde(rename = "handshake_timeout_ms", serialize_with = "serialize_opt_millis")]
+    pub handshake_timeout: Option<Duration>,
+
+    /// Shutdown wait for the relay's close reply (exit immediately if not set)
+    #[serde(rename = "drain_timeout_ms", serialize_with = "serialize_opt_millis")]
+    pub drain_timeout: Option<Duration>,
+
+    /// Silence after which an output idle notice is sent (disabled if not set)
+    #[serde(rename = "output_idle_ms", serialize_with = "serialize_opt_millis")]
+    pub output_idle: Option<Duration>,
+
+    /// Client pings on data connections (disabled if not set)
+    #[serde(serialize_with = "serialize_keepalive")]
+    pub keepalive: Option<Keepalive>,
+
+    /// Output history recorded per terminal (0 answers scrollback requests with the screen)
+    pub scrollback_bytes: usize,
+
+    /// Snapshot size above which the oldest scrollback is left out (no limit if not set)
+    pub max_snapshot_bytes: Option<usize>,
+
+    /// Spill directory for paused output (all of it is kept in memory if not set)
+    pub pause_spill_dir: Option<PathBuf>,
+
+    /// TLS server name for relay connections (URL host if not set)
+    pub tls_sni: Option<String>,
+
+    /// Send UTF-8 output as text frames
+    pub text_output: bool,
+
+    /// Client certificate and key presented on TLS connections (none if not set)
+    pub client_identity: Option<(PathBuf, PathBuf)>,
+
+    /// Charset PTY output is transcoded from (passthrough if not set or UTF-8)
+    #[serde(serialize_with = "serialize_charset")]
+    pub output_charset: Option<&'static Encoding>,
+
+    /// Patterns masked in terminal output (none if not set)
+    #[serde(serialize_with = "serialize_redaction")]
+    pub redact: Option<Arc<Redaction>>,
 }
 
 impl Config {
-    /// Create configuration from CLI arguments and a username
-    pub fn from_args(args: Args, username: &str) -> Result<Self> {
-        // Generate session name: <username>-<8 random digits>
-        let session_name = args.session.unwrap_or_else(|| {
+    /// Create configuration from CLI arguments, config file defaults and a username
+    ///
+    /// CLI arguments take precedence over the config file, which takes precedence
+    /// over built-in defaults.
+    pub fn from_args(args: Args, file: FileConfig, username: &str) -> Result<Self> {
+        let client_identity = client_identity_paths(&args, &file)?;
+
+        // Generate session name: [<prefix>-]<username>-<8 random digits>
+        let session_prefix = match args
+            .session_prefix
+            .or_else(|| env::var(SESSION_PREFIX_ENV).ok().filter(|prefix| !prefix.is_empty()))
+            .or(file.session_prefix)
+        {
+            Some(prefix) => Some(
+                parse_session_prefix(&prefix).map_err(|e| anyhow!("invalid session prefix '{}': {}", prefix, e))?,
+            ),
+            None => None,
+        };
+        let session_name = args.session.or(file.session).unwrap_or_else(|| {
             let random_digits: u32 = rand::thread_rng().gen_range(10000000..99999999);
-            format!("{}-{}", username, random_digits)
+            match session_prefix {
+                Some(prefix) => format!("{}-{}-{}", prefix, username, random_digits),
+                None => format!("{}-{}", username, random_digits),
+            }
         });
 
-        // Get relay URL from environment or use default
-        let relay_base = env::var("PAIRCODED_RELAY_URL")
-            .unwrap_or_else(|_| DEFAULT_RELAY_URL.to_string());
+        // Get relay URL from CLI, environment or config file, or use default
+        let relay_base = args
+            .relay_url
+            .or_else(|| env::var("PAIRCODED_RELAY_URL").ok())
+            .or(file.relay_url)
+            .unwrap_or_else(|| DEFAULT_RELAY_URL.to_string());
 
         // Parse the base URL to determine scheme
         let base_url = Url::parse(&relay_base)
@@ -115,9 +781,27 @@ impl Config {
             .ok_or_else(|| anyhow!("relay URL has no host"))?;
         let port_str = base_url.port().map(|p| format!(":{}", p)).unwrap_or_default();
 
+        let control_path_template = match args.control_path_template.or(file.control_path_template) {
+            Some(template) => parse_control_path_template(&template).map_err(|e| anyhow!("invalid control path template: {}", e))?,
+            None => DEFAULT_CONTROL_PATH_TEMPLATE.to_string(),
+        };
+        let reconnect_strategy = match args.reconnect_strategy {
+            Some(strategy) => Some(strategy),
+            None => file
+                .reconnect_strategy
+                .map(|s| parse_reconnect_strategy(&s).map_err(|e| anyhow!("invalid reconnect strategy '{}': {}", s, e)))
+                .transpose()?,
+        };
+        let data_url_template = match args.data_url_template.or(file.data_url_template) {
+            Some(template) => parse_data_url_template(&template).map_err(|e| anyhow!("invalid data URL template: {}", e))?,
+            None => DEFAULT_DATA_URL_TEMPLATE.to_string(),
+        };
         let relay_url = Url::parse(&format!(
-            "{}://{}{}/ws/control/{}",
-            ws_scheme, host, port_str, session_name
+            "{}://{}{}{}",
+            ws_scheme,
+            host,
+            port_str,
+            fill_path_template(&control_path_template, &session_name, "")
         ))?;
 
         // Construct dashboard URL
@@ -130,8 +814,10 @@ impl Config {
             http_scheme, host, port_str
         );
 
+        let browser_url = build_browser_url(&dashboard_url, &session_name)?;
+
         // Determine working directory
-        let working_dir = if let Some(path) = args.path {
+        let working_dir = if let Some(path) = args.path.or(file.path) {
             if path.is_absolute() {
                 path
             } else {
@@ -146,17 +832,22 @@ impl Config {
             .unwrap_or(working_dir);
 
         // Determine shell to use
-        let shell = args.shell.unwrap_or_else(|| {
+        let shell = args.shell.or(file.shell).unwrap_or_else(|| {
             env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string())
         });
 
-        // Get system info
-        let hostname = hostname::get()
-            .map(|h| h.to_string_lossy().to_string())
-            .unwrap_or_else(|_| "unknown".to_string());
+        // Get system info (an explicit hostname wins over the OS one)
+        let hostname = match args.hostname {
+            Some(hostname) => hostname,
+            None => match file.hostname {
+                Some(hostname) => parse_hostname(&hostname)
+                    .map_err(|e| anyhow!("invalid hostname in config file: {}", e))?,
+                None => os_hostname(),
+            },
+        };
 
         // Determine sandbox mode (disabled by default, enable with --sandbox)
-        let sandbox = if args.sandbox {
+        let sandbox = if args.sandbox || file.sandbox.unwrap_or(false) {
             if sandbox::is_sandbox_available() {
                 true
             } else {
@@ -167,99 +858,1023 @@ impl Config {
             false
         };
 
+        let memory_max = match args.memory_limit {
+            Some(bytes) => Some(bytes),
+            None => file
+                .memory_limit
+                .map(|s| parse_memory_limit(&s).map_err(|e| anyhow!("invalid memory limit '{}': {}", s, e)))
+                .transpose()?,
+        };
+        let cpu_quota = args.cpu_quota.or(file.cpu_quota);
+        if cpu_quota == Some(0) {
+            return Err(anyhow!("cpu_quota must be at least 1"));
+        }
+        let resource_limits = ResourceLimits { memory_max, cpu_quota };
+
+        // Headers from the config file, with CLI headers replacing any of the same name
+        let mut headers = file
+            .header
+            .unwrap_or_default()
+            .iter()
+            .map(|h| parse_header(h).map_err(|e| anyhow!("config file: {}", e)))
+            .collect::<Result<Vec<_>>>()?;
+        headers.retain(|(name, _)| {
+            !args.headers.iter().any(|(cli_name, _)| cli_name.eq_ignore_ascii_case(name))
+        });
+        headers.extend(args.headers);
+
+        let command = args.command.or(file.command);
+        let init_command = args.init_command.or(file.init_command).filter(|_| {
+            if command.is_some() {
+                warn!("--init-command is ignored with --command");
+            }
+            command.is_none()
+        });
+        let command_timeout = args
+            .command_timeout
+            .or(file.command_timeout)
+            .filter(|_| {
+                if command.is_none() {
+                    warn!("--command-timeout is ignored without --command");
+                }
+                command.is_some()
+            })
+            .map(Duration::from_secs);
+
+        // Later entries win: config file env files, config file `env`, then
+        // `--env-file`, then `--env`
+        let mut env = Vec::new();
+        for path in file.env_file.iter().flatten() {
+            env.extend(env_file::load(path)?);
+        }
+        for var in file.env.iter().flatten() {
+            env.push(parse_env_var(var).map_err(|e| anyhow!("config file: {}", e))?);
+        }
+        for path in &args.env_files {
+            env.extend(env_file::load(path)?);
+        }
+        env.extend(args.env);
+
+        let output_charset = match args.output_charset {
+            Some(charset) => Some(charset),
+            None => file
+                .output_charset
+                .as_deref()
+                .map(parse_charset)
+                .transpose()
+                .map_err(|e| anyhow!("config file: {}", e))?,
+        };
+
+        // Config file patterns apply alongside the CLI ones
+        let mut redact = file.redact.unwrap_or_default();
+        redact.extend(args.redact);
+        let redact = Some(redact)
+            .filter(|patterns| !patterns.is_empty())
+            .map(|patterns| Redaction::new(&patterns))
+            .transpose()
+            .map_err(|e| anyhow!(e))?
+            .map(Arc::new);
+
         Ok(Config {
             relay_url,
             session_name,
+            control_path_template,
+            data_url_template,
             dashboard_url,
+            browser_url,
             working_dir,
             shell,
-            command: args.command,
-            reconnect: !args.no_reconnect,
+            shell_args: if args.shell_args.is_empty() {
+                file.shell_arg.unwrap_or_default()
+            } else {
+                args.shell_args
+            },
+            command,
+            command_timeout,
+            init_command,
+            motd: args.motd.or(file.motd).filter(|motd| !motd.is_empty()),
+            env,
+            once: args.once || file.once.unwrap_or(false),
+            session_lock: args.session_lock || file.session_lock.unwrap_or(false),
+            reconnect: !(args.no_reconnect || file.no_reconnect.unwrap_or(false)),
+            no_relay_token: args.no_relay_token || file.no_relay_token.unwrap_or(false),
+            reconnect_strategy,
+            data_quick_retries: args
+                .data_quick_retries
+                .or(file.data_quick_retries)
+                .unwrap_or(DEFAULT_QUICK_RETRIES.attempts),
+            data_quick_retry_interval: args
+                .data_quick_retry_ms
+                .or(file.data_quick_retry_ms)
+                .map_or(DEFAULT_QUICK_RETRIES.interval, Duration::from_millis),
+            kill_on_disconnect: args.kill_on_disconnect || file.kill_on_disconnect.unwrap_or(false),
+            status: args.status || file.status.unwrap_or(false),
+            announce_join: args.announce_join || file.announce_join.unwrap_or(false),
+            color: color_enabled(
+                args.force_color || file.force_color.unwrap_or(false),
+                args.no_color || file.no_color.unwrap_or(false),
+                env::var_os(NO_COLOR_ENV).as_deref(),
+            ),
+            reset_on_reconnect: args.reset_on_reconnect.or(file.reset_on_reconnect),
+            stdin_input: args.stdin_input || file.stdin_input.unwrap_or(false),
             hostname,
             username: username.to_string(),
             sandbox,
+            resource_limits,
+            audit_log: args.audit_log.or(file.audit_log),
+            state_file: args.state_file.or(file.state_file),
+            log_input: args.log_input.or(file.log_input),
+            headers,
+            ready_file: args.ready_file.or(file.ready_file),
+            health_file: args.health_file.or(file.health_file),
+            resize_debounce: args
+                .resize_debounce_ms
+                .or(file.resize_debounce_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_RESIZE_DEBOUNCE),
+            exit_grace: args
+                .exit_grace_ms
+                .or(file.exit_grace_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_EXIT_GRACE),
+            exit_flush_delay: args
+                .preserve_exit_output_ms
+                .or(file.preserve_exit_output_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_EXIT_FLUSH_DELAY),
+            max_reader_restarts: args
+                .max_reader_restarts
+                .or(file.max_reader_restarts)
+                .unwrap_or(DEFAULT_MAX_READER_RESTARTS),
+            max_output_rate: args.max_output_rate.or(file.max_output_rate).filter(|&rate| rate > 0),
+            handshake_timeout: args
+                .handshake_timeout_ms
+                .or(file.handshake_timeout_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            drain_timeout: args
+                .drain_timeout_ms
+                .or(file.drain_timeout_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            output_idle: args
+                .output_idle_ms
+                .or(file.output_idle_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            keepalive: args
+                .keepalive_interval_ms
+                .or(file.keepalive_interval_ms)
+                .filter(|&ms| ms > 0)
+                .map(|ms| Keepalive {
+                    interval: Duration::from_millis(ms),
+                    jitter: args
+                        .keepalive_jitter_ms
+                        .or(file.keepalive_jitter_ms)
+                        .map_or(Duration::from_millis(ms / 10), Duration::from_millis),
+                }),
+            scrollback_bytes: args.scrollback_bytes.or(file.scrollback_bytes).unwrap_or(0),
+            max_snapshot_bytes: args.max_snapshot_bytes.or(file.max_snapshot_bytes),
+            pause_spill_dir: args.pause_spill_dir.or(file.pause_spill_dir),
+            client_identity,
+            tls_sni: args.tls_sni.or(file.tls_sni).filter(|sni| !sni.is_empty()),
+            text_output: args.text_output || file.text_output.unwrap_or(false),
+            output_charset: output_charset.filter(|&charset| charset != UTF_8),
+            redact,
         })
     }
 
+    /// Switch to a new session name, rebuilding the control and browser URLs
+    pub fn rotate_session(&mut self, new_session: &str) -> Result<()> {
+        if new_session.is_empty() {
+            return Err(anyhow!("session name cannot be empty"));
+        }
+        self.relay_url
+            .set_path(&fill_path_template(&self.control_path_template, new_session, ""));
+        self.browser_url = build_browser_url(&self.dashboard_url, new_session)?;
+        self.session_name = new_session.to_string();
+        Ok(())
+    }
+
     /// Get the command and arguments to spawn
     pub fn spawn_command(&self) -> (&str, Vec<&str>) {
         if let Some(ref cmd) = self.command {
             // Run command with shell -c
             (&self.shell, vec!["-c", cmd])
         } else {
-            // Interactive shell
-            (&self.shell, vec![])
+            // Interactive shell, with any extra --shell-arg flags
+            (&self.shell, self.shell_args.iter().map(|s| s.as_str()).collect())
         }
     }
 }
 
+impl Config {
+    /// Pretty JSON dump of the configuration with secrets redacted
+    pub fn redacted_json(&self) -> Result<String> {
+        serde_json::to_string_pretty(self).context("failed to serialize config")
+    }
+}
+
+/// Placeholder for redacted values in `--print-config`
+const REDACTED: &str = "<redacted>";
+
+/// Whether a header's value should be hidden in config dumps
+fn is_sensitive_header(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    ["authorization", "cookie", "token", "secret", "key", "password"]
+        .iter()
+        .any(|needle| name.contains(needle))
+}
+
+fn serialize_display<T: std::fmt::Display, S: Serializer>(value: &T, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+fn serialize_opt_display<T: std::fmt::Display, S: Serializer>(
+    value: &Option<T>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.collect_str(value),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_millis<S: Serializer>(value: &Duration, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.serialize_u64(value.as_millis() as u64)
+}
+
+/// Browser URL for a session: /terminal/<session>/<session> (split view, same session both sides)
+fn build_browser_url(dashboard_url: &str, session: &str) -> Result<String> {
+    let mut browser_url = Url::parse(dashboard_url)?;
+    browser_url
+        .path_segments_mut()
+        .map_err(|_| anyhow!("relay URL cannot be a base"))?
+        .extend(["terminal", session, session]);
+    Ok(browser_url.to_string())
+}
+
+fn serialize_keepalive<S: Serializer>(value: &Option<Keepalive>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(keepalive) => serializer.serialize_some(&serde_json::json!({
+            "interval_ms": keepalive.interval.as_millis() as u64,
+            "jitter_ms": keepalive.jitter.as_millis() as u64,
+        })),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_opt_millis<S: Serializer>(value: &Option<Duration>, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_some(&(value.as_millis() as u64)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_charset<S: Serializer>(
+    value: &Option<&'static Encoding>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        Some(charset) => serializer.serialize_some(charset.name()),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_redaction<S: Serializer>(
+    value: &Option<Arc<Redaction>>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match value {
+        // The patterns themselves often spell out the secrets
+        Some(redaction) => serializer.collect_seq(redaction.patterns().iter().map(|_| REDACTED)),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_redacted_env<S: Serializer>(
+    env: &[(String, String)],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(env.iter().map(|(key, _)| format!("{}={}", key, REDACTED)))
+}
+
+fn serialize_redacted_headers<S: Serializer>(
+    headers: &[(String, String)],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_seq(headers.iter().map(|(name, value)| {
+        let value = if is_sensitive_header(name) { REDACTED } else { value.as_str() };
+        format!("{}: {}", name, value)
+    }))
+}
+
+/// Resolve `--client-cert`/`--client-key` (CLI over config file); both or
+/// neither must be set
+pub fn client_identity_paths(args: &Args, file: &FileConfig) -> Result<Option<(PathBuf, PathBuf)>> {
+    let cert = args.client_cert.clone().or_else(|| file.client_cert.clone());
+    let key = args.client_key.clone().or_else(|| file.client_key.clone());
+    match (cert, key) {
+        (Some(cert), Some(key)) => Ok(Some((cert, key))),
+        (None, None) => Ok(None),
+        (Some(_), None) => Err(anyhow!("client_cert is set but client_key is not")),
+        (None, Some(_)) => Err(anyhow!("client_key is set but client_cert is not")),
+    }
+}
+
+/// Parse a `--reconnect-strategy`: `exponential` (1s doubling up to 60s),
+/// `exponential:<BASE_MS>:<MAX_MS>` or `fixed:<MS>`
+pub fn parse_reconnect_strategy(value: &str) -> Result<BackoffStrategy, String> {
+    let ms = |s: &str| {
+        s.parse::<u64>()
+            .ok()
+            .filter(|&ms| ms > 0)
+            .map(Duration::from_millis)
+            .ok_or_else(|| format!("'{}' is not a positive number of milliseconds", s))
+    };
+    let mut parts = value.split(':');
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("exponential"), None, None, None) => Ok(BackoffStrategy::Exponential {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }),
+        (Some("exponential"), Some(base), Some(max), None) => {
+            let (base, max) = (ms(base)?, ms(max)?);
+            if max < base {
+                return Err("maximum delay must not be below the base delay".to_string());
+            }
+            Ok(BackoffStrategy::Exponential { base, max })
+        }
+        (Some("fixed"), Some(delay), None, None) => Ok(BackoffStrategy::Fixed(ms(delay)?)),
+        _ => Err("expected exponential, exponential:<BASE_MS>:<MAX_MS> or fixed:<MS>".to_string()),
+    }
+}
+
+/// Validate a `--control-path-template` (needs `{session}`)
+pub fn parse_control_path_template(template: &str) -> Result<String, String> {
+    validate_path_template(template, &["{session}"])
+}
+
+/// Validate a `--data-url-template` (needs `{session}` and `{name}`)
+pub fn parse_data_url_template(template: &str) -> Result<String, String> {
+    validate_path_template(template, &["{session}", "{name}"])
+}
+
+/// Parse a `--memory-limit`: a byte count, optionally with a binary K/M/G/T suffix
+pub fn parse_memory_limit(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (digits, shift) = match value.char_indices().last() {
+        Some((i, suffix)) if suffix.is_ascii_alphabetic() => {
+            let shift = match suffix.to_ascii_uppercase() {
+                'K' => 10,
+                'M' => 20,
+                'G' => 30,
+                'T' => 40,
+                _ => return Err(format!("unknown size suffix '{}' (use K, M, G or T)", suffix)),
+            };
+            (&value[..i], shift)
+        }
+        _ => (value, 0),
+    };
+    let number: u64 = digits.parse().map_err(|_| format!("'{}' is not a size in bytes", value))?;
+    if number == 0 {
+        return Err("memory limit must be greater than zero".to_string());
+    }
+    number.checked_mul(1 << shift).ok_or_else(|| format!("'{}' is too large", value))
+}
+
+/// Environment variable that turns off styling (<https://no-color.org>)
+pub const NO_COLOR_ENV: &str = "NO_COLOR";
+
+/// Whether paircoded's own messages are styled: `--force-color` wins, then
+/// `--no-color`, then a non-empty `NO_COLOR`
+fn color_enabled(force_color: bool, no_color: bool, no_color_env: Option<&OsStr>) -> bool {
+    force_color || !(no_color || no_color_env.is_some_and(|value| !value.is_empty()))
+}
+
+/// Environment variable with a prefix for generated session names
+pub const SESSION_PREFIX_ENV: &str = "PAIRCODED_SESSION_PREFIX";
+
+/// Maximum length of a session name prefix
+const MAX_SESSION_PREFIX_LEN: usize = 32;
+
+/// Validate a session name prefix: ASCII letters, digits, '-' and '_', not
+/// starting or ending with '-'
+pub fn parse_session_prefix(prefix: &str) -> Result<String, String> {
+    if prefix.is_empty() || prefix.len() > MAX_SESSION_PREFIX_LEN {
+        return Err(format!("session prefix must be 1-{} characters", MAX_SESSION_PREFIX_LEN));
+    }
+    if !prefix.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'))
+        || prefix.starts_with('-')
+        || prefix.ends_with('-')
+    {
+        return Err("session prefix may only contain letters, digits, '-' and '_' \
+                    and must not start or end with '-'"
+            .to_string());
+    }
+    Ok(prefix.to_string())
+}
+
+/// Maximum length of a DNS hostname
+const MAX_HOSTNAME_LEN: usize = 253;
+
+/// Hostname from the OS, sanitized
+fn os_hostname() -> String {
+    hostname::get()
+        .map(|h| sanitize_hostname(&h))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// Validate a `--hostname` override: the same charset and length `sanitize_hostname` allows
+pub fn parse_hostname(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.len() > MAX_HOSTNAME_LEN {
+        return Err(format!("hostname must be 1-{} characters", MAX_HOSTNAME_LEN));
+    }
+    if sanitize_hostname(OsStr::new(name)) != name {
+        return Err("hostname may only contain letters, digits, '.', '-' and '_' \
+                    and must not start or end with '.' or '-'"
+            .to_string());
+    }
+    Ok(name.to_string())
+}
+
+/// Reduce an OS hostname to a safe charset (ASCII alphanumerics, `.`, `-`, `_`).
+///
+/// Invalid UTF-8 and other characters are dropped; an empty result becomes "unknown".
+pub fn sanitize_hostname(raw: &OsStr) -> String {
+    let sanitized: String = raw
+        .to_string_lossy()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        .take(MAX_HOSTNAME_LEN)
+        .collect();
+    let sanitized = sanitized.trim_matches(|c| c == '.' || c == '-');
+
+    if sanitized.is_empty() {
+        "unknown".to_string()
+    } else {
+        sanitized.to_string()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Parse CLI args as if passed on the command line
+    fn args(extra: &[&str]) -> Args {
+        Args::parse_from(std::iter::once("paircoded").chain(extra.iter().copied()))
+    }
+
     #[test]
     fn test_session_name_format() {
-        let args = Args {
-            path: None,
-            login: false,
-            session: None,
-            shell: None,
-            command: None,
-            verbose: false,
-            no_reconnect: false,
-            sandbox: false,
-        };
-        let config = Config::from_args(args, "testuser").unwrap();
+        let config = Config::from_args(args(&[]), FileConfig::default(), "testuser").unwrap();
         assert!(config.session_name.starts_with("testuser-"));
         assert_eq!(config.session_name.len(), "testuser-".len() + 8);
     }
 
+    #[test]
+    fn test_session_prefix() {
+        let config = Config::from_args(args(&["--session-prefix", "web_2"]), FileConfig::default(), "testuser").unwrap();
+        assert!(config.session_name.starts_with("web_2-testuser-"), "{}", config.session_name);
+        assert_eq!(config.session_name.len(), "web_2-testuser-".len() + 8);
+
+        // An explicit session wins over any prefix
+        let config = Config::from_args(
+            args(&["--session-prefix", "web", "--session", "exact"]),
+            FileConfig::default(),
+            "testuser",
+        )
+        .unwrap();
+        assert_eq!(config.session_name, "exact");
+
+        assert!(parse_session_prefix("team/a").is_err());
+        assert!(parse_session_prefix("-web").is_err());
+        assert!(parse_session_prefix("").is_err());
+        assert!(Args::try_parse_from(["paircoded", "--session-prefix", "a b"]).is_err());
+    }
+
+    #[test]
+    fn test_path_templates() {
+        let config = Config::from_args(
+            args(&[
+                "--session", "s1",
+                "--relay-url", "https://relay.example",
+                "--control-path-template", "/team/{session}/control",
+                "--data-url-template", "/team/{session}/data/{name}",
+            ]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        assert_eq!(config.relay_url.as_str(), "wss://relay.example/team/s1/control");
+        assert_eq!(
+            fill_path_template(&config.data_url_template, "s1", "42"),
+            "/team/s1/data/42"
+        );
+
+        // Defaults keep the standard routing
+        let config = Config::from_args(args(&["--session", "s1"]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.relay_url.path(), "/ws/control/s1");
+        assert_eq!(config.data_url_template, DEFAULT_DATA_URL_TEMPLATE);
+
+        assert!(Args::try_parse_from(["paircoded", "--data-url-template", "/data/{session}"]).is_err());
+        assert!(Args::try_parse_from(["paircoded", "--control-path-template", "/control"]).is_err());
+        assert!(parse_data_url_template("data/{session}/{name}").is_err());
+    }
+
+    #[test]
+    fn test_client_identity_paths() {
+        let config = Config::from_args(
+            args(&["--client-cert", "/etc/pc/client.crt", "--client-key", "/etc/pc/client.key"]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        assert_eq!(
+            config.client_identity,
+            Some((PathBuf::from("/etc/pc/client.crt"), PathBuf::from("/etc/pc/client.key")))
+        );
+
+        // A cert without its key is rejected on the CLI and in the config file
+        assert!(Args::try_parse_from(["paircoded", "--client-cert", "client.crt"]).is_err());
+        let file = FileConfig { client_cert: Some(PathBuf::from("client.crt")), ..Default::default() };
+        let err = Config::from_args(args(&[]), file, "user").unwrap_err();
+        assert!(err.to_string().contains("client_key"), "{}", err);
+    }
+
+    #[test]
+    fn test_last_session_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nested").join("last-session.json");
+        assert_eq!(LastSession::load(&path).unwrap(), None);
+
+        let config = Config::from_args(
+            args(&["--relay-url", "https://relay.example:8443", "--session", "s1"]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        let last = LastSession::from_config(&config);
+        assert_eq!(last.relay_url, "wss://relay.example:8443");
+        last.save(&path).unwrap();
+        assert_eq!(LastSession::load(&path).unwrap(), Some(last.clone()));
+
+        // --resume fills in the relay and session, reaching the same endpoint
+        let mut resumed = args(&["--resume"]);
+        resumed.resume_from(last.clone());
+        let config = Config::from_args(resumed, FileConfig::default(), "user").unwrap();
+        assert_eq!(config.relay_url.as_str(), "wss://relay.example:8443/ws/control/s1");
+        assert_eq!(config.session_name, "s1");
+
+        // Explicit flags still win
+        let mut resumed = args(&["--resume", "--session", "other"]);
+        resumed.resume_from(last);
+        assert_eq!(resumed.session.as_deref(), Some("other"));
+        assert_eq!(resumed.relay_url.as_deref(), Some("wss://relay.example:8443"));
+
+        fs::write(&path, "{").unwrap();
+        assert!(LastSession::load(&path).is_err());
+    }
+
+    #[test]
+    fn test_reconnect_strategy() {
+        assert_eq!(
+            parse_reconnect_strategy("fixed:500"),
+            Ok(BackoffStrategy::Fixed(Duration::from_millis(500)))
+        );
+        assert_eq!(
+            parse_reconnect_strategy("exponential:200:5000"),
+            Ok(BackoffStrategy::Exponential { base: Duration::from_millis(200), max: Duration::from_secs(5) })
+        );
+        assert!(matches!(parse_reconnect_strategy("exponential"), Ok(BackoffStrategy::Exponential { .. })));
+        assert!(parse_reconnect_strategy("fixed:0").is_err());
+        assert!(parse_reconnect_strategy("exponential:5000:200").is_err());
+        assert!(parse_reconnect_strategy("linear").is_err());
+
+        let config = Config::from_args(args(&["--reconnect-strategy", "fixed:250"]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.reconnect_strategy, Some(BackoffStrategy::Fixed(Duration::from_millis(250))));
+        assert!(config.redacted_json().unwrap().contains(r#""reconnect_strategy": "fixed:250""#));
+
+        // Unset keeps each connection's default backoff
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.reconnect_strategy, None);
+        let file = FileConfig { reconnect_strategy: Some("often".to_string()), ..Default::default() };
+        assert!(Config::from_args(args(&[]), file, "user").is_err());
+    }
+
+    #[test]
+    fn test_data_quick_retries() {
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.data_quick_retries, 3);
+        assert_eq!(config.data_quick_retry_interval, Duration::from_millis(200));
+
+        let file = FileConfig { data_quick_retries: Some(5), data_quick_retry_ms: Some(50), ..Default::default() };
+        let config = Config::from_args(args(&["--data-quick-retries", "0"]), file, "user").unwrap();
+        assert_eq!(config.data_quick_retries, 0);
+        assert_eq!(config.data_quick_retry_interval, Duration::from_millis(50));
+        assert!(Args::try_parse_from(["paircoded", "--data-quick-retry-ms", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_no_relay_token() {
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert!(!config.no_relay_token);
+        let config = Config::from_args(args(&["--no-relay-token"]), FileConfig::default(), "user").unwrap();
+        assert!(config.no_relay_token);
+        let file = FileConfig { no_relay_token: Some(true), ..Default::default() };
+        assert!(Config::from_args(args(&[]), file, "user").unwrap().no_relay_token);
+    }
+
+    #[test]
+    fn test_redact_patterns() {
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert!(config.redact.is_none());
+
+        let file = FileConfig { redact: Some(vec!["AKIA[A-Z0-9]{16}".to_string()]), ..Default::default() };
+        let config = Config::from_args(args(&["--redact", "ghp_\\w+", "--redact", "secret"]), file, "user").unwrap();
+        let json = config.redacted_json().unwrap();
+        assert!(!json.contains("ghp_") && !json.contains("secret"), "{}", json);
+        assert_eq!(json.matches(REDACTED).count(), 3, "{}", json);
+        assert_eq!(config.redact.unwrap().patterns(), ["AKIA[A-Z0-9]{16}", "ghp_\\w+", "secret"]);
+
+        let err = Config::from_args(args(&["--redact", "(unclosed"]), FileConfig::default(), "user").unwrap_err();
+        assert!(err.to_string().contains("(unclosed"), "{}", err);
+    }
+
+    #[test]
+    fn test_reset_on_reconnect() {
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.reset_on_reconnect, None);
+        let config = Config::from_args(args(&["--reset-on-reconnect"]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.reset_on_reconnect, Some(ResetSequence::Full));
+        let file: FileConfig = toml::from_str("reset_on_reconnect = \"full\"").unwrap();
+        let config = Config::from_args(args(&["--reset-on-reconnect", "soft"]), file, "user").unwrap();
+        assert_eq!(config.reset_on_reconnect, Some(ResetSequence::Soft));
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        assert_eq!(parse_memory_limit("1048576"), Ok(1 << 20));
+        assert_eq!(parse_memory_limit("512M"), Ok(512 << 20));
+        assert_eq!(parse_memory_limit("2g"), Ok(2 << 30));
+        assert!(parse_memory_limit("0").is_err());
+        assert!(parse_memory_limit("10X").unwrap_err().contains("suffix"));
+        assert!(parse_memory_limit("99999999999T").unwrap_err().contains("too large"));
+
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert!(config.resource_limits.is_unlimited());
+
+        let file = FileConfig { memory_limit: Some("1G".to_string()), cpu_quota: Some(50), ..Default::default() };
+        let config = Config::from_args(args(&["--cpu-quota", "200"]), file, "user").unwrap();
+        assert_eq!(config.resource_limits, ResourceLimits { memory_max: Some(1 << 30), cpu_quota: Some(200) });
+
+        let file = FileConfig { memory_limit: Some("lots".to_string()), ..Default::default() };
+        assert!(Config::from_args(args(&[]), file, "user").is_err());
+        assert!(Args::try_parse_from(["paircoded", "--cpu-quota", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_command_timeout_needs_command() {
+        let config = Config::from_args(
+            args(&["--command", "make test", "--command-timeout", "30"]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        assert_eq!(config.command_timeout, Some(Duration::from_secs(30)));
+
+        // An interactive shell is never timed out
+        let config = Config::from_args(args(&["--command-timeout", "30"]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.command_timeout, None);
+        assert!(Args::try_parse_from(["paircoded", "--command-timeout", "0"]).is_err());
+    }
+
     #[test]
     fn test_custom_session_name() {
-        let args = Args {
-            path: None,
-            login: false,
-            session: Some("my-custom-session".to_string()),
-            shell: None,
-            command: None,
-            verbose: false,
-            no_reconnect: false,
-            sandbox: false,
-        };
-        let config = Config::from_args(args, "testuser").unwrap();
+        let config = Config::from_args(args(&["--session", "my-custom-session"]), FileConfig::default(), "testuser").unwrap();
         assert_eq!(config.session_name, "my-custom-session");
     }
 
     #[test]
     fn test_default_relay_url() {
-        let args = Args {
-            path: None,
-            login: false,
-            session: Some("test".to_string()),
-            shell: None,
-            command: None,
-            verbose: false,
-            no_reconnect: false,
-            sandbox: false,
-        };
-        let config = Config::from_args(args, "user").unwrap();
+        let config = Config::from_args(args(&["--session", "test"]), FileConfig::default(), "user").unwrap();
         assert_eq!(config.relay_url.scheme(), "wss");
         assert!(config.relay_url.as_str().contains("retrievable-timidly-drusilla"));
     }
 
     #[test]
     fn test_custom_shell() {
-        let args = Args {
-            path: None,
-            login: false,
-            session: None,
+        let config = Config::from_args(args(&["--shell", "/bin/zsh"]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.shell, "/bin/zsh");
+    }
+
+    #[test]
+    fn test_browser_url() {
+        let config = Config::from_args(args(&["--session", "my-session"]), FileConfig::default(), "user").unwrap();
+        assert!(config.browser_url.starts_with("https://"));
+        assert!(config.browser_url.contains("my-session"));
+        assert!(config.browser_url.ends_with("/terminal/my-session/my-session"));
+        assert_eq!(config.username, "user");
+    }
+
+    #[test]
+    fn test_rotate_session() {
+        let mut config = Config::from_args(
+            args(&["--session", "old", "--relay-url", "http://relay.example:8080"]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        config.rotate_session("new-session").unwrap();
+        assert_eq!(config.session_name, "new-session");
+        assert_eq!(config.relay_url.as_str(), "ws://relay.example:8080/ws/control/new-session");
+        assert_eq!(config.browser_url, "http://relay.example:8080/terminal/new-session/new-session");
+        assert!(config.rotate_session("").is_err());
+    }
+
+    #[test]
+    fn test_keepalive_jitter_defaults_to_tenth_of_interval() {
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert!(config.keepalive.is_none());
+
+        let config = Config::from_args(args(&["--keepalive-interval-ms", "20000"]), FileConfig::default(), "user").unwrap();
+        assert_eq!(
+            config.keepalive,
+            Some(Keepalive { interval: Duration::from_secs(20), jitter: Duration::from_secs(2) })
+        );
+
+        let config = Config::from_args(
+            args(&["--keepalive-interval-ms", "20000", "--keepalive-jitter-ms", "0"]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        assert_eq!(config.keepalive.unwrap().jitter, Duration::ZERO);
+    }
+
+    #[test]
+    fn test_audit_log_path() {
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert!(config.audit_log.is_none());
+
+        let config = Config::from_args(args(&["--audit-log", "/var/log/paircoded.jsonl"]), FileConfig::default(), "user").unwrap();
+        assert_eq!(config.audit_log, Some(PathBuf::from("/var/log/paircoded.jsonl")));
+    }
+
+    #[test]
+    fn test_color_enabled() {
+        assert!(color_enabled(false, false, None));
+        assert!(color_enabled(false, false, Some(OsStr::new(""))));
+        assert!(!color_enabled(false, false, Some(OsStr::new("1"))));
+        assert!(!color_enabled(false, true, None));
+        assert!(color_enabled(true, false, Some(OsStr::new("1"))));
+
+        let config = Config::from_args(args(&["--no-color"]), FileConfig::default(), "user").unwrap();
+        assert!(!config.color);
+        let file = FileConfig { no_color: Some(true), ..FileConfig::default() };
+        let config = Config::from_args(args(&["--force-color"]), file, "user").unwrap();
+        assert!(config.color);
+        assert!(Args::try_parse_from(["paircoded", "--no-color", "--force-color"]).is_err());
+    }
+
+    #[test]
+    fn test_pause_spill_dir() {
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert!(config.pause_spill_dir.is_none());
+
+        let file = FileConfig { pause_spill_dir: Some(PathBuf::from("/var/tmp")), ..FileConfig::default() };
+        let config = Config::from_args(args(&["--pause-spill-dir", "/scratch"]), file, "user").unwrap();
+        assert_eq!(config.pause_spill_dir, Some(PathBuf::from("/scratch")));
+    }
+
+    #[test]
+    fn test_file_config_parse() {
+        let toml_config = FileConfig::parse(
+            Path::new("config.toml"),
+            "shell = \"/bin/zsh\"\nsandbox = true\n",
+        )
+        .unwrap();
+        assert_eq!(toml_config.shell.as_deref(), Some("/bin/zsh"));
+        assert_eq!(toml_config.sandbox, Some(true));
+
+        let json_config = FileConfig::parse(
+            Path::new("config.json"),
+            r#"{"session": "from-json"}"#,
+        )
+        .unwrap();
+        assert_eq!(json_config.session.as_deref(), Some("from-json"));
+
+        assert!(FileConfig::parse(Path::new("config.toml"), "unknown_key = 1").is_err());
+    }
+
+    #[test]
+    fn test_file_config_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        assert!(FileConfig::load(Some(&path), None).is_err());
+
+        fs::write(&path, "session = \"from-file\"\n").unwrap();
+        let file = FileConfig::load(Some(&path), None).unwrap();
+        assert_eq!(file.session.as_deref(), Some("from-file"));
+    }
+
+    #[test]
+    fn test_config_precedence() {
+        let file = FileConfig {
+            session: Some("file-session".to_string()),
             shell: Some("/bin/zsh".to_string()),
-            command: None,
-            verbose: false,
-            no_reconnect: false,
-            sandbox: false,
+            relay_url: Some("http://file.example:8080".to_string()),
+            ..Default::default()
         };
-        let config = Config::from_args(args, "user").unwrap();
+
+        // CLI overrides file
+        let config = Config::from_args(
+            args(&["--shell", "/bin/fish", "--relay-url", "https://cli.example"]),
+            file.clone(),
+            "user",
+        )
+        .unwrap();
+        assert_eq!(config.shell, "/bin/fish");
+        assert_eq!(config.relay_url.host_str(), Some("cli.example"));
+        assert_eq!(config.session_name, "file-session");
+
+        // File overrides built-in default
+        let config = Config::from_args(args(&[]), file, "user").unwrap();
         assert_eq!(config.shell, "/bin/zsh");
+        assert_eq!(config.session_name, "file-session");
+        if env::var("PAIRCODED_RELAY_URL").is_err() {
+            assert_eq!(config.relay_url.as_str(), "ws://file.example:8080/ws/control/file-session");
+        }
+
+        // Built-in default when neither is set
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert!(config.session_name.starts_with("user-"));
+        assert!(!config.sandbox);
+    }
+
+    #[test]
+    fn test_parse_header() {
+        assert_eq!(
+            parse_header("X-Team: infra").unwrap(),
+            ("X-Team".to_string(), "infra".to_string())
+        );
+        assert_eq!(parse_header("X-Url:http://a:b").unwrap().1, "http://a:b");
+        assert!(parse_header("no-colon").is_err());
+        assert!(parse_header("bad name:value").is_err());
+        assert!(parse_header(":value").is_err());
+    }
+
+    #[test]
+    fn test_headers_merge() {
+        let file = FileConfig {
+            header: Some(vec!["X-Team: file".to_string(), "X-Env: prod".to_string()]),
+            ..Default::default()
+        };
+        let config = Config::from_args(args(&["--header", "x-team:cli"]), file, "user").unwrap();
+        assert_eq!(
+            config.headers,
+            vec![
+                ("X-Env".to_string(), "prod".to_string()),
+                ("x-team".to_string(), "cli".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_env_merge() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join("terminal.env");
+        fs::write(&env_path, "PROJECT=web\nREGION=eu\nbad line\n").unwrap();
+        let env_path = env_path.to_str().unwrap();
+
+        let file_env_path = dir.path().join("file.env");
+        fs::write(&file_env_path, "OWNER=ops\n").unwrap();
+
+        // Config file env files, then config file `env`, then `--env-file`, then `--env`
+        let file = FileConfig {
+            env: Some(vec!["REGION=us".to_string(), "OWNER=dev".to_string()]),
+            env_file: Some(vec![file_env_path]),
+            ..Default::default()
+        };
+        let config = Config::from_args(args(&["--env-file", env_path, "--env", "PROJECT=api"]), file, "user").unwrap();
+        assert_eq!(
+            config.env,
+            vec![
+                ("OWNER".to_string(), "ops".to_string()),
+                ("REGION".to_string(), "us".to_string()),
+                ("OWNER".to_string(), "dev".to_string()),
+                ("PROJECT".to_string(), "web".to_string()),
+                ("REGION".to_string(), "eu".to_string()),
+                ("PROJECT".to_string(), "api".to_string()),
+            ]
+        );
+        assert!(config.redacted_json().unwrap().contains("PROJECT=<redacted>"));
+
+        assert!(Config::from_args(args(&["--env-file", "/nonexistent/terminal.env"]), FileConfig::default(), "user").is_err());
+        assert!(Args::try_parse_from(["paircoded", "--env", "NO_VALUE"]).is_err());
+        assert!(Args::try_parse_from(["paircoded", "--env", "1BAD=x"]).is_err());
+    }
+
+    #[test]
+    fn test_sanitize_hostname() {
+        assert_eq!(sanitize_hostname(OsStr::new("dev-box.local")), "dev-box.local");
+        assert_eq!(sanitize_hostname(OsStr::new("my box\n\"x")), "myboxx");
+        assert_eq!(sanitize_hostname(OsStr::new("")), "unknown");
+        assert_eq!(sanitize_hostname(OsStr::new("\u{fffd}")), "unknown");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sanitize_invalid_utf8_hostname() {
+        use crate::protocol::ControlResponse;
+        use std::os::unix::ffi::OsStrExt;
+
+        let hostname = sanitize_hostname(OsStr::from_bytes(b"build\xff\xfe-host"));
+        assert_eq!(hostname, "build-host");
+
+        let handshake = ControlResponse::ControlHandshake {
+            version: "1.0".to_string(),
+            hostname,
+            username: "user".to_string(),
+            working_dir: "/tmp".to_string(),
+            capabilities: Vec::new(),
+            connection_id: String::new(),
+        };
+        let json: serde_json::Value = serde_json::from_str(&handshake.encode().unwrap()).unwrap();
+        assert_eq!(json["hostname"], "build-host");
+    }
+
+    #[test]
+    fn test_hostname_override() {
+        use crate::protocol::ControlResponse;
+
+        let handshake_hostname = |config: &Config| {
+            let handshake = ControlResponse::ControlHandshake {
+                version: "1.0".to_string(),
+                hostname: config.hostname.clone(),
+                username: config.username.clone(),
+                working_dir: config.working_dir.display().to_string(),
+                capabilities: Vec::new(),
+                connection_id: String::new(),
+            };
+            let json: serde_json::Value = serde_json::from_str(&handshake.encode().unwrap()).unwrap();
+            json["hostname"].as_str().unwrap().to_string()
+        };
+
+        let config = Config::from_args(args(&["--hostname", "ci-runner-7"]), FileConfig::default(), "user")
+            .unwrap();
+        assert_eq!(handshake_hostname(&config), "ci-runner-7");
+
+        let config = Config::from_args(args(&[]), FileConfig::default(), "user").unwrap();
+        assert_eq!(handshake_hostname(&config), os_hostname());
+
+        assert!(Args::try_parse_from(["paircoded", "--hostname", "bad host"]).is_err());
+        assert!(Args::try_parse_from(["paircoded", "--hostname", ""]).is_err());
+        let file = FileConfig {
+            hostname: Some("-bad".to_string()),
+            ..FileConfig::default()
+        };
+        assert!(Config::from_args(args(&[]), file, "user").is_err());
+    }
+
+    #[test]
+    fn test_redacted_json() {
+        let config = Config::from_args(
+            args(&[
+                "--session", "dump-session",
+                "--header", "Authorization: Bearer secret-token",
+                "--header", "X-Team: infra",
+            ]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        let dump = config.redacted_json().unwrap();
+
+        assert!(dump.contains(config.relay_url.as_str()));
+        assert!(dump.contains("dump-session"));
+        assert!(dump.contains("X-Team: infra"));
+        assert!(dump.contains("Authorization: <redacted>"));
+        assert!(!dump.contains("secret-token"));
+    }
+
+    #[test]
+    fn test_shell_args_interactive_only() {
+        let config = Config::from_args(
+            args(&["--shell", "/bin/bash", "--shell-arg", "--norc", "--shell-arg", "-i"]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        assert_eq!(config.spawn_command(), ("/bin/bash", vec!["--norc", "-i"]));
+
+        let config = Config::from_args(
+            args(&["--shell", "/bin/bash", "--shell-arg", "--norc", "--command", "ls"]),
+            FileConfig::default(),
+            "user",
+        )
+        .unwrap();
+        assert_eq!(config.spawn_command(), ("/bin/bash", vec!["-c", "ls"]));
     }
 }
@@ -3,15 +3,17 @@
 //! The control connection is a JSON-based websocket that receives commands
 //! from the relay to start/stop terminals.
 
-use anyhow::{Context, Result};
-use futures_util::{SinkExt, StreamExt};
+use anyhow::Result;
+use futures_util::{stream, Sink, SinkExt, StreamExt};
+use std::collections::HashMap;
 use std::time::Duration;
-use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async_with_config, tungstenite::{protocol::Message, http::Request}};
-use tracing::{debug, error, info, warn};
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::{self, protocol::Message};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 use url::Url;
 
-use crate::protocol::{ControlMessage, ControlResponse};
+use crate::protocol::{self, capability, Capabilities, CloseReason, ControlMessage, ControlResponse, ProcessInfo};
+use crate::relay::{self, BackoffStrategy, ConnectError};
 
 /// Events sent from the control connection to the main loop
 #[derive(Debug)]
@@ -23,21 +25,67 @@ pub enum ControlEvent {
         cols: u16,
         rows: u16,
         request_id: String,
+        /// Extra environment for this terminal's process
+        env: Option<HashMap<String, String>>,
     },
     /// Request to close a terminal
     CloseTerminal {
         name: String,
         signal: Option<i32>,
     },
+    /// Request to signal a terminal's process
+    SignalTerminal {
+        name: String,
+        signal: i32,
+    },
+    /// Request for a terminal's scrollback, sent on its data connection
+    RequestScrollback {
+        name: String,
+        request_id: String,
+    },
+    /// Request for the processes running under a terminal
+    RequestProcessTree {
+        name: String,
+        request_id: String,
+    },
+    /// Request to change the log verbosity (the level is checked when applied)
+    SetLogLevel {
+        level: String,
+    },
+    /// Keepalive (ping/pong) received from the relay
+    Heartbeat,
     /// Control connection closed
     Disconnected {
         /// WebSocket close code if available
         close_code: Option<u16>,
         /// Whether this was a clean close (close frame received)
         clean: bool,
+        /// Where the connection broke
+        reason: DisconnectReason,
     },
 }
 
+/// Why the control connection was lost
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The relay sent a close frame
+    CloseFrame,
+    /// The stream ended without a close frame or error
+    StreamEnded,
+    /// Reading from or writing to the socket failed
+    SocketError,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::CloseFrame => "close_frame",
+            DisconnectReason::StreamEnded => "stream_ended",
+            DisconnectReason::SocketError => "socket_error",
+        }
+    }
+}
+
 /// Commands sent to the control connection
 #[derive(Debug)]
 pub enum ControlCommand {
@@ -53,14 +101,28 @@ pub enum ControlCommand {
         name: String,
         exit_code: i32,
     },
+    /// Send a process_tree response
+    ProcessTree {
+        request_id: String,
+        processes: Vec<ProcessInfo>,
+        error: Option<String>,
+    },
     /// Gracefully close the connection
-    Shutdown,
+    Shutdown(CloseReason),
 }
 
 /// Handle to the control connection
 pub struct ControlConnection {
     /// Channel to send commands to the control connection
     command_tx: mpsc::Sender<ControlCommand>,
+    /// Features enabled by the handshake and the relay's ack
+    capabilities: Capabilities,
+    /// Resolves once the control task has finished
+    finished: watch::Receiver<()>,
+    /// How long `shutdown` waits for the relay to close after our close frame
+    drain_timeout: Option<Duration>,
+    /// Sent in the handshake; every log line for the connection carries it
+    connection_id: String,
 }
 
 /// Handshake info to send to relay on control connection
@@ -69,32 +131,51 @@ pub struct HandshakeInfo {
     pub hostname: String,
     pub username: String,
     pub working_dir: String,
+    /// Relay JWT sent as a bearer token (empty to connect without one)
     pub relay_token: String,
+    /// Extra headers for the websocket upgrade request
+    pub headers: Vec<(String, String)>,
+    /// Fail the connection if the relay sends nothing this soon after the handshake
+    pub handshake_timeout: Option<Duration>,
+    /// TLS server name to present instead of the URL host
+    pub tls_sni: Option<String>,
+    /// Wait this long on shutdown for the relay to answer our close frame
+    pub drain_timeout: Option<Duration>,
 }
 
 impl ControlConnection {
     /// Connect to the relay's control endpoint and start the control loop
+    ///
+    /// The connection gets a fresh `connectionId` in its handshake and a
+    /// `control_connection` span carrying it.
     pub async fn connect(
         url: &Url,
         handshake_info: HandshakeInfo,
-    ) -> Result<(Self, mpsc::Receiver<ControlEvent>)> {
+    ) -> Result<(Self, mpsc::Receiver<ControlEvent>), ConnectError> {
+        let connection_id = protocol::new_connection_id();
+        let span = info_span!("control_connection", connection_id = %connection_id);
+        Self::open(url, handshake_info, connection_id).instrument(span).await
+    }
+
+    /// Establish the connection described by [`connect`](Self::connect) in the current span
+    async fn open(
+        url: &Url,
+        handshake_info: HandshakeInfo,
+        connection_id: String,
+    ) -> Result<(Self, mpsc::Receiver<ControlEvent>), ConnectError> {
         info!(url = %url, "connecting to control endpoint");
 
-        // Build request with Authorization header
-        let request = Request::builder()
-            .uri(url.as_str())
-            .header("Authorization", format!("Bearer {}", handshake_info.relay_token))
-            .header("Host", url.host_str().unwrap_or("localhost"))
-            .header("Connection", "Upgrade")
-            .header("Upgrade", "websocket")
-            .header("Sec-WebSocket-Version", "13")
-            .header("Sec-WebSocket-Key", tokio_tungstenite::tungstenite::handshake::client::generate_key())
-            .body(())
-            .context("failed to build WebSocket request")?;
-
-        let (ws_stream, response) = connect_async_with_config(request, None, false)
+        // Build request with Authorization header (unless there is no token)
+        let request = relay::build_request(
+            url,
+            Some(handshake_info.relay_token.as_str()).filter(|token| !token.is_empty()),
+            &handshake_info.headers,
+        )
+        .map_err(ConnectError::Protocol)?;
+
+        let (ws_stream, response) = relay::connect_websocket(request, handshake_info.tls_sni.as_deref())
             .await
-            .context("failed to connect to control endpoint")?;
+            .map_err(|e| e.context("failed to connect to control endpoint"))?;
 
         info!(status = %response.status(), "connected to control endpoint");
         debug!(headers = ?response.headers(), "control connection response headers");
@@ -111,16 +192,33 @@ impl ControlConnection {
             hostname: handshake_info.hostname,
             username: handshake_info.username,
             working_dir: handshake_info.working_dir,
+            capabilities: capability::supported(),
+            connection_id: connection_id.clone(),
         };
-        let handshake_json = handshake.encode()?;
+        let handshake_json = handshake.encode().map_err(ConnectError::Protocol)?;
+        protocol::trace_frame(|| handshake.trace_summary(handshake_json.len()));
+        // send() flushes, so a relay that is already gone fails here; either
+        // way nothing has been spawned yet, so an error leaves nothing behind
         ws_sink
             .send(Message::Text(handshake_json))
             .await
-            .context("failed to send control handshake")?;
+            .map_err(|e| ConnectError::from_websocket(e).context("failed to send control handshake"))?;
+        let (ack, first) = relay::await_handshake_ack(&mut ws_stream, handshake_info.handshake_timeout).await?;
+        let capabilities = Capabilities::negotiate(
+            &capability::supported(),
+            &ack.map(|ack| ack.capabilities).unwrap_or_default(),
+        );
+        info!(capabilities = ?capabilities.negotiated, "negotiated control connection capabilities");
+        let mut ws_stream = stream::iter(first.map(Ok)).chain(ws_stream);
         info!("Connected to relay");
 
+        let drain_timeout = handshake_info.drain_timeout;
+        let (finished_tx, finished) = watch::channel(());
+
         // Spawn task to handle control connection
         tokio::spawn(async move {
+            // Dropped when the task returns, which is what `shutdown` waits on
+            let _finished_tx = finished_tx;
             loop {
                 tokio::select! {
                     // Handle incoming messages from relay
@@ -129,15 +227,38 @@ impl ControlConnection {
                             Some(Ok(Message::Text(text))) => {
                                 match ControlMessage::parse_str(&text) {
                                     Ok(control_msg) => {
+                                        protocol::trace_frame_payload(|| control_msg.trace_summary(text.len()), text.as_bytes());
                                         let event = match control_msg {
-                                            ControlMessage::StartTerminal { name, cols, rows, request_id } => {
+                                            ControlMessage::StartTerminal { name, cols, rows, request_id, env } => {
                                                 info!(name = %name, cols, rows, request_id = %request_id, "received start_terminal");
-                                                ControlEvent::StartTerminal { name, cols, rows, request_id }
+                                                ControlEvent::StartTerminal { name, cols, rows, request_id, env }
                                             }
                                             ControlMessage::CloseTerminal { name, signal } => {
                                                 info!(name = %name, signal = ?signal, "received close_terminal");
                                                 ControlEvent::CloseTerminal { name, signal }
                                             }
+                                            ControlMessage::SignalTerminal { name, signal } => {
+                                                info!(name = %name, signal, "received signal_terminal");
+                                                ControlEvent::SignalTerminal { name, signal }
+                                            }
+                                            ControlMessage::RequestScrollback { name, request_id } => {
+                                                info!(name = %name, request_id = %request_id, "received request_scrollback");
+                                                ControlEvent::RequestScrollback { name, request_id }
+                                            }
+                                            ControlMessage::RequestProcessTree { name, request_id } => {
+                                                info!(name = %name, request_id = %request_id, "received request_process_tree");
+                                                ControlEvent::RequestProcessTree { name, request_id }
+                                            }
+                                            ControlMessage::SetLogLevel { level } => {
+                                                info!(level = %level, "received set_log_level");
+                                                ControlEvent::SetLogLevel { level }
+                                            }
+                                            ControlMessage::Unknown => {
+                                                // Sent by a newer relay; nothing to do with it here
+                                                let message_type = ControlMessage::message_type(text.as_bytes());
+                                                debug!(message_type = ?message_type, "ignoring unknown control message");
+                                                continue;
+                                            }
                                         };
                                         if event_tx.send(event).await.is_err() {
                                             debug!("event receiver dropped");
@@ -145,22 +266,45 @@ impl ControlConnection {
                                         }
                                     }
                                     Err(e) => {
-                                        warn!(error = %e, "failed to parse control message");
+                                        warn!(error = %e, bytes = text.len(), "failed to parse control message");
                                     }
                                 }
                             }
                             Some(Ok(Message::Binary(data))) => {
                                 match ControlMessage::parse(&data) {
                                     Ok(control_msg) => {
+                                        protocol::trace_frame_payload(|| control_msg.trace_summary(data.len()), &data);
                                         let event = match control_msg {
-                                            ControlMessage::StartTerminal { name, cols, rows, request_id } => {
+                                            ControlMessage::StartTerminal { name, cols, rows, request_id, env } => {
                                                 info!(name = %name, cols, rows, request_id = %request_id, "received start_terminal");
-                                                ControlEvent::StartTerminal { name, cols, rows, request_id }
+                                                ControlEvent::StartTerminal { name, cols, rows, request_id, env }
                                             }
                                             ControlMessage::CloseTerminal { name, signal } => {
                                                 info!(name = %name, signal = ?signal, "received close_terminal");
                                                 ControlEvent::CloseTerminal { name, signal }
                                             }
+                                            ControlMessage::SignalTerminal { name, signal } => {
+                                                info!(name = %name, signal, "received signal_terminal");
+                                                ControlEvent::SignalTerminal { name, signal }
+                                            }
+                                            ControlMessage::RequestScrollback { name, request_id } => {
+                                                info!(name = %name, request_id = %request_id, "received request_scrollback");
+                                                ControlEvent::RequestScrollback { name, request_id }
+                                            }
+                                            ControlMessage::RequestProcessTree { name, request_id } => {
+                                                info!(name = %name, request_id = %request_id, "received request_process_tree");
+                                                ControlEvent::RequestProcessTree { name, request_id }
+                                            }
+                                            ControlMessage::SetLogLevel { level } => {
+                                                info!(level = %level, "received set_log_level");
+                                                ControlEvent::SetLogLevel { level }
+                                            }
+                                            ControlMessage::Unknown => {
+                                                // Sent by a newer relay; nothing to do with it here
+                                                let message_type = ControlMessage::message_type(&data);
+                                                debug!(message_type = ?message_type, "ignoring unknown control message");
+                                                continue;
+                                            }
                                         };
                                         if event_tx.send(event).await.is_err() {
                                             debug!("event receiver dropped");
@@ -168,15 +312,17 @@ impl ControlConnection {
                                         }
                                     }
                                     Err(e) => {
-                                        warn!(error = %e, "failed to parse binary control message");
+                                        warn!(error = %e, bytes = data.len(), "failed to parse binary control message");
                                     }
                                 }
                             }
                             Some(Ok(Message::Ping(_))) => {
                                 debug!("received ping from control connection");
+                                let _ = event_tx.try_send(ControlEvent::Heartbeat);
                             }
                             Some(Ok(Message::Pong(_))) => {
                                 debug!("received pong from control connection");
+                                let _ = event_tx.try_send(ControlEvent::Heartbeat);
                             }
                             Some(Ok(Message::Close(frame))) => {
                                 let close_code = frame.as_ref().map(|f| f.code.into());
@@ -184,17 +330,20 @@ impl ControlConnection {
                                 let _ = event_tx.send(ControlEvent::Disconnected {
                                     close_code,
                                     clean: true,
+                                    reason: DisconnectReason::CloseFrame,
                                 }).await;
                                 break;
                             }
-                            Some(Ok(Message::Frame(_))) => {
-                                // Raw frame, ignore
+                            Some(Ok(Message::Frame(frame))) => {
+                                // Fragmented messages arrive reassembled; raw frames are never read
+                                warn!(header = ?frame.header(), len = frame.len(), "dropping unexpected raw frame on control connection");
                             }
                             Some(Err(e)) => {
                                 error!(error = %e, "control connection error");
                                 let _ = event_tx.send(ControlEvent::Disconnected {
                                     close_code: None,
                                     clean: false,
+                                    reason: DisconnectReason::SocketError,
                                 }).await;
                                 break;
                             }
@@ -203,6 +352,7 @@ impl ControlConnection {
                                 let _ = event_tx.send(ControlEvent::Disconnected {
                                     close_code: None,
                                     clean: false,
+                                    reason: DisconnectReason::StreamEnded,
                                 }).await;
                                 break;
                             }
@@ -212,35 +362,33 @@ impl ControlConnection {
                     // Handle outgoing commands
                     cmd = command_rx.recv() => {
                         match cmd {
-                            Some(ControlCommand::Shutdown) => {
-                                info!("sending graceful shutdown close frame");
-                                let close_frame = tokio_tungstenite::tungstenite::protocol::CloseFrame {
-                                    code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Normal,
-                                    reason: "client shutdown".into(),
-                                };
-                                let _ = ws_sink.send(Message::Close(Some(close_frame))).await;
+                            Some(ControlCommand::Shutdown(reason)) => {
+                                // Deliver responses queued behind the shutdown first, so the
+                                // relay still hears about terminals that just closed
+                                while let Ok(command) = command_rx.try_recv() {
+                                    let Some(response) = command_response(command) else { continue };
+                                    if let Err(e) = send_response(&mut ws_sink, &response).await {
+                                        warn!(error = %e, "failed to send control response during shutdown");
+                                        break;
+                                    }
+                                }
+                                info!(reason = reason.as_str(), "sending graceful shutdown close frame");
+                                let _ = ws_sink.send(Message::Close(Some(relay::close_frame(reason)))).await;
+                                if let Some(timeout) = drain_timeout {
+                                    await_relay_close(&mut ws_stream, timeout).await;
+                                }
                                 break;
                             }
                             Some(command) => {
-                                let response = match command {
-                                    ControlCommand::TerminalStarted { name, request_id, success, error } => {
-                                        ControlResponse::TerminalStarted { name, request_id, success, error }
-                                    }
-                                    ControlCommand::TerminalClosed { name, exit_code } => {
-                                        ControlResponse::TerminalClosed { name, exit_code }
-                                    }
-                                    ControlCommand::Shutdown => unreachable!(),
-                                };
-                                match response.encode() {
-                                    Ok(json) => {
-                                        if let Err(e) = ws_sink.send(Message::Text(json)).await {
-                                            error!(error = %e, "failed to send control response");
-                                            break;
-                                        }
-                                    }
-                                    Err(e) => {
-                                        error!(error = %e, "failed to encode control response");
-                                    }
+                                let Some(response) = command_response(command) else { continue };
+                                if let Err(e) = send_response(&mut ws_sink, &response).await {
+                                    error!(error = %e, "failed to send control response");
+                                    let _ = event_tx.send(ControlEvent::Disconnected {
+                                        close_code: None,
+                                        clean: false,
+                                        reason: DisconnectReason::SocketError,
+                                    }).await;
+                                    break;
                                 }
                             }
                             None => {
@@ -253,14 +401,24 @@ impl ControlConnection {
             }
 
             debug!("control connection task finished");
-        });
+        }.instrument(Span::current()));
 
         Ok((
-            ControlConnection { command_tx },
+            ControlConnection { command_tx, capabilities, finished, drain_timeout, connection_id },
             event_rx,
         ))
     }
 
+    /// ID sent in this connection's handshake
+    pub fn connection_id(&self) -> &str {
+        &self.connection_id
+    }
+
+    /// Features both this client and the relay support
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     /// Send a terminal_started response
     pub async fn terminal_started(
         &self,
@@ -288,35 +446,121 @@ impl ControlConnection {
             .map_err(|_| anyhow::anyhow!("control connection closed"))
     }
 
-    /// Gracefully shutdown the control connection
-    pub async fn shutdown(&self) {
-        let _ = self.command_tx.send(ControlCommand::Shutdown).await;
+    /// Send a process_tree response (`error` set if the tree couldn't be read)
+    pub async fn process_tree(
+        &self,
+        request_id: String,
+        processes: Vec<ProcessInfo>,
+        error: Option<String>,
+    ) -> Result<()> {
+        self.command_tx
+            .send(ControlCommand::ProcessTree { request_id, processes, error })
+            .await
+            .map_err(|_| anyhow::anyhow!("control connection closed"))
+    }
+
+    /// Gracefully shutdown the control connection, telling the relay why
+    ///
+    /// With a drain timeout this returns once the relay has answered the close
+    /// frame, or after at most that long, so final notifications are not lost
+    /// to a fast exit.
+    pub async fn shutdown(&self, reason: CloseReason) {
+        let _ = self.command_tx.send(ControlCommand::Shutdown(reason)).await;
+        if let Some(timeout) = self.drain_timeout {
+            let mut finished = self.finished.clone();
+            // `changed` errors once the task drops its sender
+            let _ = tokio::time::timeout(timeout, finished.changed()).await;
+        }
+    }
+}
+
+/// Read until the relay echoes our close frame or the stream ends, giving up
+/// after `timeout`
+async fn await_relay_close<S>(ws_stream: &mut S, timeout: Duration)
+where
+    S: futures_util::Stream<Item = tungstenite::Result<Message>> + Unpin,
+{
+    let drained = tokio::time::timeout(timeout, async {
+        while let Some(msg) = ws_stream.next().await {
+            match msg {
+                Ok(Message::Close(frame)) => {
+                    debug!(frame = ?frame, "relay acknowledged close");
+                    return;
+                }
+                Ok(_) => continue,
+                Err(e) => {
+                    debug!(error = %e, "control connection error while draining");
+                    return;
+                }
+            }
+        }
+    })
+    .await;
+    if drained.is_err() {
+        warn!(timeout_ms = timeout.as_millis() as u64, "relay did not acknowledge shutdown within drain timeout");
+    }
+}
+
+/// Response a command sends to the relay (`None` for `Shutdown`)
+fn command_response(command: ControlCommand) -> Option<ControlResponse> {
+    match command {
+        ControlCommand::TerminalStarted { name, request_id, success, error } => {
+            Some(ControlResponse::TerminalStarted { name, request_id, success, error })
+        }
+        ControlCommand::TerminalClosed { name, exit_code } => Some(ControlResponse::TerminalClosed { name, exit_code }),
+        ControlCommand::ProcessTree { request_id, processes, error } => {
+            Some(ControlResponse::ProcessTree { request_id, processes, error })
+        }
+        ControlCommand::Shutdown(_) => None,
+    }
+}
+
+/// Send a response on the control socket; encoding failures are logged and skipped
+async fn send_response<S>(ws_sink: &mut S, response: &ControlResponse) -> Result<(), tungstenite::Error>
+where
+    S: Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    match response.encode() {
+        Ok(json) => {
+            protocol::trace_frame(|| response.trace_summary(json.len()));
+            ws_sink.send(Message::Text(json)).await
+        }
+        Err(e) => {
+            error!(error = %e, "failed to encode control response");
+            Ok(())
+        }
     }
 }
 
-/// Reconnection manager with exponential backoff
+/// Default control connection backoff: 1s doubling up to 60s
+pub const DEFAULT_CONTROL_BACKOFF: BackoffStrategy = BackoffStrategy::Exponential {
+    base: Duration::from_secs(1),
+    max: Duration::from_secs(60),
+};
+
+/// Reconnection manager for the control connection
 pub struct ReconnectManager {
-    base_delay: Duration,
-    max_delay: Duration,
+    strategy: BackoffStrategy,
     current_attempt: u32,
 }
 
 impl ReconnectManager {
     pub fn new() -> Self {
+        Self::with_strategy(DEFAULT_CONTROL_BACKOFF)
+    }
+
+    /// Wait between attempts as `strategy` says (`--reconnect-strategy`)
+    pub fn with_strategy(strategy: BackoffStrategy) -> Self {
         ReconnectManager {
-            base_delay: Duration::from_secs(1),
-            max_delay: Duration::from_secs(60),
+            strategy,
             current_attempt: 0,
         }
     }
 
     /// Get the next reconnection delay
     pub fn next_delay(&mut self) -> Duration {
-        let delay = std::cmp::min(
-            self.base_delay * 2u32.pow(self.current_attempt),
-            self.max_delay,
-        );
-        self.current_attempt += 1;
+        let delay = self.strategy.delay(self.current_attempt);
+        self.current_attempt = self.current_attempt.saturating_add(1);
         delay
     }
 
@@ -336,3 +580,315 @@ impl Default for ReconnectManager {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+    #[test]
+    fn test_reconnect_manager_strategies() {
+        let mut mgr = ReconnectManager::new();
+        let delays: Vec<u64> = (0..8).map(|_| mgr.next_delay().as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 8, 16, 32, 60, 60]);
+        assert_eq!(mgr.attempts(), 8);
+        mgr.reset();
+        assert_eq!(mgr.next_delay(), Duration::from_secs(1));
+
+        let mut mgr = ReconnectManager::with_strategy(BackoffStrategy::Fixed(Duration::from_millis(500)));
+        let delays: Vec<Duration> = (0..3).map(|_| mgr.next_delay()).collect();
+        assert_eq!(delays, [Duration::from_millis(500); 3]);
+
+        // Long outages don't overflow the exponent
+        let mut mgr = ReconnectManager::with_strategy(BackoffStrategy::Exponential {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(5),
+        });
+        for _ in 0..100 {
+            mgr.next_delay();
+        }
+        assert_eq!(mgr.next_delay(), Duration::from_secs(5));
+    }
+
+    fn handshake_info() -> HandshakeInfo {
+        HandshakeInfo {
+            version: "0.1.0".to_string(),
+            hostname: "host".to_string(),
+            username: "user".to_string(),
+            working_dir: "/tmp".to_string(),
+            relay_token: "jwt".to_string(),
+            headers: Vec::new(),
+            handshake_timeout: None,
+            tls_sni: None,
+            drain_timeout: None,
+        }
+    }
+
+    /// Connect to a one-shot relay that acks the handshake, then hands the
+    /// socket to `behave`; returns the first disconnect the client reports
+    async fn disconnect_after<F, Fut>(behave: F) -> ControlEvent
+    where
+        F: FnOnce(tokio_tungstenite::WebSocketStream<tokio::net::TcpStream>) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            ws.send(Message::Text(r#"{"type":"handshake_ack"}"#.to_string())).await.unwrap();
+            behave(ws).await;
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/control/s", addr)).unwrap();
+        let (_conn, mut events) = ControlConnection::connect(&url, handshake_info()).await.unwrap();
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+                .await
+                .expect("no disconnect reported")
+                .expect("event channel closed");
+            if matches!(event, ControlEvent::Disconnected { .. }) {
+                return event;
+            }
+        }
+    }
+
+    /// Authorization header of the upgrade request a control connection sends
+    async fn authorization_sent(relay_token: &str) -> Option<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut authorization = None;
+            // The Err type (an HTTP response) is fixed by tungstenite
+            #[allow(clippy::result_large_err)]
+            let callback = |request: &tokio_tungstenite::tungstenite::handshake::server::Request, response| {
+                authorization = request
+                    .headers()
+                    .get("Authorization")
+                    .map(|value| value.to_str().unwrap().to_string());
+                Ok(response)
+            };
+            let mut ws = tokio_tungstenite::accept_hdr_async(stream, callback).await.unwrap();
+            let _ = ws.next().await;
+            ws.send(Message::Text(r#"{"type":"handshake_ack"}"#.to_string())).await.unwrap();
+            authorization
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/control/s", addr)).unwrap();
+        let info = HandshakeInfo { relay_token: relay_token.to_string(), ..handshake_info() };
+        let (_conn, _events) = ControlConnection::connect(&url, info).await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connect_without_relay_token() {
+        assert_eq!(authorization_sent("jwt").await.as_deref(), Some("Bearer jwt"));
+        // --no-relay-token (or a failed token exchange) connects unauthenticated
+        assert_eq!(authorization_sent("").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_carries_connection_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let Some(Ok(Message::Text(text))) = ws.next().await else { panic!("no handshake") };
+            ws.send(Message::Text(r#"{"type":"handshake_ack"}"#.to_string())).await.unwrap();
+            let handshake: serde_json::Value = serde_json::from_str(&text).unwrap();
+            handshake["connectionId"].as_str().unwrap().to_string()
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/control/s", addr)).unwrap();
+        let (conn, _events) = ControlConnection::connect(&url, handshake_info()).await.unwrap();
+        let sent = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert!(!sent.is_empty());
+        assert_eq!(conn.connection_id(), sent);
+    }
+
+    /// The error from connecting to a one-shot server that gets the accepted
+    /// TCP stream and does `behave` with it
+    async fn connect_error<F, Fut>(behave: F) -> ConnectError
+    where
+        F: FnOnce(tokio::net::TcpStream) -> Fut + Send + 'static,
+        Fut: std::future::Future<Output = ()> + Send,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            behave(stream).await;
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/control/s", addr)).unwrap();
+        let result = tokio::time::timeout(Duration::from_secs(5), ControlConnection::connect(&url, handshake_info()))
+            .await
+            .expect("connect did not finish");
+        match result {
+            Ok(_) => panic!("connect succeeded"),
+            Err(e) => e,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_when_relay_closes_during_handshake() {
+        // Closed before the websocket upgrade
+        let err = connect_error(|stream| async move { drop(stream) }).await;
+        assert!(format!("{:#}", err).contains("failed to connect"), "{:#}", err);
+        assert!(matches!(err, ConnectError::Transport(_)), "{:?}", err);
+
+        // Upgraded, then dropped before the handshake could be read
+        let err = connect_error(|stream| async move {
+            drop(tokio_tungstenite::accept_async(stream).await.unwrap());
+        })
+        .await;
+        assert!(format!("{:#}", err).contains("during handshake"), "{:#}", err);
+
+        // Handshake read, then answered with a close frame instead of an ack
+        let err = connect_error(|stream| async move {
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            let frame = CloseFrame { code: CloseCode::Again, reason: "overloaded".into() };
+            let _ = ws.close(Some(frame)).await;
+            let _ = ws.next().await;
+        })
+        .await;
+        let message = format!("{:#}", err);
+        assert!(message.contains("during handshake") && message.contains("overloaded"), "{}", message);
+        assert!(matches!(err, ConnectError::Closed { code: 1013, .. }), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_reason_close_frame() {
+        let event = disconnect_after(|mut ws| async move {
+            let frame = CloseFrame { code: CloseCode::Away, reason: "restart".into() };
+            let _ = ws.send(Message::Close(Some(frame))).await;
+            let _ = ws.next().await;
+        })
+        .await;
+        match event {
+            ControlEvent::Disconnected { close_code, clean, reason } => {
+                assert_eq!(reason, DisconnectReason::CloseFrame);
+                assert_eq!(close_code, Some(1001));
+                assert!(clean);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_queued_responses() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            ws.send(Message::Text(r#"{"type":"handshake_ack"}"#.to_string())).await.unwrap();
+
+            // Everything the client sends, up to and including its close frame
+            let mut received = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                match msg {
+                    Message::Text(text) => received.push(text),
+                    Message::Close(_) => {
+                        received.push("<close>".to_string());
+                        break;
+                    }
+                    _ => {}
+                }
+            }
+            received
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/control/s", addr)).unwrap();
+        let (conn, _events) = ControlConnection::connect(&url, handshake_info()).await.unwrap();
+
+        // Queued without yielding, so the control task sees them all at once,
+        // including a notification that lands behind the shutdown
+        let closed = |name: &str| ControlCommand::TerminalClosed { name: name.to_string(), exit_code: 0 };
+        conn.command_tx.try_send(closed("1")).unwrap();
+        conn.command_tx.try_send(ControlCommand::Shutdown(CloseReason::Shutdown)).unwrap();
+        conn.command_tx.try_send(closed("2")).unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert_eq!(received.len(), 3, "{:?}", received);
+        for (text, name) in received.iter().zip(["1", "2"]) {
+            let json: serde_json::Value = serde_json::from_str(text).unwrap();
+            assert_eq!(json["type"], "terminal_closed");
+            assert_eq!(json["name"], name);
+        }
+        assert_eq!(received[2], "<close>");
+    }
+
+    /// Relay that acks the handshake, then replies to the client's close
+    /// frame after `reply_after` (or never)
+    async fn slow_closing_relay(reply_after: Option<Duration>) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            ws.send(Message::Text(r#"{"type":"handshake_ack"}"#.to_string())).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Close(_) = msg {
+                    break;
+                }
+            }
+            match reply_after {
+                Some(delay) => {
+                    tokio::time::sleep(delay).await;
+                    let _ = ws.close(None).await;
+                }
+                None => std::future::pending().await,
+            }
+        });
+        Url::parse(&format!("ws://{}/ws/control/s", addr)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_relay_close() {
+        let url = slow_closing_relay(Some(Duration::from_millis(200))).await;
+        let info = HandshakeInfo { drain_timeout: Some(Duration::from_secs(5)), ..handshake_info() };
+        let (conn, _events) = ControlConnection::connect(&url, info).await.unwrap();
+
+        let started = std::time::Instant::now();
+        conn.shutdown(CloseReason::Shutdown).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "returned before the relay closed: {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(5), "waited for the full timeout: {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drain_is_bounded() {
+        let url = slow_closing_relay(None).await;
+        let info = HandshakeInfo { drain_timeout: Some(Duration::from_millis(200)), ..handshake_info() };
+        let (conn, _events) = ControlConnection::connect(&url, info).await.unwrap();
+
+        let started = std::time::Instant::now();
+        conn.shutdown(CloseReason::Shutdown).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "did not wait for the relay: {:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "drain was not bounded: {:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_reason_socket_error() {
+        // Dropping the TCP stream without a close handshake is a protocol error
+        let event = disconnect_after(|ws| async move { drop(ws) }).await;
+        match event {
+            ControlEvent::Disconnected { close_code, clean, reason } => {
+                assert_eq!(reason, DisconnectReason::SocketError);
+                assert_eq!(close_code, None);
+                assert!(!clean);
+            }
+            other => panic!("unexpected event {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Running in the background (`--detach`) and pid files (`--pid-file`).
+//!
+//! Detaching forks twice before the async runtime starts. The original
+//! process waits on a pipe until the daemon reports that it reached the relay
+//! (or exits), prints what it was told and exits, so the shell gets its prompt
+//! back with the pairing URL on screen. If the relay can't be reached within
+//! [`STARTUP_TIMEOUT`] it stops waiting and points at the log instead.
+//! Everything the daemon prints, the session banner included, goes to the log
+//! file.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long the foreground process waits for the daemon to reach the relay
+pub const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A pid file, removed again when dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the current process ID to `path`
+    pub fn create(path: &Path) -> Result<Self> {
+        fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("failed to write pid file {}", path.display()))?;
+        Ok(PidFile { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Leave it alone if another instance has replaced it since
+        let ours = fs::read_to_string(&self.path)
+            .map(|pid| pid.trim() == std::process::id().to_string())
+            .unwrap_or(false);
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The daemon's end of the pipe to the process waiting in the foreground
+pub struct Detached {
+    #[cfg(unix)]
+    notify: fs::File,
+}
+
+impl Detached {
+    /// Tell the waiting process that startup succeeded; it prints `message` and exits
+    pub fn ready(self, message: &str) {
+        #[cfg(unix)]
+        {
+            use std::io::Write;
+            let mut notify = self.notify;
+            // Nothing to do if it was killed in the meantime
+            let _ = notify.write_all(message.as_bytes());
+        }
+        #[cfg(not(unix))]
+        let _ = message;
+    }
+}
+
+/// Fork into the background with stdout and stderr appended to `log_file`.
+///
+/// Only the daemon returns. The calling process exits once the daemon calls
+/// [`Detached::ready`] (status 0), exits without doing so (status 1), or is
+/// still connecting after [`STARTUP_TIMEOUT`] (status 0). Must be called
+/// while the process has a single thread.
+#[cfg(unix)]
+pub fn detach(log_file: &Path) -> Result<Detached> {
+    use std::os::fd::{AsRawFd, FromRawFd};
+
+    // Opened up front so a bad path is reported on the terminal
+    let log = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_file)
+        .with_context(|| format!("failed to open log file {}", log_file.display()))?;
+    let dev_null = fs::File::open("/dev/null").context("failed to open /dev/null")?;
+
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two descriptors pipe() writes
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to create pipe");
+    }
+    // SAFETY: pipe() just returned these descriptors and nothing else owns them
+    let (mut reader, writer) = unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) };
+    // Shells started by the daemon must not hold the pipe open
+    for fd in fds {
+        // SAFETY: fd is an open descriptor owned by reader or writer
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+
+    if let Some(child) = fork()? {
+        drop(writer);
+        let message = wait_for_message(&mut reader, STARTUP_TIMEOUT);
+        // SAFETY: reaps the intermediate child, which exits right after its own fork
+        unsafe { libc::waitpid(child, std::ptr::null_mut(), 0) };
+        let Some(message) = message else {
+            println!("paircoded is still connecting in the background; see {}", log_file.display());
+            std::process::exit(0);
+        };
+        if message.is_empty() {
+            eprintln!("paircoded exited during startup; see {}", log_file.display());
+            std::process::exit(1);
+        }
+        print!("{}", message);
+        std::process::exit(0);
+    }
+    drop(reader);
+
+    // A new session has no controlling terminal; forking again means the
+    // daemon isn't a session leader and can never acquire one
+    // SAFETY: setsid has no preconditions
+    if unsafe { libc::setsid() } < 0 {
+        return Err(std::io::Error::last_os_error()).context("failed to start a new session");
+    }
+    if fork()?.is_some() {
+        // SAFETY: _exit skips destructors and atexit handlers, which belong to the daemon now
+        unsafe { libc::_exit(0) };
+    }
+
+    for (from, to) in [(dev_null.as_raw_fd(), 0), (log.as_raw_fd(), 1), (log.as_raw_fd(), 2)] {
+        // SAFETY: both are open descriptors
+        if unsafe { libc::dup2(from, to) } < 0 {
+            return Err(std::io::Error::last_os_error()).context("failed to redirect stdio");
+        }
+    }
+    Ok(Detached { notify: writer })
+}
+
+/// Read the daemon's startup message, or `None` if nothing arrives within `timeout`
+///
+/// An empty message means the daemon exited without reporting.
+#[cfg(unix)]
+fn wait_for_message(reader: &mut fs::File, timeout: Duration) -> Option<String> {
+    use std::io::Read;
+    use std::os::fd::AsRawFd;
+    use std::time::Instant;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let mut pollfd = libc::pollfd { fd: reader.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        // SAFETY: pollfd is a single valid entry for an open descriptor
+        let ready = unsafe { libc::poll(&mut pollfd, 1, remaining.as_millis().min(i32::MAX as u128) as i32) };
+        match ready {
+            0 => return None,
+            n if n > 0 => break,
+            // Interrupted by a signal: wait out the rest
+            _ if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted => continue,
+            _ => break,
+        }
+    }
+    // The daemon writes the whole message and closes its end in one go
+    let mut message = String::new();
+    let _ = reader.read_to_string(&mut message);
+    Some(message)
+}
+
+/// Detaching needs fork(), so Unix only
+#[cfg(not(unix))]
+pub fn detach(_log_file: &Path) -> Result<Detached> {
+    anyhow::bail!("--detach is only supported on Unix")
+}
+
+/// Fork, returning the child's PID in the parent and `None` in the child
+#[cfg(unix)]
+fn fork() -> Result<Option<libc::pid_t>> {
+    // SAFETY: only called by detach(), before any other thread exists
+    match unsafe { libc::fork() } {
+        -1 => Err(std::io::Error::last_os_error()).context("failed to fork"),
+        0 => Ok(None),
+        pid => Ok(Some(pid)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("paircoded.pid");
+        let pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+        drop(pid_file);
+        assert!(!path.exists());
+
+        // A pid file taken over by another process is left in place
+        let pid_file = PidFile::create(&path).unwrap();
+        fs::write(&path, "1\n").unwrap();
+        drop(pid_file);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\n");
+
+        assert!(PidFile::create(&dir.path().join("missing/paircoded.pid")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_for_message() {
+        use std::io::Write;
+        use std::os::fd::FromRawFd;
+
+        let pipe = || {
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            unsafe { (fs::File::from_raw_fd(fds[0]), fs::File::from_raw_fd(fds[1])) }
+        };
+
+        // Still connecting: the daemon holds its end open without writing
+        let (mut reader, _writer) = pipe();
+        assert_eq!(wait_for_message(&mut reader, Duration::from_millis(100)), None);
+
+        let (mut reader, mut writer) = pipe();
+        writer.write_all(b"pairing URL\n").unwrap();
+        drop(writer);
+        assert_eq!(wait_for_message(&mut reader, Duration::from_secs(5)).as_deref(), Some("pairing URL\n"));
+
+        // Exited without reporting
+        let (mut reader, writer) = pipe();
+        drop(writer);
+        assert_eq!(wait_for_message(&mut reader, Duration::from_secs(5)).as_deref(), Some(""));
+    }
+}
@@ -0,0 +1,141 @@
+//! Dotenv-style environment files for spawned terminals (`--env-file`).
+//!
+//! Each line is `KEY=VALUE`, optionally prefixed with `export`. Blank lines
+//! and `#` comments are ignored. Values may be double-quoted (with `\n`, `\t`,
+//! `\"` and `\\` escapes), single-quoted (taken literally) or bare, where a
+//! ` #` starts a trailing comment. Malformed lines are skipped with a warning
+//! rather than failing the whole file.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+use tracing::warn;
+
+use crate::pty::validate_env_var;
+
+/// Read the variables in the env file at `path`, in file order
+pub fn load(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read env file {}", path.display()))?;
+    Ok(parse(path, &content))
+}
+
+/// Parse env file content; `path` only labels warnings about skipped lines
+pub fn parse(path: &Path, content: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(var) => vars.push(var),
+            Err(reason) => warn!(path = %path.display(), line = index + 1, "skipping env file line: {}", reason),
+        }
+    }
+    vars
+}
+
+/// Parse one non-blank, non-comment `KEY=VALUE` line
+fn parse_line(line: &str) -> std::result::Result<(String, String), String> {
+    let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+    let (key, value) = line.split_once('=').ok_or("expected KEY=VALUE")?;
+    let key = key.trim();
+    let value = parse_value(value.trim_start())?;
+    validate_env_var(key, &value).map_err(|e| e.to_string())?;
+    Ok((key.to_string(), value))
+}
+
+fn parse_value(raw: &str) -> std::result::Result<String, String> {
+    let (value, rest) = if let Some(quoted) = raw.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = quoted.char_indices();
+        let end = loop {
+            match chars.next() {
+                Some((i, '"')) => break i,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => value.push('\n'),
+                    Some((_, 't')) => value.push('\t'),
+                    Some((_, c)) => value.push(c),
+                    None => return Err("unterminated double quote".to_string()),
+                },
+                Some((_, c)) => value.push(c),
+                None => return Err("unterminated double quote".to_string()),
+            }
+        };
+        (value, &quoted[end + 1..])
+    } else if let Some(quoted) = raw.strip_prefix('\'') {
+        let (value, rest) = quoted.split_once('\'').ok_or("unterminated single quote")?;
+        (value.to_string(), rest)
+    } else {
+        let value = match raw.find(" #") {
+            Some(comment) => &raw[..comment],
+            None => raw,
+        };
+        return Ok(value.trim_end().to_string());
+    };
+
+    // Only a comment may follow a quoted value
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err("unexpected text after quoted value".to_string());
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_str(content: &str) -> Vec<(String, String)> {
+        parse(Path::new("test.env"), content)
+    }
+
+    fn var(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn test_parse_plain_and_quoted_values() {
+        let content = "\
+# project settings
+
+PROJECT=web
+export REGION = eu-west-1   # trailing comment
+GREETING=\"hello world\" # also a comment
+ESCAPED=\"line1\\nline2 \\\"quoted\\\"\"
+LITERAL='$HOME \\n'
+HASH=a#b
+EMPTY=
+";
+        assert_eq!(
+            parse_str(content),
+            vec![
+                var("PROJECT", "web"),
+                var("REGION", "eu-west-1"),
+                var("GREETING", "hello world"),
+                var("ESCAPED", "line1\nline2 \"quoted\""),
+                var("LITERAL", "$HOME \\n"),
+                var("HASH", "a#b"),
+                var("EMPTY", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skips_malformed_lines() {
+        let content = "\
+NO_EQUALS
+1BAD=x
+OPEN=\"unterminated
+TRAILING='x' y
+GOOD=yes
+";
+        assert_eq!(parse_str(content), vec![var("GOOD", "yes")]);
+    }
+
+    #[test]
+    fn test_load_missing_file_is_error() {
+        assert!(load(Path::new("/nonexistent/paircoded.env")).is_err());
+    }
+}
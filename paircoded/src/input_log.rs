@@ -0,0 +1,128 @@
+//! Keystroke recording for reproducing input-handling bugs (`--log-input`).
+//!
+//! Every input payload from the relay is appended as one line of JSON with a
+//! millisecond timestamp and the base64-encoded bytes. This captures everything
+//! typed into the session, passwords included, so it is only ever enabled by an
+//! explicit flag and the file is created with mode 0600 on Unix.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// One recorded input payload
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputRecord {
+    /// Milliseconds since the Unix epoch
+    #[serde(rename = "t")]
+    pub timestamp_ms: u64,
+    /// Raw input bytes (base64 in the file)
+    #[serde(with = "base64_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// Append-only input log shared by all terminals
+#[derive(Debug)]
+pub struct InputLog {
+    file: Mutex<File>,
+}
+
+impl InputLog {
+    /// Open (or create) the log at `path` for appending
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        let file = options
+            .open(path)
+            .with_context(|| format!("failed to open input log {}", path.display()))?;
+        Ok(InputLog { file: Mutex::new(file) })
+    }
+
+    /// Append an input payload; failures are logged, never fatal
+    pub fn record(&self, data: &[u8]) {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let record = InputRecord { timestamp_ms, data: data.to_vec() };
+
+        let result = serde_json::to_vec(&record).map_err(anyhow::Error::from).and_then(|mut line| {
+            line.push(b'\n');
+            let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+            file.write_all(&line).map_err(anyhow::Error::from)
+        });
+        if let Err(e) = result {
+            warn!(error = %e, "failed to write input log");
+        }
+    }
+}
+
+mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        STANDARD.decode(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    /// Read back every record from an input log
+    fn read_input_log(path: &Path) -> Result<Vec<InputRecord>> {
+        let file = File::open(path).with_context(|| format!("failed to open input log {}", path.display()))?;
+        BufReader::new(file)
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !matches!(line, Ok(l) if l.trim().is_empty()))
+            .map(|(n, line)| {
+                let line = line?;
+                serde_json::from_str(&line).with_context(|| format!("invalid input log line {}", n + 1))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_input_log_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.ndjson");
+
+        let log = InputLog::open(&path).unwrap();
+        log.record(b"ls -la\r");
+        log.record(b"\x1b[A\x03");
+        drop(log);
+
+        // Reopening appends
+        InputLog::open(&path).unwrap().record(b"exit\r");
+
+        let records = read_input_log(&path).unwrap();
+        let data: Vec<&[u8]> = records.iter().map(|r| r.data.as_slice()).collect();
+        assert_eq!(data, vec![&b"ls -la\r"[..], b"\x1b[A\x03", b"exit\r"]);
+        assert!(records.windows(2).all(|w| w[0].timestamp_ms <= w[1].timestamp_ms));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! Library half of paircoded: PTY management, relay protocol and connection logic.
+//!
+//! The `paircoded` binary is a thin CLI over these modules; they are also used
+//! by the integration tests under `tests/`.
+
+pub mod audit;
+pub mod auth;
+pub mod bridge;
+pub mod cgroup;
+pub mod config;
+pub mod control;
+pub mod daemon;
+pub mod env_file;
+pub mod input_log;
+pub mod log_level;
+pub mod metrics;
+pub mod privilege;
+pub mod probe;
+pub mod process_tree;
+pub mod protocol;
+pub mod pty;
+pub mod redact;
+pub mod relay;
+pub mod sandbox;
+pub mod scripted_relay;
+pub mod session_lock;
+pub mod status;
+pub mod terminal_manager;
+pub mod tls;
@@ -0,0 +1,92 @@
+//! Log verbosity changed at runtime by the relay (`set_log_level`).
+//!
+//! The global filter is installed behind a reload layer, so an operator can
+//! turn a live host's logging up while debugging, and back down, without a
+//! restart. The relay can't go past `debug`: `trace` is for local use only.
+
+use anyhow::{anyhow, Result};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::protocol;
+
+/// Level names accepted in `set_log_level`; `trace` is left out so the relay
+/// can't turn on the most detailed logs on the host
+const LEVELS: [&str; 5] = ["off", "error", "warn", "info", "debug"];
+
+/// Add the directive that keeps frame traces visible at any level (`--trace-protocol`)
+pub fn with_protocol_traces(filter: EnvFilter, trace_protocol: bool) -> EnvFilter {
+    if !trace_protocol {
+        return filter;
+    }
+    match format!("{}=info", protocol::TRACE_TARGET).parse() {
+        Ok(directive) => filter.add_directive(directive),
+        Err(_) => filter,
+    }
+}
+
+/// Changes the active log filter
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+    trace_protocol: bool,
+}
+
+impl LogLevel {
+    /// A filter layer starting out as `filter`, and the handle that replaces it
+    pub fn layer(filter: EnvFilter, trace_protocol: bool) -> (reload::Layer<EnvFilter, Registry>, Self) {
+        let (layer, handle) = reload::Layer::new(with_protocol_traces(filter, trace_protocol));
+        (layer, LogLevel { handle, trace_protocol })
+    }
+
+    /// Log everything at `level` and above from now on (one of `off`, `error`,
+    /// `warn`, `info` or `debug`, in any case)
+    pub fn set(&self, level: &str) -> Result<()> {
+        let level = level.trim().to_ascii_lowercase();
+        if !LEVELS.contains(&level.as_str()) {
+            return Err(anyhow!("invalid log level '{}' (expected one of {})", level, LEVELS.join(", ")));
+        }
+        let filter = with_protocol_traces(EnvFilter::new(&level), self.trace_protocol);
+        self.handle.reload(filter).map_err(|e| anyhow!("failed to change log level: {}", e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Level;
+    use tracing_subscriber::prelude::*;
+
+    #[test]
+    fn test_set_changes_active_filter() {
+        let (layer, log_level) = LogLevel::layer(EnvFilter::new("info"), false);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            assert!(tracing::enabled!(Level::INFO));
+            assert!(!tracing::enabled!(Level::DEBUG));
+
+            log_level.set("DEBUG").unwrap();
+            assert!(tracing::enabled!(Level::DEBUG));
+            assert!(!tracing::enabled!(Level::TRACE));
+
+            log_level.set("error").unwrap();
+            assert!(!tracing::enabled!(Level::WARN));
+
+            // A bad level leaves the filter as it was, and so does one past debug
+            assert!(log_level.set("loud").unwrap_err().to_string().contains("invalid log level"));
+            assert!(log_level.set("trace").is_err());
+            assert!(tracing::enabled!(Level::ERROR));
+            assert!(!tracing::enabled!(Level::WARN));
+        });
+    }
+
+    #[test]
+    fn test_protocol_traces_survive_a_change() {
+        let (layer, log_level) = LogLevel::layer(EnvFilter::new("warn"), true);
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            log_level.set("error").unwrap();
+            assert!(tracing::enabled!(target: protocol::TRACE_TARGET, Level::INFO));
+            assert!(!tracing::enabled!(Level::INFO));
+        });
+    }
+}
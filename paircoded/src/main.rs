@@ -11,61 +11,219 @@
 //! 3. Paircoded spawns a PTY and opens a data websocket for that terminal
 //! 4. Multiple terminals can be active simultaneously, each with their own PTY
 
-mod auth;
-mod bridge;
-mod config;
-mod control;
-mod protocol;
-mod pty;
-mod relay;
-mod sandbox;
-mod terminal_manager;
-
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
 use clap::Parser;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tracing::{error, info, warn};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{mpsc, RwLock};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use crate::auth::{get_auth, get_relay_token};
-use crate::config::{Args, Config};
-use crate::control::{ControlConnection, ControlEvent, HandshakeInfo, ReconnectManager};
-use crate::terminal_manager::{TerminalEvent, TerminalManager};
-
-/// Shared JWT token that can be updated when refreshed
-pub type SharedToken = Arc<RwLock<String>>;
-
-fn setup_logging(verbose: bool) {
+use paircoded::{cgroup, privilege, protocol, pty, tls};
+use paircoded::protocol::CloseReason;
+use paircoded::auth::{get_auth, get_relay_token, load_auth, GITHUB_TOKEN_ENV};
+use paircoded::bridge::{BridgeOptions, LocalInput};
+use paircoded::config::{client_identity_paths, Args, Config, FileConfig, LastSession};
+use paircoded::control::{ControlConnection, ControlEvent, HandshakeInfo, ReconnectManager};
+use paircoded::daemon::{self, Detached, PidFile};
+use paircoded::input_log::InputLog;
+use paircoded::log_level::LogLevel;
+use paircoded::metrics::Metrics;
+use paircoded::probe::ProbeFiles;
+use paircoded::relay::{should_log_attempt, ConnectError};
+use paircoded::session_lock::SessionLock;
+use paircoded::tls::ClientIdentity;
+use paircoded::status::{ConnectionState, StatusDisplay};
+use paircoded::terminal_manager::{
+    is_process_alive, QuickRetries, SharedToken, TerminalEvent, TerminalManager, TerminalOptions, DEFAULT_DATA_BACKOFF,
+};
+
+/// Install the global subscriber; the returned handle changes its filter later
+fn setup_logging(verbose: bool, trace_protocol: bool, log_file: Option<&Path>) -> Result<LogLevel> {
     let filter = if verbose {
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("debug"))
     } else {
         EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
     };
 
-    tracing_subscriber::registry()
-        .with(fmt::layer().with_target(true))
-        .with(filter)
-        .init();
+    // Frame traces stay visible whatever the general log level is
+    let (filter, log_level) = LogLevel::layer(filter, trace_protocol);
+    protocol::set_trace_protocol(trace_protocol);
+
+    let layer = fmt::layer().with_target(true);
+    match log_file {
+        Some(path) => {
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {}", path.display()))?;
+            tracing_subscriber::registry()
+                .with(filter)
+                .with(layer.with_ansi(false).with_writer(std::sync::Mutex::new(file)))
+                .init();
+        }
+        None => {
+            // No color codes in a detached daemon's log or other redirected output
+            tracing_subscriber::registry()
+                .with(filter)
+                .with(layer.with_ansi(std::io::stdout().is_terminal()))
+                .init();
+        }
+    }
+    Ok(log_level)
+}
+
+/// Read piped stdin on a task; the channel closes on EOF
+fn spawn_stdin_reader() -> mpsc::Receiver<Vec<u8>> {
+    let (tx, rx) = mpsc::channel(16);
+    tokio::spawn(async move {
+        let mut stdin = tokio::io::stdin();
+        let mut buf = vec![0u8; pty::INPUT_CHUNK_SIZE];
+        loop {
+            match stdin.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    if tx.send(buf[..n].to_vec()).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => {
+                    warn!(error = %e, "failed to read stdin");
+                    break;
+                }
+            }
+        }
+    });
+    rx
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     let args = Args::parse();
+    let file_config = FileConfig::load(args.config.as_deref(), args.profile.as_deref())?;
+    let log_file = args.log_file.clone().or_else(|| file_config.log_file.clone());
+
+    // Fork before the runtime starts any threads; the daemon's stdout and
+    // stderr are the log file, so it needs no separate log writer
+    let (detached, log_file) = if args.detach {
+        let path = log_file.ok_or_else(|| anyhow!("--detach needs --log-file to write the session URL and logs to"))?;
+        (Some(daemon::detach(&path)?), None)
+    } else {
+        (None, log_file)
+    };
+    let pid_file = match args.pid_file.clone().or_else(|| file_config.pid_file.clone()) {
+        Some(path) => Some(PidFile::create(&path)?),
+        None => None,
+    };
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("failed to start the async runtime")?
+        .block_on(run(args, file_config, log_file, detached, pid_file))
+}
+
+async fn run(
+    mut args: Args,
+    file_config: FileConfig,
+    log_file: Option<PathBuf>,
+    mut detached: Option<Detached>,
+    pid_file: Option<PidFile>,
+) -> Result<()> {
+    let profile = args.profile.clone();
     let force_login = args.login;
-    let verbose = args.verbose;
+    let skip_token_validation = args.skip_token_validation || file_config.skip_token_validation.unwrap_or(false);
+    let github_token = args
+        .github_token
+        .clone()
+        .or_else(|| std::env::var(GITHUB_TOKEN_ENV).ok())
+        .filter(|token| !token.is_empty());
+    let verbose = args.verbose || file_config.verbose.unwrap_or(false);
+    let trace_protocol = args.trace_protocol || file_config.trace_protocol.unwrap_or(false);
 
     // Set up logging early (but quiet by default)
-    setup_logging(verbose);
+    let log_level = setup_logging(verbose, trace_protocol, log_file.as_deref())?;
+    if let Some(max) = args.max_frame_log_bytes.or(file_config.max_frame_log_bytes) {
+        protocol::set_max_frame_log_bytes(max);
+    }
+
+    // Present a client certificate on every TLS connection, including login
+    if let Some((cert, key)) = client_identity_paths(&args, &file_config)? {
+        tls::set_client_identity(ClientIdentity::load(&cert, &key)?);
+    }
+
+    // Reuse the relay and session of the last successful connection
+    let last_session_path = LastSession::default_path(profile.as_deref())?;
+    if args.resume {
+        let last = LastSession::load(&last_session_path)?
+            .ok_or_else(|| anyhow::anyhow!("no previous session to resume ({} not found)", last_session_path.display()))?;
+        info!(relay_url = %last.relay_url, session = %last.session, "resuming last session");
+        args.resume_from(last);
+    }
+
+    // Dump the resolved config without authenticating (uses the saved login if any)
+    if args.print_config {
+        let username = load_auth(profile.as_deref())
+            .ok()
+            .flatten()
+            .map(|auth| auth.user.login)
+            .unwrap_or_else(whoami::username);
+        let config = Config::from_args(args, file_config, &username)?;
+        println!("{}", config.redacted_json()?);
+        return Ok(());
+    }
+
+    // Refuse to expose a root shell unless explicitly allowed
+    let is_root = privilege::is_root();
+    if is_root {
+        let sandboxed = args.sandbox || file_config.sandbox.unwrap_or(false);
+        eprintln!();
+        eprintln!("  {}", privilege::root_warning(sandboxed));
+        eprintln!();
+    }
+    let allow_root = args.allow_root || file_config.allow_root.unwrap_or(false);
+    if privilege::should_refuse(is_root, allow_root) {
+        anyhow::bail!("refusing to run as root; pass --allow-root to override");
+    }
 
     // Authenticate with GitHub
-    let auth = get_auth(force_login).await?;
+    let auth = get_auth(
+        args.auth_provider,
+        profile.as_deref(),
+        force_login,
+        skip_token_validation,
+        github_token.as_deref(),
+    )
+    .await?;
 
     // Create config with username from auth
-    let config = Config::from_args(args, &auth.user.login)?;
+    let config = Config::from_args(args, file_config, &auth.user.login)?;
+    if !config.resource_limits.is_unlimited() {
+        cgroup::check_available(&config.resource_limits)?;
+        info!(limits = ?config.resource_limits, "terminals run under resource limits");
+    }
+    let session_lock = if config.session_lock {
+        let path = SessionLock::default_path(&config.session_name)?;
+        Some(SessionLock::acquire(&path, &config.session_name)?)
+    } else {
+        None
+    };
 
-    // Get relay JWT token
-    let relay_token = get_relay_token(&config.relay_url, &auth.access_token).await?;
+    // Get relay JWT token; local and test relays work without one
+    let relay_token = if config.no_relay_token {
+        info!("connecting without a relay token (--no-relay-token)");
+        String::new()
+    } else {
+        match get_relay_token(&config.relay_url, &auth.access_token).await {
+            Ok(token) => token,
+            Err(e) => {
+                warn!(error = %e, "failed to get relay token, connecting without one");
+                String::new()
+            }
+        }
+    };
 
     // Print user-friendly session info (always visible regardless of log level)
     println!();
@@ -74,9 +232,14 @@ async fn main() -> Result<()> {
     println!("  Path:      {}", config.working_dir.display());
     println!("  Dashboard: {}", config.dashboard_url);
     println!();
+    println!("  Pair at:");
+    println!("  {}", config.browser_url);
+    println!();
 
     info!(
         relay_url = %config.relay_url,
+        browser_url = %config.browser_url,
+        username = %config.username,
         shell = %config.shell,
         working_dir = %config.working_dir.display(),
         "starting paircoded"
@@ -86,34 +249,137 @@ async fn main() -> Result<()> {
     let (shell, shell_args) = config.spawn_command();
     let shell_args: Vec<String> = shell_args.iter().map(|s| s.to_string()).collect();
 
+    // Traffic counters shared by all terminal bridges
+    let metrics = Arc::new(Metrics::default());
+
+    // Keystroke log (opt-in: it records everything typed, passwords included)
+    let input_log = match config.log_input {
+        Some(ref path) => {
+            warn!(path = %path.display(), "recording all terminal input, including passwords");
+            Some(Arc::new(InputLog::open(path)?))
+        }
+        None => None,
+    };
+
+    // Piped stdin for the first terminal (--stdin-input)
+    let local_input: Option<LocalInput> = if !config.stdin_input {
+        None
+    } else if std::io::stdin().is_terminal() {
+        warn!("--stdin-input ignored: stdin is a terminal");
+        None
+    } else {
+        Some(Arc::new(std::sync::Mutex::new(Some(spawn_stdin_reader()))))
+    };
+
     // Create shared token holder for JWT (used by terminal data connections)
     let shared_token: SharedToken = Arc::new(RwLock::new(relay_token.clone()));
 
     // Create terminal manager with working directory and shared token
     let (terminal_manager, mut terminal_event_rx) = TerminalManager::new(
         config.relay_url.clone(),
-        shell.to_string(),
-        shell_args,
-        config.working_dir.clone(),
+        &config.session_name,
         shared_token.clone(),
-        config.sandbox,
+        TerminalOptions {
+            shell: shell.to_string(),
+            shell_args,
+            working_dir: config.working_dir.clone(),
+            env: config.env.clone(),
+            sandboxed: config.sandbox,
+            resource_limits: config.resource_limits,
+            username: config.username.clone(),
+            audit_log: config.audit_log.clone(),
+            state_file: config.state_file.clone(),
+            headers: config.headers.clone(),
+            handshake_timeout: config.handshake_timeout,
+            tls_sni: config.tls_sni.clone(),
+            keepalive: config.keepalive,
+            text_output: config.text_output,
+            reconnect: config.reconnect_strategy.unwrap_or(DEFAULT_DATA_BACKOFF),
+            quick_retries: QuickRetries {
+                attempts: config.data_quick_retries,
+                interval: config.data_quick_retry_interval,
+            },
+            motd: config.motd.clone(),
+            command_timeout: config.command_timeout,
+            data_url_template: config.data_url_template.clone(),
+            kill_on_disconnect: config.kill_on_disconnect,
+            max_reader_restarts: config.max_reader_restarts,
+            // Attached runs start terminals at the size of the terminal we're in
+            default_size: pty::host_terminal_size().unwrap_or(pty::DEFAULT_TERMINAL_SIZE),
+            bridge: BridgeOptions {
+                resize_debounce: config.resize_debounce,
+                exit_grace: config.exit_grace,
+                exit_flush_delay: config.exit_flush_delay,
+                metrics: metrics.clone(),
+                announce_join: config.announce_join,
+                color: config.color,
+                reset_on_reconnect: config.reset_on_reconnect,
+                // Rendered from `motd` above when each terminal starts
+                motd: None,
+                input_log,
+                max_output_rate: config.max_output_rate,
+                output_charset: config.output_charset,
+                init_command: config.init_command.clone(),
+                local_input,
+                output_idle: config.output_idle,
+                scrollback_bytes: config.scrollback_bytes,
+                max_snapshot_bytes: config.max_snapshot_bytes,
+                pause_spill_dir: config.pause_spill_dir.clone(),
+                redact: config.redact.clone(),
+            },
+        },
     );
 
+    // Terminals from a previous run can't be reattached yet; say which are still running
+    if let Some(ref path) = config.state_file {
+        match TerminalManager::load_state(path) {
+            Ok(previous) => {
+                for terminal in previous.iter().filter(|t| is_process_alive(t.pid)) {
+                    warn!(
+                        pid = terminal.pid,
+                        data_url = %terminal.data_url,
+                        "terminal from a previous run is still running and will not be reattached"
+                    );
+                }
+            }
+            Err(e) => warn!(error = %e, "failed to read state file"),
+        }
+        if let Err(e) = terminal_manager.persist_state().await {
+            warn!(error = %e, "failed to write state file");
+        }
+    }
+
     // Handle graceful shutdown
     let shutdown = tokio::signal::ctrl_c();
     tokio::pin!(shutdown);
 
+    // Readiness/liveness probe files
+    let probes = ProbeFiles::new(config.ready_file.clone(), config.health_file.clone());
+
+    // Optional live status line (--status)
+    let mut status = StatusDisplay::new(config.status, metrics.clone());
+
     // Reconnection manager for control connection
-    let mut reconnect_mgr = ReconnectManager::new();
+    let mut reconnect_mgr = match config.reconnect_strategy {
+        Some(strategy) => ReconnectManager::with_strategy(strategy),
+        None => ReconnectManager::new(),
+    };
 
     // Main loop with reconnection support
     let mut current_relay_token = relay_token;
     let mut needs_token_refresh = false;
 
+    // Exit code for the process (set from the terminal in --once mode)
+    let mut process_exit_code = 0;
+
     'main: loop {
         // Refresh JWT token if needed (after abnormal disconnection)
-        if needs_token_refresh {
-            info!("refreshing relay token before reconnection");
+        if needs_token_refresh && !config.no_relay_token {
+            // Retried on every attempt while the relay is down
+            let logged = should_log_attempt(reconnect_mgr.attempts());
+            if logged {
+                info!("refreshing relay token before reconnection");
+            }
             match get_relay_token(&config.relay_url, &auth.access_token).await {
                 Ok(new_token) => {
                     current_relay_token = new_token.clone();
@@ -122,10 +388,13 @@ async fn main() -> Result<()> {
                     needs_token_refresh = false;
                     info!("relay token refreshed successfully");
                 }
-                Err(e) => {
+                Err(e) if logged => {
                     warn!(error = %e, "failed to refresh relay token, will retry");
                     // Continue with old token, might still work
                 }
+                Err(e) => {
+                    debug!(error = %e, "failed to refresh relay token, will retry");
+                }
             }
         }
 
@@ -136,6 +405,10 @@ async fn main() -> Result<()> {
             username: config.username.clone(),
             working_dir: config.working_dir.display().to_string(),
             relay_token: current_relay_token.clone(),
+            headers: config.headers.clone(),
+            handshake_timeout: config.handshake_timeout,
+            tls_sni: config.tls_sni.clone(),
+            drain_timeout: config.drain_timeout,
         };
 
         let connect_result = ControlConnection::connect(
@@ -146,37 +419,69 @@ async fn main() -> Result<()> {
 
         let (control_conn, mut control_event_rx) = match connect_result {
             Ok(result) => {
+                if reconnect_mgr.attempts() > 0 {
+                    info!(attempts = reconnect_mgr.attempts(), "reconnected to relay control endpoint");
+                }
                 reconnect_mgr.reset();
+                probes.mark_ready();
+                status.set_connection(ConnectionState::Connected);
                 info!("connected to relay control endpoint, waiting for terminal requests");
+                if let Some(detached) = detached.take() {
+                    detached.ready(&format!(
+                        "paircoded is running in the background (pid {})\n  Pair at: {}\n",
+                        std::process::id(),
+                        config.browser_url
+                    ));
+                }
+                if let Err(e) = LastSession::from_config(&config).save(&last_session_path) {
+                    warn!(error = %e, "failed to save last session");
+                }
                 result
             }
             Err(e) => {
-                let error_str = e.to_string();
-                error!(error = %e, "failed to connect to control endpoint");
+                // Attempts past the first few are mostly logged at debug; a
+                // refusal ends the loop, so it always shows
+                let attempt = reconnect_mgr.attempts() + 1;
+                let logged = !e.is_retryable() || should_log_attempt(attempt);
+                if logged {
+                    error!(error = %e, attempt, "failed to connect to control endpoint");
+                } else {
+                    debug!(error = %e, attempt, "failed to connect to control endpoint");
+                }
 
-                // Check if this looks like an auth error (HTTP 401 or WebSocket 4401)
-                let is_auth_error = error_str.contains("401")
-                    || error_str.contains("4401")
-                    || error_str.contains("Unauthorized")
-                    || error_str.contains("authorization")
-                    || error_str.contains("token");
+                // Auth error: HTTP 401, or a close with 4401 once upgraded
+                let is_auth_error = match &e {
+                    ConnectError::HttpStatus(status) => *status == 401,
+                    ConnectError::Closed { code, .. } => *code == 4401,
+                    _ => false,
+                };
 
                 if is_auth_error {
-                    info!("connection failed with auth error, will refresh JWT token");
+                    if logged {
+                        info!("connection failed with auth error, will refresh JWT token");
+                    }
                     needs_token_refresh = true;
                 }
 
+                if !e.is_retryable() {
+                    error!("relay refused the connection, not retrying");
+                    process_exit_code = 1;
+                    break 'main;
+                }
+
                 if !config.reconnect {
                     error!("reconnection disabled, exiting");
                     break 'main;
                 }
 
                 let delay = reconnect_mgr.next_delay();
-                info!(
-                    delay_ms = delay.as_millis(),
-                    attempt = reconnect_mgr.attempts(),
-                    "waiting before reconnect"
-                );
+                metrics.record_reconnect_attempt();
+                status.set_connection(ConnectionState::Reconnecting);
+                if logged {
+                    info!(delay_ms = delay.as_millis(), attempt = reconnect_mgr.attempts(), "waiting before reconnect");
+                } else {
+                    debug!(delay_ms = delay.as_millis(), attempt = reconnect_mgr.attempts(), "waiting before reconnect");
+                }
 
                 tokio::select! {
                     _ = tokio::time::sleep(delay) => {
@@ -196,10 +501,11 @@ async fn main() -> Result<()> {
                 // Handle control events from relay
                 event = control_event_rx.recv() => {
                     match event {
-                        Some(ControlEvent::StartTerminal { name: _, cols, rows, request_id }) => {
+                        Some(ControlEvent::StartTerminal { name: _, cols, rows, request_id, env }) => {
                             // Name is ignored - we use the PID as the terminal name
-                            match terminal_manager.start_terminal(cols, rows).await {
+                            match terminal_manager.start_terminal(cols, rows, &env.unwrap_or_default()).await {
                                 Ok(terminal_name) => {
+                                    status.set_terminals(terminal_manager.terminal_count().await);
                                     let _ = control_conn.terminal_started(
                                         terminal_name,
                                         request_id,
@@ -223,10 +529,44 @@ async fn main() -> Result<()> {
                             if let Err(e) = terminal_manager.close_terminal(&name, signal).await {
                                 warn!(error = %e, name = %name, "failed to close terminal");
                             }
+                            status.set_terminals(terminal_manager.terminal_count().await);
                         }
 
-                        Some(ControlEvent::Disconnected { close_code, clean }) => {
-                            warn!(close_code = ?close_code, clean, "control connection lost");
+                        Some(ControlEvent::SignalTerminal { name, signal }) => {
+                            if let Err(e) = terminal_manager.signal_terminal(&name, signal).await {
+                                warn!(error = %e, name = %name, signal, "failed to signal terminal");
+                            }
+                        }
+
+                        Some(ControlEvent::RequestScrollback { name, request_id }) => {
+                            if let Err(e) = terminal_manager.request_scrollback(&name, request_id).await {
+                                warn!(error = %e, name = %name, "failed to request scrollback");
+                            }
+                        }
+
+                        Some(ControlEvent::RequestProcessTree { name, request_id }) => {
+                            let (processes, error) = match terminal_manager.process_tree(&name).await {
+                                Ok(processes) => (processes, None),
+                                Err(e) => {
+                                    warn!(error = %e, name = %name, "failed to read process tree");
+                                    (Vec::new(), Some(e.to_string()))
+                                }
+                            };
+                            let _ = control_conn.process_tree(request_id, processes, error).await;
+                        }
+
+                        Some(ControlEvent::SetLogLevel { level }) => match log_level.set(&level) {
+                            Ok(()) => info!(level = %level, "log level changed by the relay"),
+                            Err(e) => warn!(error = %e, "ignoring set_log_level"),
+                        },
+
+                        Some(ControlEvent::Heartbeat) => {
+                            probes.touch_health();
+                        }
+
+                        Some(ControlEvent::Disconnected { close_code, clean, reason }) => {
+                            warn!(close_code = ?close_code, clean, reason = reason.as_str(), "control connection lost");
+                            probes.clear_ready();
 
                             if !config.reconnect {
                                 info!("reconnection disabled, exiting");
@@ -246,6 +586,8 @@ async fn main() -> Result<()> {
                             }
 
                             let delay = reconnect_mgr.next_delay();
+                            metrics.record_reconnect_attempt();
+                            status.set_connection(ConnectionState::Reconnecting);
                             info!(
                                 delay_ms = delay.as_millis(),
                                 attempt = reconnect_mgr.attempts(),
@@ -265,6 +607,7 @@ async fn main() -> Result<()> {
 
                         None => {
                             warn!("control event channel closed");
+                            probes.clear_ready();
                             if config.reconnect {
                                 continue 'main;
                             }
@@ -280,6 +623,16 @@ async fn main() -> Result<()> {
                             info!(name = %name, exit_code, "terminal exited");
                             let _ = control_conn.terminal_closed(name.clone(), exit_code).await;
                             terminal_manager.remove_terminal(&name).await;
+                            status.set_terminals(terminal_manager.terminal_count().await);
+
+                            if config.once {
+                                info!(exit_code, "terminal exited in --once mode, shutting down");
+                                process_exit_code = pty::process_exit_code(exit_code);
+                                terminal_manager.shutdown_all(CloseReason::OnceExited).await;
+                                control_conn.shutdown(CloseReason::OnceExited).await;
+                                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                                break 'main;
+                            }
                         }
 
                         Some(TerminalEvent::Disconnected { name }) => {
@@ -299,9 +652,9 @@ async fn main() -> Result<()> {
                 _ = &mut shutdown => {
                     info!("received shutdown signal, initiating graceful shutdown");
                     // First shutdown all terminals (sends close frames on data connections)
-                    terminal_manager.shutdown_all().await;
+                    terminal_manager.shutdown_all(CloseReason::Shutdown).await;
                     // Then shutdown control connection
-                    control_conn.shutdown().await;
+                    control_conn.shutdown(CloseReason::Shutdown).await;
                     // Give a moment for the close frames to be sent
                     tokio::time::sleep(std::time::Duration::from_millis(100)).await;
                     break 'main;
@@ -310,6 +663,10 @@ async fn main() -> Result<()> {
         }
     }
 
-    info!("paircoded exiting");
-    std::process::exit(0);
+    probes.clear_ready();
+    status.finish();
+    info!(exit_code = process_exit_code, "paircoded exiting");
+    drop(session_lock);
+    drop(pid_file);
+    std::process::exit(process_exit_code);
 }
@@ -0,0 +1,134 @@
+//! Traffic counters shared between terminal bridges and the status display.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Process-wide traffic and connection counters
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Input bytes received from the relay and written to PTYs
+    bytes_in: AtomicU64,
+    /// Output bytes read from PTYs
+    bytes_out: AtomicU64,
+    /// Control connection reconnect attempts since startup
+    reconnect_attempts: AtomicU64,
+    /// Microseconds from `start_terminal` to the PTY spawning, for the last terminal (0 if none yet)
+    last_spawn_latency_us: AtomicU64,
+    /// Microseconds from `start_terminal` to the data connection, for the last terminal (0 if none yet)
+    last_ready_latency_us: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add_bytes_in(&self, n: usize) {
+        self.bytes_in.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_out(&self, n: usize) {
+        self.bytes_out.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_reconnect_attempt(&self) {
+        self.reconnect_attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnect_attempts(&self) -> u64 {
+        self.reconnect_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Record how long the latest terminal took to start
+    pub fn record_spawn(&self, timing: &SpawnTiming) {
+        store_latency(&self.last_spawn_latency_us, timing.spawn_latency());
+        if let Some(latency) = timing.ready_latency() {
+            store_latency(&self.last_ready_latency_us, latency);
+        }
+    }
+
+    /// Time from `start_terminal` to the PTY spawning, for the latest terminal
+    pub fn last_spawn_latency(&self) -> Option<Duration> {
+        load_latency(&self.last_spawn_latency_us)
+    }
+
+    /// Time from `start_terminal` to its data connection, for the latest terminal that got one
+    pub fn last_ready_latency(&self) -> Option<Duration> {
+        load_latency(&self.last_ready_latency_us)
+    }
+}
+
+/// Stored as at least 1µs, so 0 can mean "not recorded"
+fn store_latency(slot: &AtomicU64, latency: Duration) {
+    let us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX).max(1);
+    slot.store(us, Ordering::Relaxed);
+}
+
+fn load_latency(slot: &AtomicU64) -> Option<Duration> {
+    Some(slot.load(Ordering::Relaxed)).filter(|&us| us > 0).map(Duration::from_micros)
+}
+
+/// When a terminal's start was requested, its PTY spawned and its data
+/// connection came up
+#[derive(Debug, Clone, Copy)]
+pub struct SpawnTiming {
+    requested: Instant,
+    spawned: Instant,
+    connected: Option<Instant>,
+}
+
+impl SpawnTiming {
+    pub fn new(requested: Instant, spawned: Instant) -> Self {
+        SpawnTiming { requested, spawned, connected: None }
+    }
+
+    /// Note the first data connection (later reconnects don't count)
+    pub fn connected(&mut self, at: Instant) {
+        self.connected.get_or_insert(at);
+    }
+
+    /// Request to PTY spawned: opening the PTY and starting the child only,
+    /// since the shell reads its rc files after this
+    pub fn spawn_latency(&self) -> Duration {
+        self.spawned.saturating_duration_since(self.requested)
+    }
+
+    /// Request to data connection established
+    pub fn ready_latency(&self) -> Option<Duration> {
+        self.connected.map(|at| at.saturating_duration_since(self.requested))
+    }
+
+    /// PTY spawned to data connection established, including the wait for a
+    /// shell that fails straight away
+    pub fn connect_latency(&self) -> Option<Duration> {
+        self.connected.map(|at| at.saturating_duration_since(self.spawned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_timing() {
+        let requested = Instant::now();
+        let mut timing = SpawnTiming::new(requested, requested + Duration::from_millis(40));
+        assert_eq!(timing.spawn_latency(), Duration::from_millis(40));
+        assert_eq!(timing.ready_latency(), None);
+
+        timing.connected(requested + Duration::from_millis(150));
+        timing.connected(requested + Duration::from_secs(9));
+        assert_eq!(timing.ready_latency(), Some(Duration::from_millis(150)));
+        assert_eq!(timing.connect_latency(), Some(Duration::from_millis(110)));
+
+        let metrics = Metrics::default();
+        assert_eq!(metrics.last_spawn_latency(), None);
+        metrics.record_spawn(&timing);
+        assert_eq!(metrics.last_spawn_latency(), Some(Duration::from_millis(40)));
+        assert_eq!(metrics.last_ready_latency(), Some(Duration::from_millis(150)));
+    }
+}
@@ -0,0 +1,54 @@
+//! Guard against exposing a root shell over the relay.
+//!
+//! Anyone who can reach the session gets the spawned shell's privileges, so
+//! running as root is refused unless explicitly allowed with `--allow-root`.
+
+/// Whether the process runs with an effective UID of 0
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+    false
+}
+
+/// Whether startup should be refused for the given privilege state
+pub fn should_refuse(is_root: bool, allow_root: bool) -> bool {
+    is_root && !allow_root
+}
+
+/// Warning printed when running as root, whether refused or allowed
+pub fn root_warning(sandboxed: bool) -> String {
+    let mut warning = String::from(
+        "WARNING: paircoded is running as root. Anyone who joins this session \
+         gets a root shell on this machine.",
+    );
+    if sandboxed {
+        warning.push_str(
+            " The sandbox limits filesystem access, but processes inside it still run as root.",
+        );
+    }
+    warning
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_refuse() {
+        assert!(should_refuse(true, false));
+        assert!(!should_refuse(true, true));
+        assert!(!should_refuse(false, false));
+        assert!(!should_refuse(false, true));
+    }
+
+    #[test]
+    fn test_root_warning_mentions_sandbox() {
+        assert!(!root_warning(false).contains("sandbox"));
+        assert!(root_warning(true).contains("sandbox"));
+    }
+}
@@ -0,0 +1,146 @@
+//! Readiness and liveness files for container orchestration.
+//!
+//! The ready file exists only while the control connection is up; the health
+//! file holds the Unix timestamp of the last keepalive so orchestrators can use
+//! file-age probes. Both are written atomically (temp file + rename).
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Ready/health probe files
+#[derive(Debug, Clone, Default)]
+pub struct ProbeFiles {
+    ready_file: Option<PathBuf>,
+    health_file: Option<PathBuf>,
+}
+
+impl ProbeFiles {
+    pub fn new(ready_file: Option<PathBuf>, health_file: Option<PathBuf>) -> Self {
+        ProbeFiles { ready_file, health_file }
+    }
+
+    /// Create the ready file (control connection established)
+    pub fn mark_ready(&self) {
+        if let Some(ref path) = self.ready_file {
+            if let Err(e) = write_atomic(path, &timestamp_line()) {
+                warn!(error = %e, "failed to write ready file");
+            }
+        }
+        self.touch_health();
+    }
+
+    /// Remove the ready file (disconnected or shutting down)
+    pub fn clear_ready(&self) {
+        if let Some(ref path) = self.ready_file {
+            match fs::remove_file(path) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => warn!(error = %e, ?path, "failed to remove ready file"),
+            }
+        }
+    }
+
+    /// Record a successful keepalive in the health file
+    pub fn touch_health(&self) {
+        if let Some(ref path) = self.health_file {
+            if let Err(e) = write_atomic(path, &timestamp_line()) {
+                warn!(error = %e, "failed to write health file");
+            }
+        }
+    }
+}
+
+fn timestamp_line() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("{}\n", secs)
+}
+
+/// Write `contents` to `path` via a temp file in the same directory and a rename
+pub(crate) fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("invalid file path {}", path.display()))?;
+    let mut tmp_name = file_name.to_os_string();
+    tmp_name.push(format!(".tmp.{}", std::process::id()));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    fs::write(&tmp_path, contents)
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to rename {} to {}", tmp_path.display(), path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ready_file_lifecycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let ready = dir.path().join("ready");
+        let health = dir.path().join("health");
+        let probes = ProbeFiles::new(Some(ready.clone()), Some(health.clone()));
+
+        assert!(!ready.exists());
+
+        // Simulated successful connect
+        probes.mark_ready();
+        assert!(ready.exists());
+        assert!(health.exists());
+
+        // Keepalive refreshes the health timestamp
+        probes.touch_health();
+        let stamp: u64 = fs::read_to_string(&health).unwrap().trim().parse().unwrap();
+        assert!(stamp > 0);
+
+        // Shutdown
+        probes.clear_ready();
+        assert!(!ready.exists());
+        probes.clear_ready();
+
+        // No temp files left behind
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    /// Names of the files in `dir`, sorted
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> =
+            fs::read_dir(dir).unwrap().map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned()).collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_disabled_probes() {
+        let dir = tempfile::tempdir().unwrap();
+        let exercise = |probes: &ProbeFiles| {
+            probes.mark_ready();
+            probes.touch_health();
+        };
+
+        // Neither file configured: nothing is written
+        let probes = ProbeFiles::default();
+        exercise(&probes);
+        probes.clear_ready();
+        assert!(file_names(dir.path()).is_empty());
+
+        // Only the configured file is written
+        let probes = ProbeFiles::new(Some(dir.path().join("ready")), None);
+        exercise(&probes);
+        assert_eq!(file_names(dir.path()), ["ready"]);
+        probes.clear_ready();
+        assert!(file_names(dir.path()).is_empty());
+
+        let probes = ProbeFiles::new(None, Some(dir.path().join("health")));
+        exercise(&probes);
+        probes.clear_ready();
+        assert_eq!(file_names(dir.path()), ["health"]);
+    }
+}
@@ -0,0 +1,96 @@
+//! Listing the processes running under a terminal (`request_process_tree`).
+//!
+//! Only descendants of the terminal's own process are reported, so the relay
+//! learns what runs in the terminal without seeing the rest of the host.
+
+use anyhow::Result;
+
+use crate::protocol::ProcessInfo;
+
+/// Processes descended from `root` (not including it), parents before children
+#[cfg(target_os = "linux")]
+pub fn descendants(root: u32) -> Result<Vec<ProcessInfo>> {
+    use anyhow::Context;
+    use std::collections::HashMap;
+
+    let mut children: HashMap<u32, Vec<ProcessInfo>> = HashMap::new();
+    for entry in std::fs::read_dir("/proc").context("failed to read /proc")? {
+        let Ok(entry) = entry else { continue };
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else {
+            continue;
+        };
+        // Processes can exit while the table is read; skip those
+        let Some((comm, ppid)) = std::fs::read_to_string(entry.path().join("stat"))
+            .ok()
+            .and_then(|stat| parse_stat(&stat))
+        else {
+            continue;
+        };
+        let command = std::fs::read(entry.path().join("cmdline"))
+            .ok()
+            .and_then(|cmdline| command_line(&cmdline))
+            .unwrap_or(comm);
+        children.entry(ppid).or_default().push(ProcessInfo { pid, ppid, command });
+    }
+
+    let mut processes = Vec::new();
+    let mut queue = std::collections::VecDeque::from([root]);
+    while let Some(pid) = queue.pop_front() {
+        let Some(mut direct) = children.remove(&pid) else { continue };
+        direct.sort_by_key(|process| process.pid);
+        queue.extend(direct.iter().map(|process| process.pid));
+        processes.extend(direct);
+    }
+    Ok(processes)
+}
+
+/// Processes descended from `root`; needs `/proc`, so Linux only
+#[cfg(not(target_os = "linux"))]
+pub fn descendants(_root: u32) -> Result<Vec<ProcessInfo>> {
+    anyhow::bail!("process trees are only supported on Linux")
+}
+
+/// Command name and parent PID from the contents of `/proc/<pid>/stat`.
+///
+/// The name is in parentheses and may itself contain spaces and `)`, so the
+/// fields after it are found from the last `)`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_stat(stat: &str) -> Option<(String, u32)> {
+    let open = stat.find('(')?;
+    let close = stat.rfind(')')?;
+    let comm = stat.get(open + 1..close)?.to_string();
+    // state, then ppid
+    let ppid = stat.get(close + 1..)?.split_whitespace().nth(1)?.parse().ok()?;
+    Some((comm, ppid))
+}
+
+/// Arguments from `/proc/<pid>/cmdline` joined with spaces (`None` for kernel
+/// threads and zombies, which have none)
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn command_line(cmdline: &[u8]) -> Option<String> {
+    let args: Vec<String> = cmdline
+        .split(|&b| b == 0)
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| String::from_utf8_lossy(arg).into_owned())
+        .collect();
+    (!args.is_empty()).then(|| args.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_stat() {
+        assert_eq!(parse_stat("1234 (sleep) S 1200 1234 1200 0"), Some(("sleep".to_string(), 1200)));
+        // Names can contain spaces and parentheses
+        assert_eq!(parse_stat("77 (a) b (c)) R 5 77 5"), Some(("a) b (c)".to_string(), 5)));
+        assert_eq!(parse_stat("garbage"), None);
+    }
+
+    #[test]
+    fn test_command_line() {
+        assert_eq!(command_line(b"sleep\x0030\x00").as_deref(), Some("sleep 30"));
+        assert_eq!(command_line(b""), None);
+    }
+}
@@ -8,26 +8,56 @@
 //! - `'2'` → Pause PTY output
 //! - `'3'` → Resume PTY output
 //! - `'4'` + JSON → Request snapshot `{"requestId": "..."}`
+//! - `'5'` + JSON → Relay handshake `{"role": "controller" | "observer", "replay": bool, "sequence": bool}`
+//!
+//! The relay may also reply to the handshake with a JSON ack (see [`AckMessage`]).
+//! Both handshakes list the client's `capabilities`; the relay advertises its own
+//! in the ack and only the common ones are enabled (see [`Capabilities`]).
 //!
 //! **Client (paircoded) → Server (Relay):**
 //! - `'0'` + data → PTY output
 //! - `'1'` + JSON → Initial handshake / metadata
 //! - `'2'` + exit code → PTY exited
 //! - `'3'` + JSON → Snapshot response `{"requestId": "...", "screen": "...", ...}`
+//! - `'4'` + u32 seq (big-endian) + data → Sequenced PTY output, sent instead of
+//!   `'0'` once the relay handshake sets `"sequence": true`
+//! - `'5'` → Output idle: no PTY output for the `--output-idle-ms` interval
+//!   (sent once per quiet period)
+//! - `'6'` + u32 length (big-endian) + metadata JSON + raw screen bytes → Snapshot
+//!   response without base64, sent instead of `'3'` once `raw_snapshot` is negotiated
+//! - `'7'` + u32 length (big-endian) + JSON `{"requestId": "...", "index": N}` + output →
+//!   Scrollback chunk of recorded PTY output, in order
+//! - `'8'` + JSON `{"requestId": "...", "chunks": N}` → End of a scrollback response
+//! - `'9'` → Input pause: queued PTY input passed the high watermark, hold input
+//!   (only sent once `input_flow` is negotiated)
+//! - `':'` → Input resume: queued PTY input drained to the low watermark
+//! - `';'` + JSON `{"cols": N, "rows": N}` → The program asked for a new window
+//!   size (`ESC [ 8 ; rows ; cols t`)
+//!
+//! Connections paircoded closes on purpose end with a normal (1000) close frame
+//! whose reason says why (see [`CloseReason`]).
 //!
 //! ## Control Protocol (JSON, control websocket)
 //!
 //! **Relay → Paircoded:**
-//! - `{"type": "start_terminal", "name": "...", "cols": N, "rows": N, "requestId": "..."}`
+//! - `{"type": "start_terminal", "name": "...", "cols": N, "rows": N, "requestId": "...", "env": {...}}`
+//!   (`env` is optional; `cols`/`rows` may be omitted or 0 when the size isn't known)
 //! - `{"type": "close_terminal", "name": "...", "signal": N}`
+//! - `{"type": "signal_terminal", "name": "...", "signal": N}` (e.g. SIGSTOP/SIGCONT,
+//!   leaving the terminal open)
+//! - `{"type": "request_scrollback", "name": "...", "requestId": "..."}`, answered on
+//!   the terminal's data connection with scrollback chunks and an end marker
 //!
 //! **Paircoded → Relay:**
-//! - `{"type": "control_handshake", "version": "..."}`
+//! - `{"type": "control_handshake", "version": "...", "capabilities": [...]}`
 //! - `{"type": "terminal_started", "name": "...", "requestId": "...", "success": bool, "error": "..."}`
 //! - `{"type": "terminal_closed", "name": "...", "exitCode": N}`
 
 use anyhow::{anyhow, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use tracing::info;
 
 /// Message type prefixes for relay → client messages
 pub mod relay_prefix {
@@ -36,6 +66,7 @@ pub mod relay_prefix {
     pub const PAUSE: u8 = b'2';
     pub const RESUME: u8 = b'3';
     pub const REQUEST_SNAPSHOT: u8 = b'4';
+    pub const HANDSHAKE: u8 = b'5';
 }
 
 /// Message type prefixes for client → relay messages
@@ -44,6 +75,65 @@ pub mod client_prefix {
     pub const HANDSHAKE: u8 = b'1';
     pub const EXIT: u8 = b'2';
     pub const SNAPSHOT: u8 = b'3';
+    pub const SEQUENCED_OUTPUT: u8 = b'4';
+    pub const OUTPUT_IDLE: u8 = b'5';
+    pub const RAW_SNAPSHOT: u8 = b'6';
+    pub const SCROLLBACK_CHUNK: u8 = b'7';
+    pub const SCROLLBACK_END: u8 = b'8';
+    pub const INPUT_PAUSE: u8 = b'9';
+    pub const INPUT_RESUME: u8 = b':';
+    pub const SIZE_CHANGED: u8 = b';';
+}
+
+/// Names of optional protocol features, as listed in handshakes and acks
+pub mod capability {
+    /// Output missed while disconnected is replayed on request
+    pub const REPLAY: &str = "replay";
+    /// Sequenced output frames (`'4'`)
+    pub const SEQUENCE: &str = "sequence";
+    /// Binary snapshot frames with the screen sent raw (`'6'`)
+    pub const RAW_SNAPSHOT: &str = "raw_snapshot";
+    /// Input pause/resume frames (`'9'`/`':'`)
+    pub const INPUT_FLOW: &str = "input_flow";
+
+    /// Everything this client implements
+    pub const SUPPORTED: &[&str] = &[REPLAY, SEQUENCE, RAW_SNAPSHOT, INPUT_FLOW];
+
+    /// [`SUPPORTED`] as owned strings, for handshakes
+    pub fn supported() -> Vec<String> {
+        SUPPORTED.iter().map(|name| name.to_string()).collect()
+    }
+}
+
+/// Features both sides of a connection support
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Capability names in the intersection, in the client's order
+    pub negotiated: Vec<String>,
+    pub replay: bool,
+    pub sequence: bool,
+    pub raw_snapshot: bool,
+    pub input_flow: bool,
+}
+
+impl Capabilities {
+    /// Intersect what the client offered with what the relay advertised
+    pub fn negotiate(ours: &[String], theirs: &[String]) -> Self {
+        let negotiated: Vec<String> = ours.iter().filter(|name| theirs.contains(name)).cloned().collect();
+        let has = |name: &str| negotiated.iter().any(|n| n == name);
+        Capabilities {
+            replay: has(capability::REPLAY),
+            sequence: has(capability::SEQUENCE),
+            raw_snapshot: has(capability::RAW_SNAPSHOT),
+            input_flow: has(capability::INPUT_FLOW),
+            negotiated,
+        }
+    }
+
+    /// Whether a capability (by name) was negotiated
+    pub fn supports(&self, name: &str) -> bool {
+        self.negotiated.iter().any(|n| n == name)
+    }
 }
 
 /// Terminal resize dimensions
@@ -58,10 +148,94 @@ pub struct ResizeMessage {
 pub struct HandshakeMessage {
     pub version: String,
     pub shell: String,
+    /// Terminal size the PTY is running at: the `start_terminal` request's, or
+    /// the latest resize on a reconnect. Authoritative until the relay sends a resize.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cols: Option<u16>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub rows: Option<u16>,
+    /// PTY slave device path (e.g. `/dev/pts/3`), when available
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tty: Option<String>,
+    /// Optional features this client supports (see [`capability`])
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    /// Identifies this connection in client and relay logs (see [`new_connection_id`])
+    #[serde(rename = "connectionId", default)]
+    pub connection_id: String,
+}
+
+/// A random (version 4) UUID for one control or data connection.
+///
+/// Sent in the handshake and recorded on the connection's tracing span, so
+/// client and relay logs for the same connection can be matched up.
+pub fn new_connection_id() -> String {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Role of a data connection: observers receive output but their input is ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionRole {
+    #[default]
+    Controller,
+    Observer,
+}
+
+/// Relay handshake describing the data connection
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RelayHandshake {
+    #[serde(default)]
+    pub role: ConnectionRole,
+    /// Replay output the client missed while disconnected
+    #[serde(default)]
+    pub replay: bool,
+    /// Send output as sequenced frames so the relay can detect gaps
+    #[serde(default)]
+    pub sequence: bool,
+}
+
+/// Handshake acknowledgement the relay may send as its first message on either
+/// connection: `{"type": "handshake_ack"}` or
+/// `{"type": "error", "error": "...", "code": "..."}`
+///
+/// An ack may list the relay's `capabilities`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AckMessage {
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl AckMessage {
+    /// Parse an ack; `None` if the message is anything else
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let ack: AckMessage = serde_json::from_slice(data).ok()?;
+        matches!(ack.kind.as_str(), "handshake_ack" | "error").then_some(ack)
+    }
+
+    /// Whether the relay rejected the handshake
+    pub fn is_error(&self) -> bool {
+        self.kind == "error" || self.error.is_some()
+    }
+
+    /// Error text for logs and the user, with the relay's code if given
+    pub fn error_message(&self) -> String {
+        let error = self.error.as_deref().unwrap_or("unspecified error");
+        match self.code {
+            Some(ref code) => format!("{} ({})", error, code),
+            None => error.to_string(),
+        }
+    }
 }
 
 /// Request for terminal state snapshot
@@ -76,7 +250,7 @@ pub struct SnapshotRequest {
 pub struct SnapshotMessage {
     #[serde(rename = "requestId")]
     pub request_id: String,
-    /// Screen content with ANSI escape sequences (base64 encoded in JSON)
+    /// Scrollback, then the screen, with ANSI escape sequences (base64 encoded in JSON)
     #[serde(with = "base64_serde")]
     pub screen: Vec<u8>,
     pub cols: u16,
@@ -85,6 +259,52 @@ pub struct SnapshotMessage {
     pub cursor_x: u16,
     #[serde(rename = "cursorY")]
     pub cursor_y: u16,
+    /// Cursor shape set via DECSCUSR: "block", "underline" or "bar"
+    #[serde(rename = "cursorShape", default = "default_cursor_shape")]
+    pub cursor_shape: String,
+    /// Scrollback was left out to stay under `--max-snapshot-bytes` (or, if the
+    /// visible screen alone was over it, the screen sent without styling)
+    #[serde(default)]
+    pub truncated: bool,
+}
+
+fn default_cursor_shape() -> String {
+    "block".to_string()
+}
+
+/// Header of a scrollback chunk frame
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrollbackChunkMeta {
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    /// Position of the chunk in the response, from 0
+    pub index: u32,
+}
+
+/// End marker of a scrollback response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScrollbackEnd {
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    /// Number of chunks sent before the marker
+    pub chunks: u32,
+}
+
+/// Snapshot fields other than the screen, the JSON part of a raw snapshot frame
+#[derive(Serialize, Deserialize)]
+struct RawSnapshotMeta {
+    #[serde(rename = "requestId")]
+    request_id: String,
+    cols: u16,
+    rows: u16,
+    #[serde(rename = "cursorX")]
+    cursor_x: u16,
+    #[serde(rename = "cursorY")]
+    cursor_y: u16,
+    #[serde(rename = "cursorShape", default = "default_cursor_shape")]
+    cursor_shape: String,
+    #[serde(default)]
+    truncated: bool,
 }
 
 mod base64_serde {
@@ -120,6 +340,8 @@ pub enum RelayMessage {
     Resume,
     /// Request terminal state snapshot
     RequestSnapshot(SnapshotRequest),
+    /// Relay handshake for this connection
+    Handshake(RelayHandshake),
 }
 
 /// Messages sent to the relay
@@ -127,12 +349,28 @@ pub enum RelayMessage {
 pub enum ClientMessage {
     /// PTY output data
     Output(Vec<u8>),
+    /// PTY output data with a per-terminal sequence number
+    SequencedOutput { seq: u32, data: Vec<u8> },
     /// Initial handshake
     Handshake(HandshakeMessage),
     /// PTY process exited
     Exit(i32),
     /// Terminal state snapshot
     Snapshot(SnapshotMessage),
+    /// Terminal state snapshot with the screen sent as raw bytes
+    RawSnapshot(SnapshotMessage),
+    /// No PTY output for the configured idle interval
+    OutputIdle,
+    /// Part of a scrollback response
+    ScrollbackChunk { meta: ScrollbackChunkMeta, data: Vec<u8> },
+    /// Last frame of a scrollback response
+    ScrollbackEnd(ScrollbackEnd),
+    /// The PTY is not keeping up with input; hold further input
+    InputPause,
+    /// The PTY has caught up; input may flow again
+    InputResume,
+    /// The program requested a different window size
+    SizeChanged { cols: u16, rows: u16 },
 }
 
 impl RelayMessage {
@@ -157,12 +395,71 @@ impl RelayMessage {
                 let request: SnapshotRequest = serde_json::from_slice(payload)?;
                 Ok(RelayMessage::RequestSnapshot(request))
             }
+            relay_prefix::HANDSHAKE => {
+                let handshake: RelayHandshake = serde_json::from_slice(payload)?;
+                Ok(RelayMessage::Handshake(handshake))
+            }
             _ => Err(anyhow!("unknown message prefix: {}", prefix)),
         }
     }
 }
 
+impl RelayMessage {
+    /// Wire prefix and name of the message type
+    fn kind(&self) -> (u8, &'static str) {
+        match self {
+            RelayMessage::Input(_) => (relay_prefix::INPUT, "input"),
+            RelayMessage::Resize(_) => (relay_prefix::RESIZE, "resize"),
+            RelayMessage::Pause => (relay_prefix::PAUSE, "pause"),
+            RelayMessage::Resume => (relay_prefix::RESUME, "resume"),
+            RelayMessage::RequestSnapshot(_) => (relay_prefix::REQUEST_SNAPSHOT, "request_snapshot"),
+            RelayMessage::Handshake(_) => (relay_prefix::HANDSHAKE, "handshake"),
+        }
+    }
+
+    /// One-line summary of an inbound data frame of `len` bytes
+    pub fn trace_summary(&self, len: usize) -> String {
+        let (prefix, name) = self.kind();
+        format!("<- data '{}' {} {} bytes", prefix as char, name, len)
+    }
+
+    /// Frame log line for this message, received as `frame`: the summary plus
+    /// the payload, except for input, which is keystrokes (typed passwords
+    /// included) and only ever logged by size
+    pub fn trace_line(&self, frame: &[u8]) -> String {
+        let summary = self.trace_summary(frame.len());
+        match self {
+            RelayMessage::Input(_) => summary,
+            _ => format!("{} {}", summary, payload_preview(frame)),
+        }
+    }
+}
+
 impl ClientMessage {
+    /// Wire prefix and name of the message type
+    fn kind(&self) -> (u8, &'static str) {
+        match self {
+            ClientMessage::Output(_) => (client_prefix::OUTPUT, "output"),
+            ClientMessage::SequencedOutput { .. } => (client_prefix::SEQUENCED_OUTPUT, "sequenced_output"),
+            ClientMessage::Handshake(_) => (client_prefix::HANDSHAKE, "handshake"),
+            ClientMessage::Exit(_) => (client_prefix::EXIT, "exit"),
+            ClientMessage::Snapshot(_) => (client_prefix::SNAPSHOT, "snapshot"),
+            ClientMessage::RawSnapshot(_) => (client_prefix::RAW_SNAPSHOT, "raw_snapshot"),
+            ClientMessage::OutputIdle => (client_prefix::OUTPUT_IDLE, "output_idle"),
+            ClientMessage::ScrollbackChunk { .. } => (client_prefix::SCROLLBACK_CHUNK, "scrollback_chunk"),
+            ClientMessage::ScrollbackEnd(_) => (client_prefix::SCROLLBACK_END, "scrollback_end"),
+            ClientMessage::InputPause => (client_prefix::INPUT_PAUSE, "input_pause"),
+            ClientMessage::InputResume => (client_prefix::INPUT_RESUME, "input_resume"),
+            ClientMessage::SizeChanged { .. } => (client_prefix::SIZE_CHANGED, "size_changed"),
+        }
+    }
+
+    /// One-line summary of an outbound data frame of `len` bytes
+    pub fn trace_summary(&self, len: usize) -> String {
+        let (prefix, name) = self.kind();
+        format!("-> data '{}' {} {} bytes", prefix as char, name, len)
+    }
+
     /// Encode a message to send to the relay
     pub fn encode(&self) -> Result<Vec<u8>> {
         match self {
@@ -172,6 +469,13 @@ impl ClientMessage {
                 msg.extend_from_slice(data);
                 Ok(msg)
             }
+            ClientMessage::SequencedOutput { seq, data } => {
+                let mut msg = Vec::with_capacity(5 + data.len());
+                msg.push(client_prefix::SEQUENCED_OUTPUT);
+                msg.extend_from_slice(&seq.to_be_bytes());
+                msg.extend_from_slice(data);
+                Ok(msg)
+            }
             ClientMessage::Handshake(handshake) => {
                 let json = serde_json::to_vec(handshake)?;
                 let mut msg = Vec::with_capacity(1 + json.len());
@@ -193,14 +497,140 @@ impl ClientMessage {
                 msg.extend_from_slice(&json);
                 Ok(msg)
             }
+            ClientMessage::RawSnapshot(snapshot) => {
+                let meta = serde_json::to_vec(&RawSnapshotMeta {
+                    request_id: snapshot.request_id.clone(),
+                    cols: snapshot.cols,
+                    rows: snapshot.rows,
+                    cursor_x: snapshot.cursor_x,
+                    cursor_y: snapshot.cursor_y,
+                    cursor_shape: snapshot.cursor_shape.clone(),
+                    truncated: snapshot.truncated,
+                })?;
+                Ok(encode_with_meta(client_prefix::RAW_SNAPSHOT, &meta, &snapshot.screen))
+            }
+            ClientMessage::OutputIdle => Ok(vec![client_prefix::OUTPUT_IDLE]),
+            ClientMessage::ScrollbackChunk { meta, data } => {
+                let meta = serde_json::to_vec(meta)?;
+                Ok(encode_with_meta(client_prefix::SCROLLBACK_CHUNK, &meta, data))
+            }
+            ClientMessage::ScrollbackEnd(end) => {
+                let json = serde_json::to_vec(end)?;
+                let mut msg = Vec::with_capacity(1 + json.len());
+                msg.push(client_prefix::SCROLLBACK_END);
+                msg.extend_from_slice(&json);
+                Ok(msg)
+            }
+            ClientMessage::InputPause => Ok(vec![client_prefix::INPUT_PAUSE]),
+            ClientMessage::InputResume => Ok(vec![client_prefix::INPUT_RESUME]),
+            ClientMessage::SizeChanged { cols, rows } => {
+                let json = serde_json::to_vec(&ResizeMessage { cols: *cols, rows: *rows })?;
+                let mut msg = Vec::with_capacity(1 + json.len());
+                msg.push(client_prefix::SIZE_CHANGED);
+                msg.extend_from_slice(&json);
+                Ok(msg)
+            }
         }
     }
 }
 
+/// Split a sequenced output frame (including its prefix) into sequence number and data
+pub fn parse_sequenced_output(frame: &[u8]) -> Result<(u32, &[u8])> {
+    match frame {
+        [client_prefix::SEQUENCED_OUTPUT, a, b, c, d, data @ ..] => Ok((u32::from_be_bytes([*a, *b, *c, *d]), data)),
+        [client_prefix::SEQUENCED_OUTPUT, ..] => Err(anyhow!("sequenced output frame too short")),
+        _ => Err(anyhow!("not a sequenced output frame")),
+    }
+}
+
+/// Frame `data` behind `prefix`, a u32 length and the `meta` JSON
+fn encode_with_meta(prefix: u8, meta: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(5 + meta.len() + data.len());
+    msg.push(prefix);
+    msg.extend_from_slice(&(meta.len() as u32).to_be_bytes());
+    msg.extend_from_slice(meta);
+    msg.extend_from_slice(data);
+    msg
+}
+
+/// Split a frame built by [`encode_with_meta`] into metadata JSON and data
+fn split_meta<'a>(frame: &'a [u8], prefix: u8, kind: &str) -> Result<(&'a [u8], &'a [u8])> {
+    let rest = match frame.split_first() {
+        Some((&first, rest)) if first == prefix => rest,
+        _ => return Err(anyhow!("not a {} frame", kind)),
+    };
+    let (len, rest) = rest
+        .split_first_chunk::<4>()
+        .ok_or_else(|| anyhow!("{} frame too short", kind))?;
+    let len = u32::from_be_bytes(*len) as usize;
+    if rest.len() < len {
+        return Err(anyhow!("{} metadata truncated", kind));
+    }
+    Ok(rest.split_at(len))
+}
+
+/// Decode a scrollback chunk frame (including its prefix)
+pub fn parse_scrollback_chunk(frame: &[u8]) -> Result<(ScrollbackChunkMeta, &[u8])> {
+    let (meta, data) = split_meta(frame, client_prefix::SCROLLBACK_CHUNK, "scrollback chunk")?;
+    Ok((serde_json::from_slice(meta)?, data))
+}
+
+/// Decode a raw snapshot frame (including its prefix)
+pub fn parse_raw_snapshot(frame: &[u8]) -> Result<SnapshotMessage> {
+    let (meta, screen) = split_meta(frame, client_prefix::RAW_SNAPSHOT, "raw snapshot")?;
+    let meta: RawSnapshotMeta = serde_json::from_slice(meta)?;
+    Ok(SnapshotMessage {
+        request_id: meta.request_id,
+        screen: screen.to_vec(),
+        cols: meta.cols,
+        rows: meta.rows,
+        cursor_x: meta.cursor_x,
+        cursor_y: meta.cursor_y,
+        cursor_shape: meta.cursor_shape,
+        truncated: meta.truncated,
+    })
+}
+
 // ============================================================================
 // Control Protocol Messages (JSON over control websocket)
 // ============================================================================
 
+/// Why paircoded closed a websocket, carried in its close frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CloseReason {
+    /// paircoded is shutting down
+    #[default]
+    Shutdown,
+    /// paircoded is exiting because its terminal exited (`--once`)
+    OnceExited,
+    /// The terminal's process exited
+    TerminalExited,
+    /// The relay asked for the terminal to be closed
+    TerminalClosed,
+}
+
+impl CloseReason {
+    /// Close code for the frame
+    pub fn code(&self) -> u16 {
+        match self {
+            CloseReason::Shutdown
+            | CloseReason::OnceExited
+            | CloseReason::TerminalExited
+            | CloseReason::TerminalClosed => 1000,
+        }
+    }
+
+    /// Reason text for the frame
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CloseReason::Shutdown => "client shutdown",
+            CloseReason::OnceExited => "client exiting after terminal exit",
+            CloseReason::TerminalExited => "terminal exited",
+            CloseReason::TerminalClosed => "terminal closed",
+        }
+    }
+}
+
 /// Control messages received from the relay on the control connection
 #[derive(Debug, Clone, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
@@ -208,10 +638,16 @@ pub enum ControlMessage {
     /// Request to start a new terminal
     StartTerminal {
         name: String,
+        /// Initial size (0 or missing when the relay doesn't know it yet)
+        #[serde(default)]
         cols: u16,
+        #[serde(default)]
         rows: u16,
         #[serde(rename = "requestId")]
         request_id: String,
+        /// Environment variables set for this terminal on top of the defaults
+        #[serde(default)]
+        env: Option<HashMap<String, String>>,
     },
     /// Request to close a terminal
     CloseTerminal {
@@ -219,6 +655,47 @@ pub enum ControlMessage {
         #[serde(default)]
         signal: Option<i32>,
     },
+    /// Request to send a signal to a terminal's process without closing it
+    SignalTerminal {
+        name: String,
+        signal: i32,
+    },
+    /// Request for a terminal's scrollback history
+    RequestScrollback {
+        name: String,
+        #[serde(rename = "requestId")]
+        request_id: String,
+    },
+    /// Request for the processes running under a terminal
+    RequestProcessTree {
+        name: String,
+        #[serde(rename = "requestId")]
+        request_id: String,
+    },
+    /// Change this host's log verbosity until the next change or restart
+    /// (`off` to `debug`; `trace` is refused)
+    SetLogLevel {
+        level: String,
+    },
+    /// A message type from a newer relay that this version doesn't handle
+    #[serde(other)]
+    Unknown,
+}
+
+/// Just the `type` of a control message
+#[derive(Deserialize)]
+struct ControlMessageType {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// One process under a terminal, as reported in a process tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub ppid: u32,
+    /// Command line, or the process name when it has none
+    pub command: String,
 }
 
 /// Control responses sent to the relay on the control connection
@@ -232,6 +709,9 @@ pub enum ControlResponse {
         username: String,
         #[serde(rename = "workingDir")]
         working_dir: String,
+        capabilities: Vec<String>,
+        #[serde(rename = "connectionId")]
+        connection_id: String,
     },
     /// Response to start_terminal request
     TerminalStarted {
@@ -248,6 +728,14 @@ pub enum ControlResponse {
         #[serde(rename = "exitCode")]
         exit_code: i32,
     },
+    /// Response to request_process_tree: the terminal's descendant processes
+    ProcessTree {
+        #[serde(rename = "requestId")]
+        request_id: String,
+        processes: Vec<ProcessInfo>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
 }
 
 impl ControlMessage {
@@ -260,6 +748,26 @@ impl ControlMessage {
     pub fn parse_str(data: &str) -> Result<Self> {
         serde_json::from_str(data).map_err(|e| anyhow!("failed to parse control message: {}", e))
     }
+
+    /// The `type` field of a JSON control message, e.g. to log one that
+    /// parsed as [`Unknown`](Self::Unknown)
+    pub fn message_type(data: &[u8]) -> Option<String> {
+        serde_json::from_slice::<ControlMessageType>(data).ok().map(|msg| msg.kind)
+    }
+
+    /// One-line summary of an inbound control frame of `len` bytes
+    pub fn trace_summary(&self, len: usize) -> String {
+        let name = match self {
+            ControlMessage::StartTerminal { .. } => "start_terminal",
+            ControlMessage::CloseTerminal { .. } => "close_terminal",
+            ControlMessage::SignalTerminal { .. } => "signal_terminal",
+            ControlMessage::RequestScrollback { .. } => "request_scrollback",
+            ControlMessage::RequestProcessTree { .. } => "request_process_tree",
+            ControlMessage::SetLogLevel { .. } => "set_log_level",
+            ControlMessage::Unknown => "unknown",
+        };
+        format!("<- control {} {} bytes", name, len)
+    }
 }
 
 impl ControlResponse {
@@ -267,6 +775,69 @@ impl ControlResponse {
     pub fn encode(&self) -> Result<String> {
         serde_json::to_string(self).map_err(|e| anyhow!("failed to encode control response: {}", e))
     }
+
+    /// One-line summary of an outbound control frame of `len` bytes
+    pub fn trace_summary(&self, len: usize) -> String {
+        let name = match self {
+            ControlResponse::ControlHandshake { .. } => "control_handshake",
+            ControlResponse::TerminalStarted { .. } => "terminal_started",
+            ControlResponse::TerminalClosed { .. } => "terminal_closed",
+            ControlResponse::ProcessTree { .. } => "process_tree",
+        };
+        format!("-> control {} {} bytes", name, len)
+    }
+}
+
+// ============================================================================
+// Protocol tracing (--trace-protocol)
+// ============================================================================
+
+/// Log target for frame traces, enabled at info level by `--trace-protocol`
+pub const TRACE_TARGET: &str = "paircoded::wire";
+
+/// Default cap on payload bytes shown in frame logs
+pub const DEFAULT_MAX_FRAME_LOG_BYTES: usize = 256;
+
+static TRACE_PROTOCOL: AtomicBool = AtomicBool::new(false);
+
+static MAX_FRAME_LOG_BYTES: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME_LOG_BYTES);
+
+/// Turn per-frame tracing on or off for the whole process
+pub fn set_trace_protocol(enabled: bool) {
+    TRACE_PROTOCOL.store(enabled, Ordering::Relaxed);
+}
+
+/// Cap the payload bytes shown in frame logs for the whole process (`--max-frame-log-bytes`)
+pub fn set_max_frame_log_bytes(max: usize) {
+    MAX_FRAME_LOG_BYTES.store(max, Ordering::Relaxed);
+}
+
+/// Log a frame summary if tracing is enabled (the summary is only built then)
+pub fn trace_frame(summary: impl FnOnce() -> String) {
+    if TRACE_PROTOCOL.load(Ordering::Relaxed) {
+        info!(target: TRACE_TARGET, "{}", summary());
+    }
+}
+
+/// Like [`trace_frame`], followed by the frame's payload (see [`payload_preview`])
+pub fn trace_frame_payload(summary: impl FnOnce() -> String, payload: &[u8]) {
+    trace_frame(|| format!("{} {}", summary(), payload_preview(payload)));
+}
+
+/// Payload as logged: escaped, and cut at the `--max-frame-log-bytes` limit
+pub fn payload_preview(payload: &[u8]) -> String {
+    truncate_payload(payload, MAX_FRAME_LOG_BYTES.load(Ordering::Relaxed))
+}
+
+/// Escape `payload` for a log line, keeping at most `max` bytes and noting how
+/// many were left out as `…(N more bytes)`
+pub fn truncate_payload(payload: &[u8], max: usize) -> String {
+    let shown = &payload[..payload.len().min(max)];
+    let mut preview = String::from_utf8_lossy(shown).escape_debug().to_string();
+    if payload.len() > shown.len() {
+        preview.push_str(&format!("\u{2026}({} more bytes)", payload.len() - shown.len()));
+    }
+    preview
 }
 
 #[cfg(test)]
@@ -304,6 +875,23 @@ mod tests {
         assert_eq!(&encoded[1..], b"world");
     }
 
+    #[test]
+    fn test_sequenced_output_round_trip() {
+        let msg = ClientMessage::SequencedOutput { seq: 0x01020304, data: b"world".to_vec() };
+        let encoded = msg.encode().unwrap();
+        assert_eq!(&encoded[..5], b"4\x01\x02\x03\x04");
+
+        let (seq, data) = parse_sequenced_output(&encoded).unwrap();
+        assert_eq!(seq, 0x01020304);
+        assert_eq!(data, b"world");
+
+        // Empty payloads are valid; truncated headers and legacy frames are not
+        let encoded = ClientMessage::SequencedOutput { seq: u32::MAX, data: Vec::new() }.encode().unwrap();
+        assert_eq!(parse_sequenced_output(&encoded).unwrap(), (u32::MAX, &b""[..]));
+        assert!(parse_sequenced_output(b"4\x00\x01").is_err());
+        assert!(parse_sequenced_output(b"0world").is_err());
+    }
+
     #[test]
     fn test_encode_handshake() {
         let msg = ClientMessage::Handshake(HandshakeMessage {
@@ -311,11 +899,26 @@ mod tests {
             shell: "/bin/bash".to_string(),
             cols: Some(80),
             rows: Some(24),
+            tty: None,
+            connection_id: String::new(),
+            capabilities: Vec::new(),
         });
         let encoded = msg.encode().unwrap();
         assert_eq!(encoded[0], b'1');
         let json: serde_json::Value = serde_json::from_slice(&encoded[1..]).unwrap();
         assert_eq!(json["version"], "0.1.0");
+        assert!(json.get("tty").is_none());
+    }
+
+    #[test]
+    fn test_new_connection_id_is_uuid_v4() {
+        let id = new_connection_id();
+        let groups: Vec<&str> = id.split('-').collect();
+        assert_eq!(groups.iter().map(|g| g.len()).collect::<Vec<_>>(), [8, 4, 4, 4, 12], "{}", id);
+        assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()), "{}", id);
+        assert!(groups[2].starts_with('4'), "{}", id);
+        assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'), "{}", id);
+        assert_ne!(id, new_connection_id());
     }
 
     #[test]
@@ -323,16 +926,44 @@ mod tests {
         let json = r#"{"type":"start_terminal","name":"main","cols":80,"rows":24,"requestId":"abc123"}"#;
         let msg = ControlMessage::parse_str(json).unwrap();
         match msg {
-            ControlMessage::StartTerminal { name, cols, rows, request_id } => {
+            ControlMessage::StartTerminal { name, cols, rows, request_id, env } => {
                 assert_eq!(name, "main");
                 assert_eq!(cols, 80);
                 assert_eq!(rows, 24);
                 assert_eq!(request_id, "abc123");
+                assert!(env.is_none());
             }
             _ => panic!("expected StartTerminal"),
         }
     }
 
+    #[test]
+    fn test_parse_control_start_terminal_with_env() {
+        let json = r#"{"type":"start_terminal","name":"main","cols":80,"rows":24,"requestId":"r","env":{"PROJECT":"web"}}"#;
+        match ControlMessage::parse_str(json).unwrap() {
+            ControlMessage::StartTerminal { env, .. } => {
+                let env = env.expect("env");
+                assert_eq!(env.len(), 1);
+                assert_eq!(env["PROJECT"], "web");
+            }
+            _ => panic!("expected StartTerminal"),
+        }
+    }
+
+    #[test]
+    fn test_parse_control_signal_terminal() {
+        let json = r#"{"type":"signal_terminal","name":"main","signal":19}"#;
+        match ControlMessage::parse_str(json).unwrap() {
+            ControlMessage::SignalTerminal { name, signal } => {
+                assert_eq!(name, "main");
+                assert_eq!(signal, 19);
+            }
+            _ => panic!("expected SignalTerminal"),
+        }
+        // Unlike close_terminal, the signal is required
+        assert!(ControlMessage::parse_str(r#"{"type":"signal_terminal","name":"main"}"#).is_err());
+    }
+
     #[test]
     fn test_parse_control_close_terminal() {
         let json = r#"{"type":"close_terminal","name":"main","signal":15}"#;
@@ -346,6 +977,52 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_set_log_level() {
+        match ControlMessage::parse_str(r#"{"type":"set_log_level","level":"debug"}"#).unwrap() {
+            ControlMessage::SetLogLevel { level } => assert_eq!(level, "debug"),
+            _ => panic!("expected SetLogLevel"),
+        }
+        assert!(ControlMessage::parse_str(r#"{"type":"set_log_level"}"#).is_err());
+    }
+
+    #[test]
+    fn test_parse_unknown_control_type() {
+        let json = r#"{"type":"migrate_session","target":"relay-2","deadlineMs":5000}"#;
+        assert!(matches!(ControlMessage::parse_str(json).unwrap(), ControlMessage::Unknown));
+        assert!(matches!(ControlMessage::parse(json.as_bytes()).unwrap(), ControlMessage::Unknown));
+        assert_eq!(ControlMessage::message_type(json.as_bytes()).as_deref(), Some("migrate_session"));
+
+        // A known type with bad fields, or no type at all, is still an error
+        assert!(ControlMessage::parse_str(r#"{"type":"close_terminal"}"#).is_err());
+        assert!(ControlMessage::parse_str(r#"{"name":"42"}"#).is_err());
+        assert_eq!(ControlMessage::message_type(br#"{"name":"42"}"#), None);
+    }
+
+    #[test]
+    fn test_process_tree_messages() {
+        let json = r#"{"type":"request_process_tree","name":"42","requestId":"p1"}"#;
+        match ControlMessage::parse_str(json).unwrap() {
+            ControlMessage::RequestProcessTree { name, request_id } => {
+                assert_eq!(name, "42");
+                assert_eq!(request_id, "p1");
+            }
+            _ => panic!("expected RequestProcessTree"),
+        }
+
+        let msg = ControlResponse::ProcessTree {
+            request_id: "p1".to_string(),
+            processes: vec![ProcessInfo { pid: 43, ppid: 42, command: "sleep 30".to_string() }],
+            error: None,
+        };
+        let json: serde_json::Value = serde_json::from_str(&msg.encode().unwrap()).unwrap();
+        assert_eq!(json["type"], "process_tree");
+        assert_eq!(json["requestId"], "p1");
+        assert_eq!(json["processes"][0]["ppid"], 42);
+        assert_eq!(json["processes"][0]["command"], "sleep 30");
+        assert!(json.get("error").is_none());
+    }
+
     #[test]
     fn test_encode_control_handshake() {
         let msg = ControlResponse::ControlHandshake {
@@ -353,6 +1030,8 @@ mod tests {
             hostname: "myhost".to_string(),
             username: "testuser".to_string(),
             working_dir: "/home/testuser".to_string(),
+            connection_id: String::new(),
+            capabilities: Vec::new(),
         };
         let encoded = msg.encode().unwrap();
         let json: serde_json::Value = serde_json::from_str(&encoded).unwrap();
@@ -361,6 +1040,7 @@ mod tests {
         assert_eq!(json["hostname"], "myhost");
         assert_eq!(json["username"], "testuser");
         assert_eq!(json["workingDir"], "/home/testuser");
+        assert_eq!(json["capabilities"], serde_json::json!([]));
     }
 
     #[test]
@@ -405,6 +1085,218 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_relay_handshake_role() {
+        for (data, expected) in [
+            (&b"5{\"role\":\"observer\"}"[..], ConnectionRole::Observer),
+            (&b"5{\"role\":\"controller\"}"[..], ConnectionRole::Controller),
+            (&b"5{}"[..], ConnectionRole::Controller),
+        ] {
+            match RelayMessage::parse(data).unwrap() {
+                RelayMessage::Handshake(handshake) => assert_eq!(handshake.role, expected),
+                _ => panic!("expected Handshake"),
+            }
+        }
+        assert!(RelayMessage::parse(b"5{\"role\":\"admin\"}").is_err());
+
+        match RelayMessage::parse(b"5{\"replay\":true}").unwrap() {
+            RelayMessage::Handshake(handshake) => {
+                assert!(handshake.replay);
+                assert!(!handshake.sequence);
+            }
+            _ => panic!("expected Handshake"),
+        }
+
+        match RelayMessage::parse(b"5{\"sequence\":true}").unwrap() {
+            RelayMessage::Handshake(handshake) => assert!(handshake.sequence),
+            _ => panic!("expected Handshake"),
+        }
+    }
+
+    #[test]
+    fn test_parse_ack() {
+        let ack = AckMessage::parse(br#"{"type":"handshake_ack"}"#).unwrap();
+        assert!(!ack.is_error());
+
+        let ack = AckMessage::parse(br#"{"type":"error","error":"unknown session","code":"UNKNOWN_SESSION"}"#)
+            .unwrap();
+        assert!(ack.is_error());
+        assert_eq!(ack.error_message(), "unknown session (UNKNOWN_SESSION)");
+
+        // Regular messages are not acks
+        assert!(AckMessage::parse(br#"{"type":"start_terminal","name":"x"}"#).is_none());
+        assert!(AckMessage::parse(b"0hello").is_none());
+    }
+
+    #[test]
+    fn test_parse_ack_capabilities() {
+        let ack = AckMessage::parse(br#"{"type":"handshake_ack","capabilities":["sequence","compression"]}"#).unwrap();
+        assert_eq!(ack.capabilities, ["sequence", "compression"]);
+        assert!(AckMessage::parse(br#"{"type":"handshake_ack"}"#).unwrap().capabilities.is_empty());
+    }
+
+    #[test]
+    fn test_close_reasons_are_distinct() {
+        let reasons = [
+            CloseReason::Shutdown,
+            CloseReason::OnceExited,
+            CloseReason::TerminalExited,
+            CloseReason::TerminalClosed,
+        ];
+        let texts: std::collections::HashSet<_> = reasons.iter().map(CloseReason::as_str).collect();
+        assert_eq!(texts.len(), reasons.len());
+        // Relays match on this exact text for a graceful shutdown
+        assert_eq!(CloseReason::default().as_str(), "client shutdown");
+    }
+
+    #[test]
+    fn test_negotiate_capabilities() {
+        let names = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        // Client supports A,B; relay supports B,C: only B is enabled
+        let caps = Capabilities::negotiate(&names(&["a", "b"]), &names(&["b", "c"]));
+        assert_eq!(caps.negotiated, ["b"]);
+        assert!(caps.supports("b"));
+        assert!(!caps.supports("a") && !caps.supports("c"));
+
+        let caps = Capabilities::negotiate(&capability::supported(), &names(&["sequence", "compression"]));
+        assert!(caps.sequence);
+        assert!(!caps.replay);
+        assert_eq!(caps.negotiated, ["sequence"]);
+
+        // A relay that advertises nothing gets nothing
+        assert_eq!(Capabilities::negotiate(&capability::supported(), &[]), Capabilities::default());
+    }
+
+    #[test]
+    fn test_trace_summaries() {
+        let relay = [
+            (RelayMessage::Input(b"ls\n".to_vec()), "<- data '0' input 4 bytes"),
+            (RelayMessage::Resize(ResizeMessage { cols: 80, rows: 24 }), "<- data '1' resize 4 bytes"),
+            (RelayMessage::Pause, "<- data '2' pause 4 bytes"),
+            (RelayMessage::Resume, "<- data '3' resume 4 bytes"),
+            (
+                RelayMessage::RequestSnapshot(SnapshotRequest { request_id: "r".to_string() }),
+                "<- data '4' request_snapshot 4 bytes",
+            ),
+            (
+                RelayMessage::Handshake(RelayHandshake { role: ConnectionRole::Observer, ..Default::default() }),
+                "<- data '5' handshake 4 bytes",
+            ),
+        ];
+        for (msg, expected) in relay {
+            assert_eq!(msg.trace_summary(4), expected);
+        }
+
+        let client = [
+            (ClientMessage::Output(b"hi".to_vec()), "-> data '0' output 3 bytes"),
+            (
+                ClientMessage::Handshake(HandshakeMessage {
+                    version: "0.1.0".to_string(),
+                    shell: "/bin/sh".to_string(),
+                    cols: None,
+                    rows: None,
+                    tty: None,
+                    connection_id: String::new(),
+                    capabilities: Vec::new(),
+                }),
+                "-> data '1' handshake 3 bytes",
+            ),
+            (ClientMessage::Exit(0), "-> data '2' exit 3 bytes"),
+            (
+                ClientMessage::Snapshot(SnapshotMessage {
+                    request_id: "r".to_string(),
+                    screen: Vec::new(),
+                    cols: 80,
+                    rows: 24,
+                    cursor_x: 0,
+                    cursor_y: 0,
+                    cursor_shape: "block".to_string(),
+                    truncated: false,
+                }),
+                "-> data '3' snapshot 3 bytes",
+            ),
+        ];
+        for (msg, expected) in client {
+            assert_eq!(msg.trace_summary(3), expected);
+        }
+
+        let start = ControlMessage::StartTerminal {
+            name: "main".to_string(),
+            cols: 80,
+            rows: 24,
+            request_id: "r".to_string(),
+            env: None,
+        };
+        assert_eq!(start.trace_summary(87), "<- control start_terminal 87 bytes");
+        let close = ControlMessage::CloseTerminal { name: "main".to_string(), signal: None };
+        assert_eq!(close.trace_summary(40), "<- control close_terminal 40 bytes");
+
+        let handshake = ControlResponse::ControlHandshake {
+            version: "1.0".to_string(),
+            hostname: "h".to_string(),
+            username: "u".to_string(),
+            working_dir: "/".to_string(),
+            connection_id: String::new(),
+            capabilities: Vec::new(),
+        };
+        assert_eq!(handshake.trace_summary(90), "-> control control_handshake 90 bytes");
+        let started = ControlResponse::TerminalStarted {
+            name: "1".to_string(),
+            request_id: "r".to_string(),
+            success: true,
+            error: None,
+        };
+        assert_eq!(started.trace_summary(60), "-> control terminal_started 60 bytes");
+        let closed = ControlResponse::TerminalClosed { name: "1".to_string(), exit_code: 0 };
+        assert_eq!(closed.trace_summary(50), "-> control terminal_closed 50 bytes");
+    }
+
+    #[test]
+    fn test_encode_output_idle() {
+        let msg = ClientMessage::OutputIdle;
+        assert_eq!(msg.encode().unwrap(), b"5");
+        assert_eq!(msg.trace_summary(1), "-> data '5' output_idle 1 bytes");
+    }
+
+    #[test]
+    fn test_truncate_payload() {
+        let payload = vec![b'a'; 300];
+        let preview = truncate_payload(&payload, 256);
+        assert_eq!(preview, format!("{}\u{2026}(44 more bytes)", "a".repeat(256)));
+
+        // Short payloads are shown whole, with control bytes escaped
+        assert_eq!(truncate_payload(b"ls\r", 256), "ls\\r");
+        assert_eq!(truncate_payload(b"abc", 3), "abc");
+        assert_eq!(truncate_payload(b"abc", 0), "\u{2026}(3 more bytes)");
+    }
+
+    #[test]
+    fn test_trace_line_hides_input() {
+        let frame = b"0hunter2\r";
+        let line = RelayMessage::parse(frame).unwrap().trace_line(frame);
+        assert_eq!(line, "<- data '0' input 9 bytes");
+
+        let frame = br#"1{"cols":80,"rows":24}"#;
+        let line = RelayMessage::parse(frame).unwrap().trace_line(frame);
+        assert!(line.ends_with(r#"1{\"cols\":80,\"rows\":24}"#), "{}", line);
+    }
+
+    #[test]
+    fn test_encode_input_flow() {
+        assert_eq!(ClientMessage::InputPause.encode().unwrap(), b"9");
+        assert_eq!(ClientMessage::InputResume.encode().unwrap(), b":");
+        assert_eq!(ClientMessage::InputPause.trace_summary(1), "-> data '9' input_pause 1 bytes");
+    }
+
+    #[test]
+    fn test_encode_size_changed() {
+        let encoded = ClientMessage::SizeChanged { cols: 100, rows: 30 }.encode().unwrap();
+        assert_eq!(encoded[0], b';');
+        let json: serde_json::Value = serde_json::from_slice(&encoded[1..]).unwrap();
+        assert_eq!(json, serde_json::json!({"cols": 100, "rows": 30}));
+    }
+
     #[test]
     fn test_encode_snapshot() {
         let msg = ClientMessage::Snapshot(SnapshotMessage {
@@ -414,6 +1306,8 @@ mod tests {
             rows: 24,
             cursor_x: 5,
             cursor_y: 0,
+            cursor_shape: "bar".to_string(),
+            truncated: false,
         });
         let encoded = msg.encode().unwrap();
         assert_eq!(encoded[0], b'3');
@@ -423,7 +1317,94 @@ mod tests {
         assert_eq!(json["rows"], 24);
         assert_eq!(json["cursorX"], 5);
         assert_eq!(json["cursorY"], 0);
+        assert_eq!(json["cursorShape"], "bar");
         // Screen is base64 encoded
         assert!(json["screen"].is_string());
     }
+
+    fn sample_snapshot(screen: Vec<u8>) -> SnapshotMessage {
+        SnapshotMessage {
+            request_id: "abc123".to_string(),
+            screen,
+            cols: 80,
+            rows: 24,
+            cursor_x: 5,
+            cursor_y: 1,
+            cursor_shape: "underline".to_string(),
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn test_raw_snapshot_round_trip() {
+        // Arbitrary bytes, not just valid UTF-8
+        let screen: Vec<u8> = (0..=255).collect();
+        let encoded = ClientMessage::RawSnapshot(sample_snapshot(screen.clone())).encode().unwrap();
+        assert_eq!(encoded[0], b'6');
+        assert!(encoded.ends_with(&screen));
+
+        let parsed = parse_raw_snapshot(&encoded).unwrap();
+        assert_eq!(parsed.request_id, "abc123");
+        assert_eq!(parsed.screen, screen);
+        assert_eq!((parsed.cols, parsed.rows), (80, 24));
+        assert_eq!((parsed.cursor_x, parsed.cursor_y), (5, 1));
+        assert_eq!(parsed.cursor_shape, "underline");
+
+        // No base64 overhead on the screen
+        let json = ClientMessage::Snapshot(sample_snapshot(screen)).encode().unwrap();
+        assert!(encoded.len() < json.len());
+    }
+
+    #[test]
+    fn test_parse_raw_snapshot_rejects_bad_frames() {
+        let encoded = ClientMessage::RawSnapshot(sample_snapshot(b"screen".to_vec())).encode().unwrap();
+        assert!(parse_raw_snapshot(&encoded[..3]).is_err());
+        assert!(parse_raw_snapshot(&encoded[..10]).is_err());
+        assert!(parse_raw_snapshot(b"3{}").is_err());
+
+        // The screen may be empty, the metadata may not
+        let empty = ClientMessage::RawSnapshot(sample_snapshot(Vec::new())).encode().unwrap();
+        assert!(parse_raw_snapshot(&empty).unwrap().screen.is_empty());
+        assert!(parse_raw_snapshot(b"6\x00\x00\x00\x00").is_err());
+    }
+
+    #[test]
+    fn test_negotiate_raw_snapshot() {
+        let theirs = vec!["raw_snapshot".to_string()];
+        let caps = Capabilities::negotiate(&capability::supported(), &theirs);
+        assert!(caps.raw_snapshot);
+        assert!(!Capabilities::negotiate(&capability::supported(), &[]).raw_snapshot);
+    }
+
+    #[test]
+    fn test_parse_control_request_scrollback() {
+        let json = r#"{"type":"request_scrollback","name":"main","requestId":"r1"}"#;
+        match ControlMessage::parse_str(json).unwrap() {
+            ControlMessage::RequestScrollback { name, request_id } => {
+                assert_eq!(name, "main");
+                assert_eq!(request_id, "r1");
+            }
+            _ => panic!("expected RequestScrollback"),
+        }
+    }
+
+    #[test]
+    fn test_scrollback_frames_round_trip() {
+        let meta = ScrollbackChunkMeta { request_id: "r1".to_string(), index: 3 };
+        let msg = ClientMessage::ScrollbackChunk { meta: meta.clone(), data: b"line 1\nline 2".to_vec() };
+        let encoded = msg.encode().unwrap();
+        assert_eq!(encoded[0], b'7');
+        assert_eq!(msg.trace_summary(encoded.len()), format!("-> data '7' scrollback_chunk {} bytes", encoded.len()));
+        let (parsed, data) = parse_scrollback_chunk(&encoded).unwrap();
+        assert_eq!(parsed, meta);
+        assert_eq!(data, b"line 1\nline 2");
+        assert!(parse_scrollback_chunk(&encoded[..4]).is_err());
+        assert!(parse_scrollback_chunk(b"8{}").is_err());
+
+        let end = ClientMessage::ScrollbackEnd(ScrollbackEnd { request_id: "r1".to_string(), chunks: 4 });
+        let encoded = end.encode().unwrap();
+        assert_eq!(encoded[0], b'8');
+        let json: serde_json::Value = serde_json::from_slice(&encoded[1..]).unwrap();
+        assert_eq!(json, serde_json::json!({"requestId": "r1", "chunks": 4}));
+    }
 }
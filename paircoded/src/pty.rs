@@ -2,42 +2,134 @@
 //!
 //! Uses portable-pty for cross-platform support (Unix PTY and Windows ConPTY).
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use portable_pty::{native_pty_system, CommandBuilder, MasterPty, PtySize};
+use std::collections::HashMap;
 use std::io::{Read, Write};
-use std::path::Path;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 use tokio::sync::{mpsc, Mutex};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
+use crate::cgroup::{self, ResourceLimits};
 use crate::sandbox;
 
 /// Default terminal size
 pub const DEFAULT_COLS: u16 = 80;
 pub const DEFAULT_ROWS: u16 = 24;
 
+/// Largest single write to the PTY; bigger inputs are split so no one write
+/// blocks for long when the program reads slowly
+pub const INPUT_CHUNK_SIZE: usize = 4096;
+
+/// Chunks queued for the PTY writer thread before senders have to wait
+const INPUT_QUEUE_CHUNKS: usize = 16;
+
+/// Default bound on how long [`AsyncPty::write`] waits for room in the writer queue
+pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// [`AsyncPty::write`] gave up because the program stopped reading its input
+///
+/// Recoverable: the PTY is still usable, and the rest of the data can be
+/// retried once the program catches up.
+#[derive(Debug)]
+pub struct WriteTimeout {
+    /// Bytes queued before the timeout
+    pub written: usize,
+}
+
+impl std::fmt::Display for WriteTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "PTY write timed out after {} bytes (program is not reading input)", self.written)
+    }
+}
+
+impl std::error::Error for WriteTimeout {}
+
+/// How long the reader waits for output before checking whether it should stop
+const READER_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Times the reader is reopened after a read error before output is treated as ended
+pub const DEFAULT_MAX_READER_RESTARTS: u32 = 3;
+
+/// What [`PtyHandle::spawn`] runs, and how
+#[derive(Debug, Clone)]
+pub struct SpawnOptions {
+    pub shell: String,
+    pub args: Vec<String>,
+    pub working_dir: PathBuf,
+    /// Wrap the shell with bubblewrap (Linux), restricting filesystem access
+    /// to the working directory
+    pub sandboxed: bool,
+    /// Run it (sandbox included) in a cgroup scope with these limits
+    pub limits: ResourceLimits,
+    /// Size the PTY is opened at
+    pub cols: u16,
+    pub rows: u16,
+    /// Variables set on top of the inherited environment
+    pub env: HashMap<String, String>,
+}
+
+#[cfg(test)]
+impl SpawnOptions {
+    /// `/bin/sh -c command` in the temp directory
+    pub(crate) fn sh(command: &str) -> Self {
+        SpawnOptions {
+            args: vec!["-c".to_string(), command.to_string()],
+            working_dir: std::env::temp_dir(),
+            ..SpawnOptions::default()
+        }
+    }
+}
+
+impl Default for SpawnOptions {
+    fn default() -> Self {
+        SpawnOptions {
+            shell: "/bin/sh".to_string(),
+            args: Vec::new(),
+            working_dir: PathBuf::from("."),
+            sandboxed: false,
+            limits: ResourceLimits::default(),
+            cols: DEFAULT_COLS,
+            rows: DEFAULT_ROWS,
+            env: HashMap::new(),
+        }
+    }
+}
+
 /// Handle to a spawned PTY process
 pub struct PtyHandle {
     /// The master side of the PTY for I/O
     master: Box<dyn MasterPty + Send>,
-    /// Writer for sending input to PTY (taken once from master)
-    writer: Box<dyn Write + Send>,
+    /// Writer for sending input to PTY (taken once from master, then moved to
+    /// the writer thread by `AsyncPty`)
+    writer: Option<Box<dyn Write + Send>>,
     /// Child process
     child: Box<dyn portable_pty::Child + Send + Sync>,
+    /// Slave device path (e.g. `/dev/pts/3`), where the platform exposes it
+    tty_name: Option<String>,
 }
 
 impl PtyHandle {
     /// Spawn a new PTY with the given shell command and working directory
     ///
-    /// If `sandboxed` is true on Linux, the shell will be wrapped with bubblewrap
-    /// to restrict filesystem access to the working directory only.
-    pub fn spawn(shell: &str, args: &[&str], working_dir: &Path, sandboxed: bool) -> Result<Self> {
+    /// The PTY is opened at the final size, so the program sees it from the
+    /// start rather than a default size followed by a resize.
+    pub fn spawn(options: &SpawnOptions) -> Result<Self> {
+        let SpawnOptions { ref shell, ref args, ref working_dir, sandboxed, ref limits, cols, rows, ref env } = *options;
+        let working_dir = working_dir.as_path();
+        for (key, value) in env {
+            validate_env_var(key, value)?;
+        }
+
         let pty_system = native_pty_system();
 
         let pair = pty_system
             .openpty(PtySize {
-                rows: DEFAULT_ROWS,
-                cols: DEFAULT_COLS,
+                rows,
+                cols,
                 pixel_width: 0,
                 pixel_height: 0,
             })
@@ -45,10 +137,12 @@ impl PtyHandle {
 
         // Determine the actual command to run (with or without sandbox)
         let (actual_cmd, actual_args): (String, Vec<String>) = if sandboxed {
-            sandbox::build_sandbox_args(shell, args, working_dir)?
+            let args: Vec<&str> = args.iter().map(String::as_str).collect();
+            sandbox::build_sandbox_args(shell, &args, working_dir)?
         } else {
-            (shell.to_string(), args.iter().map(|s| s.to_string()).collect())
+            (shell.clone(), args.clone())
         };
+        let (actual_cmd, actual_args) = cgroup::build_scope_args(limits, actual_cmd, actual_args);
 
         let mut cmd = CommandBuilder::new(&actual_cmd);
         for arg in &actual_args {
@@ -77,10 +171,18 @@ impl PtyHandle {
             cmd.env("TERM", "xterm-256color");
         }
 
+        // Per-terminal variables from the relay win over the defaults
+        for (key, value) in env {
+            cmd.env(key, value);
+        }
+
         let child = pair
             .slave
             .spawn_command(cmd)
-            .context("failed to spawn command")?;
+            .map_err(|e| {
+                let message = spawn_error_message(&actual_cmd, working_dir, sandboxed, &e);
+                e.context(message)
+            })?;
 
         // Take the writer once and store it
         let writer = pair
@@ -94,10 +196,13 @@ impl PtyHandle {
             "spawned PTY process"
         );
 
+        let tty_name = slave_tty_name(pair.master.as_ref());
+
         Ok(PtyHandle {
             master: pair.master,
-            writer,
+            writer: Some(writer),
             child,
+            tty_name,
         })
     }
 
@@ -115,11 +220,15 @@ impl PtyHandle {
         Ok(())
     }
 
-    /// Write data to the PTY (input from remote)
-    pub fn write(&mut self, data: &[u8]) -> Result<()> {
-        self.writer.write_all(data)?;
-        self.writer.flush()?;
-        Ok(())
+    /// Get the current PTY size as (cols, rows)
+    pub fn size(&self) -> Result<(u16, u16)> {
+        let size = self.master.get_size().context("failed to get PTY size")?;
+        Ok((size.cols, size.rows))
+    }
+
+    /// Take the PTY writer so input can be written from another thread
+    pub fn take_writer(&mut self) -> Option<Box<dyn Write + Send>> {
+        self.writer.take()
     }
 
     /// Try to get the reader for PTY output
@@ -136,6 +245,21 @@ impl PtyHandle {
             .context("failed to check child status")
     }
 
+    /// Whether the child wrote output that nothing has read yet
+    #[cfg(unix)]
+    pub fn has_unread_output(&self) -> bool {
+        let Some(fd) = self.master.as_raw_fd() else { return false };
+        let mut unread: libc::c_int = 0;
+        // SAFETY: `fd` is the open PTY master and FIONREAD writes one c_int
+        unsafe { libc::ioctl(fd, libc::FIONREAD, &mut unread) == 0 && unread > 0 }
+    }
+
+    /// Unread output can't be checked; assume there is none
+    #[cfg(not(unix))]
+    pub fn has_unread_output(&self) -> bool {
+        false
+    }
+
     /// Wait for the child process to exit
     #[allow(dead_code)]
     pub fn wait(&mut self) -> Result<portable_pty::ExitStatus> {
@@ -148,43 +272,225 @@ impl PtyHandle {
         self.child.kill().context("failed to kill child")
     }
 
+    /// Send a Unix signal (e.g. `libc::SIGTERM`) to the child
+    #[cfg(unix)]
+    pub fn signal(&mut self, signal: i32) -> Result<()> {
+        let pid = self.process_id().context("child has no process ID")?;
+        // SAFETY: kill has no memory-safety preconditions
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("failed to send signal {} to child", signal));
+        }
+        Ok(())
+    }
+
+    /// Signals aren't available; terminate the child instead
+    #[cfg(not(unix))]
+    pub fn signal(&mut self, _signal: i32) -> Result<()> {
+        self.kill()
+    }
+
     /// Get the process ID of the child
     pub fn process_id(&self) -> Option<u32> {
         self.child.process_id()
     }
+
+    /// Slave device path of the PTY, if known
+    pub fn tty_name(&self) -> Option<&str> {
+        self.tty_name.as_deref()
+    }
+}
+
+/// Look up the slave device path for a PTY master
+#[cfg(target_os = "linux")]
+fn slave_tty_name(master: &dyn MasterPty) -> Option<String> {
+    let fd = master.as_raw_fd()?;
+    let mut buf = [0 as libc::c_char; 128];
+    // SAFETY: `fd` is the open PTY master and `buf` is valid for `buf.len()` bytes;
+    // ptsname_r NUL-terminates the name on success
+    let name = unsafe {
+        if libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) != 0 {
+            return None;
+        }
+        std::ffi::CStr::from_ptr(buf.as_ptr())
+    };
+    name.to_str().ok().map(str::to_string)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn slave_tty_name(_master: &dyn MasterPty) -> Option<String> {
+    None
+}
+
+/// Waits for the PTY master to become readable, so the reader thread can notice
+/// a stop request instead of blocking in `read` forever
+#[cfg(unix)]
+struct ReadWaiter {
+    /// Our own duplicate of the master fd, valid however long the handle lives
+    fd: Option<std::os::fd::OwnedFd>,
+}
+
+#[cfg(unix)]
+impl ReadWaiter {
+    fn new(master: &dyn MasterPty) -> Self {
+        use std::os::fd::FromRawFd;
+
+        let fd = master.as_raw_fd().and_then(|fd| {
+            // SAFETY: `fd` is the open PTY master; dup has no other preconditions
+            let dup = unsafe { libc::dup(fd) };
+            // SAFETY: a non-negative result is a new fd that nothing else owns
+            (dup >= 0).then(|| unsafe { std::os::fd::OwnedFd::from_raw_fd(dup) })
+        });
+        ReadWaiter { fd }
+    }
+
+    /// Wait up to `timeout`; true when a read won't block (data, EOF or error)
+    fn wait(&self, timeout: Duration) -> std::io::Result<bool> {
+        use std::os::fd::AsRawFd;
+
+        let Some(ref fd) = self.fd else {
+            return Ok(true);
+        };
+        let mut pollfd = libc::pollfd { fd: fd.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+        // SAFETY: `pollfd` is a valid array of one entry for the duration of the call
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) };
+        if ready < 0 {
+            let err = std::io::Error::last_os_error();
+            return if err.kind() == std::io::ErrorKind::Interrupted { Ok(false) } else { Err(err) };
+        }
+        Ok(ready > 0)
+    }
+}
+
+/// Without poll the read blocks until output or EOF
+#[cfg(not(unix))]
+struct ReadWaiter;
+
+#[cfg(not(unix))]
+impl ReadWaiter {
+    fn new(_master: &dyn MasterPty) -> Self {
+        ReadWaiter
+    }
+
+    fn wait(&self, _timeout: Duration) -> std::io::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// PTY output stream plus its readiness waiter, handed to the reader thread
+type PtyReader = (Box<dyn Read + Send>, ReadWaiter);
+
+/// Sends signals to a PTY's child without keeping the PTY alive
+#[derive(Clone)]
+pub struct SignalHandle(Weak<Mutex<PtyHandle>>);
+
+impl SignalHandle {
+    /// Send a signal to the child, failing if the PTY is gone or the child has exited
+    pub async fn signal(&self, signal: i32) -> Result<()> {
+        let handle = self.0.upgrade().context("terminal is no longer running")?;
+        let mut handle = handle.lock().await;
+        // A reaped child's PID may already belong to another process
+        if handle.try_wait()?.is_some() {
+            bail!("terminal process has already exited");
+        }
+        handle.signal(signal)
+    }
 }
 
 /// Async wrapper around PTY operations
 pub struct AsyncPty {
     handle: Arc<Mutex<PtyHandle>>,
     /// Pre-cloned reader, wrapped in Option so we can take it once
-    reader: Arc<Mutex<Option<Box<dyn Read + Send>>>>,
+    reader: Arc<Mutex<Option<PtyReader>>>,
+    /// Queue of input chunks for the writer thread
+    input_tx: mpsc::Sender<Vec<u8>>,
+    /// Tells the reader thread to exit; set by `stop_reader` and on drop
+    reader_stop: Arc<AtomicBool>,
+    /// Read errors the reader may recover from by reopening the PTY master
+    max_reader_restarts: u32,
+    /// Times the reader has been reopened so far
+    reader_restarts: Arc<AtomicU32>,
+    /// Longest `write` waits for room in the writer queue
+    write_timeout: Duration,
 }
 
 impl AsyncPty {
     /// Create a new async PTY wrapper
     ///
-    /// This clones the PTY reader immediately to avoid blocking in async context later.
-    pub fn new(handle: PtyHandle) -> Result<Self> {
+    /// This clones the PTY reader immediately to avoid blocking in async context later,
+    /// and moves the writer to a dedicated thread so a program that is slow to read
+    /// its input never blocks the caller (or the lock that resize and exit checks use).
+    pub fn new(mut handle: PtyHandle) -> Result<Self> {
         // Clone the reader now, before entering async context
         let reader = handle.try_clone_reader()?;
+        let waiter = ReadWaiter::new(handle.master.as_ref());
+        let writer = handle.take_writer().context("PTY writer already taken")?;
+        let input_tx = spawn_writer(writer)?;
 
         Ok(AsyncPty {
             handle: Arc::new(Mutex::new(handle)),
-            reader: Arc::new(Mutex::new(Some(reader))),
+            reader: Arc::new(Mutex::new(Some((reader, waiter)))),
+            input_tx,
+            reader_stop: Arc::new(AtomicBool::new(false)),
+            max_reader_restarts: DEFAULT_MAX_READER_RESTARTS,
+            reader_restarts: Arc::default(),
+            write_timeout: DEFAULT_WRITE_TIMEOUT,
         })
     }
 
+    /// Set how long `write` waits for the program to make room for input
+    pub fn set_write_timeout(&mut self, timeout: Duration) {
+        self.write_timeout = timeout;
+    }
+
+    /// Set how many read errors the reader recovers from (before `start_reader`)
+    pub fn set_max_reader_restarts(&mut self, max: u32) {
+        self.max_reader_restarts = max;
+    }
+
+    /// Times the reader was reopened after a read error
+    pub fn reader_restarts(&self) -> u32 {
+        self.reader_restarts.load(Ordering::Relaxed)
+    }
+
+    /// Ask the reader thread to exit within `READER_POLL_INTERVAL`, even if the
+    /// PTY stays open and silent (e.g. a background job still holds the slave)
+    pub fn stop_reader(&self) {
+        self.reader_stop.store(true, Ordering::Relaxed);
+    }
+
     /// Resize the PTY
     pub async fn resize(&self, cols: u16, rows: u16) -> Result<()> {
         let handle = self.handle.lock().await;
         handle.resize(cols, rows)
     }
 
-    /// Write data to the PTY
+    /// Get the current PTY size as (cols, rows)
+    pub async fn size(&self) -> Result<(u16, u16)> {
+        let handle = self.handle.lock().await;
+        handle.size()
+    }
+
+    /// Queue data for the PTY, waiting while the writer's queue is full
+    ///
+    /// Fails with [`WriteTimeout`] if the queue stays full for the write timeout,
+    /// so a program that never reads its input can't stall the caller.
     pub async fn write(&self, data: &[u8]) -> Result<()> {
-        let mut handle = self.handle.lock().await;
-        handle.write(data)
+        let deadline = tokio::time::Instant::now() + self.write_timeout;
+        let mut written = 0;
+        for chunk in data.chunks(INPUT_CHUNK_SIZE) {
+            match tokio::time::timeout_at(deadline, self.input_tx.send(chunk.to_vec())).await {
+                Ok(Ok(())) => written += chunk.len(),
+                Ok(Err(_)) => bail!("PTY writer closed"),
+                Err(_) => return Err(WriteTimeout { written }.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Sender for the writer thread's queue (chunks of at most `INPUT_CHUNK_SIZE`)
+    pub fn input_sender(&self) -> mpsc::Sender<Vec<u8>> {
+        self.input_tx.clone()
     }
 
     /// Check if the child has exited
@@ -194,31 +500,60 @@ impl AsyncPty {
     }
 
     /// Kill the child process
-    #[allow(dead_code)]
     pub async fn kill(&self) -> Result<()> {
         let mut handle = self.handle.lock().await;
         handle.kill()
     }
 
+    /// Send a signal to the child process
+    pub async fn signal(&self, signal: i32) -> Result<()> {
+        let mut handle = self.handle.lock().await;
+        handle.signal(signal)
+    }
+
+    /// Handle for signalling the child from elsewhere while this wrapper is in use
+    pub fn signal_handle(&self) -> SignalHandle {
+        SignalHandle(Arc::downgrade(&self.handle))
+    }
+
     /// Start reading from PTY and send output to a channel
     /// Returns a receiver for PTY output data
     ///
+    /// A read error reopens the reader from the PTY master and carries on, up to
+    /// `max_reader_restarts` times; after that the channel closes as on EOF.
+    ///
     /// This can only be called once per AsyncPty instance.
     pub async fn start_reader(&self) -> Result<mpsc::Receiver<Vec<u8>>> {
         let (tx, rx) = mpsc::channel(64);
 
         // Take the pre-cloned reader
-        let reader = {
+        let (mut reader, mut waiter) = {
             let mut reader_guard = self.reader.lock().await;
             reader_guard.take().context("PTY reader already started")?
         };
+        let stop = self.reader_stop.clone();
+        let handle = self.handle.clone();
+        let max_restarts = self.max_reader_restarts;
+        let restarts = self.reader_restarts.clone();
 
         // Spawn a blocking task to read from PTY
         tokio::task::spawn_blocking(move || {
-            let mut reader = reader;
             let mut buf = [0u8; 4096];
 
             loop {
+                if stop.load(Ordering::Relaxed) || tx.is_closed() {
+                    debug!("PTY reader stopped");
+                    break;
+                }
+                match waiter.wait(READER_POLL_INTERVAL) {
+                    Ok(true) => {}
+                    Ok(false) => continue,
+                    Err(e) => {
+                        error!(error = %e, "PTY poll error");
+                        break;
+                    }
+                }
+
                 match reader.read(&mut buf) {
                     Ok(0) => {
                         debug!("PTY reader got EOF");
@@ -231,11 +566,29 @@ impl AsyncPty {
                             break;
                         }
                     }
+                    Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted) => {}
+                    Err(e) if is_slave_closed(&e) => {
+                        debug!("PTY slave closed");
+                        break;
+                    }
                     Err(e) => {
-                        if e.kind() != std::io::ErrorKind::WouldBlock {
-                            error!(error = %e, "PTY read error");
+                        let restarted = restarts.load(Ordering::Relaxed);
+                        if restarted >= max_restarts {
+                            error!(error = %e, restarts = restarted, "PTY read error, giving up");
                             break;
                         }
+                        warn!(error = %e, attempt = restarted + 1, max = max_restarts, "PTY read error, restarting reader");
+                        match reopen_reader(&handle) {
+                            Ok((new_reader, new_waiter)) => {
+                                reader = new_reader;
+                                waiter = new_waiter;
+                                restarts.fetch_add(1, Ordering::Relaxed);
+                            }
+                            Err(e) => {
+                                error!(error = %e, "failed to reopen PTY reader");
+                                break;
+                            }
+                        }
                     }
                 }
             }
@@ -247,13 +600,516 @@ impl AsyncPty {
     }
 }
 
+/// Fresh reader and waiter on the PTY master (called from the reader thread)
+fn reopen_reader(handle: &Mutex<PtyHandle>) -> Result<PtyReader> {
+    let handle = handle.blocking_lock();
+    let reader = handle.try_clone_reader()?;
+    Ok((reader, ReadWaiter::new(handle.master.as_ref())))
+}
+
+/// Linux reports EIO on the master once every slave fd is closed: that's EOF, not a failure
+#[cfg(unix)]
+fn is_slave_closed(err: &std::io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EIO)
+}
+
+#[cfg(not(unix))]
+fn is_slave_closed(_err: &std::io::Error) -> bool {
+    false
+}
+
+impl Drop for AsyncPty {
+    fn drop(&mut self) {
+        self.stop_reader();
+    }
+}
+
+/// Start the thread that writes queued input to the PTY
+///
+/// The thread exits once every sender is dropped or a write fails.
+fn spawn_writer(mut writer: Box<dyn Write + Send>) -> Result<mpsc::Sender<Vec<u8>>> {
+    let (tx, mut rx) = mpsc::channel::<Vec<u8>>(INPUT_QUEUE_CHUNKS);
+
+    std::thread::Builder::new()
+        .name("pty-writer".to_string())
+        .spawn(move || {
+            while let Some(chunk) = rx.blocking_recv() {
+                if let Err(e) = writer.write_all(&chunk).and_then(|_| writer.flush()) {
+                    error!(error = %e, "PTY write error");
+                    break;
+                }
+            }
+            debug!("PTY writer thread finished");
+        })
+        .context("failed to start PTY writer thread")?;
+
+    Ok(tx)
+}
+
 /// Get exit code from portable_pty ExitStatus
-#[allow(dead_code)]
+///
+/// A process killed by a signal reports 128 + the signal number, as shells do.
 pub fn exit_code(status: &portable_pty::ExitStatus) -> i32 {
     if status.success() {
-        0
+        return 0;
+    }
+    #[cfg(unix)]
+    if let Some(signal) = termination_signal(status) {
+        return 128 + signal;
+    }
+    // portable_pty reports 1 when no code is available
+    status.exit_code() as i32
+}
+
+/// Highest signal number looked up by [`termination_signal`] (covers real-time signals)
+#[cfg(unix)]
+const MAX_SIGNAL: i32 = 64;
+
+/// Signal that killed the process, if any.
+///
+/// portable_pty only keeps the signal's `strsignal` description (or
+/// "Signal N" when there is none), so map it back to the number.
+#[cfg(unix)]
+fn termination_signal(status: &portable_pty::ExitStatus) -> Option<i32> {
+    let display = status.to_string();
+    let description = display.strip_prefix("Terminated by ")?;
+    if let Some(signal) = description.strip_prefix("Signal ").and_then(|n| n.parse().ok()) {
+        return Some(signal);
+    }
+    (1..=MAX_SIGNAL).find(|&signal| {
+        // SAFETY: strsignal returns null or a NUL-terminated string valid until the next call
+        let name = unsafe { libc::strsignal(signal) };
+        !name.is_null() && unsafe { std::ffi::CStr::from_ptr(name) }.to_bytes() == description.as_bytes()
+    })
+}
+
+/// Check a per-terminal environment variable: the name must be a portable
+/// identifier (`[A-Za-z_][A-Za-z0-9_]*`) and the value free of NUL bytes
+pub fn validate_env_var(key: &str, value: &str) -> Result<()> {
+    let mut chars = key.chars();
+    let valid_name = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_name {
+        anyhow::bail!("invalid environment variable name '{}'", key.escape_debug());
+    }
+    if value.contains('\0') {
+        anyhow::bail!("environment variable '{}' contains a NUL byte", key);
+    }
+    Ok(())
+}
+
+/// Build a user-facing message for a failed spawn of `program`
+///
+/// portable-pty resolves the program itself and reports failures as plain
+/// strings, so when no `io::Error` is in the chain we re-check the path to tell
+/// "not found" apart from "not executable".
+fn spawn_error_message(
+    program: &str,
+    working_dir: &Path,
+    sandboxed: bool,
+    err: &anyhow::Error,
+) -> String {
+    let kind = err
+        .chain()
+        .find_map(|e| e.downcast_ref::<std::io::Error>())
+        .map(|e| e.kind())
+        .or_else(|| probe_program(program, working_dir));
+
+    let (what, hint) = if sandboxed {
+        ("sandbox command", "check that the sandbox tool is installed")
     } else {
-        // Try to extract the exit code, default to 1 for failure
-        1
+        ("shell", "check --shell")
+    };
+
+    match kind {
+        Some(std::io::ErrorKind::NotFound) => {
+            format!("{} '{}' not found \u{2014} {}", what, program, hint)
+        }
+        Some(std::io::ErrorKind::PermissionDenied) => {
+            format!("{} '{}' is not executable (permission denied) \u{2014} {}", what, program, hint)
+        }
+        _ => format!("failed to spawn {} '{}'", what, program),
+    }
+}
+
+/// Work out why `program` can't be executed, mirroring portable-pty's lookup
+fn probe_program(program: &str, working_dir: &Path) -> Option<std::io::ErrorKind> {
+    let path = Path::new(program);
+    let candidates: Vec<std::path::PathBuf> = if path.is_absolute() {
+        vec![path.to_path_buf()]
+    } else if program.contains('/') {
+        vec![working_dir.join(path)]
+    } else {
+        std::env::var_os("PATH")
+            .map(|p| std::env::split_paths(&p).map(|dir| dir.join(path)).collect())
+            .unwrap_or_default()
+    };
+
+    let mut found = false;
+    for candidate in candidates {
+        if let Ok(metadata) = std::fs::metadata(&candidate) {
+            if metadata.is_file() && is_executable(&metadata) {
+                return None;
+            }
+            found = true;
+        }
+    }
+
+    Some(if found {
+        std::io::ErrorKind::PermissionDenied
+    } else {
+        std::io::ErrorKind::NotFound
+    })
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    true
+}
+
+/// Initial terminal size when neither the relay nor the host terminal gives one
+pub const DEFAULT_TERMINAL_SIZE: (u16, u16) = (80, 24);
+
+/// Size of the terminal paircoded itself runs in, as `(cols, rows)`, if
+/// stdout is a terminal that reports one
+pub fn host_terminal_size() -> Option<(u16, u16)> {
+    use std::io::IsTerminal;
+    if !std::io::stdout().is_terminal() {
+        return None;
+    }
+    #[cfg(unix)]
+    {
+        terminal_size(libc::STDOUT_FILENO)
+    }
+    #[cfg(not(unix))]
+    {
+        None
+    }
+}
+
+/// Window size of the terminal open on `fd`, as `(cols, rows)`
+#[cfg(unix)]
+pub fn terminal_size(fd: std::os::fd::RawFd) -> Option<(u16, u16)> {
+    let mut winsize: libc::winsize = unsafe { std::mem::zeroed() };
+    // SAFETY: TIOCGWINSZ only writes a winsize through the pointer
+    if unsafe { libc::ioctl(fd, libc::TIOCGWINSZ, &mut winsize) } != 0 {
+        return None;
+    }
+    winsize_dimensions(&winsize)
+}
+
+/// `(cols, rows)` of a reported window size; terminals that don't know their
+/// size report zeros
+#[cfg(unix)]
+fn winsize_dimensions(winsize: &libc::winsize) -> Option<(u16, u16)> {
+    (winsize.ws_col > 0 && winsize.ws_row > 0).then_some((winsize.ws_col, winsize.ws_row))
+}
+
+/// Clamp a terminal exit code to the range a process can exit with (0-255)
+pub fn process_exit_code(code: i32) -> i32 {
+    code.clamp(0, 255)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&portable_pty::ExitStatus::with_exit_code(0)), 0);
+        assert_eq!(exit_code(&portable_pty::ExitStatus::with_exit_code(3)), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_code_from_signal() {
+        let mut pty = PtyHandle::spawn(&SpawnOptions::sh("kill -SEGV $$")).unwrap();
+        let status = pty.wait().unwrap();
+        assert!(!status.success());
+        assert_eq!(exit_code(&status), 128 + libc::SIGSEGV);
+
+        assert_eq!(exit_code(&portable_pty::ExitStatus::with_signal("Signal 77")), 128 + 77);
+        assert_eq!(exit_code(&portable_pty::ExitStatus::with_signal("not a signal")), 1);
+    }
+
+    #[test]
+    fn test_process_exit_code_clamping() {
+        assert_eq!(process_exit_code(0), 0);
+        assert_eq!(process_exit_code(42), 42);
+        assert_eq!(process_exit_code(255), 255);
+        assert_eq!(process_exit_code(256), 255);
+        assert_eq!(process_exit_code(-1), 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_winsize_dimensions() {
+        let winsize = |ws_col, ws_row| libc::winsize { ws_row, ws_col, ws_xpixel: 0, ws_ypixel: 0 };
+        assert_eq!(winsize_dimensions(&winsize(132, 43)), Some((132, 43)));
+        assert_eq!(winsize_dimensions(&winsize(0, 0)), None);
+        assert_eq!(winsize_dimensions(&winsize(80, 0)), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_terminal_size_of_pty() {
+        use std::os::fd::AsRawFd;
+        let mut pty = PtyHandle::spawn(&SpawnOptions { cols: 120, rows: 33, ..SpawnOptions::sh("sleep 1") }).unwrap();
+        let tty = std::fs::File::open(pty.tty_name().expect("tty name")).unwrap();
+        assert_eq!(terminal_size(tty.as_raw_fd()), Some((120, 33)));
+
+        let file = tempfile::tempfile().unwrap();
+        assert_eq!(terminal_size(file.as_raw_fd()), None);
+        let _ = pty.kill();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tty_name() {
+        let mut pty = PtyHandle::spawn(&SpawnOptions::sh("sleep 1")).unwrap();
+        let tty = pty.tty_name().expect("tty name").to_string();
+        assert!(tty.starts_with("/dev/pts/"), "{}", tty);
+        let _ = pty.kill();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_spawn_opens_at_requested_size() {
+        // Any resize after startup would deliver SIGWINCH and print "winch"
+        let script = "trap 'echo winch' WINCH; stty size; sleep 0.3";
+        let handle = PtyHandle::spawn(&SpawnOptions { cols: 132, rows: 43, ..SpawnOptions::sh(script) }).unwrap();
+        assert_eq!(handle.size().unwrap(), (132, 43));
+
+        let pty = AsyncPty::new(handle).unwrap();
+        let mut rx = pty.start_reader().await.unwrap();
+        let mut output = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(data) = rx.recv().await {
+                output.extend_from_slice(&data);
+            }
+        })
+        .await;
+
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("43 132"), "output was {:?}", output);
+        assert!(!output.contains("winch"), "output was {:?}", output);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_under_resource_limits() {
+        let limits = ResourceLimits { memory_max: Some(256 << 20), cpu_quota: Some(50) };
+        if cgroup::check_available(&limits).is_err() {
+            // Needs cgroup v2 and a systemd manager that allows scopes
+            return;
+        }
+        let script = "cat /sys/fs/cgroup$(cut -d: -f3 /proc/self/cgroup)/memory.max";
+        let handle = PtyHandle::spawn(&SpawnOptions { limits, ..SpawnOptions::sh(script) }).unwrap();
+        let pty = AsyncPty::new(handle).unwrap();
+        let mut rx = pty.start_reader().await.unwrap();
+        let mut output = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(data) = rx.recv().await {
+                output.extend_from_slice(&data);
+            }
+        })
+        .await;
+
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("268435456"), "output was {:?}", output);
+    }
+
+    #[tokio::test]
+    async fn test_spawn_applies_terminal_env() {
+        let env = HashMap::from([
+            ("PAIRCODED_PROJECT".to_string(), "web".to_string()),
+            ("TERM".to_string(), "vt220".to_string()),
+        ]);
+        let script = "echo \"$PAIRCODED_PROJECT/$TERM\"";
+        let handle = PtyHandle::spawn(&SpawnOptions { env: env.clone(), ..SpawnOptions::sh(script) }).unwrap();
+        let pty = AsyncPty::new(handle).unwrap();
+        let mut rx = pty.start_reader().await.unwrap();
+        let mut output = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(data) = rx.recv().await {
+                output.extend_from_slice(&data);
+            }
+        })
+        .await;
+
+        // Relay-supplied variables override the defaults, TERM included
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.contains("web/vt220"), "output was {:?}", output);
+    }
+
+    #[test]
+    fn test_spawn_rejects_invalid_env() {
+        for (key, value) in [("", "x"), ("1ST", "x"), ("A=B", "x"), ("OK", "nul\0byte")] {
+            let env = HashMap::from([(key.to_string(), value.to_string())]);
+            assert!(
+                PtyHandle::spawn(&SpawnOptions { env: env.clone(), ..SpawnOptions::sh("true") }).is_err(),
+                "{:?}={:?} accepted",
+                key,
+                value
+            );
+        }
+        assert!(validate_env_var("_PROJECT_2", "any value").is_ok());
+    }
+
+    /// Yields some output, then fails once with a non-EOF error
+    struct FlakyReader {
+        output: Option<&'static [u8]>,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.output.take() {
+                Some(output) => {
+                    buf[..output.len()].copy_from_slice(output);
+                    Ok(output.len())
+                }
+                None => Err(std::io::Error::other("transient read failure")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reader_restarts_after_read_error() {
+        let handle = PtyHandle::spawn(&SpawnOptions::sh("sleep 0.3; echo after")).unwrap();
+        let pty = AsyncPty::new(handle).unwrap();
+        {
+            // Swap in a reader that fails after its first read
+            let waiter = ReadWaiter::new(pty.handle.lock().await.master.as_ref());
+            let flaky: Box<dyn Read + Send> = Box::new(FlakyReader { output: Some(b"before ") });
+            *pty.reader.lock().await = Some((flaky, waiter));
+        }
+
+        let mut rx = pty.start_reader().await.unwrap();
+        let mut output = Vec::new();
+        let _ = tokio::time::timeout(Duration::from_secs(5), async {
+            while let Some(data) = rx.recv().await {
+                output.extend_from_slice(&data);
+            }
+        })
+        .await;
+
+        let output = String::from_utf8_lossy(&output);
+        assert!(output.starts_with("before "), "output was {:?}", output);
+        assert!(output.contains("after"), "output was {:?}", output);
+        assert_eq!(pty.reader_restarts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reader_gives_up_without_restarts() {
+        let handle = PtyHandle::spawn(&SpawnOptions::sh("sleep 0.3; echo after")).unwrap();
+        let mut pty = AsyncPty::new(handle).unwrap();
+        pty.set_max_reader_restarts(0);
+        {
+            let waiter = ReadWaiter::new(pty.handle.lock().await.master.as_ref());
+            let flaky: Box<dyn Read + Send> = Box::new(FlakyReader { output: None });
+            *pty.reader.lock().await = Some((flaky, waiter));
+        }
+
+        let mut rx = pty.start_reader().await.unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap();
+        assert!(closed.is_none());
+        assert_eq!(pty.reader_restarts(), 0);
+        let _ = pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_write_times_out_when_input_is_not_read() {
+        // Nothing reads the input, so the PTY buffer and then the writer queue fill
+        // up (in raw mode; canonical mode discards input past a full line instead)
+        let handle = PtyHandle::spawn(&SpawnOptions::sh("stty raw -echo; sleep 30")).unwrap();
+        let mut pty = AsyncPty::new(handle).unwrap();
+        pty.set_write_timeout(Duration::from_millis(200));
+
+        let data = vec![b'x'; 1024 * 1024];
+        let result = tokio::time::timeout(Duration::from_secs(5), pty.write(&data))
+            .await
+            .expect("write hung instead of timing out");
+        let err = result.unwrap_err();
+        let timeout = err.downcast_ref::<WriteTimeout>().expect("not a write timeout");
+        assert!(timeout.written < data.len());
+
+        // The PTY is still usable afterwards
+        assert!(pty.resize(100, 30).await.is_ok());
+        let _ = pty.kill().await;
+    }
+
+    #[tokio::test]
+    async fn test_reader_stops_on_request() {
+        // `sleep` keeps the PTY open without writing, so a plain read would block forever
+        let handle = PtyHandle::spawn(&SpawnOptions::sh("sleep 30")).unwrap();
+        let pty = AsyncPty::new(handle).unwrap();
+        let mut rx = pty.start_reader().await.unwrap();
+
+        pty.stop_reader();
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            while rx.recv().await.is_some() {}
+        })
+        .await;
+        assert!(closed.is_ok(), "reader did not stop");
+        let _ = pty.kill().await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reader_stops_when_pty_dropped() {
+        let handle = PtyHandle::spawn(&SpawnOptions::sh("sleep 30")).unwrap();
+        let pid = handle.process_id().unwrap();
+        let pty = AsyncPty::new(handle).unwrap();
+        let mut rx = pty.start_reader().await.unwrap();
+
+        drop(pty);
+        let closed = tokio::time::timeout(Duration::from_secs(2), async {
+            while rx.recv().await.is_some() {}
+        })
+        .await;
+        assert!(closed.is_ok(), "reader did not stop");
+
+        // SAFETY: kill has no memory-safety preconditions
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+    }
+
+    #[test]
+    fn test_spawn_missing_shell() {
+        let err = PtyHandle::spawn(&SpawnOptions { shell: "/nonexistent/bin/zsh".to_string(), ..SpawnOptions::default() })
+            .err()
+            .expect("spawn should fail");
+        let message = err.to_string();
+        assert!(message.contains("/nonexistent/bin/zsh"), "{}", message);
+        assert!(message.contains("not found"), "{}", message);
+        // The original portable-pty error is kept as the source
+        assert!(err.chain().count() > 1);
+    }
+
+    #[test]
+    fn test_spawn_missing_shell_in_path() {
+        let err = PtyHandle::spawn(&SpawnOptions { shell: "paircoded-no-such-shell".to_string(), ..SpawnOptions::default() })
+            .err()
+            .expect("spawn should fail");
+        let message = err.to_string();
+        assert!(message.contains("paircoded-no-such-shell"), "{}", message);
+        assert!(message.contains("not found"), "{}", message);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_spawn_non_executable_shell() {
+        let dir = tempfile::tempdir().unwrap();
+        let shell = dir.path().join("shell");
+        std::fs::write(&shell, "#!/bin/sh\n").unwrap();
+
+        let err = PtyHandle::spawn(&SpawnOptions { shell: shell.to_str().unwrap().to_string(), working_dir: dir.path().to_path_buf(), ..SpawnOptions::default() })
+            .err()
+            .expect("spawn should fail");
+        assert!(err.to_string().contains("permission denied"), "{}", err);
     }
 }
@@ -0,0 +1,234 @@
+//! Secret redaction for terminal output (`--redact`).
+//!
+//! Matches of the configured patterns are replaced with `***` before output
+//! leaves the host. PTY reads can end in the middle of a secret, so a
+//! [`Redactor`] holds back the tail of each chunk that could still become a
+//! match and releases it once more output shows that it didn't (or once the
+//! bridge flushes it after a short quiet period).
+
+use regex_automata::hybrid::dfa::{Cache, DFA};
+use regex_automata::util::start;
+use regex_automata::{meta, Anchored, Input};
+use std::sync::Arc;
+
+/// What a match is replaced with
+pub const REDACTED: &[u8] = b"***";
+
+/// Longest output tail held back waiting for a match to complete; a secret
+/// longer than this that is split across reads is only partly redacted
+pub const REDACT_LOOKBACK: usize = 256;
+
+/// Redaction patterns, compiled once and shared by all terminals
+pub struct Redaction {
+    patterns: Vec<String>,
+    /// Finds matches to replace
+    regex: meta::Regex,
+    /// Tells whether a match could start at a position and continue past the
+    /// output seen so far (same patterns, stepped byte by byte)
+    prefix: DFA,
+}
+
+impl std::fmt::Debug for Redaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Redaction").field("patterns", &self.patterns).finish_non_exhaustive()
+    }
+}
+
+impl Redaction {
+    /// Compile `patterns` (regex syntax) into one matcher
+    pub fn new(patterns: &[String]) -> Result<Self, String> {
+        for pattern in patterns {
+            let regex = meta::Regex::new(pattern)
+                .map_err(|e| format!("invalid redact pattern '{}': {}", pattern, e))?;
+            if regex.is_match("") {
+                return Err(format!("redact pattern '{}' matches empty text", pattern));
+            }
+        }
+        let regex = meta::Regex::new_many(patterns).map_err(|e| format!("invalid redact patterns: {}", e))?;
+        let prefix = DFA::builder()
+            .configure(DFA::config().unicode_word_boundary(true))
+            .build_many(patterns)
+            .map_err(|e| format!("invalid redact patterns: {}", e))?;
+        Ok(Redaction { patterns: patterns.to_vec(), regex, prefix })
+    }
+
+    /// The patterns as given
+    pub fn patterns(&self) -> &[String] {
+        &self.patterns
+    }
+}
+
+/// Streaming redaction state for one terminal's output
+pub struct Redactor {
+    redaction: Arc<Redaction>,
+    cache: Cache,
+    /// Output not released yet, preceded by one byte of released output as
+    /// context for look-behind assertions (`\b`, `^`)
+    held: Vec<u8>,
+    /// Whether `held` starts with a context byte
+    has_context: bool,
+}
+
+impl Redactor {
+    pub fn new(redaction: Arc<Redaction>) -> Self {
+        let cache = redaction.prefix.create_cache();
+        Redactor { redaction, cache, held: Vec::new(), has_context: false }
+    }
+
+    /// Whether output is being held back
+    pub fn is_holding(&self) -> bool {
+        self.held.len() > self.start()
+    }
+
+    /// Redact a chunk of output, holding back a tail that could be the start of
+    /// a secret continued by the next chunk
+    pub fn process(&mut self, data: &[u8]) -> Vec<u8> {
+        self.held.extend_from_slice(data);
+        let forced = self.held.len().saturating_sub(REDACT_LOOKBACK).max(self.start());
+        self.release(Some(forced))
+    }
+
+    /// Release everything held back, redacting the matches in it
+    pub fn flush(&mut self) -> Vec<u8> {
+        self.release(None)
+    }
+
+    /// Index of the first held byte (after the context byte)
+    fn start(&self) -> usize {
+        usize::from(self.has_context)
+    }
+
+    /// Redact and release held output up to the first position a match could
+    /// still start at (at or after `forced`), or all of it if `forced` is `None`
+    fn release(&mut self, forced: Option<usize>) -> Vec<u8> {
+        let buf = std::mem::take(&mut self.held);
+        let mut out = Vec::with_capacity(buf.len());
+        let mut pos = self.start();
+        let mut hold = None;
+
+        let input = Input::new(&buf).span(pos..buf.len());
+        let prefix = &self.redaction.prefix;
+        for m in self.redaction.regex.find_iter(input) {
+            if let Some(forced) = forced {
+                // A match that may still grow, or an earlier one that may still start
+                let from = pos.max(forced);
+                if let Some(h) = (from..=m.start()).find(|&i| may_continue(prefix, &mut self.cache, &buf, i)) {
+                    hold = Some(h);
+                    break;
+                }
+            }
+            out.extend_from_slice(&buf[pos..m.start()]);
+            out.extend_from_slice(REDACTED);
+            pos = m.end();
+        }
+        let end = match (hold, forced) {
+            (Some(h), _) => h,
+            (None, Some(forced)) => (pos.max(forced)..buf.len())
+                .find(|&i| may_continue(prefix, &mut self.cache, &buf, i))
+                .unwrap_or(buf.len()),
+            (None, None) => buf.len(),
+        };
+        out.extend_from_slice(&buf[pos..end]);
+
+        // Keep the last released byte as context for the next search
+        self.has_context = end > 0;
+        self.held = buf[end.saturating_sub(1)..].to_vec();
+        out
+    }
+}
+
+/// Whether a match starting at `at` is still possible once more output follows
+/// (the automaton hasn't ruled one out by the end of `buf`)
+fn may_continue(dfa: &DFA, cache: &mut Cache, buf: &[u8], at: usize) -> bool {
+    let config = start::Config::new()
+        .anchored(Anchored::Yes)
+        .look_behind(at.checked_sub(1).map(|i| buf[i]));
+    let Ok(mut state) = dfa.start_state(cache, &config) else {
+        return true;
+    };
+    for &byte in &buf[at..] {
+        state = match dfa.next_state(cache, state, byte) {
+            Ok(state) => state,
+            // Can't tell without a cache; err on the side of holding back
+            Err(_) => return true,
+        };
+        if state.is_dead() {
+            return false;
+        }
+        if state.is_quit() {
+            // Non-ASCII text next to \b; hold back rather than risk a leak
+            return true;
+        }
+    }
+    // Matches are reported one byte late, so a finished match still looks
+    // alive; it's only done if no further byte can extend it
+    !state.is_match()
+        || (0..=u8::MAX).any(|byte| {
+            dfa.next_state(cache, state, byte).map_or(true, |next| !next.is_dead() && !next.is_quit())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn redactor(patterns: &[&str]) -> Redactor {
+        let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+        Redactor::new(Arc::new(Redaction::new(&patterns).unwrap()))
+    }
+
+    #[test]
+    fn test_redacts_matches() {
+        let mut r = redactor(&["ghp_[A-Za-z0-9]+", "password=\\S+"]);
+        assert_eq!(r.process(b"token ghp_abc123 and password=hunter2\n"), b"token *** and ***\n");
+        assert!(!r.is_holding());
+        assert_eq!(r.process(b"plain output\n"), b"plain output\n");
+    }
+
+    #[test]
+    fn test_secret_split_across_reads() {
+        let mut r = redactor(&["ghp_[A-Za-z0-9]+"]);
+        let first = r.process(b"export TOKEN=gh");
+        assert_eq!(first, b"export TOKEN=");
+        assert!(r.is_holding());
+        let second = r.process(b"p_abc");
+        assert!(second.is_empty());
+        let third = r.process(b"def123\r\n$ ");
+        assert_eq!([first, second, third].concat(), b"export TOKEN=***\r\n$ ");
+        assert!(!r.is_holding());
+    }
+
+    #[test]
+    fn test_flush_releases_held_output() {
+        let mut r = redactor(&["ghp_[A-Za-z0-9]+", "secret"]);
+        assert_eq!(r.process(b"typed: sec"), b"typed: ");
+        assert_eq!(r.flush(), b"sec");
+        assert_eq!(r.process(b"ghp_abc"), b"");
+        assert_eq!(r.flush(), b"***");
+        assert!(!r.is_holding());
+    }
+
+    #[test]
+    fn test_word_boundary_uses_released_context() {
+        let mut r = redactor(&["\\bkey\\b"]);
+        assert_eq!(r.process(b"monkey "), b"monkey ");
+        assert_eq!([r.process(b"ke"), r.process(b"y!")].concat(), b"***!");
+    }
+
+    #[test]
+    fn test_lookback_is_bounded() {
+        let mut r = redactor(&["x[a-z]+"]);
+        let mut out = r.process(b"x");
+        for _ in 0..1000 {
+            out.extend(r.process(b"a"));
+            assert!(r.held.len() <= REDACT_LOOKBACK + 1);
+        }
+        assert!(out.starts_with(b"***a"), "{:?}", String::from_utf8_lossy(&out));
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        assert!(Redaction::new(&["(".to_string()]).unwrap_err().contains("invalid redact pattern '('"));
+        assert!(Redaction::new(&["a*".to_string()]).unwrap_err().contains("matches empty"));
+    }
+}
@@ -1,13 +1,451 @@
 //! WebSocket client for connecting to the relay service.
 
-use anyhow::{Context, Result};
-use futures_util::{SinkExt, StreamExt};
+use anyhow::{anyhow, Context, Result};
+use futures_util::{stream, SinkExt, Stream, StreamExt};
+use rand::Rng;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
 use tokio::sync::mpsc;
-use tokio_tungstenite::{connect_async_with_config, tungstenite::{protocol::Message, http::Request}};
-use tracing::{debug, error, info, warn};
+use tokio_tungstenite::tungstenite::handshake::client::Response;
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tokio_tungstenite::{
+    client_async_with_config, connect_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream,
+    tungstenite::{self, protocol::Message, http::Request},
+};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
 use url::Url;
 
-use crate::protocol::{ClientMessage, HandshakeMessage, RelayMessage};
+use crate::protocol::{self, AckMessage, Capabilities, ClientMessage, CloseReason, HandshakeMessage, RelayMessage};
+use crate::tls;
+
+/// Default path of the control endpoint (`--control-path-template`)
+pub const DEFAULT_CONTROL_PATH_TEMPLATE: &str = "/ws/control/{session}";
+
+/// Default path of a terminal's data endpoint (`--data-url-template`)
+pub const DEFAULT_DATA_URL_TEMPLATE: &str = "/ws/terminal-data/{session}/{name}";
+
+/// Check a relay path template: it must be an absolute path containing every
+/// placeholder in `required` (e.g. `{session}`)
+pub fn validate_path_template(template: &str, required: &[&str]) -> Result<String, String> {
+    if !template.starts_with('/') {
+        return Err("path template must start with '/'".to_string());
+    }
+    for placeholder in required {
+        if !template.contains(placeholder) {
+            return Err(format!("path template must contain {}", placeholder));
+        }
+    }
+    Ok(template.to_string())
+}
+
+/// Fill `{session}` and `{name}` into a relay path template
+pub fn fill_path_template(template: &str, session: &str, name: &str) -> String {
+    template.replace("{session}", session).replace("{name}", name)
+}
+
+/// How long to wait after the handshake for the relay's optional ack
+pub const HANDSHAKE_ACK_WAIT: Duration = Duration::from_millis(250);
+
+/// How long to wait between reconnect attempts (`--reconnect-strategy`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackoffStrategy {
+    /// Start at `base` and double after every attempt, up to `max`
+    Exponential { base: Duration, max: Duration },
+    /// Always wait the same time
+    Fixed(Duration),
+}
+
+impl BackoffStrategy {
+    /// Delay before reconnect attempt `attempt` (0 for the first)
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            BackoffStrategy::Exponential { base, max } => {
+                base.saturating_mul(2u32.saturating_pow(attempt)).min(max)
+            }
+            BackoffStrategy::Fixed(delay) => delay,
+        }
+    }
+}
+
+/// Formats in the `--reconnect-strategy` syntax
+impl std::fmt::Display for BackoffStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackoffStrategy::Exponential { base, max } => {
+                write!(f, "exponential:{}:{}", base.as_millis(), max.as_millis())
+            }
+            BackoffStrategy::Fixed(delay) => write!(f, "fixed:{}", delay.as_millis()),
+        }
+    }
+}
+
+/// Reconnect attempts that are all logged before thinning out
+pub const LOG_ALL_ATTEMPTS: u32 = 5;
+
+/// Past [`LOG_ALL_ATTEMPTS`], only every this many attempts is logged
+pub const LOG_ATTEMPT_INTERVAL: u32 = 10;
+
+/// Whether reconnect attempt `attempt` (1 for the first) is logged at its usual
+/// level; the rest go to debug so a long outage doesn't flood the logs
+pub fn should_log_attempt(attempt: u32) -> bool {
+    attempt <= LOG_ALL_ATTEMPTS || attempt.is_multiple_of(LOG_ATTEMPT_INTERVAL)
+}
+
+/// Client-sent pings on a data connection (`--keepalive-interval-ms`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    pub interval: Duration,
+    /// Upper bound of the random delay added before the first ping, so
+    /// terminals connected together don't ping in lockstep
+    pub jitter: Duration,
+}
+
+impl Keepalive {
+    /// Delay before the first ping: one interval plus a random phase offset below `jitter`
+    pub fn first_delay(&self, rng: &mut impl Rng) -> Duration {
+        let jitter = self.jitter.as_millis() as u64;
+        let offset = if jitter == 0 { 0 } else { rng.gen_range(0..jitter) };
+        self.interval + Duration::from_millis(offset)
+    }
+
+    /// Ping timer for a new connection
+    fn timer(&self) -> tokio::time::Interval {
+        let start = tokio::time::Instant::now() + self.first_delay(&mut rand::thread_rng());
+        let mut timer = tokio::time::interval_at(start, self.interval);
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        timer
+    }
+}
+
+/// Wait for the next keepalive tick, forever if keepalive is off
+async fn next_keepalive(timer: &mut Option<tokio::time::Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// Default User-Agent sent on websocket upgrades
+pub fn default_user_agent() -> String {
+    format!("paircoded/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Build a websocket upgrade request with optional JWT and extra headers.
+///
+/// A `User-Agent` in `extra_headers` replaces the default one.
+pub fn build_request(
+    url: &Url,
+    token: Option<&str>,
+    extra_headers: &[(String, String)],
+) -> Result<Request<()>> {
+    let mut request = Request::builder()
+        .uri(url.as_str())
+        .header("Host", url.host_str().unwrap_or("localhost"))
+        .header("Connection", "Upgrade")
+        .header("Upgrade", "websocket")
+        .header("Sec-WebSocket-Version", "13")
+        .header("Sec-WebSocket-Key", tokio_tungstenite::tungstenite::handshake::client::generate_key());
+
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+
+    if !extra_headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("user-agent")) {
+        request = request.header("User-Agent", default_user_agent());
+    }
+    for (name, value) in extra_headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+
+    request
+        .body(())
+        .context("failed to build WebSocket request")
+}
+
+/// Close frame for a client-initiated close
+pub fn close_frame(reason: CloseReason) -> CloseFrame<'static> {
+    CloseFrame {
+        code: CloseCode::from(reason.code()),
+        reason: reason.as_str().into(),
+    }
+}
+
+/// Sets the reason sent in a data connection's close frame.
+///
+/// The frame goes out once every sender for the connection is dropped, so set
+/// the reason before dropping the last one.
+#[derive(Debug, Clone, Default)]
+pub struct CloseHandle(Arc<Mutex<CloseReason>>);
+
+impl CloseHandle {
+    pub fn set(&self, reason: CloseReason) {
+        *self.0.lock().unwrap() = reason;
+    }
+
+    fn get(&self) -> CloseReason {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Wait for the relay's first message and check it as a handshake ack.
+///
+/// Fails if the relay rejected the handshake. Returns the ack, if any, and a
+/// first message that isn't an ack so the caller can process it normally.
+///
+/// With `timeout` unset, relays that don't send acks just cost a short wait.
+/// With `timeout` set, the relay must send something (an ack, any message or a
+/// ping) within it, otherwise the connection is treated as dead.
+pub async fn await_handshake_ack<S>(
+    ws_stream: &mut S,
+    timeout: Option<Duration>,
+) -> Result<(Option<AckMessage>, Option<Message>), ConnectError>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    let wait = timeout.unwrap_or(HANDSHAKE_ACK_WAIT);
+    let msg = match tokio::time::timeout(wait, ws_stream.next()).await {
+        Err(_) if timeout.is_some() => {
+            error!(timeout_ms = wait.as_millis(), "relay did not respond to handshake");
+            return Err(ConnectError::Protocol(anyhow!(
+                "relay sent nothing within {}ms of the handshake",
+                wait.as_millis()
+            )));
+        }
+        Err(_) => return Ok((None, None)),
+        Ok(None) => return Err(ConnectError::Protocol(anyhow!("relay closed the connection during handshake"))),
+        Ok(Some(Err(e))) => {
+            return Err(ConnectError::from_websocket(e).context("relay connection failed during handshake"))
+        }
+        Ok(Some(Ok(msg))) => msg,
+    };
+
+    let ack = match msg {
+        // Returning the close as a first message would pass for a connection
+        // that was established and then lost, skipping reconnect backoff
+        Message::Close(frame) => {
+            return Err(match frame {
+                Some(frame) => ConnectError::Closed { code: frame.code.into(), reason: frame.reason.into_owned() },
+                None => ConnectError::Protocol(anyhow!("relay closed the connection during handshake")),
+            })
+        }
+        Message::Text(ref text) => AckMessage::parse(text.as_bytes()),
+        Message::Binary(ref data) => AckMessage::parse(data),
+        _ => None,
+    };
+    match ack {
+        Some(ack) if ack.is_error() => {
+            let message = ack.error_message();
+            error!(error = %message, "relay rejected handshake");
+            Err(ConnectError::Protocol(anyhow!("relay rejected handshake: {}", message)))
+        }
+        Some(ack) => {
+            debug!(capabilities = ?ack.capabilities, "relay acknowledged handshake");
+            Ok((Some(ack), None))
+        }
+        None => Ok((None, Some(msg))),
+    }
+}
+
+/// Why a connection to the relay (data or control endpoint) couldn't be
+/// established.
+///
+/// Reconnect loops look at the variant to decide whether another attempt can
+/// succeed (see [`is_retryable`](Self::is_retryable)).
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The relay couldn't be reached, or the connection dropped while being set up
+    Transport(anyhow::Error),
+    /// The TLS handshake failed, e.g. the relay's certificate isn't trusted
+    Tls(anyhow::Error),
+    /// The relay answered the websocket upgrade with this HTTP status
+    HttpStatus(u16),
+    /// The relay answered the handshake with a close frame (4401 means the
+    /// token was rejected)
+    Closed { code: u16, reason: String },
+    /// The connection was up but the handshake failed (the relay rejected it,
+    /// closed the connection or stayed silent), or there was no valid request
+    /// to send in the first place
+    Protocol(anyhow::Error),
+}
+
+impl ConnectError {
+    /// Whether trying again later can succeed.
+    ///
+    /// The relay gives the same answer to the same request, so an HTTP 4xx
+    /// refusal isn't retried, except 401 (the token is refreshed in the
+    /// meantime), 408 and 429. Anything else may be a relay that is
+    /// restarting or overloaded.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ConnectError::HttpStatus(status) => {
+                !(400..500).contains(status) || matches!(status, 401 | 408 | 429)
+            }
+            _ => true,
+        }
+    }
+
+    /// Classify an error from the websocket library
+    pub(crate) fn from_websocket(error: tungstenite::Error) -> Self {
+        match error {
+            tungstenite::Error::Http(response) => ConnectError::HttpStatus(response.status().as_u16()),
+            tungstenite::Error::Tls(e) => ConnectError::Tls(e.into()),
+            e @ (tungstenite::Error::Io(_)
+            | tungstenite::Error::ConnectionClosed
+            | tungstenite::Error::AlreadyClosed) => ConnectError::Transport(e.into()),
+            e => ConnectError::Protocol(e.into()),
+        }
+    }
+
+    /// Prefix the message with `context`, keeping the variant; an HTTP
+    /// status or close frame is left alone
+    pub fn context(self, context: &'static str) -> Self {
+        match self {
+            ConnectError::Transport(e) => ConnectError::Transport(e.context(context)),
+            ConnectError::Tls(e) => ConnectError::Tls(e.context(context)),
+            ConnectError::Protocol(e) => ConnectError::Protocol(e.context(context)),
+            e @ (ConnectError::HttpStatus(_) | ConnectError::Closed { .. }) => e,
+        }
+    }
+}
+
+impl std::fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Transport(e) | ConnectError::Tls(e) | ConnectError::Protocol(e) => write!(f, "{:#}", e),
+            ConnectError::HttpStatus(status) => {
+                write!(f, "relay refused the websocket upgrade with HTTP {}", status)
+            }
+            ConnectError::Closed { code, reason } => {
+                write!(f, "relay closed the connection during handshake (code {}: {})", code, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {}
+
+/// Open a websocket for `request`, optionally presenting `tls_sni` as the TLS
+/// server name instead of the URL host.
+///
+/// The override only affects the TLS handshake (SNI and certificate
+/// verification); the `Host` header is left as built by [`build_request`].
+/// It is ignored for plain `ws://` URLs. `wss://` connections present the
+/// client certificate installed with [`tls::set_client_identity`], if any.
+pub async fn connect_websocket(
+    request: Request<()>,
+    tls_sni: Option<&str>,
+) -> Result<(WebSocketStream<MaybeTlsStream<TcpStream>>, Response), ConnectError> {
+    let uri = request.uri();
+    let wss = uri.scheme_str() == Some("wss");
+    let Some(sni) = tls_sni.filter(|_| wss) else {
+        let connector = match tls::client_identity().filter(|_| wss) {
+            Some(identity) => Some(Connector::NativeTls(tls::connector_with(Some(identity)).map_err(ConnectError::Tls)?)),
+            None => None,
+        };
+        return connect_async_tls_with_config(request, None, false, connector)
+            .await
+            .map_err(ConnectError::from_websocket);
+    };
+    let host = uri.host().context("relay URL has no host").map_err(ConnectError::Protocol)?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(443);
+
+    debug!(host, port, sni, "connecting with TLS server name override");
+    let tcp = TcpStream::connect((host, port))
+        .await
+        .with_context(|| format!("failed to connect to {}:{}", host, port))
+        .map_err(ConnectError::Transport)?;
+    let connector = tokio_native_tls::TlsConnector::from(tls::connector().map_err(ConnectError::Tls)?);
+    let tls = connector
+        .connect(sni, tcp)
+        .await
+        .with_context(|| format!("TLS handshake with server name {} failed", sni))
+        .map_err(ConnectError::Tls)?;
+    client_async_with_config(request, MaybeTlsStream::NativeTls(tls), None)
+        .await
+        .map_err(ConnectError::from_websocket)
+}
+
+/// Frames output as websocket text when it is UTF-8 (`--text-output`).
+///
+/// A character split across output chunks is held back until the rest of it
+/// arrives; output that isn't UTF-8, and every other message, goes out binary.
+/// That includes sequenced output, whose frames start with a binary sequence
+/// number, so a relay that asks for it gets no text frames at all.
+#[derive(Debug, Default)]
+struct TextFramer {
+    /// Leading bytes of a character whose remaining bytes haven't arrived yet
+    partial: Vec<u8>,
+    /// Already warned that sequenced output can't be sent as text
+    warned_sequenced: bool,
+}
+
+impl TextFramer {
+    /// Websocket frames to send for `msg`, already encoded as `encoded`
+    fn frames(&mut self, msg: &ClientMessage, encoded: Vec<u8>) -> Vec<Message> {
+        if matches!(msg, ClientMessage::SequencedOutput { .. }) && !std::mem::replace(&mut self.warned_sequenced, true) {
+            warn!("relay asked for sequenced output, which is always binary; --text-output has no effect");
+        }
+        let ClientMessage::Output(chunk) = msg else {
+            let mut frames: Vec<Message> = self.flush().into_iter().collect();
+            frames.push(Message::Binary(encoded));
+            return frames;
+        };
+        let mut data = std::mem::take(&mut self.partial);
+        data.extend_from_slice(chunk);
+        match std::str::from_utf8(&data) {
+            Ok(text) => vec![text_output_frame(text)],
+            Err(e) if e.error_len().is_none() => {
+                // Ends partway through a character: send what's complete
+                self.partial = data.split_off(e.valid_up_to());
+                match std::str::from_utf8(&data) {
+                    Ok(text) if !text.is_empty() => vec![text_output_frame(text)],
+                    _ => Vec::new(),
+                }
+            }
+            Err(_) => vec![binary_output_frame(&data)],
+        }
+    }
+
+    /// Held-back bytes as a binary output frame, if there are any
+    fn flush(&mut self) -> Option<Message> {
+        (!self.partial.is_empty()).then(|| binary_output_frame(&std::mem::take(&mut self.partial)))
+    }
+}
+
+fn text_output_frame(text: &str) -> Message {
+    let mut frame = String::with_capacity(1 + text.len());
+    frame.push(protocol::client_prefix::OUTPUT as char);
+    frame.push_str(text);
+    Message::Text(frame)
+}
+
+fn binary_output_frame(data: &[u8]) -> Message {
+    let mut frame = Vec::with_capacity(1 + data.len());
+    frame.push(protocol::client_prefix::OUTPUT);
+    frame.extend_from_slice(data);
+    Message::Binary(frame)
+}
+
+/// How a data connection is made (see [`RelayConnection::connect`])
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// JWT sent in the Authorization header
+    pub token: Option<String>,
+    /// Extra headers for the websocket upgrade
+    pub headers: Vec<(String, String)>,
+    /// Fail a relay that stays silent after the handshake (see [`await_handshake_ack`])
+    pub handshake_timeout: Option<Duration>,
+    /// TLS server name override (see [`connect_websocket`])
+    pub tls_sni: Option<String>,
+    /// Ping the relay on a timer
+    pub keepalive: Option<Keepalive>,
+    /// Send UTF-8 output as text frames (see [`TextFramer`])
+    pub text_output: bool,
+}
 
 /// Relay connection state
 pub struct RelayConnection {
@@ -15,33 +453,42 @@ pub struct RelayConnection {
     tx: mpsc::Sender<ClientMessage>,
     /// Channel to receive messages from the relay
     rx: mpsc::Receiver<RelayMessage>,
+    /// Features enabled by the handshake and the relay's ack
+    capabilities: Capabilities,
+    /// Reason for the close frame sent when the connection is dropped
+    close: CloseHandle,
+    /// Sent in the handshake; every log line for the connection carries it
+    connection_id: String,
+    /// Span the connection's tasks log in
+    span: Span,
 }
 
 impl RelayConnection {
-    /// Connect to the relay service with optional JWT authentication
-    pub async fn connect(url: &Url, handshake: HandshakeMessage, token: Option<&str>) -> Result<Self> {
+    /// Connect to the relay service, as described by `options`
+    ///
+    /// Each call is a new connection with a fresh `connection_id` in the
+    /// handshake and a `data_connection` span carrying it.
+    pub async fn connect(
+        url: &Url,
+        mut handshake: HandshakeMessage,
+        options: &ConnectOptions,
+    ) -> Result<Self, ConnectError> {
+        handshake.connection_id = protocol::new_connection_id();
+        let span = info_span!("data_connection", connection_id = %handshake.connection_id);
+        Self::open(url, handshake, options).instrument(span).await
+    }
+
+    /// Establish the connection described by [`connect`](Self::connect) in the current span
+    async fn open(url: &Url, handshake: HandshakeMessage, options: &ConnectOptions) -> Result<Self, ConnectError> {
+        let token = options.token.as_deref();
         info!(url = %url, has_token = token.is_some(), "connecting to relay");
 
         // Build request with optional Authorization header
-        let mut request = Request::builder()
-            .uri(url.as_str())
-            .header("Host", url.host_str().unwrap_or("localhost"))
-            .header("Connection", "Upgrade")
-            .header("Upgrade", "websocket")
-            .header("Sec-WebSocket-Version", "13")
-            .header("Sec-WebSocket-Key", tokio_tungstenite::tungstenite::handshake::client::generate_key());
+        let request = build_request(url, token, &options.headers).map_err(ConnectError::Protocol)?;
 
-        if let Some(token) = token {
-            request = request.header("Authorization", format!("Bearer {}", token));
-        }
-
-        let request = request
-            .body(())
-            .context("failed to build WebSocket request")?;
-
-        let (ws_stream, response) = connect_async_with_config(request, None, false)
+        let (ws_stream, response) = connect_websocket(request, options.tls_sni.as_deref())
             .await
-            .context("failed to connect to relay")?;
+            .map_err(|e| e.context("failed to connect to relay"))?;
 
         info!(status = %response.status(), "connected to relay");
         debug!(headers = ?response.headers(), "relay response headers");
@@ -53,22 +500,54 @@ impl RelayConnection {
         let (tx_to_bridge, rx_from_relay) = mpsc::channel::<RelayMessage>(64);
 
         // Send handshake
+        let offered = handshake.capabilities.clone();
+        let connection_id = handshake.connection_id.clone();
         let handshake_msg = ClientMessage::Handshake(handshake);
-        let encoded = handshake_msg.encode()?;
+        let encoded = handshake_msg.encode().map_err(ConnectError::Protocol)?;
+        protocol::trace_frame(|| handshake_msg.trace_summary(encoded.len()));
         ws_sink
             .send(Message::Binary(encoded))
             .await
-            .context("failed to send handshake")?;
+            .map_err(|e| ConnectError::from_websocket(e).context("failed to send handshake"))?;
         info!("sent handshake to relay");
 
+        let (ack, first) = await_handshake_ack(&mut ws_stream, options.handshake_timeout).await?;
+        let capabilities = Capabilities::negotiate(&offered, &ack.map(|ack| ack.capabilities).unwrap_or_default());
+        info!(capabilities = ?capabilities.negotiated, "negotiated data connection capabilities");
+        let mut ws_stream = stream::iter(first.map(Ok)).chain(ws_stream);
+
         // Spawn task to forward messages from bridge to relay
+        let close = CloseHandle::default();
+        let task_close = close.clone();
+        let mut keepalive_timer = options.keepalive.map(|keepalive| keepalive.timer());
+        let mut text_framer = options.text_output.then(TextFramer::default);
         tokio::spawn(async move {
-            while let Some(msg) = rx_from_bridge.recv().await {
+            'send: loop {
+                let msg = tokio::select! {
+                    msg = rx_from_bridge.recv() => match msg {
+                        Some(msg) => msg,
+                        None => break,
+                    },
+                    _ = next_keepalive(&mut keepalive_timer) => {
+                        if let Err(e) = ws_sink.send(Message::Ping(Vec::new())).await {
+                            error!(error = %e, "failed to send keepalive ping to relay");
+                            break;
+                        }
+                        continue;
+                    }
+                };
                 match msg.encode() {
                     Ok(encoded) => {
-                        if let Err(e) = ws_sink.send(Message::Binary(encoded)).await {
-                            error!(error = %e, "failed to send to relay");
-                            break;
+                        protocol::trace_frame(|| msg.trace_summary(encoded.len()));
+                        let frames = match text_framer {
+                            Some(ref mut framer) => framer.frames(&msg, encoded),
+                            None => vec![Message::Binary(encoded)],
+                        };
+                        for frame in frames {
+                            if let Err(e) = ws_sink.send(frame).await {
+                                error!(error = %e, "failed to send to relay");
+                                break 'send;
+                            }
                         }
                     }
                     Err(e) => {
@@ -76,15 +555,15 @@ impl RelayConnection {
                     }
                 }
             }
-            // Channel closed - send a graceful close frame
-            info!("sending graceful close frame on data connection");
-            let close_frame = tokio_tungstenite::tungstenite::protocol::CloseFrame {
-                code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::Normal,
-                reason: "client shutdown".into(),
-            };
-            let _ = ws_sink.send(Message::Close(Some(close_frame))).await;
+            // Channel closed - send anything held back, then a graceful close frame
+            if let Some(frame) = text_framer.as_mut().and_then(TextFramer::flush) {
+                let _ = ws_sink.send(frame).await;
+            }
+            let reason = task_close.get();
+            info!(reason = reason.as_str(), "sending graceful close frame on data connection");
+            let _ = ws_sink.send(Message::Close(Some(close_frame(reason)))).await;
             debug!("relay send task finished");
-        });
+        }.instrument(Span::current()));
 
         // Spawn task to forward messages from relay to bridge
         tokio::spawn(async move {
@@ -93,13 +572,15 @@ impl RelayConnection {
                     Ok(Message::Binary(data)) => {
                         match RelayMessage::parse(&data) {
                             Ok(msg) => {
+                                protocol::trace_frame(|| msg.trace_line(&data));
                                 if tx_to_bridge.send(msg).await.is_err() {
                                     debug!("bridge receiver dropped");
                                     break;
                                 }
                             }
                             Err(e) => {
-                                warn!(error = %e, "failed to parse relay message");
+                                // The frame may be input, so only its size is logged
+                                warn!(error = %e, bytes = data.len(), "failed to parse relay message");
                             }
                         }
                     }
@@ -107,13 +588,14 @@ impl RelayConnection {
                         // Try to parse text as binary (some relays might send text)
                         match RelayMessage::parse(text.as_bytes()) {
                             Ok(msg) => {
+                                protocol::trace_frame(|| msg.trace_line(text.as_bytes()));
                                 if tx_to_bridge.send(msg).await.is_err() {
                                     debug!("bridge receiver dropped");
                                     break;
                                 }
                             }
                             Err(e) => {
-                                warn!(error = %e, "failed to parse relay text message");
+                                warn!(error = %e, bytes = text.len(), "failed to parse relay text message");
                             }
                         }
                     }
@@ -129,8 +611,10 @@ impl RelayConnection {
                         info!(frame = ?frame, "relay closed connection");
                         break;
                     }
-                    Ok(Message::Frame(_)) => {
-                        // Raw frame, ignore
+                    Ok(Message::Frame(frame)) => {
+                        // tungstenite reassembles fragmented messages itself and
+                        // never yields raw frames when reading, so this would be a bug
+                        warn!(header = ?frame.header(), len = frame.len(), "dropping unexpected raw frame from relay");
                     }
                     Err(e) => {
                         error!(error = %e, "websocket error");
@@ -139,14 +623,38 @@ impl RelayConnection {
                 }
             }
             debug!("relay receive task finished");
-        });
+        }.instrument(Span::current()));
 
         Ok(RelayConnection {
             tx: tx_to_relay,
             rx: rx_from_relay,
+            capabilities,
+            close,
+            connection_id,
+            span: Span::current(),
         })
     }
 
+    /// ID sent in this connection's handshake
+    pub fn connection_id(&self) -> &str {
+        &self.connection_id
+    }
+
+    /// Span carrying the connection ID, for logging work done on this connection
+    pub fn span(&self) -> &Span {
+        &self.span
+    }
+
+    /// Handle for choosing the reason in this connection's close frame
+    pub fn close_handle(&self) -> CloseHandle {
+        self.close.clone()
+    }
+
+    /// Features both this client and the relay support
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
     /// Send a message to the relay
     #[allow(dead_code)]
     pub async fn send(&self, msg: ClientMessage) -> Result<()> {
@@ -167,3 +675,486 @@ impl RelayConnection {
         (self.tx, self.rx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Handshake with no size, tty or capabilities
+    fn test_handshake() -> HandshakeMessage {
+        HandshakeMessage {
+            version: "0.1.0".to_string(),
+            shell: "/bin/sh".to_string(),
+            cols: None,
+            rows: None,
+            tty: None,
+            connection_id: String::new(),
+            capabilities: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_should_log_attempt() {
+        let logged: Vec<u32> = (1..=45).filter(|&attempt| should_log_attempt(attempt)).collect();
+        assert_eq!(logged, [1, 2, 3, 4, 5, 10, 20, 30, 40]);
+        assert!(should_log_attempt(u32::MAX - 5));
+        assert!(!should_log_attempt(u32::MAX));
+    }
+
+    #[test]
+    fn test_build_request_default_user_agent() {
+        let url = Url::parse("ws://relay.example/ws/terminal-data/s/1").unwrap();
+        let request = build_request(&url, Some("jwt"), &[]).unwrap();
+        let headers = request.headers();
+        assert_eq!(headers["User-Agent"], default_user_agent().as_str());
+        assert_eq!(headers["Authorization"], "Bearer jwt");
+        assert_eq!(headers["Host"], "relay.example");
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_on_error_ack() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            // Read the handshake, then reject it
+            let _ = ws.next().await;
+            let ack = r#"{"type":"error","error":"unknown session","code":"UNKNOWN_SESSION"}"#;
+            ws.send(Message::Text(ack.to_string())).await.unwrap();
+            let _ = ws.next().await;
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let handshake = test_handshake();
+        let err = RelayConnection::connect(&url, handshake, &ConnectOptions::default()).await.err().expect("connect should fail");
+        assert!(err.to_string().contains("unknown session"), "{}", err);
+        assert!(matches!(err, ConnectError::Protocol(_)), "{:?}", err);
+    }
+
+    /// The error from connecting to `scheme://addr`, where a one-shot server
+    /// answers any request with `reply` and hangs up
+    async fn connect_error(scheme: &str, reply: Option<&'static str>) -> ConnectError {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        match reply {
+            Some(reply) => {
+                tokio::spawn(async move {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let mut buf = [0u8; 1024];
+                    let _ = socket.read(&mut buf).await;
+                    let _ = socket.write_all(reply.as_bytes()).await;
+                });
+            }
+            // Nothing listening on the port any more
+            None => drop(listener),
+        }
+
+        let url = Url::parse(&format!("{}://{}/ws/terminal-data/s/1", scheme, addr)).unwrap();
+        let request = build_request(&url, None, &[]).unwrap();
+        match tokio::time::timeout(Duration::from_secs(5), connect_websocket(request, None)).await.unwrap() {
+            Ok(_) => panic!("connect succeeded"),
+            Err(e) => e,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_error_variants() {
+        let err = connect_error("ws", None).await;
+        assert!(matches!(err, ConnectError::Transport(_)), "{:?}", err);
+        assert!(err.is_retryable());
+
+        let err = connect_error("ws", Some("HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n")).await;
+        assert!(matches!(err, ConnectError::HttpStatus(403)), "{:?}", err);
+        assert!(!err.is_retryable());
+        assert!(err.to_string().contains("HTTP 403"), "{}", err);
+
+        // Not a websocket server at all
+        let err = connect_error("ws", Some("SSH-2.0-OpenSSH_9.6\r\n")).await;
+        assert!(matches!(err, ConnectError::Protocol(_)), "{:?}", err);
+
+        // A plain HTTP server on a wss:// URL
+        let err = connect_error("wss", Some("HTTP/1.1 400 Bad Request\r\n\r\n")).await;
+        assert!(matches!(err, ConnectError::Tls(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_connect_error_is_retryable() {
+        for status in [401, 408, 429, 500, 502, 503] {
+            assert!(ConnectError::HttpStatus(status).is_retryable(), "{}", status);
+        }
+        for status in [400, 403, 404, 410] {
+            assert!(!ConnectError::HttpStatus(status).is_retryable(), "{}", status);
+        }
+        assert!(ConnectError::Tls(anyhow!("bad certificate")).is_retryable());
+        assert!(ConnectError::Protocol(anyhow!("relay rejected handshake")).is_retryable());
+
+        // Context goes in front of the message and keeps the variant
+        let err = ConnectError::Transport(anyhow!("connection refused")).context("failed to connect to relay");
+        assert_eq!(err.to_string(), "failed to connect to relay: connection refused");
+        assert!(matches!(ConnectError::HttpStatus(403).context("ignored"), ConnectError::HttpStatus(403)));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_carries_connection_id() {
+        // Relay that reports the connection ID of each handshake it receives
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (ids_tx, mut ids_rx) = mpsc::channel(2);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let ids_tx = ids_tx.clone();
+                tokio::spawn(async move {
+                    let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    let Some(Ok(Message::Binary(frame))) = ws.next().await else { return };
+                    let handshake: serde_json::Value = serde_json::from_slice(&frame[1..]).unwrap();
+                    ids_tx.send(handshake["connectionId"].as_str().unwrap().to_string()).await.unwrap();
+                    ws.send(Message::Text(r#"{"type":"handshake_ack"}"#.to_string())).await.unwrap();
+                    while let Some(Ok(_)) = ws.next().await {}
+                });
+            }
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let handshake = test_handshake();
+        let conn = RelayConnection::connect(&url, handshake.clone(), &ConnectOptions::default()).await.unwrap();
+        let sent = ids_rx.recv().await.unwrap();
+        assert!(!sent.is_empty());
+        assert_eq!(conn.connection_id(), sent);
+
+        // The ID stays with the connection while it is used
+        conn.send(ClientMessage::Output(b"hi".to_vec())).await.unwrap();
+        assert_eq!(conn.connection_id(), sent);
+
+        // A reconnect is a new connection with its own ID
+        let again = RelayConnection::connect(&url, handshake, &ConnectOptions::default()).await.unwrap();
+        assert_eq!(again.connection_id(), ids_rx.recv().await.unwrap());
+        assert_ne!(again.connection_id(), sent);
+    }
+
+    #[tokio::test]
+    async fn test_connect_negotiates_capabilities() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            let ack = r#"{"type":"handshake_ack","capabilities":["sequence","diff"]}"#;
+            ws.send(Message::Text(ack.to_string())).await.unwrap();
+            let _ = ws.next().await;
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let handshake = HandshakeMessage { capabilities: protocol::capability::supported(), ..test_handshake() };
+        let conn = RelayConnection::connect(&url, handshake, &ConnectOptions::default()).await.unwrap();
+        assert_eq!(conn.capabilities().negotiated, ["sequence"]);
+        assert!(conn.capabilities().sequence);
+        assert!(!conn.capabilities().replay);
+    }
+
+    #[test]
+    fn test_keepalive_first_delays_are_spread() {
+        let keepalive = Keepalive {
+            interval: Duration::from_secs(30),
+            jitter: Duration::from_secs(3),
+        };
+        let mut rng = rand::thread_rng();
+        let delays: Vec<Duration> = (0..20).map(|_| keepalive.first_delay(&mut rng)).collect();
+        for delay in &delays {
+            assert!(*delay >= keepalive.interval && *delay < keepalive.interval + keepalive.jitter, "{:?}", delay);
+        }
+        let distinct: std::collections::HashSet<_> = delays.iter().collect();
+        assert!(distinct.len() > 1, "all terminals would ping together: {:?}", delays);
+
+        let no_jitter = Keepalive { jitter: Duration::ZERO, ..keepalive };
+        assert_eq!(no_jitter.first_delay(&mut rng), keepalive.interval);
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_pings_relay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            loop {
+                match ws.next().await {
+                    Some(Ok(Message::Ping(_))) => return true,
+                    Some(Ok(_)) => continue,
+                    _ => return false,
+                }
+            }
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let handshake = test_handshake();
+        let keepalive = Keepalive {
+            interval: Duration::from_millis(50),
+            jitter: Duration::from_millis(20),
+        };
+        let _conn = RelayConnection::connect(&url, handshake, &ConnectOptions { keepalive: Some(keepalive), ..ConnectOptions::default() }).await.unwrap();
+        assert!(tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_message_is_reassembled() {
+        use tokio_tungstenite::tungstenite::protocol::frame::coding::{Data, OpCode};
+        use tokio_tungstenite::tungstenite::protocol::frame::Frame;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            // An input message split over a binary frame and two continuations
+            let fragments = [
+                Frame::message(b"0hel".to_vec(), OpCode::Data(Data::Binary), false),
+                Frame::message(b"lo, ".to_vec(), OpCode::Data(Data::Continue), false),
+                Frame::message(b"world".to_vec(), OpCode::Data(Data::Continue), true),
+            ];
+            for frame in fragments {
+                ws.send(Message::Frame(frame)).await.unwrap();
+            }
+            let _ = ws.next().await;
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let handshake = test_handshake();
+        let conn = RelayConnection::connect(&url, handshake, &ConnectOptions::default()).await.unwrap();
+        let (_tx, mut rx) = conn.into_receiver();
+        match tokio::time::timeout(Duration::from_secs(5), rx.recv()).await.unwrap() {
+            Some(RelayMessage::Input(data)) => assert_eq!(data, b"hello, world"),
+            other => panic!("expected reassembled input, got {:?}", other),
+        }
+    }
+
+    /// Connect to a relay, close the connection with `reason` set (if any) and
+    /// return the close frame the relay received
+    async fn received_close_frame(reason: Option<CloseReason>) -> (u16, String) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            while let Some(Ok(msg)) = ws.next().await {
+                if let Message::Close(Some(frame)) = msg {
+                    return (u16::from(frame.code), frame.reason.to_string());
+                }
+            }
+            panic!("no close frame");
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let handshake = test_handshake();
+        let conn = RelayConnection::connect(&url, handshake, &ConnectOptions::default()).await.unwrap();
+        if let Some(reason) = reason {
+            conn.close_handle().set(reason);
+        }
+        drop(conn);
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_text_framer() {
+        let mut framer = TextFramer::default();
+        let frames = |framer: &mut TextFramer, msg: ClientMessage| {
+            let encoded = msg.encode().unwrap();
+            framer.frames(&msg, encoded)
+        };
+
+        assert_eq!(frames(&mut framer, ClientMessage::Output(b"hi".to_vec())), [Message::Text("0hi".into())]);
+
+        // "é" split across chunks waits for its second byte
+        let e = "é".as_bytes();
+        assert_eq!(frames(&mut framer, ClientMessage::Output([b"caf", &e[..1]].concat())), [Message::Text("0caf".into())]);
+        assert_eq!(frames(&mut framer, ClientMessage::Output([&e[1..], b"!"].concat())), [Message::Text("0é!".into())]);
+
+        // Not UTF-8 at all
+        assert_eq!(frames(&mut framer, ClientMessage::Output(vec![0xff, b'x'])), [Message::Binary(b"0\xffx".to_vec())]);
+
+        // A held-back partial character is flushed as binary before other messages
+        assert_eq!(frames(&mut framer, ClientMessage::Output(e[..1].to_vec())), []);
+        assert_eq!(
+            frames(&mut framer, ClientMessage::Exit(0)),
+            [Message::Binary([b"0", &e[..1]].concat()), Message::Binary(b"20".to_vec())]
+        );
+        assert_eq!(framer.flush(), None);
+
+        // Sequenced output stays binary, after anything held back
+        assert_eq!(frames(&mut framer, ClientMessage::Output(e[..1].to_vec())), []);
+        let sequenced = ClientMessage::SequencedOutput { seq: 7, data: b"hi".to_vec() };
+        assert_eq!(
+            frames(&mut framer, sequenced),
+            [Message::Binary([b"0", &e[..1]].concat()), Message::Binary(b"4\0\0\0\x07hi".to_vec())]
+        );
+        assert!(framer.warned_sequenced);
+    }
+
+    #[tokio::test]
+    async fn test_text_output_frames() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut frames = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                match msg {
+                    Message::Text(_) | Message::Binary(_) => frames.push(msg),
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+            frames
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let handshake = test_handshake();
+        let conn = RelayConnection::connect(&url, handshake, &ConnectOptions { text_output: true, ..ConnectOptions::default() }).await.unwrap();
+        let (tx, _rx) = conn.into_receiver();
+        tx.send(ClientMessage::Output("héllo".as_bytes().to_vec())).await.unwrap();
+        tx.send(ClientMessage::Output(vec![0x00, 0xfe, 0xff])).await.unwrap();
+        drop(tx);
+
+        let frames = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        // The handshake stays binary
+        assert!(matches!(frames[0], Message::Binary(_)), "{:?}", frames);
+        assert_eq!(frames[1..], [Message::Text("0héllo".into()), Message::Binary(vec![b'0', 0x00, 0xfe, 0xff])]);
+    }
+
+    #[tokio::test]
+    async fn test_close_frame_carries_reason() {
+        assert_eq!(received_close_frame(None).await, (1000, "client shutdown".to_string()));
+        assert_eq!(
+            received_close_frame(Some(CloseReason::TerminalExited)).await,
+            (1000, "terminal exited".to_string())
+        );
+        assert_eq!(
+            received_close_frame(Some(CloseReason::TerminalClosed)).await,
+            (1000, "terminal closed".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_silent_relay_times_out() {
+        // Upgrades the connection, reads the handshake, then never says anything
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let _ = ws.next().await;
+            tokio::time::sleep(Duration::from_secs(30)).await;
+        });
+
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let handshake = test_handshake();
+        let started = std::time::Instant::now();
+        let err = RelayConnection::connect(
+            &url,
+            handshake,
+            &ConnectOptions { handshake_timeout: Some(Duration::from_millis(200)), ..ConnectOptions::default() },
+        )
+            .await
+            .err()
+            .expect("connect should time out");
+        assert!(err.to_string().contains("sent nothing"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_ping_satisfies_handshake_timeout() {
+        let mut ws_stream = stream::iter([Ok::<_, tungstenite::Error>(Message::Ping(Vec::new()))]);
+        let (_, first) = await_handshake_ack(&mut ws_stream, Some(Duration::from_millis(50))).await.unwrap();
+        assert!(matches!(first, Some(Message::Ping(_))));
+    }
+
+    #[test]
+    fn test_build_request_custom_headers() {
+        let url = Url::parse("wss://relay.example/ws/control/s").unwrap();
+        let extra = vec![
+            ("X-Team".to_string(), "infra".to_string()),
+            ("user-agent".to_string(), "custom/1.0".to_string()),
+        ];
+        let request = build_request(&url, None, &extra).unwrap();
+        let headers = request.headers();
+        assert_eq!(headers["X-Team"], "infra");
+        assert_eq!(headers.get_all("User-Agent").iter().count(), 1);
+        assert_eq!(headers["User-Agent"], "custom/1.0");
+        assert!(headers.get("Authorization").is_none());
+    }
+
+    /// Extract the SNI host name from a TLS ClientHello record
+    fn client_hello_sni(record: &[u8]) -> Option<String> {
+        fn take<'a>(buf: &mut &'a [u8], n: usize) -> Option<&'a [u8]> {
+            if buf.len() < n {
+                return None;
+            }
+            let (head, rest) = buf.split_at(n);
+            *buf = rest;
+            Some(head)
+        }
+        fn len16(buf: &mut &[u8]) -> Option<usize> {
+            take(buf, 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+        }
+
+        // Record header, handshake header, version and random
+        let mut buf = record.get(5 + 4 + 2 + 32..)?;
+        let session_id = take(&mut buf, 1)?[0] as usize;
+        take(&mut buf, session_id)?;
+        let cipher_suites = len16(&mut buf)?;
+        take(&mut buf, cipher_suites)?;
+        let compression = take(&mut buf, 1)?[0] as usize;
+        take(&mut buf, compression)?;
+        let extensions_len = len16(&mut buf)?;
+        let mut extensions = take(&mut buf, extensions_len)?;
+        while !extensions.is_empty() {
+            let kind = len16(&mut extensions)?;
+            let len = len16(&mut extensions)?;
+            let mut body = take(&mut extensions, len)?;
+            if kind == 0 {
+                // server_name_list: list length, name type, name length, name
+                take(&mut body, 3)?;
+                let name_len = len16(&mut body)?;
+                return String::from_utf8(take(&mut body, name_len)?.to_vec()).ok();
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_connect_presents_tls_sni_override() {
+        use tokio::io::AsyncReadExt;
+
+        // Stands in for a TLS ingress: records the ClientHello and hangs up
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut record = vec![0u8; 5];
+            socket.read_exact(&mut record).await.unwrap();
+            let len = u16::from_be_bytes([record[3], record[4]]) as usize;
+            record.resize(5 + len, 0);
+            socket.read_exact(&mut record[5..]).await.unwrap();
+            record
+        });
+
+        let url = Url::parse(&format!("wss://{}/ws/terminal-data/s/1", addr)).unwrap();
+        let request = build_request(&url, None, &[]).unwrap();
+        assert_eq!(request.headers()["Host"], "127.0.0.1");
+        let Err(err) = connect_websocket(request, Some("relay.internal.example")).await else {
+            panic!("fake server never completes the handshake");
+        };
+        assert!(err.to_string().contains("relay.internal.example"), "{}", err);
+        assert!(matches!(err, ConnectError::Tls(_)), "{:?}", err);
+
+        let record = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert_eq!(record[0], 0x16, "expected a TLS handshake record");
+        assert_eq!(client_hello_sni(&record).as_deref(), Some("relay.internal.example"));
+    }
+}
@@ -0,0 +1,452 @@
+//! Scripted relay that speaks the data connection protocol, for the
+//! `test-server` binary and the integration tests.
+//!
+//! Scenarios:
+//! - `basic`: resize, run each command (default `pwd` and `ls`), then `exit`
+//! - `pause-resume`: pause output, check nothing arrives, resume and expect
+//!   the buffered output to be flushed
+//! - `snapshot`: request a snapshot and print its dimensions and cursor
+//! - `all`: every step above in one session
+//!
+//! Each scenario prints PASS/FAIL per check, exits the shell when done and
+//! reports the outcome as a [`ScenarioReport`].
+
+use clap::ValueEnum;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use tokio_tungstenite::{accept_async, tungstenite::protocol::Message, WebSocketStream};
+
+/// How long to wait for expected output before failing a check
+const EXPECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to watch for (unwanted) output while paused
+const PAUSE_QUIET_PERIOD: Duration = Duration::from_millis(500);
+
+/// Message prefixes from client (paircoded)
+mod client_prefix {
+    pub const OUTPUT: u8 = b'0';
+    pub const HANDSHAKE: u8 = b'1';
+    pub const EXIT: u8 = b'2';
+    pub const SNAPSHOT: u8 = b'3';
+}
+
+/// Message prefixes to client (from relay/server)
+mod server_prefix {
+    pub const INPUT: u8 = b'0';
+    pub const RESIZE: u8 = b'1';
+    pub const PAUSE: u8 = b'2';
+    pub const RESUME: u8 = b'3';
+    pub const REQUEST_SNAPSHOT: u8 = b'4';
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Scenario {
+    Basic,
+    PauseResume,
+    Snapshot,
+    All,
+}
+
+#[derive(Debug, Deserialize)]
+#[allow(dead_code)]
+struct HandshakeMessage {
+    version: String,
+    shell: String,
+    cols: Option<u16>,
+    rows: Option<u16>,
+    tty: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResizeMessage {
+    cols: u16,
+    rows: u16,
+}
+
+#[derive(Debug, Serialize)]
+struct SnapshotRequest {
+    #[serde(rename = "requestId")]
+    request_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SnapshotMessage {
+    #[serde(rename = "requestId")]
+    request_id: String,
+    screen: String,
+    cols: u16,
+    rows: u16,
+    #[serde(rename = "cursorX")]
+    cursor_x: u16,
+    #[serde(rename = "cursorY")]
+    cursor_y: u16,
+}
+
+/// What to do with each connection
+#[derive(Debug, Clone)]
+pub struct ServerOptions {
+    pub scenario: Scenario,
+    /// Commands for the basic scenario
+    pub commands: Vec<String>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        ServerOptions {
+            scenario: Scenario::Basic,
+            commands: vec!["pwd".to_string(), "ls".to_string()],
+        }
+    }
+}
+
+/// Outcome of one scripted connection
+#[derive(Debug, Default)]
+pub struct ScenarioReport {
+    /// Number of failed checks
+    pub failures: usize,
+    /// Every output byte received from the client
+    pub output: Vec<u8>,
+    /// Exit code reported by the client, if any
+    pub exit_code: Option<i32>,
+}
+
+/// A parsed frame from the client
+#[derive(Debug)]
+enum ClientFrame {
+    Output(Vec<u8>),
+    Handshake(HandshakeMessage),
+    Exit(i32),
+    Snapshot(SnapshotMessage),
+    Other(String),
+}
+
+fn parse_client_frame(data: &[u8]) -> ClientFrame {
+    if data.is_empty() {
+        return ClientFrame::Other("Empty message".to_string());
+    }
+
+    let prefix = data[0];
+    let payload = &data[1..];
+
+    match prefix {
+        client_prefix::OUTPUT => ClientFrame::Output(payload.to_vec()),
+        client_prefix::HANDSHAKE => match serde_json::from_slice::<HandshakeMessage>(payload) {
+            Ok(hs) => ClientFrame::Handshake(hs),
+            Err(e) => ClientFrame::Other(format!("HANDSHAKE (parse error: {}): {:?}", e, payload)),
+        },
+        client_prefix::EXIT => match serde_json::from_slice::<i32>(payload) {
+            Ok(code) => ClientFrame::Exit(code),
+            Err(_) => ClientFrame::Other(format!("EXIT: {:?}", payload)),
+        },
+        client_prefix::SNAPSHOT => match serde_json::from_slice::<SnapshotMessage>(payload) {
+            Ok(snapshot) => ClientFrame::Snapshot(snapshot),
+            Err(e) => ClientFrame::Other(format!("SNAPSHOT (parse error: {}): {:?}", e, payload)),
+        },
+        _ => ClientFrame::Other(format!("UNKNOWN({}): {:?}", prefix, payload)),
+    }
+}
+
+fn describe_frame(frame: &ClientFrame) -> String {
+    match frame {
+        ClientFrame::Output(data) => format!("OUTPUT: {:?}", String::from_utf8_lossy(data)),
+        ClientFrame::Handshake(hs) => format!("HANDSHAKE: {:?}", hs),
+        ClientFrame::Exit(code) => format!("EXIT: code={}", code),
+        ClientFrame::Snapshot(s) => format!(
+            "SNAPSHOT: id={} {}x{} cursor=({}, {}) screen={} bytes (base64)",
+            s.request_id,
+            s.cols,
+            s.rows,
+            s.cursor_x,
+            s.cursor_y,
+            s.screen.len()
+        ),
+        ClientFrame::Other(text) => text.clone(),
+    }
+}
+
+fn create_resize_message(cols: u16, rows: u16) -> Vec<u8> {
+    let resize = ResizeMessage { cols, rows };
+    let json = serde_json::to_vec(&resize).unwrap();
+    let mut msg = Vec::with_capacity(1 + json.len());
+    msg.push(server_prefix::RESIZE);
+    msg.extend_from_slice(&json);
+    msg
+}
+
+fn create_input_message(input: &str) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(1 + input.len());
+    msg.push(server_prefix::INPUT);
+    msg.extend_from_slice(input.as_bytes());
+    msg
+}
+
+fn create_snapshot_request(request_id: &str) -> Vec<u8> {
+    let request = SnapshotRequest { request_id: request_id.to_string() };
+    let json = serde_json::to_vec(&request).unwrap();
+    let mut msg = Vec::with_capacity(1 + json.len());
+    msg.push(server_prefix::REQUEST_SNAPSHOT);
+    msg.extend_from_slice(&json);
+    msg
+}
+
+/// One side of a scripted conversation with a connected client
+struct Session {
+    addr: SocketAddr,
+    sink: SplitSink<WebSocketStream<TcpStream>, Message>,
+    frames: mpsc::UnboundedReceiver<ClientFrame>,
+    report: ScenarioReport,
+}
+
+impl Session {
+    /// Next frame before `deadline`, recording output and exit codes in the report
+    async fn next_frame(&mut self, deadline: Instant) -> Option<ClientFrame> {
+        let frame = timeout(deadline.saturating_duration_since(Instant::now()), self.frames.recv())
+            .await
+            .ok()??;
+        match frame {
+            ClientFrame::Output(ref data) => self.report.output.extend_from_slice(data),
+            ClientFrame::Exit(code) => self.report.exit_code = Some(code),
+            _ => {}
+        }
+        Some(frame)
+    }
+
+    async fn send(&mut self, what: &str, msg: Vec<u8>) -> bool {
+        println!("\n[{}] Sending {}", self.addr, what);
+        if let Err(e) = self.sink.send(Message::Binary(msg)).await {
+            eprintln!("[{}] Failed to send {}: {}", self.addr, what, e);
+            return false;
+        }
+        true
+    }
+
+    fn check(&mut self, name: &str, ok: bool) {
+        if ok {
+            println!("[{}] PASS: {}", self.addr, name);
+        } else {
+            println!("[{}] FAIL: {}", self.addr, name);
+            self.report.failures += 1;
+        }
+    }
+
+    /// Collect output until `needle` appears or `EXPECT_TIMEOUT` passes
+    async fn expect_output(&mut self, needle: &str) -> bool {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        let mut output = Vec::new();
+        loop {
+            if String::from_utf8_lossy(&output).contains(needle) {
+                return true;
+            }
+            match self.next_frame(deadline).await {
+                Some(ClientFrame::Output(data)) => output.extend_from_slice(&data),
+                Some(_) => {}
+                None => return false,
+            }
+        }
+    }
+
+    /// Return all output received within `period`
+    async fn output_within(&mut self, period: Duration) -> Vec<u8> {
+        let deadline = Instant::now() + period;
+        let mut output = Vec::new();
+        while let Some(frame) = self.next_frame(deadline).await {
+            if let ClientFrame::Output(data) = frame {
+                output.extend_from_slice(&data);
+            }
+        }
+        output
+    }
+
+    async fn expect_snapshot(&mut self, request_id: &str) -> Option<SnapshotMessage> {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        loop {
+            match self.next_frame(deadline).await {
+                Some(ClientFrame::Snapshot(s)) if s.request_id == request_id => return Some(s),
+                Some(_) => {}
+                None => return None,
+            }
+        }
+    }
+
+    async fn expect_exit(&mut self) -> Option<i32> {
+        let deadline = Instant::now() + EXPECT_TIMEOUT;
+        loop {
+            match self.next_frame(deadline).await {
+                Some(ClientFrame::Exit(code)) => return Some(code),
+                Some(_) => {}
+                None => return None,
+            }
+        }
+    }
+
+    async fn run_basic(&mut self, commands: &[String]) {
+        for command in commands {
+            if !self.send(&format!("command: {}", command), create_input_message(&format!("{}\n", command))).await {
+                return;
+            }
+            self.output_within(Duration::from_millis(1000)).await;
+        }
+    }
+
+    async fn run_pause_resume(&mut self) {
+        // Drain whatever the shell printed so far
+        self.output_within(Duration::from_millis(200)).await;
+
+        if !self.send("pause", vec![server_prefix::PAUSE]).await {
+            return;
+        }
+        if !self.send("command while paused", create_input_message("echo paused-$((6*7))\n")).await {
+            return;
+        }
+        let leaked = self.output_within(PAUSE_QUIET_PERIOD).await;
+        self.check("no output while paused", leaked.is_empty());
+
+        if !self.send("resume", vec![server_prefix::RESUME]).await {
+            return;
+        }
+        let flushed = self.expect_output("paused-42").await;
+        self.check("buffered output flushed on resume", flushed);
+    }
+
+    async fn run_snapshot(&mut self) {
+        let request_id = "snapshot-1";
+        if !self.send("snapshot request", create_snapshot_request(request_id)).await {
+            return;
+        }
+        match self.expect_snapshot(request_id).await {
+            Some(s) => {
+                println!(
+                    "[{}] Snapshot: {}x{} cursor=({}, {})",
+                    self.addr, s.cols, s.rows, s.cursor_x, s.cursor_y
+                );
+                self.check("snapshot matches resize to 80x20", s.cols == 80 && s.rows == 20);
+            }
+            None => self.check("snapshot received", false),
+        }
+    }
+
+    async fn run(&mut self, options: &ServerOptions) {
+        match options.scenario {
+            Scenario::Basic => self.run_basic(&options.commands).await,
+            Scenario::PauseResume => self.run_pause_resume().await,
+            Scenario::Snapshot => self.run_snapshot().await,
+            Scenario::All => {
+                self.run_basic(&options.commands).await;
+                self.run_pause_resume().await;
+                self.run_snapshot().await;
+            }
+        }
+
+        // Send "exit" to close the shell
+        if self.send("command: exit", create_input_message("exit\n")).await {
+            let exit = self.expect_exit().await;
+            self.check("exit reported", exit.is_some());
+        }
+    }
+}
+
+/// Print every frame from the client and forward it to the scenario
+fn spawn_reader(
+    addr: SocketAddr,
+    mut ws_stream: SplitStream<WebSocketStream<TcpStream>>,
+    frames: mpsc::UnboundedSender<ClientFrame>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(result) = ws_stream.next().await {
+            let data = match result {
+                Ok(Message::Binary(data)) => data,
+                Ok(Message::Text(text)) => text.into_bytes(),
+                Ok(Message::Close(frame)) => {
+                    println!("[{}] Connection closed: {:?}", addr, frame);
+                    break;
+                }
+                Ok(other) => {
+                    println!("[{}] Received: {:?}", addr, other);
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("[{}] Read error: {}", addr, e);
+                    break;
+                }
+            };
+            let frame = parse_client_frame(&data);
+            println!("[{}] Received: {}", addr, describe_frame(&frame));
+            let _ = frames.send(frame);
+        }
+    })
+}
+
+async fn handle_connection(stream: TcpStream, addr: SocketAddr, options: &ServerOptions) -> ScenarioReport {
+    println!("\n[{}] New connection", addr);
+
+    let ws_stream = match accept_async(stream).await {
+        Ok(ws) => ws,
+        Err(e) => {
+            eprintln!("[{}] WebSocket handshake failed: {}", addr, e);
+            return ScenarioReport { failures: 1, ..Default::default() };
+        }
+    };
+
+    println!("[{}] WebSocket connection established", addr);
+
+    let (sink, ws_stream) = ws_stream.split();
+    let (frames_tx, frames) = mpsc::unbounded_channel();
+    let read_task = spawn_reader(addr, ws_stream, frames_tx);
+    let mut session = Session { addr, sink, frames, report: ScenarioReport::default() };
+
+    // Wait for handshake from client
+    println!("[{}] Waiting for handshake...", addr);
+    let handshake = timeout(EXPECT_TIMEOUT, async {
+        while let Some(frame) = session.frames.recv().await {
+            if matches!(frame, ClientFrame::Handshake(_)) {
+                return true;
+            }
+        }
+        false
+    })
+    .await
+    .unwrap_or(false);
+    session.check("handshake received", handshake);
+
+    // Send resize to 80x20
+    if session.send("resize to 80x20", create_resize_message(80, 20)).await {
+        // Wait a bit for shell to initialize
+        sleep(Duration::from_millis(500)).await;
+
+        session.run(options).await;
+    } else {
+        session.report.failures += 1;
+    }
+
+    // Wait for read task to finish
+    let _ = session.sink.close().await;
+    let _ = timeout(EXPECT_TIMEOUT, read_task).await;
+    if session.report.failures == 0 {
+        println!("[{}] Scenario {:?} passed", addr, options.scenario);
+    } else {
+        println!("[{}] Scenario {:?} had {} failure(s)", addr, options.scenario, session.report.failures);
+    }
+    session.report
+}
+
+/// Accept a single connection and run the scripted scenario against it
+pub async fn serve_one(listener: &TcpListener, options: &ServerOptions) -> std::io::Result<ScenarioReport> {
+    let (stream, addr) = listener.accept().await?;
+    Ok(handle_connection(stream, addr, options).await)
+}
+
+/// Run the scenario against every incoming connection until accept fails
+pub async fn run_server(listener: TcpListener, options: ServerOptions) {
+    let options = std::sync::Arc::new(options);
+    while let Ok((stream, addr)) = listener.accept().await {
+        let options = options.clone();
+        tokio::spawn(async move {
+            handle_connection(stream, addr, &options).await;
+        });
+    }
+}
@@ -0,0 +1,108 @@
+//! One instance per session on a machine (`--session-lock`).
+//!
+//! Two paircoded processes on the same session would keep replacing each
+//! other's control connection. With the lock, the second one to start holds
+//! an advisory `flock` on a per-session file in the config directory or
+//! refuses to run. The kernel drops the lock when the holder exits, however
+//! it exits, so a crash never leaves a stale lock behind.
+
+use anyhow::{Context, Result};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use crate::auth;
+
+/// An exclusive lock on a session, held until dropped
+#[derive(Debug)]
+pub struct SessionLock {
+    // Closing the file releases the lock
+    _file: File,
+}
+
+impl SessionLock {
+    /// Lock file for `session` in the config directory
+    pub fn default_path(session: &str) -> Result<PathBuf> {
+        Ok(auth::config_dir()?.join("locks").join(lock_file_name(session)))
+    }
+
+    /// Take the lock at `path`, failing at once if another process holds it
+    #[cfg(unix)]
+    pub fn acquire(path: &Path, session: &str) -> Result<Self> {
+        use std::io::Write;
+        use std::os::fd::AsRawFd;
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        // Not truncated on open: until the lock is ours, the PID in it is the holder's
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("failed to open session lock {}", path.display()))?;
+        // SAFETY: the descriptor stays open for as long as `file` lives
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err).with_context(|| format!("failed to lock {}", path.display()));
+            }
+            let holder = fs::read_to_string(path).ok().and_then(|pid| pid.trim().parse::<u32>().ok());
+            let holder = holder.map(|pid| format!(" (pid {})", pid)).unwrap_or_default();
+            anyhow::bail!(
+                "another paircoded is already running session '{}'{}; stop it or pick a different --session",
+                session,
+                holder
+            );
+        }
+        file.set_len(0)
+            .and_then(|()| writeln!(file, "{}", std::process::id()))
+            .with_context(|| format!("failed to write session lock {}", path.display()))?;
+        Ok(SessionLock { _file: file })
+    }
+
+    /// File locks need flock(), so Unix only
+    #[cfg(not(unix))]
+    pub fn acquire(_path: &Path, _session: &str) -> Result<Self> {
+        anyhow::bail!("--session-lock is only supported on Unix")
+    }
+}
+
+/// File name for a session's lock; characters that can't appear in a file
+/// name are replaced
+fn lock_file_name(session: &str) -> String {
+    let name: String = session
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' })
+        .collect();
+    format!("session-{}.lock", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_file_name() {
+        assert_eq!(lock_file_name("alice-1a2b3c4d"), "session-alice-1a2b3c4d.lock");
+        assert_eq!(lock_file_name("../team/demo"), "session-.._team_demo.lock");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_second_acquire_fails_while_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("locks").join(lock_file_name("demo"));
+        let lock = SessionLock::acquire(&path, "demo").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), format!("{}\n", std::process::id()));
+
+        let err = SessionLock::acquire(&path, "demo").unwrap_err().to_string();
+        assert!(err.contains("already running session 'demo'"), "{}", err);
+        assert!(err.contains(&format!("pid {}", std::process::id())), "{}", err);
+
+        // Released with the first lock
+        drop(lock);
+        SessionLock::acquire(&path, "demo").unwrap();
+    }
+}
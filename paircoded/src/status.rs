@@ -0,0 +1,173 @@
+//! Live status line for interactive runs (`--status`).
+//!
+//! A background task redraws a single line on stdout with the connection state,
+//! active terminal count and traffic counters. The main loop pushes state
+//! changes through a watch channel; counters are read from [`Metrics`].
+
+use std::io::{IsTerminal, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::metrics::Metrics;
+
+/// How often the status line is redrawn when nothing changes
+const REDRAW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Control connection state shown in the status line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Reconnecting,
+}
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Connected => "connected",
+            ConnectionState::Reconnecting => "reconnecting",
+        }
+    }
+}
+
+/// Everything shown in the status line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusState {
+    pub connection: ConnectionState,
+    pub terminals: usize,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub reconnect_attempts: u64,
+}
+
+impl Default for StatusState {
+    fn default() -> Self {
+        StatusState {
+            connection: ConnectionState::Connecting,
+            terminals: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            reconnect_attempts: 0,
+        }
+    }
+}
+
+/// Render the status line text (without terminal control sequences)
+pub fn format_status(state: &StatusState) -> String {
+    format!(
+        "[{}] terminals: {} | in: {} | out: {} | reconnects: {}",
+        state.connection.as_str(),
+        state.terminals,
+        format_bytes(state.bytes_in),
+        format_bytes(state.bytes_out),
+        state.reconnect_attempts,
+    )
+}
+
+/// Human-readable byte count using binary units
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Handle to the status line; a no-op when disabled
+pub struct StatusDisplay {
+    state_tx: watch::Sender<StatusState>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl StatusDisplay {
+    /// Start the redraw loop if `enabled` and stdout is a terminal
+    pub fn new(enabled: bool, metrics: Arc<Metrics>) -> Self {
+        let (state_tx, state_rx) = watch::channel(StatusState::default());
+        let task = (enabled && std::io::stdout().is_terminal())
+            .then(|| tokio::spawn(redraw_loop(state_rx, metrics)));
+        StatusDisplay { state_tx, task }
+    }
+
+    pub fn set_connection(&self, connection: ConnectionState) {
+        self.state_tx.send_modify(|s| s.connection = connection);
+    }
+
+    pub fn set_terminals(&self, terminals: usize) {
+        self.state_tx.send_modify(|s| s.terminals = terminals);
+    }
+
+    /// Stop redrawing and clear the status line
+    pub fn finish(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+            let mut stdout = std::io::stdout();
+            let _ = write!(stdout, "\r\x1b[2K");
+            let _ = stdout.flush();
+        }
+    }
+}
+
+async fn redraw_loop(mut state_rx: watch::Receiver<StatusState>, metrics: Arc<Metrics>) {
+    let mut interval = tokio::time::interval(REDRAW_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            changed = state_rx.changed() => {
+                if changed.is_err() {
+                    return;
+                }
+            }
+        }
+
+        let mut state = state_rx.borrow().clone();
+        state.bytes_in = metrics.bytes_in();
+        state.bytes_out = metrics.bytes_out();
+        state.reconnect_attempts = metrics.reconnect_attempts();
+
+        let mut stdout = std::io::stdout();
+        let _ = write!(stdout, "\r\x1b[2K{}", format_status(&state));
+        let _ = stdout.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_status() {
+        let state = StatusState {
+            connection: ConnectionState::Connected,
+            terminals: 2,
+            bytes_in: 512,
+            bytes_out: 3 * 1024 * 1024 + 512 * 1024,
+            reconnect_attempts: 1,
+        };
+        assert_eq!(
+            format_status(&state),
+            "[connected] terminals: 2 | in: 512 B | out: 3.5 MiB | reconnects: 1"
+        );
+
+        assert_eq!(
+            format_status(&StatusState::default()),
+            "[connecting] terminals: 0 | in: 0 B | out: 0 B | reconnects: 0"
+        );
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1024), "1.0 KiB");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
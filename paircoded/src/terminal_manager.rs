@@ -5,21 +5,70 @@
 
 use anyhow::{anyhow, Context, Result};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::time::Instant;
 use tokio::sync::{mpsc, Mutex, RwLock, oneshot};
-use tracing::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn, Instrument};
 use url::Url;
 
-use crate::bridge::Bridge;
-use crate::protocol::HandshakeMessage;
-use crate::pty::{AsyncPty, PtyHandle};
-use crate::relay::RelayConnection;
+use crate::audit::{self, AuditRecord};
+use crate::bridge::{self, Bridge, BridgeOptions, BridgeRequest};
+use crate::metrics::SpawnTiming;
+use crate::cgroup::ResourceLimits;
+use crate::probe;
+use crate::process_tree;
+use crate::protocol::{capability, ClientMessage, CloseReason, HandshakeMessage, ProcessInfo};
+use crate::pty::{AsyncPty, PtyHandle, SignalHandle, SpawnOptions};
+use crate::relay::{self, should_log_attempt, BackoffStrategy, ConnectOptions, Keepalive, RelayConnection};
 
-/// Shared JWT token that can be updated when refreshed
+/// Default data connection backoff: 1s doubling up to 30s
+pub const DEFAULT_DATA_BACKOFF: BackoffStrategy = BackoffStrategy::Exponential {
+    base: Duration::from_secs(1),
+    max: Duration::from_secs(30),
+};
+
+/// Quick retries of a new terminal's first data connection, made before the
+/// reconnect backoff applies (the relay may not be routing the terminal yet)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuickRetries {
+    /// Retries at `interval` (0 goes straight to the backoff)
+    pub attempts: u32,
+    pub interval: Duration,
+}
+
+/// Default quick retries: 3 at 200ms
+pub const DEFAULT_QUICK_RETRIES: QuickRetries = QuickRetries {
+    attempts: 3,
+    interval: Duration::from_millis(200),
+};
+
+/// Exit code reported for a command killed by `--command-timeout` (as coreutils `timeout`)
+pub const COMMAND_TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// How long a data connection must stay up before the reconnect delay starts over
+const STABLE_CONNECTION: Duration = Duration::from_secs(10);
+
+/// How long after spawning a child is watched for a failure to start
+/// (typically a wrapper such as `bwrap` that could not exec the shell)
+const EXEC_CHECK_WINDOW: Duration = Duration::from_millis(50);
+
+/// How often the child is checked during [`EXEC_CHECK_WINDOW`]
+const EXEC_CHECK_POLL: Duration = Duration::from_millis(5);
+
+/// How long to wait for the output of a child that failed to start
+const EXEC_FAILURE_OUTPUT_WAIT: Duration = Duration::from_millis(100);
+
+/// Shared JWT token that can be updated when refreshed (empty to connect without one)
 pub type SharedToken = Arc<RwLock<String>>;
 
+/// Data websocket URL of a terminal, re-read on every (re)connect so it can be
+/// pointed at a new session
+type SharedUrl = Arc<RwLock<Url>>;
+
 /// Event from a terminal to the manager
 #[derive(Debug)]
 pub enum TerminalEvent {
@@ -29,15 +78,91 @@ pub enum TerminalEvent {
     Disconnected { name: String },
 }
 
+/// Settings applied to every terminal spawned by the manager
+#[derive(Debug, Clone)]
+pub struct TerminalOptions {
+    /// Shell command to spawn
+    pub shell: String,
+    /// Shell arguments
+    pub shell_args: Vec<String>,
+    /// Working directory for spawned terminals
+    pub working_dir: PathBuf,
+    /// Extra environment for every terminal (`--env`/`--env-file`), applied
+    /// before the relay's per-terminal variables
+    pub env: Vec<(String, String)>,
+    /// Whether to sandbox terminals with bubblewrap (Linux only)
+    pub sandboxed: bool,
+    /// CPU and memory caps applied through a cgroup scope (Linux only)
+    pub resource_limits: ResourceLimits,
+    /// Session owner, recorded in the audit log
+    pub username: String,
+    /// Append-only audit log of spawned commands
+    pub audit_log: Option<PathBuf>,
+    /// Running terminals, rewritten whenever one starts or goes away
+    pub state_file: Option<PathBuf>,
+    /// Extra headers for data websocket upgrades
+    pub headers: Vec<(String, String)>,
+    /// Treat a data connection as failed if the relay is silent this long after the handshake
+    pub handshake_timeout: Option<Duration>,
+    /// TLS server name to present instead of the data URL host
+    pub tls_sni: Option<String>,
+    /// Ping the relay on data connections
+    pub keepalive: Option<Keepalive>,
+    /// Send UTF-8 output as text frames (`--text-output`)
+    pub text_output: bool,
+    /// Delay between data connection reconnect attempts
+    pub reconnect: BackoffStrategy,
+    /// Retries of the first data connection made before `reconnect` applies
+    pub quick_retries: QuickRetries,
+    /// Kill the terminal's process after this long (`--command-timeout`)
+    pub command_timeout: Option<Duration>,
+    /// Banner sent to the relay on every data connection, never to the shell (`--motd`)
+    pub motd: Option<String>,
+    /// Path of each data connection, with `{session}` and `{name}` filled in
+    pub data_url_template: String,
+    /// Terminate the PTY instead of reconnecting when the data connection drops
+    pub kill_on_disconnect: bool,
+    /// PTY read errors recovered from by reopening the reader
+    pub max_reader_restarts: u32,
+    /// Initial `(cols, rows)` when the relay doesn't give a size
+    pub default_size: (u16, u16),
+    /// Bridge behavior for each terminal
+    pub bridge: BridgeOptions,
+}
+
 /// Active terminal instance
 struct Terminal {
     /// Name of the terminal
     #[allow(dead_code)]
     name: String,
     /// Handle to send shutdown signal
-    shutdown_tx: Option<oneshot::Sender<()>>,
+    shutdown_tx: Option<oneshot::Sender<CloseReason>>,
     /// Handle to wait for task completion
     join_handle: tokio::task::JoinHandle<()>,
+    /// Where the terminal's data connection goes
+    data_url: SharedUrl,
+    /// Signals the terminal's process without going through its task
+    signal: SignalHandle,
+    /// Requests answered by the terminal's bridge
+    requests: mpsc::Sender<BridgeRequest>,
+    /// Size the terminal was started at
+    cols: u16,
+    rows: u16,
+}
+
+/// A running terminal as recorded in the state file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TerminalState {
+    pub pid: u32,
+    pub data_url: String,
+    pub cols: u16,
+    pub rows: u16,
+}
+
+/// Contents of the state file
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StateFile {
+    terminals: Vec<TerminalState>,
 }
 
 /// Manages multiple named terminals
@@ -46,29 +171,23 @@ pub struct TerminalManager {
     terminals: Arc<Mutex<HashMap<String, Terminal>>>,
     /// Channel to send terminal events to the main loop
     event_tx: mpsc::Sender<TerminalEvent>,
-    /// Base URL for terminal data connections
-    base_url: Url,
-    /// Shell command to spawn
-    shell: String,
-    /// Shell arguments
-    shell_args: Vec<String>,
-    /// Working directory for spawned terminals
-    working_dir: PathBuf,
+    /// Base URL for terminal data connections (scheme, host and port are used)
+    base_url: RwLock<Url>,
+    /// Session the data URLs are built for
+    session: RwLock<String>,
     /// Shared JWT token for authentication
     shared_token: SharedToken,
-    /// Whether to sandbox terminals with bubblewrap (Linux only)
-    sandboxed: bool,
+    /// Settings for spawned terminals
+    options: TerminalOptions,
 }
 
 impl TerminalManager {
     /// Create a new terminal manager
     pub fn new(
         base_url: Url,
-        shell: String,
-        shell_args: Vec<String>,
-        working_dir: PathBuf,
+        session: &str,
         shared_token: SharedToken,
-        sandboxed: bool,
+        options: TerminalOptions,
     ) -> (Self, mpsc::Receiver<TerminalEvent>) {
         let (event_tx, event_rx) = mpsc::channel(64);
 
@@ -76,33 +195,78 @@ impl TerminalManager {
             TerminalManager {
                 terminals: Arc::new(Mutex::new(HashMap::new())),
                 event_tx,
-                base_url,
-                shell,
-                shell_args,
-                working_dir,
+                base_url: RwLock::new(base_url),
+                session: RwLock::new(session.to_string()),
                 shared_token,
-                sandboxed,
+                options,
             },
             event_rx,
         )
     }
 
-    /// Start a new terminal with the given dimensions.
+    /// Start a new terminal with the given dimensions and extra environment.
     /// Returns the terminal name (which is the PID of the spawned process).
+    ///
+    /// A zero dimension means the size isn't known; the terminal then starts at
+    /// [`TerminalOptions::default_size`].
     pub async fn start_terminal(
         &self,
         cols: u16,
         rows: u16,
+        env: &HashMap<String, String>,
     ) -> Result<String> {
+        let requested = std::time::Instant::now();
+
         // Spawn the PTY first to get the PID
-        let shell_args: Vec<&str> = self.shell_args.iter().map(|s| s.as_str()).collect();
-        let pty_handle = PtyHandle::spawn(&self.shell, &shell_args, &self.working_dir, self.sandboxed)
-            .context("failed to spawn PTY")?;
+        let opts = &self.options;
+        let (cols, rows) = if cols == 0 || rows == 0 { opts.default_size } else { (cols, rows) };
+        let shell_args: Vec<&str> = opts.shell_args.iter().map(|s| s.as_str()).collect();
+        let spawn_options = SpawnOptions {
+            shell: opts.shell.clone(),
+            args: opts.shell_args.clone(),
+            working_dir: opts.working_dir.clone(),
+            sandboxed: opts.sandboxed,
+            limits: opts.resource_limits,
+            cols,
+            rows,
+            env: opts.env.iter().cloned().chain(env.clone()).collect(),
+        };
+        let mut pty_handle = PtyHandle::spawn(&spawn_options).context("failed to spawn PTY")?;
+        let timing = SpawnTiming::new(requested, std::time::Instant::now());
 
         // Use the PID as the terminal name
         let pid = pty_handle.process_id()
             .ok_or_else(|| anyhow!("failed to get process ID from PTY"))?;
         let name = pid.to_string();
+        info!(terminal = %name, spawn_ms = timing.spawn_latency().as_millis(), "spawned terminal PTY");
+        opts.bridge.metrics.record_spawn(&timing);
+
+        // Record the spawned command before anything else can fail
+        if let Some(ref path) = opts.audit_log {
+            let command = std::iter::once(opts.shell.as_str())
+                .chain(shell_args.iter().copied())
+                .collect::<Vec<_>>()
+                .join(" ");
+            let record = AuditRecord::now(&opts.username, pid, &opts.working_dir, command);
+            if let Err(e) = audit::append_record(path, &record) {
+                // Refuse to run a command that could not be audited
+                let _ = pty_handle.kill();
+                return Err(e);
+            }
+        }
+
+        // Report a shell that fails straight away as a start failure rather
+        // than a terminal that started and then exited
+        if let Some(status) = exec_failure(&mut pty_handle).await? {
+            let output = exec_failure_output(&pty_handle).await;
+            warn!(shell = %opts.shell, exit_code = status.exit_code(), output = %output, "shell failed to start");
+            let mut message = format!("{} exited immediately with status {}", opts.shell, status.exit_code());
+            if !output.is_empty() {
+                message.push_str(": ");
+                message.push_str(&output);
+            }
+            return Err(anyhow!(message));
+        }
 
         let mut terminals = self.terminals.lock().await;
 
@@ -112,46 +276,50 @@ impl TerminalManager {
         }
 
         // Build data websocket URL
-        let session_id = self.base_url
-            .path_segments()
-            .and_then(|s| s.last())
-            .unwrap_or("unknown");
-
-        let data_url = self.build_data_url(session_id, &name)?;
-
-        // Resize to requested dimensions
-        pty_handle.resize(cols, rows)?;
-
-        let pty = AsyncPty::new(pty_handle)?;
+        let data_url: SharedUrl = {
+            let base_url = self.base_url.read().await;
+            let session = self.session.read().await;
+            Arc::new(RwLock::new(build_data_url(&base_url, &opts.data_url_template, &session, &name)))
+        };
 
         // Create handshake
-        let handshake = HandshakeMessage {
-            version: env!("CARGO_PKG_VERSION").to_string(),
-            shell: self.shell.clone(),
-            cols: Some(cols),
-            rows: Some(rows),
-        };
+        let handshake = build_handshake(&opts.shell, &pty_handle, cols, rows);
+
+        let mut pty = AsyncPty::new(pty_handle)?;
+        pty.set_max_reader_restarts(opts.max_reader_restarts);
+        let signal = pty.signal_handle();
 
         // Create shutdown channel
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let (requests, requests_rx) = mpsc::channel(8);
 
         // Spawn terminal task
         let event_tx = self.event_tx.clone();
         let terminal_name = name.clone();
         let shared_token = self.shared_token.clone();
+        let mut bridge = opts.bridge.clone();
+        bridge.motd = opts.motd.as_deref().map(|text| motd_banner(text, opts.bridge.color));
+        let task_options = TerminalTaskOptions {
+            size: (cols, rows),
+            connect: ConnectOptions {
+                token: None,
+                headers: opts.headers.clone(),
+                handshake_timeout: opts.handshake_timeout,
+                tls_sni: opts.tls_sni.clone(),
+                keepalive: opts.keepalive,
+                text_output: opts.text_output,
+            },
+            reconnect: opts.reconnect,
+            quick_retries: opts.quick_retries,
+            command_timeout: opts.command_timeout,
+            bridge,
+            kill_on_disconnect: opts.kill_on_disconnect,
+        };
+        let task_data_url = data_url.clone();
 
         let join_handle = tokio::spawn(async move {
-            let result = run_terminal_task(
-                terminal_name.clone(),
-                pty,
-                data_url,
-                handshake,
-                shutdown_rx,
-                cols,
-                rows,
-                shared_token,
-            )
-            .await;
+            let context = TerminalTaskContext { shutdown_rx, requests: requests_rx, shared_token, timing };
+            let result = run_terminal_task(terminal_name.clone(), pty, task_data_url, handshake, context, task_options).await;
 
             match result {
                 Ok(exit_code) => {
@@ -180,8 +348,14 @@ impl TerminalManager {
                 name: name.clone(),
                 shutdown_tx: Some(shutdown_tx),
                 join_handle,
+                data_url,
+                signal,
+                requests,
+                cols,
+                rows,
             },
         );
+        self.write_state(&terminals).await;
 
         info!(pid, "New terminal opened (PID {})", pid);
         Ok(name)
@@ -194,8 +368,9 @@ impl TerminalManager {
         if let Some(mut terminal) = terminals.remove(name) {
             // Send shutdown signal (the receiver may be dropped if already exited)
             if let Some(tx) = terminal.shutdown_tx.take() {
-                let _ = tx.send(());
+                let _ = tx.send(CloseReason::TerminalClosed);
             }
+            self.write_state(&terminals).await;
             info!(name = %name, signal = ?signal, "closing terminal");
             Ok(())
         } else {
@@ -203,20 +378,81 @@ impl TerminalManager {
         }
     }
 
-    /// Gracefully shutdown all terminals, waiting for them to close
-    pub async fn shutdown_all(&self) {
+    /// Send a signal to a terminal's process, leaving the terminal open
+    pub async fn signal_terminal(&self, name: &str, signal: i32) -> Result<()> {
+        let handle = self
+            .terminals
+            .lock()
+            .await
+            .get(name)
+            .map(|terminal| terminal.signal.clone())
+            .ok_or_else(|| anyhow!("terminal '{}' not found", name))?;
+        info!(name = %name, signal, "signalling terminal");
+        handle.signal(signal).await
+    }
+
+    /// Ask a terminal for its scrollback, which its bridge sends on the data
+    /// connection (after reconnecting, if it is down)
+    pub async fn request_scrollback(&self, name: &str, request_id: String) -> Result<()> {
+        let requests = self
+            .terminals
+            .lock()
+            .await
+            .get(name)
+            .map(|terminal| terminal.requests.clone())
+            .ok_or_else(|| anyhow!("terminal '{}' not found", name))?;
+        requests
+            .try_send(BridgeRequest::Scrollback { request_id })
+            .map_err(|_| anyhow!("terminal '{}' is not accepting requests", name))
+    }
+
+    /// Processes running under a terminal (descendants of its process only)
+    pub async fn process_tree(&self, name: &str) -> Result<Vec<ProcessInfo>> {
+        if !self.terminals.lock().await.contains_key(name) {
+            return Err(anyhow!("terminal '{}' not found", name));
+        }
+        // Terminals are named after their process ID
+        let pid: u32 = name.parse().with_context(|| format!("terminal '{}' has no process ID", name))?;
+        tokio::task::spawn_blocking(move || process_tree::descendants(pid)).await?
+    }
+
+    /// Resize every active terminal, e.g. after the host terminal changed size
+    pub async fn resize_all(&self, cols: u16, rows: u16) {
+        let mut terminals = self.terminals.lock().await;
+        let mut requests = Vec::with_capacity(terminals.len());
+        for (name, terminal) in terminals.iter_mut() {
+            terminal.cols = cols;
+            terminal.rows = rows;
+            requests.push((name.clone(), terminal.requests.clone()));
+        }
+        self.write_state(&terminals).await;
+        drop(terminals); // Release the lock before awaiting the bridges
+
+        info!(cols, rows, terminals = requests.len(), "resizing all terminals");
+        for (name, requests) in requests {
+            if requests.send(BridgeRequest::Resize { cols, rows }).await.is_err() {
+                warn!(name = %name, "terminal exited before it could be resized");
+            }
+        }
+    }
+
+    /// Gracefully shutdown all terminals, waiting for them to close.
+    ///
+    /// `reason` goes in the close frame of each data connection.
+    pub async fn shutdown_all(&self, reason: CloseReason) {
         let mut terminals = self.terminals.lock().await;
 
         // Send shutdown signal to all terminals
         for (name, terminal) in terminals.iter_mut() {
             if let Some(tx) = terminal.shutdown_tx.take() {
                 info!(name = %name, "sending shutdown signal to terminal");
-                let _ = tx.send(());
+                let _ = tx.send(reason);
             }
         }
 
         // Collect all join handles
         let handles: Vec<_> = terminals.drain().map(|(_, t)| t.join_handle).collect();
+        self.write_state(&terminals).await;
         drop(terminals); // Release the lock before awaiting
 
         // Wait for all tasks to complete (with timeout)
@@ -230,54 +466,338 @@ impl TerminalManager {
         info!("all terminals shut down");
     }
 
+    /// Number of terminals currently tracked
+    pub async fn terminal_count(&self) -> usize {
+        self.terminals.lock().await.len()
+    }
+
     /// Remove a terminal from tracking (called after exit event)
     pub async fn remove_terminal(&self, name: &str) {
         let mut terminals = self.terminals.lock().await;
-        terminals.remove(name);
+        if terminals.remove(name).is_some() {
+            self.write_state(&terminals).await;
+        }
+    }
+
+    /// Write the running terminals to the state file (if configured)
+    pub async fn persist_state(&self) -> Result<()> {
+        let Some(ref path) = self.options.state_file else {
+            return Ok(());
+        };
+        let terminals = self.terminals.lock().await;
+        save_state(path, &terminals).await
+    }
+
+    /// Rewrite the state file while `terminals` is locked; failures only warn
+    async fn write_state(&self, terminals: &HashMap<String, Terminal>) {
+        if let Some(ref path) = self.options.state_file {
+            if let Err(e) = save_state(path, terminals).await {
+                warn!(error = %e, "failed to write state file");
+            }
+        }
+    }
+
+    /// Read the terminals recorded in a state file (none if it doesn't exist)
+    pub fn load_state(path: &Path) -> Result<Vec<TerminalState>> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let state: StateFile = serde_json::from_str(&contents)
+            .with_context(|| format!("invalid state file {}", path.display()))?;
+        Ok(state.terminals)
+    }
+
+    /// Point the manager and every active terminal at a new session.
+    ///
+    /// New terminals use the new session right away; running terminals keep
+    /// their current data connection and switch on their next reconnect.
+    pub async fn rebuild_data_urls(&self, new_session: &str) -> Result<()> {
+        if new_session.is_empty() {
+            return Err(anyhow!("session name cannot be empty"));
+        }
+        *self.session.write().await = new_session.to_string();
+
+        let base_url = self.base_url.read().await;
+        let terminals = self.terminals.lock().await;
+        for (name, terminal) in terminals.iter() {
+            let url = build_data_url(&base_url, &self.options.data_url_template, new_session, name);
+            info!(terminal = %name, url = %url, "rebuilt data URL");
+            *terminal.data_url.write().await = url;
+        }
+        self.write_state(&terminals).await;
+        Ok(())
+    }
+
+    /// Current data websocket URL of a terminal
+    pub async fn data_url(&self, name: &str) -> Option<Url> {
+        let terminals = self.terminals.lock().await;
+        let terminal = terminals.get(name)?;
+        let url = terminal.data_url.read().await.clone();
+        Some(url)
+    }
+}
+
+async fn save_state(path: &Path, terminals: &HashMap<String, Terminal>) -> Result<()> {
+    let mut state = StateFile::default();
+    for (name, terminal) in terminals {
+        state.terminals.push(TerminalState {
+            pid: name.parse().context("terminal name is not a PID")?,
+            data_url: terminal.data_url.read().await.to_string(),
+            cols: terminal.cols,
+            rows: terminal.rows,
+        });
+    }
+    state.terminals.sort_by_key(|terminal| terminal.pid);
+    probe::write_atomic(path, &serde_json::to_string_pretty(&state)?)
+}
+
+/// Whether a process with this PID exists
+#[cfg(unix)]
+pub fn is_process_alive(pid: u32) -> bool {
+    // SAFETY: signal 0 only checks for existence and permission
+    let alive = unsafe { libc::kill(pid as libc::pid_t, 0) } == 0;
+    alive || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Processes can't be probed; assume the recorded terminal is gone
+#[cfg(not(unix))]
+pub fn is_process_alive(_pid: u32) -> bool {
+    false
+}
+
+/// Failure status of a child that never really started: it exits within
+/// [`EXEC_CHECK_WINDOW`] of spawning with 126/127 (the shell couldn't run the
+/// command), or with any failure status before writing anything.
+///
+/// Other quick exits, such as a `--command` that fails with output, are left to
+/// the terminal so viewers see its output and exit code.
+async fn exec_failure(pty: &mut PtyHandle) -> Result<Option<portable_pty::ExitStatus>> {
+    let deadline = Instant::now() + EXEC_CHECK_WINDOW;
+    loop {
+        if let Some(status) = pty.try_wait()? {
+            let failed = matches!(status.exit_code(), 126 | 127) || (!status.success() && !pty.has_unread_output());
+            return Ok(Some(status).filter(|_| failed));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        tokio::time::sleep(EXEC_CHECK_POLL).await;
+    }
+}
+
+/// Whatever a child that failed to start printed (e.g. the exec error), trimmed
+async fn exec_failure_output(pty: &PtyHandle) -> String {
+    let Ok(mut reader) = pty.try_clone_reader() else { return String::new() };
+    let read = tokio::task::spawn_blocking(move || {
+        let mut buf = [0u8; 4096];
+        let n = reader.read(&mut buf).unwrap_or(0);
+        String::from_utf8_lossy(&buf[..n]).trim().to_string()
+    });
+    match tokio::time::timeout(EXEC_FAILURE_OUTPUT_WAIT, read).await {
+        Ok(Ok(output)) => output,
+        _ => String::new(),
     }
+}
+
+/// Build the data websocket URL for a terminal
+fn build_data_url(base_url: &Url, template: &str, session_id: &str, terminal_name: &str) -> Url {
+    // Start from base URL and replace path
+    let mut url = base_url.clone();
+    url.set_path(&relay::fill_path_template(template, session_id, terminal_name));
+    url
+}
+
+/// Handshake sent on each data connection for a terminal
+fn build_handshake(shell: &str, pty: &PtyHandle, cols: u16, rows: u16) -> HandshakeMessage {
+    HandshakeMessage {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        shell: shell.to_string(),
+        cols: Some(cols),
+        rows: Some(rows),
+        tty: pty.tty_name().map(str::to_string),
+        capabilities: capability::supported(),
+        // Filled in for each connection by `RelayConnection::connect`
+        connection_id: String::new(),
+    }
+}
+
+/// Whether a bridge result means the PTY should be terminated rather than reconnected
+///
+/// Only a clean disconnect with the PTY still alive (`Ok(None)`) qualifies.
+fn should_kill_on_disconnect(kill_on_disconnect: bool, result: &Result<Option<i32>>) -> bool {
+    kill_on_disconnect && matches!(result, Ok(None))
+}
+
+/// Reconnect attempt count after a connection that stayed up for `uptime`.
+///
+/// Only a connection that lasted [`STABLE_CONNECTION`] starts the backoff over;
+/// one that drops right after connecting keeps escalating, so a flapping relay
+/// isn't hammered once a second.
+fn reconnect_attempt_after(attempt: u32, uptime: Duration) -> u32 {
+    if uptime >= STABLE_CONNECTION {
+        0
+    } else {
+        attempt
+    }
+}
+
+/// Banner for `--motd` on its own line, in reverse video if `color` is on
+fn motd_banner(text: &str, color: bool) -> Vec<u8> {
+    // Padded so the reverse video reads as a bar; plain text needs none
+    let text = if color { format!(" {} ", text) } else { text.to_string() };
+    format!("{}\r\n", bridge::styled(&text, "7", color)).into_bytes()
+}
+
+/// Terminate a command that ran past `--command-timeout`
+async fn kill_timed_out_command(name: &str, bridge: &Bridge) -> i32 {
+    warn!(terminal = %name, "command timed out, terminating it (--command-timeout)");
+    let exit_code = bridge.terminate_pty().await;
+    info!(terminal = %name, exit_code, "timed out command exited");
+    COMMAND_TIMEOUT_EXIT_CODE
+}
+
+/// How one terminal's task connects and runs (see [`run_terminal_task`])
+#[derive(Clone)]
+struct TerminalTaskOptions {
+    /// Size the PTY was opened at
+    size: (u16, u16),
+    /// How each data connection is made; the token is read again for every attempt
+    connect: ConnectOptions,
+    /// Backoff between data connection attempts
+    reconnect: BackoffStrategy,
+    /// Fast retries for the first data connection, before the backoff applies
+    quick_retries: QuickRetries,
+    /// Kill the process once it has run this long (`--command-timeout`)
+    command_timeout: Option<Duration>,
+    bridge: BridgeOptions,
+    /// Kill the process when the data connection is lost instead of reconnecting
+    kill_on_disconnect: bool,
+}
 
-    /// Build the data websocket URL for a terminal
-    fn build_data_url(&self, session_id: &str, terminal_name: &str) -> Result<Url> {
-        // Start from base URL and replace path
-        let mut url = self.base_url.clone();
-        url.set_path(&format!("/ws/terminal-data/{}/{}", session_id, terminal_name));
-        Ok(url)
+impl Default for TerminalTaskOptions {
+    fn default() -> Self {
+        TerminalTaskOptions {
+            size: crate::pty::DEFAULT_TERMINAL_SIZE,
+            connect: ConnectOptions::default(),
+            reconnect: DEFAULT_DATA_BACKOFF,
+            quick_retries: DEFAULT_QUICK_RETRIES,
+            command_timeout: None,
+            bridge: BridgeOptions::default(),
+            kill_on_disconnect: false,
+        }
     }
 }
 
+/// What links a terminal's task to the manager that started it (see [`run_terminal_task`])
+struct TerminalTaskContext {
+    /// Ends the task, closing the data connection with the reason sent
+    shutdown_rx: oneshot::Receiver<CloseReason>,
+    /// Requests from the control connection for this terminal's bridge
+    requests: mpsc::Receiver<BridgeRequest>,
+    /// Relay token, read again for every connection attempt
+    shared_token: SharedToken,
+    /// Startup timing, reported once the first data connection is up
+    timing: SpawnTiming,
+}
+
 /// Run a terminal's bridge loop with reconnection support
 async fn run_terminal_task(
     name: String,
     pty: AsyncPty,
-    data_url: Url,
-    handshake: HandshakeMessage,
-    mut shutdown_rx: oneshot::Receiver<()>,
-    cols: u16,
-    rows: u16,
-    shared_token: SharedToken,
+    data_url: SharedUrl,
+    mut handshake: HandshakeMessage,
+    context: TerminalTaskContext,
+    options: TerminalTaskOptions,
 ) -> Result<i32> {
-    let mut bridge = Bridge::new(pty, cols, rows).await?;
-    let mut reconnect_delay = Duration::from_secs(1);
-    let max_reconnect_delay = Duration::from_secs(30);
+    let TerminalTaskContext { mut shutdown_rx, requests, shared_token, timing } = context;
+    let TerminalTaskOptions {
+        size: (cols, rows),
+        connect: mut connect_options,
+        reconnect,
+        quick_retries,
+        command_timeout,
+        bridge: bridge_options,
+        kill_on_disconnect,
+    } = options;
+    let metrics = bridge_options.metrics.clone();
+    // Reported once, for the first data connection
+    let mut timing = Some(timing);
+    let mut bridge = match Bridge::new(pty, cols, rows, bridge_options).await {
+        Ok(bridge) => bridge,
+        Err(e) => {
+            // Reconnecting can't fix this; report the terminal as exited instead
+            error!(terminal = %name, error = %e, "terminal bridge setup failed");
+            return Ok(e.exit_code);
+        }
+    };
+    bridge.set_requests(requests);
+    let mut reconnect_attempt = 0u32;
+    let mut quick_attempts = 0u32;
+    // Connects failed in a row, which decides what gets logged during an outage
+    let mut failed_attempts = 0u32;
+
+    // The timeout runs across reconnects; without one the branch is disabled
+    let command_deadline = tokio::time::sleep(command_timeout.unwrap_or_default());
+    tokio::pin!(command_deadline);
 
     loop {
-        // Get the current token for this connection attempt
+        // Get the current token and URL for this connection attempt
         let token = shared_token.read().await.clone();
+        let data_url = data_url.read().await.clone();
+
+        // A reconnecting relay learns the size the PTY is at now, not the one it started with
+        let (cols, rows) = bridge.size();
+        handshake.cols = Some(cols);
+        handshake.rows = Some(rows);
 
-        // Connect to data websocket
-        info!(terminal = %name, url = %data_url, "connecting to data websocket");
+        // Connect to data websocket; past the first few retries, attempts are mostly logged at debug
+        let logged = should_log_attempt(failed_attempts + 1);
+        if logged {
+            info!(terminal = %name, url = %data_url, "connecting to data websocket");
+        } else {
+            debug!(terminal = %name, url = %data_url, attempt = failed_attempts + 1, "connecting to data websocket");
+        }
 
-        match RelayConnection::connect(&data_url, handshake.clone(), Some(&token)).await {
+        connect_options.token = Some(token).filter(|token| !token.is_empty());
+        match RelayConnection::connect(&data_url, handshake.clone(), &connect_options).await {
             Ok(conn) => {
-                reconnect_delay = Duration::from_secs(1); // Reset on successful connection
+                let connected_at = Instant::now();
+                if failed_attempts > 0 {
+                    info!(terminal = %name, failed_attempts, "data connection restored");
+                    failed_attempts = 0;
+                }
+                if let Some(mut timing) = timing.take() {
+                    timing.connected(std::time::Instant::now());
+                    info!(
+                        terminal = %name,
+                        spawn_ms = timing.spawn_latency().as_millis(),
+                        connect_ms = timing.connect_latency().unwrap_or_default().as_millis(),
+                        ready_ms = timing.ready_latency().unwrap_or_default().as_millis(),
+                        "terminal ready"
+                    );
+                    metrics.record_spawn(&timing);
+                }
+                let close = conn.close_handle();
+                let span = conn.span().clone();
+                bridge.set_capabilities(conn.capabilities());
                 let (tx, rx) = conn.into_receiver();
+                // Keeps the connection open until the close reason is set below
+                let relay_tx = tx.clone();
 
                 // Run bridge with shutdown signal
                 tokio::select! {
-                    result = bridge.run(tx, rx) => {
+                    result = bridge.run(tx, rx).instrument(span) => {
+                        if should_kill_on_disconnect(kill_on_disconnect, &result) {
+                            warn!(terminal = %name, "data connection lost, terminating PTY (--kill-on-disconnect)");
+                            return Ok(bridge.terminate_pty().await);
+                        }
+                        reconnect_attempt = reconnect_attempt_after(reconnect_attempt, connected_at.elapsed());
                         match result {
                             Ok(Some(exit_code)) => {
                                 info!(terminal = %name, exit_code, "terminal PTY exited");
+                                close.set(CloseReason::TerminalExited);
                                 return Ok(exit_code);
                             }
                             Ok(None) => {
@@ -292,14 +812,34 @@ async fn run_terminal_task(
                         }
                     }
 
-                    _ = &mut shutdown_rx => {
+                    reason = &mut shutdown_rx => {
                         info!(terminal = %name, "terminal shutdown requested");
+                        close.set(reason.unwrap_or_default());
                         return Ok(0);
                     }
+
+                    _ = &mut command_deadline, if command_timeout.is_some() => {
+                        let exit_code = kill_timed_out_command(&name, &bridge).await;
+                        if relay_tx.send(ClientMessage::Exit(exit_code)).await.is_err() {
+                            warn!(terminal = %name, "data connection lost before timeout exit");
+                        }
+                        close.set(CloseReason::TerminalExited);
+                        return Ok(exit_code);
+                    }
                 }
             }
+            Err(e) if !e.is_retryable() => {
+                // Retrying would only be refused again, and no one can reach this PTY
+                error!(terminal = %name, error = %e, "relay refused the data connection, terminating PTY");
+                return Ok(bridge.terminate_pty().await);
+            }
             Err(e) => {
-                error!(terminal = %name, error = %e, "failed to connect to data websocket");
+                failed_attempts = failed_attempts.saturating_add(1);
+                if logged {
+                    error!(terminal = %name, error = %e, attempt = failed_attempts, "failed to connect to data websocket");
+                } else {
+                    debug!(terminal = %name, error = %e, attempt = failed_attempts, "failed to connect to data websocket");
+                }
             }
         }
 
@@ -309,18 +849,629 @@ async fn run_terminal_task(
             return Ok(1);
         }
 
-        // Wait before reconnecting
-        info!(terminal = %name, delay_ms = reconnect_delay.as_millis(), "waiting before reconnect");
+        // Wait before reconnecting. A terminal that never connected gets its
+        // quick retries first, which don't count towards the backoff
+        let quick = timing.is_some() && quick_attempts < quick_retries.attempts;
+        let reconnect_delay = if quick {
+            quick_attempts += 1;
+            quick_retries.interval
+        } else {
+            reconnect.delay(reconnect_attempt)
+        };
+        if logged {
+            info!(terminal = %name, delay_ms = reconnect_delay.as_millis(), "waiting before reconnect");
+        } else {
+            debug!(terminal = %name, delay_ms = reconnect_delay.as_millis(), "waiting before reconnect");
+        }
+
+        let reconnect_wait = tokio::time::sleep(reconnect_delay);
+        tokio::pin!(reconnect_wait);
+        loop {
+            tokio::select! {
+                _ = &mut reconnect_wait => {
+                    // The next attempt waits longer (exponential backoff)
+                    if !quick {
+                        reconnect_attempt = reconnect_attempt.saturating_add(1);
+                    }
+                    break;
+                }
+                _ = &mut shutdown_rx => {
+                    info!(terminal = %name, "terminal shutdown requested during reconnect wait");
+                    return Ok(0);
+                }
+                _ = &mut command_deadline, if command_timeout.is_some() => {
+                    return Ok(kill_timed_out_command(&name, &bridge).await);
+                }
+                // Resizes can't wait for the relay to come back
+                Some(request) = bridge.next_request() => {
+                    bridge.handle_request_disconnected(request).await;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Task context with no token and no control requests
+    fn test_context(shutdown_rx: oneshot::Receiver<CloseReason>) -> TerminalTaskContext {
+        let now = std::time::Instant::now();
+        TerminalTaskContext {
+            shutdown_rx,
+            requests: mpsc::channel(1).1,
+            shared_token: Arc::new(RwLock::new(String::new())),
+            timing: SpawnTiming::new(now, now),
+        }
+    }
+
+    fn test_options(audit_log: Option<PathBuf>) -> TerminalOptions {
+        TerminalOptions {
+            shell: "/bin/sh".to_string(),
+            shell_args: vec!["-c".to_string(), "sleep 1".to_string()],
+            working_dir: std::env::temp_dir(),
+            env: Vec::new(),
+            sandboxed: false,
+            resource_limits: ResourceLimits::default(),
+            username: "testuser".to_string(),
+            audit_log,
+            state_file: None,
+            headers: Vec::new(),
+            handshake_timeout: None,
+            tls_sni: None,
+            keepalive: None,
+            text_output: false,
+            reconnect: DEFAULT_DATA_BACKOFF,
+            quick_retries: DEFAULT_QUICK_RETRIES,
+            motd: None,
+            command_timeout: None,
+            data_url_template: relay::DEFAULT_DATA_URL_TEMPLATE.to_string(),
+            kill_on_disconnect: false,
+            max_reader_restarts: crate::pty::DEFAULT_MAX_READER_RESTARTS,
+            default_size: crate::pty::DEFAULT_TERMINAL_SIZE,
+            bridge: BridgeOptions::default(),
+        }
+    }
+
+    fn test_manager(options: TerminalOptions) -> (TerminalManager, mpsc::Receiver<TerminalEvent>) {
+        // Nothing listens on port 1, so data connections fail and retry in the background
+        let base_url = Url::parse("ws://127.0.0.1:1/ws/control/test-session").unwrap();
+        let token: SharedToken = Arc::new(RwLock::new(String::new()));
+        TerminalManager::new(base_url, "test-session", token, options)
+    }
+
+    #[tokio::test]
+    async fn test_start_terminal_appends_audit_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_path = dir.path().join("audit.jsonl");
+        let (manager, _events) = test_manager(test_options(Some(audit_path.clone())));
+
+        let name = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
+        manager.shutdown_all(CloseReason::Shutdown).await;
+
+        let content = std::fs::read_to_string(&audit_path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 1);
+
+        let record: AuditRecord = serde_json::from_str(lines[0]).unwrap();
+        assert_eq!(record.pid.to_string(), name);
+        assert_eq!(record.user, "testuser");
+        assert_eq!(record.command, "/bin/sh -c sleep 1");
+        assert!(record.timestamp > 0);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_size_uses_default_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut options = test_options(None);
+        options.state_file = Some(state_path.clone());
+        options.default_size = (132, 43);
+        let (manager, _events) = test_manager(options);
+
+        manager.start_terminal(0, 0, &HashMap::new()).await.unwrap();
+        let state = TerminalManager::load_state(&state_path).unwrap();
+        assert_eq!((state[0].cols, state[0].rows), (132, 43));
+        manager.shutdown_all(CloseReason::Shutdown).await;
+    }
+
+    #[tokio::test]
+    async fn test_state_file_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_path = dir.path().join("state.json");
+        let mut options = test_options(None);
+        options.state_file = Some(state_path.clone());
+        let (manager, _events) = test_manager(options);
+        assert!(TerminalManager::load_state(&state_path).unwrap().is_empty());
+
+        let first = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
+        let second = manager.start_terminal(120, 40, &HashMap::new()).await.unwrap();
+        let mut state = TerminalManager::load_state(&state_path).unwrap();
+        state.sort_by_key(|t| t.cols);
+        assert_eq!(
+            state,
+            [
+                TerminalState {
+                    pid: first.parse().unwrap(),
+                    data_url: format!("ws://127.0.0.1:1/ws/terminal-data/test-session/{}", first),
+                    cols: 80,
+                    rows: 24,
+                },
+                TerminalState {
+                    pid: second.parse().unwrap(),
+                    data_url: format!("ws://127.0.0.1:1/ws/terminal-data/test-session/{}", second),
+                    cols: 120,
+                    rows: 40,
+                },
+            ]
+        );
+        assert!(state.iter().all(|t| is_process_alive(t.pid)));
+
+        manager.remove_terminal(&first).await;
+        let state = TerminalManager::load_state(&state_path).unwrap();
+        assert_eq!(state.iter().map(|t| t.pid.to_string()).collect::<Vec<_>>(), [second]);
+
+        manager.shutdown_all(CloseReason::Shutdown).await;
+        assert!(TerminalManager::load_state(&state_path).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_shell_exec_failure_is_start_error() {
+        let mut options = test_options(None);
+        options.shell_args = vec!["-c".to_string(), "exec /nonexistent/shell".to_string()];
+        let (manager, _events) = test_manager(options);
+
+        let err = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("exited immediately with status 127"), "{}", message);
+        assert!(message.contains("/nonexistent/shell"), "{}", message);
+        assert_eq!(manager.terminal_count().await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_quick_failure_with_output_starts_terminal() {
+        // A command that fails fast but says why is a terminal, not a start failure
+        let mut options = test_options(None);
+        options.shell_args = vec!["-c".to_string(), "echo no match; exit 1".to_string()];
+        let (manager, _events) = test_manager(options);
+        manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
+        assert_eq!(manager.terminal_count().await, 1);
+        manager.shutdown_all(CloseReason::Shutdown).await;
+
+        // One that fails without a word never really started
+        let mut options = test_options(None);
+        options.shell_args = vec!["-c".to_string(), "exit 1".to_string()];
+        let (manager, _events) = test_manager(options);
+        let err = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap_err();
+        assert!(err.to_string().contains("exited immediately with status 1"), "{}", err);
+    }
+
+    #[test]
+    fn test_data_url_template() {
+        let base_url = Url::parse("wss://relay.example:8443/team/s1/control").unwrap();
+        let url = build_data_url(&base_url, "/team/{session}/data/{name}", "s1", "42");
+        assert_eq!(url.as_str(), "wss://relay.example:8443/team/s1/data/42");
+        let url = build_data_url(&base_url, relay::DEFAULT_DATA_URL_TEMPLATE, "s1", "42");
+        assert_eq!(url.as_str(), "wss://relay.example:8443/ws/terminal-data/s1/42");
+    }
+
+    #[tokio::test]
+    async fn test_rebuild_data_urls() {
+        let (manager, _events) = test_manager(test_options(None));
+        let first = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
+        assert_eq!(
+            manager.data_url(&first).await.unwrap().as_str(),
+            format!("ws://127.0.0.1:1/ws/terminal-data/test-session/{}", first)
+        );
+
+        manager.rebuild_data_urls("rotated").await.unwrap();
+        assert_eq!(
+            manager.data_url(&first).await.unwrap().as_str(),
+            format!("ws://127.0.0.1:1/ws/terminal-data/rotated/{}", first)
+        );
+
+        // Terminals started afterwards use the new session too
+        let second = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
+        assert_eq!(
+            manager.data_url(&second).await.unwrap().as_str(),
+            format!("ws://127.0.0.1:1/ws/terminal-data/rotated/{}", second)
+        );
+        assert_eq!(*manager.session.read().await, "rotated");
+        manager.shutdown_all(CloseReason::Shutdown).await;
+    }
+
+    /// Wait for a process to reach `state` in /proc/<pid>/stat
+    #[cfg(target_os = "linux")]
+    async fn wait_for_process_state(pid: &str, state: char) {
+        let path = format!("/proc/{}/stat", pid);
+        for _ in 0..100 {
+            let stat = std::fs::read_to_string(&path).unwrap();
+            // The state follows the parenthesised command name
+            let current = stat.rsplit(')').next().unwrap().trim_start().chars().next();
+            if current == Some(state) {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("process {} never reached state {}", pid, state);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_signal_terminal_stops_and_continues() {
+        let mut options = test_options(None);
+        options.shell_args = vec!["-c".to_string(), "sleep 30".to_string()];
+        let (manager, _events) = test_manager(options);
+        let name = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
+
+        manager.signal_terminal(&name, libc::SIGSTOP).await.unwrap();
+        wait_for_process_state(&name, 'T').await;
+        manager.signal_terminal(&name, libc::SIGCONT).await.unwrap();
+        wait_for_process_state(&name, 'S').await;
+        assert_eq!(manager.terminal_count().await, 1);
+
+        let err = manager.signal_terminal("0", libc::SIGCONT).await.unwrap_err();
+        assert!(err.to_string().contains("not found"), "{}", err);
+        manager.shutdown_all(CloseReason::Shutdown).await;
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_process_tree_lists_subprocess() {
+        let mut options = test_options(None);
+        options.shell_args = vec!["-c".to_string(), "sleep 31 & wait".to_string()];
+        let (manager, _events) = test_manager(options);
+        let name = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
 
-        tokio::select! {
-            _ = tokio::time::sleep(reconnect_delay) => {
-                // Increase delay for next attempt (exponential backoff)
-                reconnect_delay = std::cmp::min(reconnect_delay * 2, max_reconnect_delay);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let processes = loop {
+            let processes = manager.process_tree(&name).await.unwrap();
+            if !processes.is_empty() || Instant::now() > deadline {
+                break processes;
             }
-            _ = &mut shutdown_rx => {
-                info!(terminal = %name, "terminal shutdown requested during reconnect wait");
-                return Ok(0);
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        };
+        let sleep = processes.iter().find(|p| p.command == "sleep 31").expect("sleep not in tree");
+        assert_eq!(sleep.ppid.to_string(), name);
+        // Nothing outside the terminal is reported
+        assert!(processes.iter().all(|p| p.pid != std::process::id()));
+
+        let err = manager.process_tree("0").await.unwrap_err();
+        assert!(err.to_string().contains("not found"), "{}", err);
+        manager.shutdown_all(CloseReason::Shutdown).await;
+    }
+
+    #[tokio::test]
+    async fn test_resize_all_reaches_every_terminal() {
+        // Each shell writes its size to a file named after its PID when resized
+        let dir = tempfile::tempdir().unwrap();
+        let script = format!("trap 'stty size > {}/$$' WINCH; while :; do sleep 0.05; done", dir.path().display());
+        let mut options = test_options(None);
+        options.shell_args = vec!["-c".to_string(), script];
+        let (manager, _events) = test_manager(options);
+        let first = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
+        let second = manager.start_terminal(100, 30, &HashMap::new()).await.unwrap();
+
+        manager.resize_all(132, 43).await;
+
+        for name in [&first, &second] {
+            let path = dir.path().join(name);
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while std::fs::read_to_string(&path).map_or(true, |size| size.trim().is_empty()) {
+                assert!(Instant::now() < deadline, "terminal {} never saw the resize", name);
+                tokio::time::sleep(Duration::from_millis(20)).await;
             }
+            assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), "43 132");
         }
+        manager.shutdown_all(CloseReason::Shutdown).await;
+    }
+
+    #[tokio::test]
+    async fn test_env_file_reaches_terminal() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join("terminal.env");
+        std::fs::write(&env_path, "# shared\nPROJECT=web\nGREETING=\"hello world\"\nREGION=eu\n").unwrap();
+        let out = dir.path().join("out");
+        let mut options = test_options(None);
+        options.shell_args = vec!["-c".to_string(), format!("echo \"$PROJECT/$GREETING/$REGION\" > {}", out.display())];
+        options.env = crate::env_file::load(&env_path).unwrap();
+        let (manager, _events) = test_manager(options);
+
+        // The relay's per-terminal variables win over the file
+        let relay_env = HashMap::from([("REGION".to_string(), "us".to_string())]);
+        manager.start_terminal(80, 24, &relay_env).await.unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while std::fs::read_to_string(&out).map_or(true, |line| !line.ends_with('\n')) {
+            assert!(Instant::now() < deadline, "terminal never wrote its environment");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "web/hello world/us\n");
+        manager.shutdown_all(CloseReason::Shutdown).await;
+    }
+
+    /// Delays waited between connections that each lasted `uptime`
+    fn reconnect_delays(strategy: BackoffStrategy, uptime: Duration, connections: usize) -> Vec<u64> {
+        let mut attempt = 0;
+        (0..connections)
+            .map(|_| {
+                attempt = reconnect_attempt_after(attempt, uptime);
+                let waited = strategy.delay(attempt).as_secs();
+                attempt += 1;
+                waited
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reconnect_backoff_flapping_vs_stable() {
+        // Connections that drop right away escalate like failed connects
+        assert_eq!(reconnect_delays(DEFAULT_DATA_BACKOFF, Duration::from_millis(50), 7), [1, 2, 4, 8, 16, 30, 30]);
+        // Connections that stay up start over every time
+        assert_eq!(reconnect_delays(DEFAULT_DATA_BACKOFF, STABLE_CONNECTION, 4), [1, 1, 1, 1]);
+        // A fixed strategy never escalates
+        let fixed = BackoffStrategy::Fixed(Duration::from_secs(2));
+        assert_eq!(reconnect_delays(fixed, Duration::from_millis(50), 4), [2, 2, 2, 2]);
+
+        // A stable connection after a run of flaps resets the delay
+        assert_eq!(reconnect_attempt_after(4, STABLE_CONNECTION - Duration::from_millis(1)), 4);
+        assert_eq!(reconnect_attempt_after(4, STABLE_CONNECTION), 0);
+    }
+
+    #[test]
+    fn test_should_kill_on_disconnect() {
+        assert!(should_kill_on_disconnect(true, &Ok(None)));
+        assert!(!should_kill_on_disconnect(false, &Ok(None)));
+        // Exits and bridge errors keep their usual handling
+        assert!(!should_kill_on_disconnect(true, &Ok(Some(0))));
+        assert!(!should_kill_on_disconnect(true, &Err(anyhow!("bridge failed"))));
+    }
+
+    #[tokio::test]
+    async fn test_bridge_setup_failure_reports_exit() {
+        let handle = PtyHandle::spawn(&SpawnOptions::sh("sleep 30")).unwrap();
+        let handshake = build_handshake("/bin/sh", &handle, 80, 24);
+        let pty = AsyncPty::new(handle).unwrap();
+        // The bridge can't take a reader that's already been taken
+        let _stolen = pty.start_reader().await.unwrap();
+
+        let (_shutdown_tx, shutdown_rx) = oneshot::channel();
+        let url = Url::parse("ws://127.0.0.1:1/ws/terminal-data/s/1").unwrap();
+        let result = tokio::time::timeout(
+            Duration::from_secs(5),
+            run_terminal_task(
+                "1".to_string(),
+                pty,
+                Arc::new(RwLock::new(url)),
+                handshake,
+                test_context(shutdown_rx),
+                TerminalTaskOptions::default(),
+            ),
+        )
+        .await
+        .expect("task kept retrying after a fatal setup error");
+        // The shell was killed rather than left running unbridged
+        assert_ne!(result.unwrap(), 0);
+    }
+
+    /// What the recording relay got on a data connection
+    #[derive(Debug, PartialEq)]
+    enum Received {
+        Output(Vec<u8>),
+        Exit(i32),
+        Close,
+    }
+
+    /// Relay that takes one data connection and records what arrives, with
+    /// arrival times, until the connection closes
+    async fn recording_relay() -> (Url, tokio::task::JoinHandle<Vec<(Received, Instant)>>) {
+        use crate::protocol::client_prefix;
+        use futures_util::StreamExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            let mut received = Vec::new();
+            while let Some(Ok(msg)) = ws.next().await {
+                let frame = match msg {
+                    Message::Binary(data) => match data.split_first() {
+                        Some((&client_prefix::OUTPUT, payload)) => Received::Output(payload.to_vec()),
+                        Some((&client_prefix::EXIT, payload)) => Received::Exit(serde_json::from_slice(payload).unwrap()),
+                        _ => continue,
+                    },
+                    Message::Close(_) => Received::Close,
+                    _ => continue,
+                };
+                let closed = frame == Received::Close;
+                received.push((frame, Instant::now()));
+                if closed {
+                    break;
+                }
+            }
+            received
+        });
+        (url, server)
+    }
+
+    /// A terminal task for a shell script, as started by the manager
+    struct TestTask {
+        task: tokio::task::JoinHandle<Result<i32>>,
+        shutdown_tx: oneshot::Sender<CloseReason>,
+        pid: u32,
+    }
+
+    /// Run `script` in a terminal task connecting to `url`
+    fn spawn_task(script: &str, url: Url, options: TerminalTaskOptions) -> TestTask {
+        let handle = PtyHandle::spawn(&SpawnOptions::sh(script)).unwrap();
+        let handshake = build_handshake("/bin/sh", &handle, 80, 24);
+        let pid = handle.process_id().unwrap();
+        let pty = AsyncPty::new(handle).unwrap();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task = tokio::spawn(run_terminal_task(
+            "1".to_string(),
+            pty,
+            Arc::new(RwLock::new(url)),
+            handshake,
+            test_context(shutdown_rx),
+            options,
+        ));
+        TestTask { task, shutdown_tx, pid }
+    }
+
+    /// Exit code of a task expected to finish within a few seconds
+    async fn finished(task: tokio::task::JoinHandle<Result<i32>>) -> i32 {
+        tokio::time::timeout(Duration::from_secs(5), task).await.expect("task did not finish").unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_final_output_precedes_close_frame() {
+        let (url, relay) = recording_relay().await;
+        let options = BridgeOptions { exit_flush_delay: Duration::from_millis(300), ..BridgeOptions::default() };
+        let task = spawn_task(
+            "sleep 0.2; printf goodbye",
+            url,
+            TerminalTaskOptions { bridge: options, ..TerminalTaskOptions::default() },
+        );
+        assert_eq!(finished(task.task).await, 0);
+
+        let received = relay.await.unwrap();
+        let (last, rest) = received.split_last().unwrap();
+        let (exit, output) = rest.split_last().unwrap();
+        let output: Vec<u8> = output
+            .iter()
+            .flat_map(|(frame, _)| match frame {
+                Received::Output(data) => data.clone(),
+                other => panic!("unexpected {:?}", other),
+            })
+            .collect();
+        assert!(String::from_utf8_lossy(&output).contains("goodbye"), "{:?}", received);
+        assert_eq!((&exit.0, &last.0), (&Received::Exit(0), &Received::Close));
+        // The close held back for the flush delay after the exit notice
+        assert!(last.1 - exit.1 >= Duration::from_millis(250), "{:?}", last.1 - exit.1);
+    }
+
+    #[test]
+    fn test_motd_banner_without_color() {
+        assert_eq!(motd_banner("Session recorded", true), b"\x1b[7m Session recorded \x1b[0m\r\n");
+        assert_eq!(motd_banner("Session recorded", false), b"Session recorded\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_motd_is_first_output() {
+        let (url, relay) = recording_relay().await;
+        let motd = motd_banner("Session recorded", true);
+        let task = spawn_task(
+            "printf shell-output",
+            url,
+            TerminalTaskOptions {
+                bridge: BridgeOptions { motd: Some(motd.clone()), ..BridgeOptions::default() },
+                ..TerminalTaskOptions::default()
+            },
+        );
+        assert_eq!(finished(task.task).await, 0);
+
+        let outputs: Vec<Vec<u8>> = relay
+            .await
+            .unwrap()
+            .into_iter()
+            .filter_map(|(frame, _)| match frame {
+                Received::Output(data) => Some(data),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(outputs.first(), Some(&motd), "{:?}", outputs);
+        let rest: Vec<u8> = outputs[1..].concat();
+        assert!(String::from_utf8_lossy(&rest).contains("shell-output"), "{:?}", outputs);
+    }
+
+    #[tokio::test]
+    async fn test_quick_retries_of_first_data_connection() {
+        // Relay that isn't ready for the first two attempts
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!("ws://{}/ws/terminal-data/s/1", listener.local_addr().unwrap())).unwrap();
+        let server = tokio::spawn(async move {
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().await.unwrap();
+                drop(stream);
+            }
+            let (stream, _) = listener.accept().await.unwrap();
+            let connected_at = Instant::now();
+            let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+            (connected_at, ws)
+        });
+
+        let started = Instant::now();
+        let task = spawn_task(
+            "sleep 5",
+            url,
+            TerminalTaskOptions {
+                quick_retries: QuickRetries { attempts: 3, interval: Duration::from_millis(100) },
+                ..TerminalTaskOptions::default()
+            },
+        );
+
+        // The backoff alone would have waited 1s and then 2s
+        let (connected_at, _ws) = tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        let elapsed = connected_at - started;
+        assert!(elapsed < Duration::from_millis(900), "third attempt took {:?}", elapsed);
+
+        task.shutdown_tx.send(CloseReason::default()).unwrap();
+        assert_eq!(finished(task.task).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_command_timeout_kills_command() {
+        let (url, relay) = recording_relay().await;
+        let started = Instant::now();
+        let task = spawn_task(
+            "sleep 10",
+            url,
+            TerminalTaskOptions { command_timeout: Some(Duration::from_secs(1)), ..TerminalTaskOptions::default() },
+        );
+
+        assert_eq!(finished(task.task).await, COMMAND_TIMEOUT_EXIT_CODE);
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(!is_process_alive(task.pid));
+        let received = relay.await.unwrap();
+        assert!(received.iter().any(|(frame, _)| *frame == Received::Exit(COMMAND_TIMEOUT_EXIT_CODE)), "{:?}", received);
+    }
+
+    #[tokio::test]
+    async fn test_terminate_pty() {
+        let handle = PtyHandle::spawn(&SpawnOptions::sh("sleep 5")).unwrap();
+        let bridge = Bridge::new(AsyncPty::new(handle).unwrap(), 80, 24, BridgeOptions::default())
+            .await
+            .unwrap();
+        bridge.terminate_pty().await;
+        assert!(!bridge.is_pty_alive().await);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_handshake_includes_tty() {
+        let mut pty = PtyHandle::spawn(&SpawnOptions::sh("sleep 1")).unwrap();
+        let handshake = build_handshake("/bin/sh", &pty, 100, 30);
+        let _ = pty.kill();
+
+        let json = serde_json::to_value(&handshake).unwrap();
+        let tty = json["tty"].as_str().expect("tty in handshake");
+        assert!(tty.starts_with("/dev/pts/"), "{}", tty);
+    }
+
+    #[test]
+    fn test_handshake_encodes_requested_size() {
+        let mut pty = PtyHandle::spawn(&SpawnOptions { cols: 132, rows: 43, ..SpawnOptions::sh("sleep 1") }).unwrap();
+        let handshake = build_handshake("/bin/sh", &pty, 132, 43);
+        let _ = pty.kill();
+
+        let encoded = ClientMessage::Handshake(handshake).encode().unwrap();
+        assert_eq!(encoded[0], b'1');
+        let json: serde_json::Value = serde_json::from_slice(&encoded[1..]).unwrap();
+        assert_eq!(json["cols"], 132);
+        assert_eq!(json["rows"], 43);
     }
 }
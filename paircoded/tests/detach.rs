@@ -0,0 +1,70 @@
+//! Smoke test of `--detach`: the real binary forks into the background and the
+//! foreground process reports how the daemon's startup went.
+
+#![cfg(unix)]
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+/// Wait up to five seconds for `path` to contain `text`
+fn wait_for_text(path: &Path, text: &str) -> String {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let contents = std::fs::read_to_string(path).unwrap_or_default();
+        if contents.contains(text) || Instant::now() >= deadline {
+            return contents;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_detach_reports_failed_startup() {
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("paircoded.log");
+    let pid = dir.path().join("paircoded.pid");
+
+    // A missing client certificate fails right after the fork, without touching the network
+    let cert = dir.path().join("missing.pem");
+    let output = Command::new(env!("CARGO_BIN_EXE_paircoded"))
+        .arg("--detach")
+        .arg("--log-file")
+        .arg(&log)
+        .arg("--pid-file")
+        .arg(&pid)
+        .arg("--client-cert")
+        .arg(&cert)
+        .arg("--client-key")
+        .arg(&cert)
+        .arg("--allow-root")
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path())
+        .env_remove("PAIRCODED_GITHUB_TOKEN")
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("exited during startup"), "{}", stderr);
+    assert!(stderr.contains(log.to_str().unwrap()), "{}", stderr);
+
+    // The daemon's own error went to the log, and its pid file was cleaned up
+    let contents = wait_for_text(&log, "failed to read client certificate");
+    assert!(contents.contains("failed to read client certificate"), "{}", contents);
+    assert!(!pid.exists());
+}
+
+#[test]
+fn test_detach_needs_log_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_paircoded"))
+        .arg("--detach")
+        .env("HOME", dir.path())
+        .env("XDG_CONFIG_HOME", dir.path())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--log-file"));
+}
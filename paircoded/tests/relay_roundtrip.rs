@@ -0,0 +1,94 @@
+//! End-to-end test: a real PTY bridged to the scripted test server over localhost.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use paircoded::bridge::BridgeOptions;
+use paircoded::cgroup::ResourceLimits;
+use paircoded::scripted_relay::{self, Scenario, ScenarioReport, ServerOptions};
+use paircoded::terminal_manager::{
+    SharedToken, TerminalEvent, TerminalManager, TerminalOptions, DEFAULT_DATA_BACKOFF, DEFAULT_QUICK_RETRIES,
+};
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+use url::Url;
+
+/// Run one terminal against the test server, returning the server's report
+/// and the manager's exit event for the terminal
+async fn run_scenario(server_options: ServerOptions) -> (ScenarioReport, (String, i32)) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let server = tokio::spawn(async move { scripted_relay::serve_one(&listener, &server_options).await.unwrap() });
+
+    let options = TerminalOptions {
+        shell: "/bin/sh".to_string(),
+        shell_args: Vec::new(),
+        working_dir: std::env::temp_dir(),
+        env: Vec::new(),
+        sandboxed: false,
+        resource_limits: ResourceLimits::default(),
+        username: "testuser".to_string(),
+        audit_log: None,
+        headers: Vec::new(),
+        handshake_timeout: None,
+        tls_sni: None,
+        keepalive: None,
+        text_output: false,
+        reconnect: DEFAULT_DATA_BACKOFF,
+        quick_retries: DEFAULT_QUICK_RETRIES,
+        motd: None,
+        command_timeout: None,
+        data_url_template: paircoded::relay::DEFAULT_DATA_URL_TEMPLATE.to_string(),
+        state_file: None,
+        kill_on_disconnect: false,
+        max_reader_restarts: paircoded::pty::DEFAULT_MAX_READER_RESTARTS,
+        default_size: (80, 24),
+        bridge: BridgeOptions::default(),
+    };
+    let base_url = Url::parse(&format!("ws://127.0.0.1:{}/ws/control/test-session", port)).unwrap();
+    let token: SharedToken = Arc::new(RwLock::new(String::new()));
+    let (manager, mut events) = TerminalManager::new(base_url, "test-session", token, options);
+
+    let name = manager.start_terminal(80, 24, &HashMap::new()).await.unwrap();
+
+    let report = tokio::time::timeout(Duration::from_secs(20), server)
+        .await
+        .expect("scenario timed out")
+        .unwrap();
+
+    match tokio::time::timeout(Duration::from_secs(5), events.recv()).await {
+        Ok(Some(TerminalEvent::Exited { name: exited, exit_code })) => {
+            assert_eq!(exited, name);
+            (report, (exited, exit_code))
+        }
+        other => panic!("expected exit event, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn test_echo_round_trips_through_relay() {
+    let (report, (_, exit_code)) = run_scenario(ServerOptions {
+        scenario: Scenario::Basic,
+        commands: vec!["echo hello".to_string()],
+    })
+    .await;
+
+    let output = String::from_utf8_lossy(&report.output);
+    assert!(output.contains("hello"), "output was {:?}", output);
+    assert_eq!(report.exit_code, Some(0));
+    assert_eq!(report.failures, 0);
+    assert_eq!(exit_code, 0);
+}
+
+#[tokio::test]
+async fn test_pause_resume_and_snapshot_scenarios_pass() {
+    let (report, _) = run_scenario(ServerOptions {
+        scenario: Scenario::All,
+        commands: vec!["true".to_string()],
+    })
+    .await;
+
+    assert_eq!(report.failures, 0);
+    assert_eq!(report.exit_code, Some(0));
+}
@@ -34,6 +34,21 @@ export const CLIENT_PREFIX = {
   SNAPSHOT: 0x33,  // '3'
 } as const;
 
+// Close reasons paircoded sends (with code 1000) when it closes a connection on purpose
+export const GRACEFUL_CLOSE_REASONS: ReadonlySet<string> = new Set([
+  'client shutdown',
+  'client exiting after terminal exit',
+  'terminal exited',
+  'terminal closed',
+]);
+
+/**
+ * Whether paircoded closed the connection deliberately (as opposed to a network drop)
+ */
+export function isGracefulClose(code: number, reason: string): boolean {
+  return code === 1000 && GRACEFUL_CLOSE_REASONS.has(reason);
+}
+
 export interface ResizeMessage {
   cols: number;
   rows: number;
@@ -96,7 +111,23 @@ export interface CloseTerminalMessage {
   signal?: number;
 }
 
-export type ControlMessage = StartTerminalMessage | CloseTerminalMessage;
+export interface SignalTerminalMessage {
+  type: 'signal_terminal';
+  name: string;
+  signal: number;
+}
+
+export interface RequestScrollbackMessage {
+  type: 'request_scrollback';
+  name: string;
+  requestId: string;
+}
+
+export type ControlMessage =
+  | StartTerminalMessage
+  | CloseTerminalMessage
+  | SignalTerminalMessage
+  | RequestScrollbackMessage;
 
 /**
  * Control responses received from paircoded on the control connection.
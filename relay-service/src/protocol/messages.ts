@@ -142,6 +142,7 @@ import type {
   ControlResponse,
   StartTerminalMessage,
   CloseTerminalMessage,
+  SignalTerminalMessage,
   BrowserSetupMessage,
   SetupResponse,
 } from './index.js';
@@ -178,6 +179,20 @@ export function createCloseTerminalMessage(
   };
 }
 
+/**
+ * Create a signal_terminal control message (the terminal stays open).
+ */
+export function createSignalTerminalMessage(
+  name: string,
+  signal: number
+): SignalTerminalMessage {
+  return {
+    type: 'signal_terminal',
+    name,
+    signal,
+  };
+}
+
 /**
  * Parse a control response from paircoded.
  */
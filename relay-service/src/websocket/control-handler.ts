@@ -13,6 +13,7 @@ import {
   parseControlResponse,
   createStartTerminalMessage,
   createSetupResponse,
+  isGracefulClose,
 } from '../protocol/index.js';
 import { SessionManager, SessionState } from '../session/index.js';
 import { createChildLogger } from '../utils/logger.js';
@@ -236,8 +237,8 @@ function handleControlDisconnect(
     return;
   }
 
-  // Check if this was a graceful shutdown (close code 1000 with "client shutdown" reason)
-  const isGracefulShutdown = closeCode === 1000 && closeReason === 'client shutdown';
+  // Check if this was a graceful shutdown (close code 1000 with a paircoded close reason)
+  const isGracefulShutdown = isGracefulClose(closeCode, closeReason);
 
   if (isGracefulShutdown) {
     // Graceful shutdown - immediately close session
@@ -8,7 +8,7 @@
  */
 
 import type { WebSocket, RawData } from 'ws';
-import { parseClientMessage, createResizeMessage, type ParsedSnapshotMessage } from '../protocol/index.js';
+import { parseClientMessage, createResizeMessage, isGracefulClose, type ParsedSnapshotMessage } from '../protocol/index.js';
 import { SessionManager, Session, SessionState, type ClientState } from '../session/index.js';
 import { createChildLogger } from '../utils/logger.js';
 import { extractBearerToken, verifyToken } from '../server/jwt.js';
@@ -270,7 +270,7 @@ function handleDataDisconnect(
   if (!terminal) return;
 
   // Check if this was a graceful shutdown
-  const isGracefulShutdown = closeCode === 1000 && closeReason === 'client shutdown';
+  const isGracefulShutdown = isGracefulClose(closeCode, closeReason);
 
   if (isGracefulShutdown) {
     // Graceful shutdown - notify clients that session ended